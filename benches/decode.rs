@@ -0,0 +1,60 @@
+//! How quickly page writes are encoded and decoded.
+//!
+//! Run it with and without the word-at-a-time escape scan to compare:
+//!
+//! ```text
+//! cargo bench --bench decode
+//! cargo bench --bench decode --features fast-scan
+//! ```
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use tockloader_proto::consts::{INT_PAGE_SIZE, MAX_FRAME_LEN};
+use tockloader_proto::{encode_all, Command, CommandDecoder};
+
+const ROUNDS: u32 = 20_000;
+
+fn main() {
+    // One escape near the end, as in most real pages
+    let mut page = [0u8; INT_PAGE_SIZE];
+    for (i, b) in page.iter_mut().enumerate() {
+        *b = (i % 251) as u8;
+    }
+    page[INT_PAGE_SIZE - 8] = 0xFC;
+    let cmd = Command::WritePage {
+        address: 0x30000,
+        data: &page,
+    };
+    let mut wire = [0u8; 2 * MAX_FRAME_LEN];
+
+    let start = Instant::now();
+    let mut len = 0;
+    for _ in 0..ROUNDS {
+        len = encode_all(black_box(&cmd), &mut wire).unwrap();
+    }
+    report("encode_all", start, len);
+
+    let mut decoder = CommandDecoder::new();
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let mut rest = black_box(&wire[0..len]);
+        while !rest.is_empty() {
+            let (used, result) = decoder.push_bytes(rest);
+            black_box(result.unwrap());
+            rest = &rest[used..];
+        }
+    }
+    report("push_bytes", start, len);
+}
+
+fn report(name: &str, start: Instant, frame_len: usize) {
+    let elapsed = start.elapsed();
+    let bytes = frame_len as f64 * f64::from(ROUNDS);
+    println!(
+        "{:<12} {:>8.1} ns/frame {:>8.1} MB/s",
+        name,
+        elapsed.as_nanos() as f64 / f64::from(ROUNDS),
+        bytes / elapsed.as_secs_f64() / 1e6
+    );
+}
@@ -0,0 +1,69 @@
+//! How quickly a page write is encoded: one byte at a time through the
+//! `CommandEncoder` iterator, in chunks with `next_chunk`, and all at once
+//! with `encode_all`.
+//!
+//! ```text
+//! cargo bench --bench encode
+//! ```
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use tockloader_proto::consts::{INT_PAGE_SIZE, MAX_FRAME_LEN};
+use tockloader_proto::{encode_all, Command, CommandEncoder, MAX_CHUNK_LEN};
+
+const ROUNDS: u32 = 20_000;
+
+fn main() {
+    // One escape near the end, as in most real pages
+    let mut page = [0u8; INT_PAGE_SIZE];
+    for (i, b) in page.iter_mut().enumerate() {
+        *b = (i % 251) as u8;
+    }
+    page[INT_PAGE_SIZE - 8] = 0xFC;
+    let cmd = Command::WritePage {
+        address: 0x30000,
+        data: &page,
+    };
+    let mut wire = [0u8; 2 * MAX_FRAME_LEN];
+
+    let start = Instant::now();
+    let mut len = 0;
+    for _ in 0..ROUNDS {
+        len = 0;
+        for byte in CommandEncoder::new(black_box(&cmd)).unwrap() {
+            wire[len] = byte;
+            len += 1;
+        }
+    }
+    report("iterator", start, len);
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        len = 0;
+        let mut encoder = CommandEncoder::new(black_box(&cmd)).unwrap();
+        while let Some(chunk) = encoder.next_chunk(MAX_CHUNK_LEN) {
+            wire[len..len + chunk.len()].copy_from_slice(chunk);
+            len += chunk.len();
+        }
+    }
+    report("next_chunk", start, len);
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        len = encode_all(black_box(&cmd), &mut wire).unwrap();
+    }
+    report("encode_all", start, len);
+    black_box(&wire);
+}
+
+fn report(name: &str, start: Instant, frame_len: usize) {
+    let elapsed = start.elapsed();
+    let bytes = frame_len as f64 * f64::from(ROUNDS);
+    println!(
+        "{:<12} {:>8.1} ns/frame {:>8.1} MB/s",
+        name,
+        elapsed.as_nanos() as f64 / f64::from(ROUNDS),
+        bytes / elapsed.as_secs_f64() / 1e6
+    );
+}
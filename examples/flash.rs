@@ -0,0 +1,89 @@
+//! Write an ELF file to a board over a serial port.
+//!
+//! ```text
+//! cargo run --example flash --features elf -- target.elf [/dev/ttyUSB0]
+//! ```
+//!
+//! Segments in internal flash are written where the linker put them. Ones
+//! in the memory mapped window of the external flash, if the board has one,
+//! go to the same offset in the external flash. Change `REGIONS` to match
+//! your board's linker script.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+extern crate tockloader_proto;
+
+use std::env;
+use std::fs;
+use std::process;
+
+use tockloader_proto::{FlashRegion, Host, Image, Progress};
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const BAUD: u32 = 115200;
+
+/// Internal flash, then (with `ext-flash`) 16 MiB of external flash mapped
+/// at 0x9000_0000.
+const REGIONS: &[FlashRegion] = &[
+    FlashRegion::internal(0x0000_0000, 0x10_0000),
+    #[cfg(feature = "ext-flash")]
+    FlashRegion::external(0x9000_0000, 0x100_0000, 0),
+];
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (path, port) = match args.as_slice() {
+        [path] => (path, "/dev/ttyUSB0"),
+        [path, port] => (path, port.as_str()),
+        _ => {
+            eprintln!("Usage: flash <file.elf> [serial port]");
+            process::exit(2);
+        }
+    };
+    let elf = fs::read(path).unwrap_or_else(|e| fail(&format!("Can't read {}: {}", path, e)));
+    let plan = Image::from_elf(&elf)
+        .and_then(|image| image.plan(REGIONS))
+        .unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    let mut host = Host::open(port, BAUD).unwrap_or_else(|e| fail(&e.to_string()));
+    host.set_progress(show_progress);
+    for image in &plan {
+        let first = image.pages().first().map_or(0, |p| p.address);
+        println!("{:?} flash: {} pages from {:#010x}", image.target(), image.len(), first);
+        if let Err(e) = host.write_paged(image) {
+            fail(&format!("Writing failed: {}", e));
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+fn show_progress(progress: &Progress) {
+    eprint!("\r{}/{} pages", progress.pages_done, progress.pages_total);
+    if progress.is_done() {
+        eprintln!(" ({:.0} bytes/s)", progress.bytes_per_sec());
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
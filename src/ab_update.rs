@@ -0,0 +1,443 @@
+//! Updates to one of two image slots, A and B.
+//!
+//! A board with room for two images runs from one slot while the host
+//! writes the other. Once the new image is written and its CRC checked, the
+//! host stores a `PendingSwap` attribute saying which slot to boot next and
+//! what should be in it. On its next start, the bootloader looks for that
+//! attribute, checks the slot still holds that image, and switches over.
+//! If anything goes wrong before the attribute is stored, the old image is
+//! untouched and still runs.
+//!
+//! On the host, `AbUpdate` does the writing, one command at a time, like
+//! the helpers in the `workflow` module. On the bootloader,
+//! `PendingSwap::find` and `PendingSwap::check` do the reading:
+//!
+//! ```ignore
+//! if let Some((index, swap)) = PendingSwap::find(&mut attributes)? {
+//!     if swap.check(&LAYOUT, &mut flash)? {
+//!         boot_from(LAYOUT.address(swap.slot));
+//!     }
+//!     attributes.set_attr(index, PendingSwap::KEY, &[])?;
+//! }
+//! ```
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use byteorder::{ByteOrder, LittleEndian};
+
+#[cfg(feature = "device")]
+use super::bootloader::{Attributes, Flash};
+use super::crc::crc32;
+#[cfg(feature = "device")]
+use super::device::FlashError;
+use super::known_attrs::KnownAttr;
+use super::pages::{FlashTarget, PadPolicy, PageWriter};
+use super::{Command, Error, Response};
+use super::{KEY_LEN, MAX_ATTR_LEN};
+#[cfg(feature = "device")]
+use super::MAX_INDEX;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// One of the two image slots.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Slot {
+    /// The first slot.
+    A,
+    /// The second slot.
+    B,
+}
+
+/// Where the two slots are in internal flash.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SlotLayout {
+    a: u32,
+    b: u32,
+    size: u32,
+}
+
+/// The attribute which tells the bootloader to boot from `slot` next, once
+/// it has checked the slot holds `length` bytes with a CRC of `crc`.
+/// Stored under the `swap` key.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PendingSwap {
+    /// The slot to boot from.
+    pub slot: Slot,
+    /// The length of the new image.
+    pub length: u32,
+    /// The CRC32 of the new image.
+    pub crc: u32,
+}
+
+/// Writes an image into the slot which isn't running, checks it, and
+/// marks it to be booted next.
+///
+/// Call `next_command`, send the command, and pass the decoded reply to
+/// `handle_response`. Repeat until `next_command` returns `None`. Every
+/// command sent gets a reply.
+#[derive(Clone)]
+pub struct AbUpdate<'a> {
+    image: &'a [u8],
+    writer: PageWriter<'a>,
+    pages_sent: usize,
+    swap: PendingSwap,
+    address: u32,
+    index: u8,
+    value: [u8; MAX_ATTR_LEN],
+    step: AbStep,
+}
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum AbStep {
+    Write,
+    Verify,
+    Mark,
+    Done,
+}
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+/// Slot, then the length and the CRC.
+const SWAP_LEN: usize = 9;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl Slot {
+    /// The other slot.
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+}
+
+impl SlotLayout {
+    /// Slots of `size` bytes at `a` and `b`. They shouldn't overlap, and
+    /// should start on page boundaries.
+    pub const fn new(a: u32, b: u32, size: u32) -> SlotLayout {
+        SlotLayout { a, b, size }
+    }
+
+    /// Where `slot` starts.
+    pub fn address(&self, slot: Slot) -> u32 {
+        match slot {
+            Slot::A => self.a,
+            Slot::B => self.b,
+        }
+    }
+
+    /// How big each slot is.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Which slot `address` is in, if either.
+    pub fn slot_at(&self, address: u32) -> Option<Slot> {
+        let within = |start: u32| address >= start && address - start < self.size;
+        if within(self.a) {
+            Some(Slot::A)
+        } else if within(self.b) {
+            Some(Slot::B)
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> KnownAttr<'a> for PendingSwap {
+    const KEY: &'static [u8; KEY_LEN] = b"swap\0\0\0\0";
+
+    fn encode(&self, buffer: &mut [u8; MAX_ATTR_LEN]) -> usize {
+        buffer[0] = match self.slot {
+            Slot::A => b'A',
+            Slot::B => b'B',
+        };
+        LittleEndian::write_u32(&mut buffer[1..5], self.length);
+        LittleEndian::write_u32(&mut buffer[5..9], self.crc);
+        SWAP_LEN
+    }
+
+    fn decode(value: &'a [u8]) -> Option<PendingSwap> {
+        if value.len() != SWAP_LEN {
+            return None;
+        }
+        let slot = match value[0] {
+            b'A' => Slot::A,
+            b'B' => Slot::B,
+            _ => return None,
+        };
+        Some(PendingSwap {
+            slot,
+            length: LittleEndian::read_u32(&value[1..5]),
+            crc: LittleEndian::read_u32(&value[5..9]),
+        })
+    }
+}
+
+#[cfg(feature = "device")]
+impl PendingSwap {
+    /// Look through `attributes` for a pending swap. Returns its index too,
+    /// so it can be cleared once dealt with.
+    pub fn find<A>(attributes: &mut A) -> Result<Option<(u8, PendingSwap)>, FlashError>
+    where
+        A: Attributes,
+    {
+        let mut key = [0u8; KEY_LEN];
+        let mut value = [0u8; MAX_ATTR_LEN];
+        for index in 0..MAX_INDEX {
+            let len = match attributes.get_attr(index, &mut key, &mut value) {
+                Ok(len) => len,
+                // There are fewer slots than that
+                Err(FlashError::BadArguments) => break,
+                Err(e) => return Err(e),
+            };
+            if key != *<PendingSwap as KnownAttr>::KEY {
+                continue;
+            }
+            if let Some(swap) = value.get(0..len).and_then(PendingSwap::decode) {
+                return Ok(Some((index, swap)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Does the slot in `layout` hold the image this swap is for?
+    pub fn check<F>(&self, layout: &SlotLayout, flash: &mut F) -> Result<bool, FlashError>
+    where
+        F: Flash,
+    {
+        if self.length > layout.size() {
+            return Ok(false);
+        }
+        Ok(flash.crc_range(layout.address(self.slot), self.length)? == self.crc)
+    }
+}
+
+impl<'a> AbUpdate<'a> {
+    /// Write `image` into whichever slot of `layout` isn't `active`, then
+    /// store the `PendingSwap` attribute at `index`. Returns
+    /// `Error::BadArguments` if the image is empty or won't fit in a slot.
+    /// A partial last page is padded with 0xFF.
+    pub fn new(
+        layout: &SlotLayout,
+        active: Slot,
+        index: u8,
+        image: &'a [u8],
+    ) -> Result<AbUpdate<'a>, Error> {
+        AbUpdate::with_pad_policy(layout, active, index, image, PadPolicy::Fill(0xFF))
+    }
+
+    /// Like `new`, but padding a partial last page as `policy` says. With
+    /// `PadPolicy::Reject`, the image has to be a whole number of pages.
+    pub fn with_pad_policy(
+        layout: &SlotLayout,
+        active: Slot,
+        index: u8,
+        image: &'a [u8],
+        policy: PadPolicy,
+    ) -> Result<AbUpdate<'a>, Error> {
+        if image.is_empty() || image.len() as u64 > layout.size() as u64 {
+            return Err(Error::BadArguments);
+        }
+        let slot = active.other();
+        let address = layout.address(slot);
+        Ok(AbUpdate {
+            image,
+            writer: PageWriter::with_pad_policy(FlashTarget::Internal, address, image, policy)?,
+            pages_sent: 0,
+            swap: PendingSwap {
+                slot,
+                length: image.len() as u32,
+                crc: crc32(image),
+            },
+            address,
+            index,
+            value: [0u8; MAX_ATTR_LEN],
+            step: AbStep::Write,
+        })
+    }
+
+    /// Get the next command to send, or `None` when the update is ready to
+    /// boot.
+    pub fn next_command(&mut self) -> Option<Command<'_>> {
+        match self.step {
+            AbStep::Write => {
+                self.pages_sent += 1;
+                self.writer.next_command()
+            }
+            AbStep::Verify => Some(Command::CrcIntFlash {
+                address: self.address,
+                length: self.swap.length,
+            }),
+            AbStep::Mark => Some(self.swap.set_command(self.index, &mut self.value)),
+            AbStep::Done => None,
+        }
+    }
+
+    /// Process the reply to the last command from `next_command`.
+    ///
+    /// Returns `Error::Refused` if the bootloader sent back an error,
+    /// `Error::CrcMismatch` if the slot didn't verify, and
+    /// `Error::MismatchedResponse` for any other unexpected reply.
+    pub fn handle_response(&mut self, response: &Response) -> Result<(), Error> {
+        if response.is_error() {
+            return Err(Error::Refused);
+        }
+        match (self.step, response) {
+            (AbStep::Write, &Response::Ok) => {
+                if self.pages_sent >= self.writer.num_pages() {
+                    self.step = AbStep::Verify;
+                }
+                Ok(())
+            }
+            (AbStep::Verify, &Response::CrcIntFlash { crc }) => {
+                if crc != crc32(self.image) {
+                    return Err(Error::CrcMismatch);
+                }
+                self.step = AbStep::Mark;
+                Ok(())
+            }
+            (AbStep::Mark, &Response::Ok) => {
+                self.step = AbStep::Done;
+                Ok(())
+            }
+            _ => Err(Error::MismatchedResponse),
+        }
+    }
+
+    /// Has the new image been written, checked and marked?
+    pub fn is_done(&self) -> bool {
+        self.step == AbStep::Done
+    }
+
+    /// The slot being written, and the attribute which will be stored.
+    pub fn pending_swap(&self) -> PendingSwap {
+        self.swap
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(all(test, feature = "device"))]
+mod tests {
+    use super::*;
+    use super::super::attributes::AttributeStore;
+    use super::super::INT_PAGE_SIZE;
+
+    const LAYOUT: SlotLayout = SlotLayout::new(0x40000, 0x60000, 0x20000);
+
+    /// Slot B of `LAYOUT`.
+    struct SlotB([u8; 4 * INT_PAGE_SIZE]);
+
+    impl Flash for SlotB {
+        fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), FlashError> {
+            let start = address.checked_sub(0x60000).ok_or(FlashError::BadAddress)? as usize;
+            let data = self.0.get(start..start + buffer.len()).ok_or(FlashError::BadAddress)?;
+            buffer.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn write_page(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+            let start = (address - 0x60000) as usize;
+            self.0[start..start + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn erase_page(&mut self, _address: u32) -> Result<(), FlashError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn check_layout() {
+        assert_eq!(LAYOUT.address(Slot::A.other()), 0x60000);
+        assert_eq!(LAYOUT.slot_at(0x5FFFF), Some(Slot::A));
+        assert_eq!(LAYOUT.slot_at(0x60000), Some(Slot::B));
+        assert_eq!(LAYOUT.slot_at(0x80000), None);
+        assert!(AbUpdate::new(&LAYOUT, Slot::A, 0, &[0; 0x20001]).is_err());
+    }
+
+    #[test]
+    fn check_update() {
+        let mut flash = SlotB([0xFF; 4 * INT_PAGE_SIZE]);
+        let mut attributes = AttributeStore::new();
+        let image = [0x42u8; 3 * INT_PAGE_SIZE - 10];
+        let mut update = AbUpdate::new(&LAYOUT, Slot::A, 3, &image).unwrap();
+        while let Some(command) = update.next_command() {
+            let response = match command {
+                Command::WritePage { address, data } => {
+                    flash.write_page(address, data).unwrap();
+                    Response::Ok
+                }
+                Command::CrcIntFlash { address, length } => Response::CrcIntFlash {
+                    crc: flash.crc_range(address, length).unwrap(),
+                },
+                Command::SetAttr { index, key, value } => {
+                    attributes.set_attr(index, key, value).unwrap();
+                    Response::Ok
+                }
+                c => panic!("{:?}", c),
+            };
+            update.handle_response(&response).unwrap();
+        }
+        assert!(update.is_done());
+
+        // What the bootloader sees on its next start
+        let (index, swap) = PendingSwap::find(&mut attributes).unwrap().unwrap();
+        assert_eq!((index, swap), (3, update.pending_swap()));
+        assert_eq!(swap.slot, Slot::B);
+        assert_eq!(swap.check(&LAYOUT, &mut flash), Ok(true));
+        flash.0[100] = 0;
+        assert_eq!(swap.check(&LAYOUT, &mut flash), Ok(false));
+    }
+
+    #[test]
+    fn check_update_errors() {
+        let image = [0x42u8; INT_PAGE_SIZE];
+        let mut update = AbUpdate::new(&LAYOUT, Slot::B, 0, &image).unwrap();
+        assert_eq!(
+            update.next_command(),
+            Some(Command::WritePage {
+                address: 0x40000,
+                data: &image,
+            })
+        );
+        assert_eq!(update.handle_response(&Response::Ok), Ok(()));
+        update.next_command();
+        let bad = Response::CrcIntFlash { crc: 0 };
+        assert_eq!(update.handle_response(&bad), Err(Error::CrcMismatch));
+        assert_eq!(update.handle_response(&Response::BadAddress), Err(Error::Refused));
+        assert!(!update.is_done());
+
+        assert_eq!(AbUpdate::new(&LAYOUT, Slot::B, 0, &[]).err(), Some(Error::BadArguments));
+        let short = &image[0..100];
+        assert!(AbUpdate::with_pad_policy(&LAYOUT, Slot::B, 0, short, PadPolicy::Reject).is_err());
+    }
+}
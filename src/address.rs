@@ -0,0 +1,246 @@
+//! Page aligned flash addresses.
+//!
+//! The bootloader answers `BadAddress` if a page command is given an
+//! address which isn't the start of a page. `IntFlashAddr` and
+//! `ExtFlashAddr` can only hold page aligned addresses, so building the
+//! command from one can't go wrong that way, and the commands they build
+//! take the page as an array, so a short page won't compile.
+//!
+//! How big an external flash erase block is depends on the chip. The spec
+//! assumes 8 pages of 256 bytes; an `ExtFlashGeometry` describes any other.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::{Command, Error, EXT_PAGE_SIZE, INT_PAGE_SIZE};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// The start of a page in internal flash.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct IntFlashAddr(u32);
+
+/// The start of a page in external flash.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct ExtFlashAddr(u32);
+
+/// The layout of an external flash chip: how much `EraseExBlock` erases,
+/// and how much `EraseExPage` does. Both are powers of two. The default is
+/// the spec's, 2048 byte blocks of 256 byte pages.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct ExtFlashGeometry {
+    block_size: u32,
+    page_size: u32,
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl IntFlashAddr {
+    /// Returns `Error::BadArguments` if `address` isn't the start of a page.
+    pub fn new(address: u32) -> Result<IntFlashAddr, Error> {
+        check_aligned(address, INT_PAGE_SIZE).map(IntFlashAddr)
+    }
+
+    /// The start of the page holding `address`.
+    pub fn containing(address: u32) -> IntFlashAddr {
+        IntFlashAddr(address & !(INT_PAGE_SIZE as u32 - 1))
+    }
+
+    /// The address.
+    pub fn get(self) -> u32 {
+        self.0
+    }
+
+    /// The page after this one, or `None` at the top of the address space.
+    pub fn next_page(self) -> Option<IntFlashAddr> {
+        self.0.checked_add(INT_PAGE_SIZE as u32).map(IntFlashAddr)
+    }
+
+    /// Erase this page.
+    pub fn erase(self) -> Command<'static> {
+        Command::ErasePage { address: self.0 }
+    }
+
+    /// Write `data` to this page.
+    pub fn write(self, data: &[u8; INT_PAGE_SIZE]) -> Command<'_> {
+        Command::WritePage {
+            address: self.0,
+            data,
+        }
+    }
+}
+
+impl ExtFlashAddr {
+    /// Returns `Error::BadArguments` if `address` isn't the start of an
+    /// external flash page.
+    pub fn new(address: u32) -> Result<ExtFlashAddr, Error> {
+        check_aligned(address, EXT_PAGE_SIZE).map(ExtFlashAddr)
+    }
+
+    /// The start of the external flash page holding `address`.
+    pub fn containing(address: u32) -> ExtFlashAddr {
+        ExtFlashAddr(address & !(EXT_PAGE_SIZE as u32 - 1))
+    }
+
+    /// The address.
+    pub fn get(self) -> u32 {
+        self.0
+    }
+
+    /// The page after this one, or `None` at the top of the address space.
+    pub fn next_page(self) -> Option<ExtFlashAddr> {
+        self.0.checked_add(EXT_PAGE_SIZE as u32).map(ExtFlashAddr)
+    }
+
+    /// Erase this page.
+    #[cfg(feature = "ext-flash")]
+    pub fn erase(self) -> Command<'static> {
+        Command::EraseExPage { address: self.0 }
+    }
+
+    /// Write `data` to this page.
+    #[cfg(feature = "ext-flash")]
+    pub fn write(self, data: &[u8; EXT_PAGE_SIZE]) -> Command<'_> {
+        Command::WriteExPage {
+            address: self.0,
+            data,
+        }
+    }
+}
+
+impl ExtFlashGeometry {
+    /// Blocks of `block_size` bytes, made of pages of `page_size` bytes.
+    /// Returns `Error::BadArguments` unless both are powers of two and
+    /// `EXT_PAGE_SIZE <= page_size <= block_size`, so that a `WriteExPage`
+    /// never spans two erase pages.
+    pub fn new(block_size: u32, page_size: u32) -> Result<ExtFlashGeometry, Error> {
+        if !block_size.is_power_of_two()
+            || !page_size.is_power_of_two()
+            || page_size < EXT_PAGE_SIZE as u32
+            || page_size > block_size
+        {
+            return Err(Error::BadArguments);
+        }
+        Ok(ExtFlashGeometry {
+            block_size,
+            page_size,
+        })
+    }
+
+    /// The number of bytes `EraseExBlock` erases.
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// The number of bytes `EraseExPage` erases.
+    pub fn page_size(&self) -> u32 {
+        self.page_size
+    }
+
+    /// Whether `address` is the start of an erase block.
+    pub fn is_block_aligned(&self, address: u32) -> bool {
+        check_aligned(address, self.block_size as usize).is_ok()
+    }
+
+    /// Whether `address` is the start of an erase page.
+    pub fn is_page_aligned(&self, address: u32) -> bool {
+        check_aligned(address, self.page_size as usize).is_ok()
+    }
+
+    /// The start of the erase page holding `address`.
+    pub fn page_containing(&self, address: u32) -> u32 {
+        address & !(self.page_size - 1)
+    }
+}
+
+impl Default for ExtFlashGeometry {
+    fn default() -> ExtFlashGeometry {
+        ExtFlashGeometry {
+            block_size: 8 * EXT_PAGE_SIZE as u32,
+            page_size: EXT_PAGE_SIZE as u32,
+        }
+    }
+}
+
+impl From<IntFlashAddr> for u32 {
+    fn from(address: IntFlashAddr) -> u32 {
+        address.0
+    }
+}
+
+impl From<ExtFlashAddr> for u32 {
+    fn from(address: ExtFlashAddr) -> u32 {
+        address.0
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Page sizes are all powers of two.
+fn check_aligned(address: u32, page_size: usize) -> Result<u32, Error> {
+    if address & (page_size as u32 - 1) == 0 {
+        Ok(address)
+    } else {
+        Err(Error::BadArguments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_int_flash_addr() {
+        assert_eq!(IntFlashAddr::new(0x30001), Err(Error::BadArguments));
+        let address = IntFlashAddr::new(0x30000).unwrap();
+        assert_eq!(IntFlashAddr::containing(0x301FF), address);
+        assert_eq!(address.next_page().map(u32::from), Some(0x30200));
+        assert_eq!(address.erase(), Command::ErasePage { address: 0x30000 });
+        let page = [0xAA; INT_PAGE_SIZE];
+        assert_eq!(
+            address.write(&page),
+            Command::WritePage {
+                address: 0x30000,
+                data: &page,
+            }
+        );
+        assert_eq!(IntFlashAddr::new(0xFFFF_FE00).unwrap().next_page(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "ext-flash")]
+    fn check_ext_flash_addr() {
+        assert_eq!(ExtFlashAddr::new(0x180), Err(Error::BadArguments));
+        let address = ExtFlashAddr::containing(0x180);
+        assert_eq!(address.get(), 0x100);
+        assert_eq!(address.erase(), Command::EraseExPage { address: 0x100 });
+    }
+
+    #[test]
+    fn check_ext_geometry() {
+        let spec = ExtFlashGeometry::default();
+        assert_eq!((spec.block_size(), spec.page_size()), (2048, 256));
+        assert!(spec.is_block_aligned(0x1800) && !spec.is_block_aligned(0x1900));
+        let g = ExtFlashGeometry::new(0x10000, 0x1000).unwrap();
+        assert!(g.is_page_aligned(0x3000) && !g.is_page_aligned(0x3100));
+        assert_eq!(g.page_containing(0x3100), 0x3000);
+        assert_eq!(ExtFlashGeometry::new(0x10000, 0x20000), Err(Error::BadArguments));
+        assert_eq!(ExtFlashGeometry::new(0x3000, 0x1000), Err(Error::BadArguments));
+        assert_eq!(ExtFlashGeometry::new(0x1000, 0x80), Err(Error::BadArguments));
+    }
+}
@@ -0,0 +1,234 @@
+//! Turning a capture into a readable transcript.
+//!
+//! `analyze` replays a capture (see the `capture` module) and writes one
+//! line per command, response or error, with arrows showing which way it
+//! went:
+//!
+//! ```text
+//! → WRITE_PAGE addr=0x30000 len=512
+//! ← OK
+//! ```
+//!
+//! The names follow tockloader's (and the bootloader's `CMD_` and `RES_`
+//! constants), which makes it easier to line a transcript up against a
+//! debug log from the Python tool.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::fmt::{self, Write};
+use std::string::String;
+
+use super::capture::{Direction, Event, Record, Replay};
+#[cfg(feature = "baud-change")]
+use super::BaudMode;
+use super::{Command, Response};
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+/// Bytes shown as a string, with anything unprintable escaped.
+struct Text<'a>(&'a [u8]);
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Produce a transcript of the traffic in `records`.
+pub fn analyze<'a, I>(records: I) -> String
+where
+    I: IntoIterator<Item = Record<'a>>,
+{
+    let mut out = String::new();
+    let mut replay = Replay::new();
+    for record in records {
+        replay.feed(&record, |event| {
+            // Writing to a String can't fail
+            let _ = write_event(&mut out, &event);
+        });
+    }
+    out
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+fn write_event(out: &mut String, event: &Event) -> fmt::Result {
+    match *event {
+        Event::Command(ref command) => {
+            out.push_str("→ ");
+            write_command(out, command)?;
+        }
+        Event::Response(ref response) => {
+            out.push_str("← ");
+            write_response(out, response)?;
+        }
+        Event::Error(Direction::HostToDevice, e) => write!(out, "→ error: {}", e)?,
+        Event::Error(Direction::DeviceToHost, e) => write!(out, "← error: {}", e)?,
+    }
+    out.push('\n');
+    Ok(())
+}
+
+fn write_command(out: &mut String, command: &Command) -> fmt::Result {
+    match *command {
+        Command::Ping => write!(out, "PING"),
+        Command::Info => write!(out, "INFO"),
+        Command::Id => write!(out, "ID"),
+        Command::Reset => write!(out, "RESET"),
+        Command::ErasePage { address } => write!(out, "ERASE_PAGE addr={:#x}", address),
+        Command::WritePage { address, data } => {
+            write!(out, "WRITE_PAGE addr={:#x} len={}", address, data.len())
+        }
+        #[cfg(feature = "ext-flash")]
+        Command::EraseExBlock { address } => write!(out, "XEBLOCK addr={:#x}", address),
+        #[cfg(feature = "ext-flash")]
+        Command::WriteExPage { address, data } => {
+            write!(out, "XWPAGE addr={:#x} len={}", address, data.len())
+        }
+        Command::CrcRxBuffer => write!(out, "CRCRX"),
+        Command::ReadRange { address, length } => {
+            write!(out, "READ_RANGE addr={:#x} len={}", address, length)
+        }
+        #[cfg(feature = "ext-flash")]
+        Command::ExReadRange { address, length } => {
+            write!(out, "XRRANGE addr={:#x} len={}", address, length)
+        }
+        #[cfg(feature = "attributes")]
+        Command::SetAttr { index, key, value } => write!(
+            out,
+            "SET_ATTRIBUTE index={} key=\"{}\" value=\"{}\"",
+            index,
+            Text(key),
+            Text(value)
+        ),
+        #[cfg(feature = "attributes")]
+        Command::GetAttr { index } => write!(out, "GET_ATTRIBUTE index={}", index),
+        Command::CrcIntFlash { address, length } => {
+            write!(out, "CRC_INTERNAL_FLASH addr={:#x} len={}", address, length)
+        }
+        #[cfg(feature = "ext-flash")]
+        Command::CrcExtFlash { address, length } => {
+            write!(out, "CRCEF addr={:#x} len={}", address, length)
+        }
+        #[cfg(feature = "ext-flash")]
+        Command::EraseExPage { address } => write!(out, "XEPAGE addr={:#x}", address),
+        #[cfg(feature = "ext-flash")]
+        Command::ExtFlashInit => write!(out, "XFINIT"),
+        Command::ClockOut => write!(out, "CLKOUT"),
+        #[cfg(feature = "user-pages")]
+        Command::WriteFlashUserPages { page1, page2 } => {
+            write!(out, "WUSER page1={:#010x} page2={:#010x}", page1, page2)
+        }
+        #[cfg(feature = "baud-change")]
+        Command::ChangeBaud { mode, baud } => {
+            let mode = match mode {
+                BaudMode::Set => "set",
+                BaudMode::Verify => "verify",
+            };
+            write!(out, "CHANGE_BAUD_RATE mode={} baud={}", mode, baud)
+        }
+        #[cfg(feature = "multi-drop")]
+        Command::SetAddress { address } => write!(out, "SET_ADDRESS address={}", address),
+        #[cfg(feature = "long-attributes")]
+        Command::SetLongAttr { index, key, value } => write!(
+            out,
+            "SET_LONG_ATTRIBUTE index={} key=\"{}\" len={}",
+            index,
+            Text(key),
+            value.len()
+        ),
+        #[cfg(feature = "long-attributes")]
+        Command::GetLongAttr { index } => write!(out, "GET_LONG_ATTRIBUTE index={}", index),
+        Command::Exit => write!(out, "EXIT"),
+    }
+}
+
+fn write_response(out: &mut String, response: &Response) -> fmt::Result {
+    match *response {
+        Response::Overflow => write!(out, "OVERFLOW"),
+        Response::Pong => write!(out, "PONG"),
+        Response::BadAddress => write!(out, "BADADDR"),
+        Response::InternalError => write!(out, "INTERROR"),
+        Response::BadArguments => write!(out, "BADARGS"),
+        Response::Ok => write!(out, "OK"),
+        Response::Unknown => write!(out, "UNKNOWN"),
+        #[cfg(feature = "ext-flash")]
+        Response::ExtFlashTimeout => write!(out, "XFTIMEOUT"),
+        #[cfg(feature = "ext-flash")]
+        Response::ExtFlashPageError => write!(out, "XFEPE"),
+        Response::CrcRxBuffer { length, crc } => {
+            write!(out, "CRCRX len={} crc={:#010x}", length, crc)
+        }
+        Response::ReadRange { data } => write!(out, "READ_RANGE len={}", data.len()),
+        #[cfg(feature = "ext-flash")]
+        Response::ExReadRange { data } => write!(out, "XRRANGE len={}", data.len()),
+        #[cfg(feature = "attributes")]
+        Response::GetAttr { key, value } => write!(
+            out,
+            "GET_ATTRIBUTE key=\"{}\" value=\"{}\"",
+            Text(key),
+            Text(value)
+        ),
+        Response::CrcIntFlash { crc } => write!(out, "CRC_INTERNAL_FLASH crc={:#010x}", crc),
+        #[cfg(feature = "ext-flash")]
+        Response::CrcExtFlash { crc } => write!(out, "CRCXF crc={:#010x}", crc),
+        Response::Info { info } => write!(out, "INFO \"{}\"", Text(info)),
+        #[cfg(feature = "baud-change")]
+        Response::ChangeBaudFail => write!(out, "CHANGE_BAUD_FAIL"),
+        Response::Id { id } => write!(out, "ID id={:02x?}", id),
+        #[cfg(feature = "long-attributes")]
+        Response::GetLongAttr { key, value } => write!(
+            out,
+            "GET_LONG_ATTRIBUTE key=\"{}\" len={}",
+            Text(key),
+            value.len()
+        ),
+    }
+}
+
+impl<'a> fmt::Display for Text<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Keys are padded with nulls, which we don't want to see
+        let end = self.0.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+        for &b in &self.0[0..end] {
+            for c in core::ascii::escape_default(b) {
+                f.write_char(c as char)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "attributes"))]
+mod tests {
+    use super::*;
+    use super::super::capture::records;
+
+    #[test]
+    fn check_analyze() {
+        let capture = [
+            0, 0, 0, 0, 0, 12, 0, 1, b'b', b'o', b'a', b'r', b'd', 0, 0, 0, 2, b'h', b'i',
+            0, 12, 0, 0, 0, 2, 0, 0xFC, 0x13,
+            1, 14, 0, 0, 0, 2, 0, 0xFC, 0x15,
+            0, 16, 0, 0, 0, 2, 0, 0xFC, 0x06,
+        ];
+        assert_eq!(
+            analyze(records(&capture)),
+            "→ SET_ATTRIBUTE index=1 key=\"board\" value=\"hi\"\n\
+             ← OK\n\
+             → error: frame 0x06 should have 4 bytes of arguments, not 0\n"
+        );
+    }
+}
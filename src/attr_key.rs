@@ -0,0 +1,133 @@
+//! Attribute keys.
+//!
+//! On the wire, and in flash, a key is always 8 bytes with nulls on the
+//! end. An `AttrKey` takes care of the padding, so `AttrKey::new("board")`
+//! can go straight into `Command::set_attr`, and compares keys the way
+//! tockloader does, ignoring the trailing nulls.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::convert::TryFrom;
+use core::fmt;
+
+use super::{Error, KEY_LEN};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// An attribute key, null padded to `KEY_LEN` bytes.
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+pub struct AttrKey([u8; KEY_LEN]);
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl AttrKey {
+    /// Make a key from up to `KEY_LEN` bytes (or characters). Any trailing
+    /// nulls are dropped before the length is checked. Longer keys get
+    /// `Error::BadArguments`.
+    pub fn new<K: AsRef<[u8]> + ?Sized>(key: &K) -> Result<AttrKey, Error> {
+        let key = trim_key(key.as_ref());
+        if key.len() > KEY_LEN {
+            return Err(Error::BadArguments);
+        }
+        let mut padded = [0x00; KEY_LEN];
+        padded[0..key.len()].copy_from_slice(key);
+        Ok(AttrKey(padded))
+    }
+
+    /// Use a key that is already padded, such as one read from flash.
+    pub fn from_padded(key: [u8; KEY_LEN]) -> AttrKey {
+        AttrKey(key)
+    }
+
+    /// The key as sent on the wire, with its padding.
+    pub fn as_padded(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    /// The key without its padding.
+    pub fn as_bytes(&self) -> &[u8] {
+        trim_key(&self.0)
+    }
+
+    /// Whether `key` is this key, ignoring trailing nulls on either.
+    pub fn matches(&self, key: &[u8]) -> bool {
+        self.as_bytes() == trim_key(key)
+    }
+}
+
+impl<'a> TryFrom<&'a str> for AttrKey {
+    type Error = Error;
+
+    fn try_from(key: &'a str) -> Result<AttrKey, Error> {
+        AttrKey::new(key)
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for AttrKey {
+    type Error = Error;
+
+    fn try_from(key: &'a [u8]) -> Result<AttrKey, Error> {
+        AttrKey::new(key)
+    }
+}
+
+impl AsRef<[u8]> for AttrKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for AttrKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AttrKey(\"")?;
+        for &b in self.as_bytes() {
+            for c in core::ascii::escape_default(b) {
+                fmt::Write::write_char(f, c as char)?;
+            }
+        }
+        write!(f, "\")")
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+fn trim_key(key: &[u8]) -> &[u8] {
+    let len = key.iter().rposition(|&b| b != 0x00).map_or(0, |p| p + 1);
+    &key[0..len]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+
+    #[test]
+    fn check_attr_key() {
+        let key = AttrKey::new("board").unwrap();
+        assert_eq!(key.as_padded(), b"board\0\0\0");
+        assert_eq!(key.as_bytes(), b"board");
+        assert_eq!(key, AttrKey::new(b"board\0\0\0").unwrap());
+        assert_eq!(key, AttrKey::from_padded(*b"board\0\0\0"));
+        assert!(key.matches(b"board\0"));
+        assert!(!key.matches(b"boar"));
+        assert_eq!(AttrKey::new("appaddr!").map(|k| k.as_bytes().len()), Ok(8));
+        assert_eq!(AttrKey::try_from("too-long-"), Err(Error::BadArguments));
+        assert_eq!(format!("{:?}", key), "AttrKey(\"board\")");
+    }
+}
@@ -0,0 +1,412 @@
+//! The attribute table.
+//!
+//! The Tock bootloader keeps up to 16 key/value attributes in a 1 KiB region
+//! of internal flash. Each 64 byte slot holds an 8 byte key (null padded), a
+//! one byte value length and up to 55 bytes of value. A slot whose length is
+//! zero or more than 55 (as it is in erased flash) is empty.
+//!
+//! The `AttributeStore` holds a copy of that table and implements the same
+//! rules as tockloader for finding, setting and removing attributes. It's
+//! useful both to a bootloader answering `GetAttr`/`SetAttr` and to a host
+//! tool which has read the whole table with `ReadRange`.
+//!
+//! A host can change the table all at once, as tockloader does, rather than
+//! a slot at a time: read it, change the copy, and send the `WritePage`
+//! commands from `page_writes` (or `changed_page_writes`, to leave pages
+//! which are the same alone).
+//!
+//! ```ignore
+//! let original = AttributeStore::from_bytes(&table)?;
+//! let mut store = original.clone();
+//! store.set_known(&BoardName(b"hail"))?;
+//! for command in store.changed_page_writes(&original) {
+//!     // send it, and check the reply
+//! }
+//! ```
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+#[cfg(feature = "attributes")]
+use super::known_attrs::KnownAttr;
+use super::{Command, Error};
+use super::{INT_PAGE_SIZE, KEY_LEN, MAX_ATTR_LEN};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// One entry from the attribute table.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Attribute<'a> {
+    /// The slot the attribute lives in.
+    pub index: u8,
+    /// The key, with any null padding removed.
+    pub key: &'a [u8],
+    /// The value.
+    pub value: &'a [u8],
+}
+
+/// A copy of the attribute table.
+#[derive(Clone)]
+pub struct AttributeStore {
+    table: [u8; TABLE_LEN],
+}
+
+/// The commands which write an `AttributeStore` to flash. See
+/// `AttributeStore::page_writes`.
+pub struct PageWrites<'a> {
+    store: &'a AttributeStore,
+    original: Option<&'a AttributeStore>,
+    page: usize,
+}
+
+/// An iterator over the occupied slots in an `AttributeStore`.
+pub struct Iter<'a> {
+    store: &'a AttributeStore,
+    index: u8,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// Where the attribute table lives in internal flash.
+pub const TABLE_ADDRESS: u32 = 0x600;
+
+/// The number of slots in the attribute table.
+pub const NUM_SLOTS: usize = 16;
+
+/// The size of each slot in the attribute table.
+pub const SLOT_LEN: usize = 64;
+
+/// The size of the attribute table.
+pub const TABLE_LEN: usize = NUM_SLOTS * SLOT_LEN;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl AttributeStore {
+    /// Create an empty table, as found in erased flash.
+    #[inline(always)]
+    pub const fn new() -> AttributeStore {
+        AttributeStore { table: [0xFF; TABLE_LEN] }
+    }
+
+    /// Parse a table read from flash. `raw` must be exactly `TABLE_LEN`
+    /// bytes long.
+    pub fn from_bytes(raw: &[u8]) -> Result<AttributeStore, Error> {
+        if raw.len() != TABLE_LEN {
+            return Err(Error::BadArguments);
+        }
+        let mut store = AttributeStore::new();
+        store.table.copy_from_slice(raw);
+        Ok(store)
+    }
+
+    /// The table in the form it is stored in flash.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.table
+    }
+
+    /// The raw 64 bytes of one slot.
+    pub fn slot(&self, index: u8) -> Option<&[u8]> {
+        let index = index as usize;
+        if index < NUM_SLOTS {
+            Some(&self.table[index * SLOT_LEN..(index + 1) * SLOT_LEN])
+        } else {
+            None
+        }
+    }
+
+    /// Get the attribute in a slot, if the slot is occupied.
+    pub fn get(&self, index: u8) -> Option<Attribute<'_>> {
+        let slot = self.slot(index)?;
+        let len = slot[KEY_LEN] as usize;
+        if len == 0 || len > MAX_ATTR_LEN {
+            return None;
+        }
+        Some(Attribute {
+            index,
+            key: trim_key(&slot[0..KEY_LEN]),
+            value: &slot[KEY_LEN + 1..KEY_LEN + 1 + len],
+        })
+    }
+
+    /// Iterate over the occupied slots.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            store: self,
+            index: 0,
+        }
+    }
+
+    /// Find an attribute by key. Trailing nulls on `key` are ignored.
+    pub fn find(&self, key: &[u8]) -> Option<Attribute<'_>> {
+        let key = trim_key(key);
+        self.iter().find(|a| a.key == key)
+    }
+
+    /// Set an attribute, returning the slot it was stored in.
+    ///
+    /// As with tockloader, an existing attribute with the same key is
+    /// overwritten, otherwise the lowest free slot is used. Keys longer than
+    /// 8 bytes and values longer than 55 bytes are rejected with
+    /// `Error::BadArguments`, as is an empty value (which would read back as
+    /// an empty slot). If every slot is taken, `Error::BufferFull` is
+    /// returned.
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<u8, Error> {
+        check_key_value(key, value)?;
+        let index = match self.find(key) {
+            Some(a) => a.index,
+            None => {
+                (0..NUM_SLOTS as u8)
+                    .find(|&i| self.get(i).is_none())
+                    .ok_or(Error::BufferFull)?
+            }
+        };
+        self.set_at(index, key, value)?;
+        Ok(index)
+    }
+
+    /// Write an attribute into a particular slot, as `Command::SetAttr`
+    /// does. The key is null padded and unused value bytes are zeroed.
+    pub fn set_at(&mut self, index: u8, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        check_key_value(key, value)?;
+        let slot = self.slot_mut(index)?;
+        for b in slot.iter_mut() {
+            *b = 0x00;
+        }
+        slot[0..key.len()].copy_from_slice(key);
+        slot[KEY_LEN] = value.len() as u8;
+        slot[KEY_LEN + 1..KEY_LEN + 1 + value.len()].copy_from_slice(value);
+        Ok(())
+    }
+
+    /// Remove an attribute by key, returning the slot it was in. As with
+    /// tockloader, the slot is zeroed.
+    pub fn remove(&mut self, key: &[u8]) -> Option<u8> {
+        let index = self.find(key)?.index;
+        self.clear(index).ok()?;
+        Some(index)
+    }
+
+    /// Get a well-known attribute, if it's there and its value is valid.
+    #[cfg(feature = "attributes")]
+    pub fn get_known<'a, A: KnownAttr<'a>>(&'a self) -> Option<A> {
+        A::decode(self.find(A::KEY)?.value)
+    }
+
+    /// Set a well-known attribute, as `set` does, returning the slot it was
+    /// stored in.
+    #[cfg(feature = "attributes")]
+    pub fn set_known<'a, A: KnownAttr<'a>>(&mut self, attr: &A) -> Result<u8, Error> {
+        let mut buffer = [0u8; MAX_ATTR_LEN];
+        let len = attr.encode(&mut buffer);
+        self.set(A::KEY, &buffer[0..len])
+    }
+
+    /// The `WritePage` commands which write the whole table back to flash
+    /// at `TABLE_ADDRESS`.
+    pub fn page_writes(&self) -> PageWrites<'_> {
+        PageWrites {
+            store: self,
+            original: None,
+            page: 0,
+        }
+    }
+
+    /// The `WritePage` commands for just the pages of the table which
+    /// differ from `original`, the table as it is in flash.
+    pub fn changed_page_writes<'a>(&'a self, original: &'a AttributeStore) -> PageWrites<'a> {
+        PageWrites {
+            store: self,
+            original: Some(original),
+            page: 0,
+        }
+    }
+
+    /// Zero a slot, making it empty.
+    pub fn clear(&mut self, index: u8) -> Result<(), Error> {
+        for b in self.slot_mut(index)?.iter_mut() {
+            *b = 0x00;
+        }
+        Ok(())
+    }
+
+    fn slot_mut(&mut self, index: u8) -> Result<&mut [u8], Error> {
+        let index = index as usize;
+        if index < NUM_SLOTS {
+            Ok(&mut self.table[index * SLOT_LEN..(index + 1) * SLOT_LEN])
+        } else {
+            Err(Error::BadArguments)
+        }
+    }
+}
+
+impl Default for AttributeStore {
+    fn default() -> AttributeStore {
+        AttributeStore::new()
+    }
+}
+
+impl<'a> Iterator for PageWrites<'a> {
+    type Item = Command<'a>;
+
+    fn next(&mut self) -> Option<Command<'a>> {
+        while self.page * INT_PAGE_SIZE < TABLE_LEN {
+            let range = self.page * INT_PAGE_SIZE..(self.page + 1) * INT_PAGE_SIZE;
+            self.page += 1;
+            let data = &self.store.table[range.clone()];
+            if self.original.is_some_and(|o| &o.table[range.clone()] == data) {
+                continue;
+            }
+            return Some(Command::WritePage {
+                address: TABLE_ADDRESS + range.start as u32,
+                data,
+            });
+        }
+        None
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Attribute<'a>;
+
+    fn next(&mut self) -> Option<Attribute<'a>> {
+        while (self.index as usize) < NUM_SLOTS {
+            let index = self.index;
+            self.index += 1;
+            if let Some(a) = self.store.get(index) {
+                return Some(a);
+            }
+        }
+        None
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+fn trim_key(key: &[u8]) -> &[u8] {
+    let len = key.iter().rposition(|&b| b != 0x00).map_or(0, |p| p + 1);
+    &key[0..len]
+}
+
+fn check_key_value(key: &[u8], value: &[u8]) -> Result<(), Error> {
+    if key.len() > KEY_LEN || value.is_empty() || value.len() > MAX_ATTR_LEN {
+        Err(Error::BadArguments)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_empty() {
+        let store = AttributeStore::new();
+        assert_eq!(store.iter().count(), 0);
+        assert_eq!(store.find(b"board"), None);
+        assert_eq!(store.as_bytes().len(), TABLE_LEN);
+    }
+
+    #[test]
+    fn check_set_find_remove() {
+        let mut store = AttributeStore::new();
+        assert_eq!(store.set(b"board", b"hail"), Ok(0));
+        assert_eq!(store.set(b"arch", b"cortex-m4"), Ok(1));
+        // Overwrites in place
+        assert_eq!(store.set(b"board\0\0\0", b"imix"), Ok(0));
+        assert_eq!(
+            store.find(b"board"),
+            Some(Attribute {
+                index: 0,
+                key: b"board",
+                value: b"imix",
+            })
+        );
+        assert_eq!(store.remove(b"board"), Some(0));
+        assert_eq!(store.find(b"board"), None);
+        // The lowest free slot gets reused
+        assert_eq!(store.set(b"appaddr", &[0x00, 0x00, 0x03, 0x00]), Ok(0));
+        assert_eq!(store.iter().count(), 2);
+    }
+
+    #[test]
+    fn check_limits() {
+        let mut store = AttributeStore::new();
+        assert_eq!(store.set(b"too-long-", b"x"), Err(Error::BadArguments));
+        assert_eq!(store.set(b"key", &[0u8; MAX_ATTR_LEN + 1]), Err(Error::BadArguments));
+        assert_eq!(store.set(b"key", b""), Err(Error::BadArguments));
+        assert_eq!(store.set(b"key", &[0u8; MAX_ATTR_LEN]), Ok(0));
+        assert_eq!(store.set_at(16, b"key", b"x"), Err(Error::BadArguments));
+        for i in 1..NUM_SLOTS {
+            store.set(&[b'k', i as u8], b"x").unwrap();
+        }
+        assert_eq!(store.set(b"full", b"x"), Err(Error::BufferFull));
+    }
+
+    #[test]
+    fn check_serialise() {
+        let mut store = AttributeStore::new();
+        store.set_at(2, b"board", b"hail").unwrap();
+        let slot = store.slot(2).unwrap();
+        assert_eq!(&slot[0..13], b"board\0\0\0\x04hail");
+        assert!(slot[13..].iter().all(|&b| b == 0));
+
+        let copy = AttributeStore::from_bytes(store.as_bytes()).unwrap();
+        assert_eq!(copy.get(2).map(|a| a.value), Some(&b"hail"[..]));
+        assert!(AttributeStore::from_bytes(&[0u8; 64]).is_err());
+    }
+
+    #[test]
+    fn check_page_writes() {
+        let original = AttributeStore::new();
+        let mut store = original.clone();
+        store.set_at(9, b"board", b"hail").unwrap();
+        let addresses = |writes: PageWrites| -> [Option<u32>; 2] {
+            let mut addresses = [None; 2];
+            for (a, command) in addresses.iter_mut().zip(writes) {
+                if let Command::WritePage { address, data } = command {
+                    assert_eq!(data.len(), INT_PAGE_SIZE);
+                    *a = Some(address);
+                }
+            }
+            addresses
+        };
+        assert_eq!(addresses(store.page_writes()), [Some(0x600), Some(0x800)]);
+        // Slot 9 is in the second page
+        assert_eq!(addresses(store.changed_page_writes(&original)), [Some(0x800), None]);
+        assert_eq!(store.changed_page_writes(&store).count(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_known() {
+        use super::super::known_attrs::{AppAddress, BoardName};
+
+        let mut store = AttributeStore::new();
+        assert_eq!(store.set_known(&BoardName(b"hail")), Ok(0));
+        assert_eq!(store.set_known(&AppAddress(0x30000)), Ok(1));
+        assert_eq!(store.get_known(), Some(BoardName(b"hail")));
+        assert_eq!(store.get_known(), Some(AppAddress(0x30000)));
+        store.set(b"appaddr", b"nonsense").unwrap();
+        assert_eq!(store.get_known::<AppAddress>(), None);
+    }
+}
@@ -0,0 +1,172 @@
+//! Encoding a sequence of commands into one buffer.
+//!
+//! For scripted flashing it can be convenient to render a whole sequence of
+//! commands (an erase, a run of page writes, a CRC check) up front and then
+//! hand the lot to the serial port in one go. The `BatchEncoder` appends
+//! complete frames to a caller-supplied buffer and keeps track of how much
+//! of it has been used.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::borrow::Borrow;
+
+use super::{Command, CommandEncoder, Error};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// The `BatchEncoder` takes `Command`s and appends their encoded frames to a
+/// buffer.
+pub struct BatchEncoder<'b> {
+    buffer: &'b mut [u8],
+    len: usize,
+    frames: usize,
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<'b> BatchEncoder<'b> {
+    /// Create a new `BatchEncoder` which writes into `buffer`.
+    pub fn new(buffer: &'b mut [u8]) -> BatchEncoder<'b> {
+        BatchEncoder {
+            buffer,
+            len: 0,
+            frames: 0,
+        }
+    }
+
+    /// Append one command to the buffer, returning the new total length.
+    ///
+    /// If the frame doesn't fit, `Error::BufferFull` is returned and the
+    /// buffer is left holding only the frames that were previously pushed.
+    pub fn push(&mut self, command: &Command) -> Result<usize, Error> {
+        let encoder = CommandEncoder::new(command)?;
+        let mut len = self.len;
+        for byte in encoder {
+            if len == self.buffer.len() {
+                return Err(Error::BufferFull);
+            }
+            self.buffer[len] = byte;
+            len += 1;
+        }
+        self.len = len;
+        self.frames += 1;
+        Ok(len)
+    }
+
+    /// Append every command from an iterator, returning the new total
+    /// length.
+    ///
+    /// Stops at the first command which fails to encode or doesn't fit,
+    /// leaving the buffer holding every frame before it. Use `frames` to see
+    /// how far it got.
+    pub fn extend<'c, I>(&mut self, commands: I) -> Result<usize, Error>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<Command<'c>>,
+    {
+        for command in commands {
+            self.push(command.borrow())?;
+        }
+        Ok(self.len)
+    }
+
+    /// The number of encoded bytes in the buffer.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether any frames have been encoded.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of complete frames in the buffer.
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// The encoded bytes so far.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buffer[0..self.len]
+    }
+
+    /// Finish encoding and return the encoded bytes.
+    pub fn finish(self) -> &'b [u8] {
+        &self.buffer[0..self.len]
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{CMD_EPAGE, CMD_PING, CMD_WPAGE, ESCAPE_CHAR, INT_PAGE_SIZE};
+
+    #[test]
+    fn check_concatenates_frames() {
+        let mut buffer = [0u8; 16];
+        let mut b = BatchEncoder::new(&mut buffer);
+        assert!(b.is_empty());
+        assert_eq!(b.push(&Command::Ping), Ok(2));
+        assert_eq!(b.push(&Command::ErasePage { address: 0x400 }), Ok(8));
+        assert_eq!(b.frames(), 2);
+        assert_eq!(
+            b.finish(),
+            &[ESCAPE_CHAR, CMD_PING, 0x00, 0x04, 0x00, 0x00, ESCAPE_CHAR, CMD_EPAGE]
+        );
+    }
+
+    #[test]
+    fn check_extend() {
+        let page = [0u8; INT_PAGE_SIZE];
+        let commands = [
+            Command::ErasePage { address: 0x30000 },
+            Command::WritePage {
+                address: 0x30000,
+                data: &page,
+            },
+        ];
+        let mut buffer = [0u8; 1024];
+        let mut b = BatchEncoder::new(&mut buffer);
+        assert_eq!(b.extend(&commands), Ok(6 + 4 + INT_PAGE_SIZE + 2));
+        assert_eq!(b.as_slice()[b.len() - 1], CMD_WPAGE);
+    }
+
+    #[test]
+    fn check_buffer_full() {
+        let page = [0u8; INT_PAGE_SIZE];
+        let commands = [
+            Command::Ping,
+            Command::WritePage {
+                address: 0x30000,
+                data: &page,
+            },
+            Command::Ping,
+        ];
+        let mut buffer = [0u8; 64];
+        let mut b = BatchEncoder::new(&mut buffer);
+        assert_eq!(b.extend(commands.iter()), Err(Error::BufferFull));
+        // Only the first complete frame is left
+        assert_eq!(b.frames(), 1);
+        assert_eq!(b.as_slice(), &[ESCAPE_CHAR, CMD_PING]);
+        // There's still room for small frames
+        assert_eq!(b.push(&Command::Ping), Ok(4));
+    }
+}
@@ -0,0 +1,123 @@
+//! Decode captured tockloader traffic.
+//!
+//! ```text
+//! tockloader-decode capture.bin
+//! tockloader-decode --hex dump.txt
+//! ```
+//!
+//! The first form reads a file written from a `Capture`. The second reads a
+//! hex dump, one line per burst of traffic. Lines starting with `>` went
+//! from the host to the bootloader and lines starting with `<` went back;
+//! a line with neither goes the same way as the one before (or to the
+//! bootloader, at the start). Anything after a `#` is ignored:
+//!
+//! ```text
+//! # ping
+//! > fc 01
+//! < fc 11
+//! ```
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+extern crate tockloader_proto;
+
+use std::env;
+use std::fs;
+use std::process;
+
+use tockloader_proto::analyze;
+use tockloader_proto::capture::{records, Direction, Record};
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (hex, path) = match args.as_slice() {
+        [path] => (false, path),
+        [flag, path] if flag == "--hex" => (true, path),
+        _ => {
+            eprintln!("Usage: tockloader-decode [--hex] <file>");
+            process::exit(2);
+        }
+    };
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("Can't read {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    let transcript = if hex {
+        match parse_hex(&String::from_utf8_lossy(&contents)) {
+            Ok(bursts) => {
+                let mut offset = 0;
+                analyze(bursts.iter().map(|&(direction, ref data)| {
+                    let record = Record {
+                        direction,
+                        offset,
+                        data,
+                    };
+                    offset += data.len() as u32;
+                    record
+                }))
+            }
+            Err(line) => {
+                eprintln!("{}:{}: bad hex", path, line);
+                process::exit(1);
+            }
+        }
+    } else {
+        analyze(records(&contents))
+    };
+    print!("{}", transcript);
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Parse a hex dump into bursts of bytes. On failure, returns the line
+/// number of the bad line.
+fn parse_hex(text: &str) -> Result<Vec<(Direction, Vec<u8>)>, usize> {
+    let mut bursts: Vec<(Direction, Vec<u8>)> = Vec::new();
+    let mut direction = Direction::HostToDevice;
+    for (number, line) in text.lines().enumerate() {
+        let mut line = line.split('#').next().unwrap_or("").trim();
+        if let Some(rest) = line.strip_prefix('>') {
+            direction = Direction::HostToDevice;
+            line = rest;
+        } else if let Some(rest) = line.strip_prefix('<') {
+            direction = Direction::DeviceToHost;
+            line = rest;
+        }
+        let mut data = Vec::new();
+        for word in line.split_whitespace() {
+            let word = word.trim_start_matches("0x");
+            if word.len() % 2 != 0 {
+                return Err(number + 1);
+            }
+            for i in (0..word.len()).step_by(2) {
+                let byte = u8::from_str_radix(&word[i..i + 2], 16).map_err(|_| number + 1)?;
+                data.push(byte);
+            }
+        }
+        if data.is_empty() {
+            continue;
+        }
+        match bursts.last_mut() {
+            Some(&mut (d, ref mut last)) if d == direction => last.extend(data),
+            _ => bursts.push((direction, data)),
+        }
+    }
+    Ok(bursts)
+}
@@ -0,0 +1,590 @@
+//! A complete bootloader, apart from the hardware.
+//!
+//! The `Bootloader` runs the whole command loop: it reads bytes from a
+//! `Transport` (usually the UART), decodes commands, carries them out and
+//! writes back the responses, laid out the way tockloader expects. It
+//! answers `Ping`, `Info` and `CrcRxBuffer` itself, and a `Reset` just
+//! clears out anything half done, as in the C bootloader. Flash and
+//! attribute commands go to the `Flash` and `Attributes` traits, which are
+//! all a new chip port has to implement.
+//!
+//! A port which wants the host to be able to start the app turns on the
+//! `Exit` command with `set_exit_command`. `run` then returns once it has
+//! answered one:
+//!
+//! ```ignore
+//! bootloader.set_exit_command(true);
+//! bootloader.run()?;
+//! jump_to_app();
+//! ```
+//!
+//! For anything more unusual, such as external flash, use a
+//! `BootloaderSession` directly.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::attributes::{AttributeStore, NUM_SLOTS};
+use super::crc::Crc32;
+use super::device::{BootloaderSession, FlashError, FlashInterface};
+use super::policy::AccessPolicy;
+#[cfg(feature = "attributes")]
+use super::signature::Verifier;
+use super::transport::{RunError, Transport};
+use super::{PaddingMode, MAX_CHUNK_LEN};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// The internal flash of a chip.
+pub trait Flash {
+    /// Fill `buffer` with the contents of flash at `address`.
+    fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), FlashError>;
+
+    /// Write a 512 byte page, erasing it first if need be.
+    fn write_page(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError>;
+
+    /// Erase a 512 byte page.
+    fn erase_page(&mut self, address: u32) -> Result<(), FlashError>;
+
+    /// Calculate the CRC32 of a range of flash. By default the range is
+    /// read back a few bytes at a time.
+    fn crc_range(&mut self, address: u32, length: u32) -> Result<u32, FlashError> {
+        let mut crc = Crc32::new();
+        let mut buffer = [0u8; 64];
+        let mut done = 0;
+        while done < length {
+            let len = (length - done).min(buffer.len() as u32);
+            let address = address.checked_add(done).ok_or(FlashError::BadAddress)?;
+            let chunk = &mut buffer[0..len as usize];
+            self.read(address, chunk)?;
+            crc.update(chunk);
+            done += len;
+        }
+        Ok(crc.finish())
+    }
+}
+
+/// Where a bootloader keeps its attributes.
+pub trait Attributes {
+    /// Read the attribute at `index`. The 8 byte key goes in `key` and the
+    /// value in `value`. Returns the length of the value, which is zero for
+    /// an empty slot.
+    fn get_attr(&mut self, index: u8, key: &mut [u8], value: &mut [u8])
+        -> Result<usize, FlashError>;
+
+    /// Store an attribute at `index`. The key is 8 bytes, null padded. An
+    /// empty value clears the slot.
+    fn set_attr(&mut self, index: u8, key: &[u8], value: &[u8]) -> Result<(), FlashError>;
+}
+
+/// For a bootloader without attributes. `GetAttr` and `SetAttr` are
+/// answered with `Unknown`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoAttributes;
+
+/// Runs the bootloader command loop over a `Transport`, a `Flash` and some
+/// `Attributes`.
+pub struct Bootloader<T, F, A> {
+    transport: T,
+    session: BootloaderSession<Port<F, A>>,
+}
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+/// Puts a `Flash` and some `Attributes` together for a `BootloaderSession`.
+struct Port<F, A> {
+    flash: F,
+    attributes: A,
+    info: &'static [u8],
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<T, F, A> Bootloader<T, F, A>
+where
+    T: Transport,
+    F: Flash,
+    A: Attributes,
+{
+    /// Create a new `Bootloader`. This is a `const fn`, so the whole
+    /// bootloader can be built into a `static`.
+    #[inline(always)]
+    pub const fn new(transport: T, flash: F, attributes: A) -> Bootloader<T, F, A> {
+        Bootloader {
+            transport,
+            session: BootloaderSession::with_rx_crc(Port {
+                flash,
+                attributes,
+                info: &[],
+            }),
+        }
+    }
+
+    /// Set the string sent in reply to `Info`. Only the first 192 bytes are
+    /// sent. Until this is called, `Info` gets an empty string.
+    pub fn set_info(&mut self, info: &'static [u8]) {
+        self.session.flash_mut().info = info;
+    }
+
+    /// Check every command against `policy` before carrying it out. See
+    /// `BootloaderSession::set_access_policy`.
+    pub fn set_access_policy(&mut self, policy: &'static dyn AccessPolicy) {
+        self.session.set_access_policy(policy);
+    }
+
+    /// Return from `run` when the host sends `Exit`. See
+    /// `BootloaderSession::set_exit_command`.
+    pub fn set_exit_command(&mut self, enabled: bool) {
+        self.session.set_exit_command(enabled);
+    }
+
+    /// Only start images signed for `verifier`. See
+    /// `BootloaderSession::set_verifier`.
+    #[cfg(feature = "attributes")]
+    pub fn set_verifier(&mut self, verifier: &'static dyn Verifier) {
+        self.session.set_verifier(verifier);
+    }
+
+    /// Run until the host sends `Exit`, then return `Ok` once it has been
+    /// answered. The port should then start the app. Without
+    /// `set_exit_command` this only returns on an error. Errors from the
+    /// transport are returned straight away, as is a response which can't
+    /// be encoded.
+    pub fn run(&mut self) -> Result<(), RunError<T::Error>> {
+        while !self.step()? {}
+        Ok(())
+    }
+
+    /// Read one byte and act on it. Returns `true` if it finished an
+    /// `Exit`. This is for ports with other things to do between bytes.
+    pub fn step(&mut self) -> Result<bool, RunError<T::Error>> {
+        let ch = self.transport.read_byte().map_err(RunError::Transport)?;
+        let format = self.session.reply_format();
+        if let Some(response) = self.session.receive(ch) {
+            let mut encoder = format.encoder(&response).map_err(RunError::Protocol)?;
+            encoder.set_padding_mode(PaddingMode::Spec);
+            while let Some(chunk) = encoder.next_chunk(MAX_CHUNK_LEN) {
+                self.transport.write_bytes(chunk).map_err(RunError::Transport)?;
+            }
+            self.transport.flush().map_err(RunError::Transport)?;
+        }
+        Ok(self.session.take_exit())
+    }
+
+    /// Get a mutable reference to the flash.
+    pub fn flash_mut(&mut self) -> &mut F {
+        &mut self.session.flash_mut().flash
+    }
+
+    /// Get a mutable reference to the attributes.
+    pub fn attributes_mut(&mut self) -> &mut A {
+        &mut self.session.flash_mut().attributes
+    }
+
+    /// Get a mutable reference to the transport.
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Destroy the bootloader and get the transport, flash and attributes
+    /// back.
+    pub fn release(self) -> (T, F, A) {
+        let port = self.session.release();
+        (self.transport, port.flash, port.attributes)
+    }
+}
+
+impl Attributes for NoAttributes {
+    fn get_attr(&mut self, _: u8, _: &mut [u8], _: &mut [u8]) -> Result<usize, FlashError> {
+        Err(FlashError::Unsupported)
+    }
+
+    fn set_attr(&mut self, _: u8, _: &[u8], _: &[u8]) -> Result<(), FlashError> {
+        Err(FlashError::Unsupported)
+    }
+}
+
+/// Keeps the attributes in RAM. A port can load the table from flash at
+/// start up, and write it back after a `SetAttr`.
+impl Attributes for AttributeStore {
+    fn get_attr(&mut self, index: u8, key: &mut [u8], value: &mut [u8])
+        -> Result<usize, FlashError> {
+        for b in key.iter_mut() {
+            *b = 0x00;
+        }
+        match self.get(index) {
+            Some(attr) => {
+                let len = attr.value.len();
+                let dest = value.get_mut(0..len).ok_or(FlashError::Internal)?;
+                dest.copy_from_slice(attr.value);
+                key[0..attr.key.len()].copy_from_slice(attr.key);
+                Ok(len)
+            }
+            None if (index as usize) < NUM_SLOTS => Ok(0),
+            None => Err(FlashError::BadArguments),
+        }
+    }
+
+    fn set_attr(&mut self, index: u8, key: &[u8], value: &[u8]) -> Result<(), FlashError> {
+        let result = if value.is_empty() {
+            self.clear(index)
+        } else {
+            self.set_at(index, key, value)
+        };
+        result.map_err(|_| FlashError::BadArguments)
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<F, A> FlashInterface for Port<F, A>
+where
+    F: Flash,
+    A: Attributes,
+{
+    fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), FlashError> {
+        self.flash.read(address, buffer)
+    }
+
+    fn write_page(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+        self.flash.write_page(address, data)
+    }
+
+    fn erase_page(&mut self, address: u32) -> Result<(), FlashError> {
+        self.flash.erase_page(address)
+    }
+
+    fn get_attr(&mut self, index: u8, key: &mut [u8], value: &mut [u8])
+        -> Result<usize, FlashError> {
+        self.attributes.get_attr(index, key, value)
+    }
+
+    fn set_attr(&mut self, index: u8, key: &[u8], value: &[u8]) -> Result<(), FlashError> {
+        self.attributes.set_attr(index, key, value)
+    }
+
+    fn crc_range(&mut self, address: u32, length: u32) -> Result<u32, FlashError> {
+        self.flash.crc_range(address, length)
+    }
+
+    fn info(&mut self, buffer: &mut [u8]) -> Result<usize, FlashError> {
+        let len = self.info.len().min(buffer.len());
+        buffer[0..len].copy_from_slice(&self.info[0..len]);
+        Ok(len)
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    #[cfg(feature = "std")]
+    use std::io;
+    #[cfg(feature = "std")]
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    #[cfg(feature = "std")]
+    use std::time::Duration;
+    use std::vec::Vec;
+
+    use super::*;
+    #[cfg(feature = "std")]
+    use super::super::host::Host;
+    use super::super::session::HostSession;
+    use super::super::{crc32, Command, InfoMode, Response, INT_PAGE_SIZE};
+    #[cfg(feature = "attributes")]
+    use super::super::KEY_LEN;
+
+    const BASE: u32 = 0x30000;
+
+    /// Reads come from a fixed script; writes are captured.
+    struct Script {
+        rx: Vec<u8>,
+        tx: Vec<u8>,
+    }
+
+    impl Transport for Script {
+        type Error = ();
+
+        fn read_byte(&mut self) -> Result<u8, ()> {
+            if self.rx.is_empty() {
+                Err(())
+            } else {
+                Ok(self.rx.remove(0))
+            }
+        }
+
+        fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), ()> {
+            self.tx.extend_from_slice(bytes);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    /// One end of a pair of pipes, for a `Host` and a `Bootloader` on
+    /// different threads.
+    #[cfg(feature = "std")]
+    struct Pipe {
+        tx: Sender<u8>,
+        rx: Receiver<u8>,
+    }
+
+    #[cfg(feature = "std")]
+    impl Pipe {
+        fn pair() -> (Pipe, Pipe) {
+            let (a_tx, b_rx) = channel();
+            let (b_tx, a_rx) = channel();
+            (Pipe { tx: a_tx, rx: a_rx }, Pipe { tx: b_tx, rx: b_rx })
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl Transport for Pipe {
+        type Error = ();
+
+        fn read_byte(&mut self) -> Result<u8, ()> {
+            self.rx.recv().map_err(|_| ())
+        }
+
+        fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), ()> {
+            bytes.iter().try_for_each(|&b| self.tx.send(b).map_err(|_| ()))
+        }
+
+        fn flush(&mut self) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl io::Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            match self.rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(b) => {
+                    buf[0] = b;
+                    Ok(1)
+                }
+                Err(_) => Err(io::ErrorKind::TimedOut.into()),
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl io::Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Transport::write_bytes(self, buf).map_err(|_| io::ErrorKind::BrokenPipe)?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct RamFlash([u8; INT_PAGE_SIZE * 2]);
+
+    impl Flash for RamFlash {
+        fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), FlashError> {
+            let start = address.checked_sub(BASE).ok_or(FlashError::BadAddress)? as usize;
+            let src = self.0.get(start..start + buffer.len()).ok_or(FlashError::BadAddress)?;
+            buffer.copy_from_slice(src);
+            Ok(())
+        }
+
+        fn write_page(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+            let start = address.checked_sub(BASE).ok_or(FlashError::BadAddress)? as usize;
+            let dest = self.0.get_mut(start..start + data.len()).ok_or(FlashError::BadAddress)?;
+            dest.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn erase_page(&mut self, address: u32) -> Result<(), FlashError> {
+            self.write_page(address, &[0xFF; INT_PAGE_SIZE])
+        }
+    }
+
+    /// Only fixed-size responses are kept, so they can outlive the session.
+    type Replies = Vec<Option<Response<'static>>>;
+
+    /// Run a `Bootloader` over `commands` and decode what it sends back.
+    fn run<A: Attributes>(
+        commands: &[Command],
+        attributes: A,
+    ) -> (Result<(), RunError<()>>, Replies) {
+        let mut session = HostSession::new();
+        let mut rx = Vec::new();
+        for cmd in commands {
+            rx.extend(session.send(cmd).unwrap());
+        }
+        let script = Script { rx, tx: Vec::new() };
+        let mut b = Bootloader::new(script, RamFlash([0xFF; INT_PAGE_SIZE * 2]), attributes);
+        b.set_info(b"tock-bootloader v1.1.0");
+        b.set_exit_command(true);
+        let result = b.run();
+        let (script, _, _) = b.release();
+
+        let mut responses = Vec::new();
+        let mut session = HostSession::new();
+        session.set_info_mode(InfoMode::LengthPrefixed);
+        // A reset gets no reply
+        let mut cmds = commands.iter().filter(|&c| *c != Command::Reset);
+        let mut in_flight = false;
+        for &byte in &script.tx {
+            if !in_flight {
+                session.send(cmds.next().unwrap()).unwrap();
+                in_flight = true;
+            }
+            match session.receive(byte) {
+                Ok(Some(r)) => {
+                    in_flight = false;
+                    responses.push(match r {
+                        Response::Pong => Some(Response::Pong),
+                        Response::Ok => Some(Response::Ok),
+                        Response::Unknown => Some(Response::Unknown),
+                        Response::CrcIntFlash { crc } => Some(Response::CrcIntFlash { crc }),
+                        Response::CrcRxBuffer { length, crc } => {
+                            Some(Response::CrcRxBuffer { length, crc })
+                        }
+                        Response::Info { info } => {
+                            assert_eq!(info, b"tock-bootloader v1.1.0");
+                            None
+                        }
+                        _ => None,
+                    });
+                }
+                Ok(None) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        (result, responses)
+    }
+
+    #[test]
+    fn check_session() {
+        let page = [0x5Au8; INT_PAGE_SIZE];
+        let commands = [
+            Command::Ping,
+            Command::Info,
+            Command::WritePage {
+                address: BASE + INT_PAGE_SIZE as u32,
+                data: &page,
+            },
+            Command::CrcRxBuffer,
+            Command::CrcIntFlash {
+                address: BASE + INT_PAGE_SIZE as u32,
+                length: INT_PAGE_SIZE as u32,
+            },
+            Command::Reset,
+            Command::Ping,
+            Command::Exit,
+            Command::Ping,
+        ];
+        let (result, responses) = run(&commands, NoAttributes);
+        // The reset has no reply and carries on; the exit stops it, leaving
+        // the last ping unread
+        assert_eq!(result, Ok(()));
+        let args_crc = {
+            let mut crc = Crc32::new();
+            crc.update(&(BASE + INT_PAGE_SIZE as u32).to_le_bytes());
+            crc.update(&page);
+            crc.finish()
+        };
+        assert_eq!(
+            responses,
+            [
+                Some(Response::Pong),
+                None,
+                Some(Response::Ok),
+                Some(Response::CrcRxBuffer {
+                    length: 4 + INT_PAGE_SIZE as u16,
+                    crc: args_crc,
+                }),
+                Some(Response::CrcIntFlash { crc: crc32(&page) }),
+                Some(Response::Pong),
+                Some(Response::Ok),
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn check_host_sync() {
+        let (host_end, board_end) = Pipe::pair();
+        let board = std::thread::spawn(move || {
+            let flash = RamFlash([0xFF; INT_PAGE_SIZE * 2]);
+            let mut b = Bootloader::new(board_end, flash, NoAttributes);
+            b.set_exit_command(true);
+            let result = b.run();
+            let (_, flash, _) = b.release();
+            (result, flash.0[0])
+        });
+        let mut host = Host::new(host_end);
+        host.sync().unwrap();
+        host.write_image(BASE, &[0x5A; 100]).unwrap();
+        // Syncing again, as a cancel does, leaves the bootloader running
+        host.sync().unwrap();
+        host.ping().unwrap();
+        host.exit().unwrap();
+        let (result, first) = board.join().unwrap();
+        assert_eq!(result, Ok(()));
+        assert_eq!(first, 0x5A);
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_attributes() {
+        let commands = [
+            Command::SetAttr {
+                index: 1,
+                key: b"board\0\0\0",
+                value: b"hail",
+            },
+            Command::GetAttr { index: 1 },
+            Command::SetAttr {
+                index: 1,
+                key: b"board\0\0\0",
+                value: b"",
+            },
+            Command::GetAttr { index: 1 },
+        ];
+        let (result, responses) = run(&commands, AttributeStore::new());
+        // The script ran out
+        assert_eq!(result, Err(RunError::Transport(())));
+        assert_eq!(responses, [Some(Response::Ok), None, Some(Response::Ok), None]);
+
+        let (_, responses) = run(&[Command::GetAttr { index: 0 }], NoAttributes);
+        assert_eq!(responses, [Some(Response::Unknown)]);
+
+        let mut store = AttributeStore::new();
+        store.set_attr(2, b"arch\0\0\0\0", b"cortex-m4").unwrap();
+        let mut key = [0xAA; KEY_LEN];
+        let mut value = [0u8; 55];
+        assert_eq!(store.get_attr(2, &mut key, &mut value), Ok(9));
+        assert_eq!(&key, b"arch\0\0\0\0");
+        assert_eq!(&value[0..9], b"cortex-m4");
+        assert_eq!(store.get_attr(3, &mut key, &mut value), Ok(0));
+        assert_eq!(&key, &[0; KEY_LEN]);
+        assert_eq!(store.get_attr(16, &mut key, &mut value), Err(FlashError::BadArguments));
+    }
+}
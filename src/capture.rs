@@ -0,0 +1,321 @@
+//! Recording and replaying the bytes on the wire.
+//!
+//! A `Capture` records the raw bytes going each way into a buffer you
+//! supply, so it works in a bootloader as well as on a host. The buffer,
+//! from `as_bytes`, is the capture file: a series of records, each a
+//! direction byte (0 for host to device, 1 for device to host), a 4 byte
+//! little endian offset, a 2 byte little endian length and then that many
+//! bytes. The offset counts every byte recorded before the record, in
+//! either direction, so it only ever goes up.
+//!
+//! A `Replay` feeds a capture back through the decoders and reports what
+//! it finds, so a capture from a bug report can be turned into a test.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use byteorder::{ByteOrder, LittleEndian};
+
+#[cfg(all(feature = "host", feature = "device"))]
+use super::session::HostSession;
+#[cfg(all(feature = "host", feature = "device"))]
+use super::{CommandDecoder, InfoMode};
+use super::{Command, Error, Response};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Which way some bytes were going.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Direction {
+    /// From the flash tool to the bootloader.
+    HostToDevice,
+    /// From the bootloader to the flash tool.
+    DeviceToHost,
+}
+
+/// Some bytes going one way.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Record<'a> {
+    /// Which way they were going.
+    pub direction: Direction,
+    /// How many bytes were recorded before these.
+    pub offset: u32,
+    /// The bytes.
+    pub data: &'a [u8],
+}
+
+/// Records bytes into a buffer.
+pub struct Capture<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+    offset: u32,
+    /// Where the header of the last record is, so it can be extended.
+    last: Option<usize>,
+}
+
+/// Iterates over the records in a capture. It stops at the first record
+/// which is truncated or has a bad direction byte.
+#[derive(Clone)]
+pub struct Records<'a> {
+    bytes: &'a [u8],
+}
+
+/// What a `Replay` found.
+#[derive(Debug, PartialEq)]
+pub enum Event<'a> {
+    /// The host sent a command.
+    Command(Command<'a>),
+    /// The bootloader replied.
+    Response(Response<'a>),
+    /// Bytes going the given way couldn't be decoded.
+    Error(Direction, Error),
+}
+
+/// Feeds captured bytes through a `CommandDecoder` and a `HostSession`.
+#[cfg(all(feature = "host", feature = "device"))]
+#[derive(Default)]
+pub struct Replay {
+    commands: CommandDecoder,
+    session: HostSession,
+}
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const HEADER_LEN: usize = 7;
+const MAX_RECORD_LEN: usize = 0xFFFF;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<'a> Capture<'a> {
+    /// Start an empty capture in `buffer`.
+    pub fn new(buffer: &'a mut [u8]) -> Capture<'a> {
+        Capture {
+            buffer,
+            len: 0,
+            offset: 0,
+            last: None,
+        }
+    }
+
+    /// Record some bytes. If they're going the same way as the last ones,
+    /// the last record is extended. Returns `Error::BufferFull`, having
+    /// recorded nothing, if they don't fit.
+    pub fn record(&mut self, direction: Direction, bytes: &[u8]) -> Result<(), Error> {
+        if let Some(last) = self.last {
+            let last_len = LittleEndian::read_u16(&self.buffer[last + 5..last + 7]) as usize;
+            if self.buffer[last] == direction.to_byte()
+                && last_len + bytes.len() <= MAX_RECORD_LEN
+                && self.len + bytes.len() <= self.buffer.len()
+            {
+                self.append(bytes);
+                let new_len = (last_len + bytes.len()) as u16;
+                LittleEndian::write_u16(&mut self.buffer[last + 5..last + 7], new_len);
+                return Ok(());
+            }
+        }
+        if bytes.len() > MAX_RECORD_LEN || self.len + HEADER_LEN + bytes.len() > self.buffer.len()
+        {
+            return Err(Error::BufferFull);
+        }
+        let header = &mut self.buffer[self.len..self.len + HEADER_LEN];
+        header[0] = direction.to_byte();
+        LittleEndian::write_u32(&mut header[1..5], self.offset);
+        LittleEndian::write_u16(&mut header[5..7], bytes.len() as u16);
+        self.last = Some(self.len);
+        self.len += HEADER_LEN;
+        self.append(bytes);
+        Ok(())
+    }
+
+    /// The capture so far, ready to save.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[0..self.len]
+    }
+
+    /// The records so far.
+    pub fn records(&self) -> Records<'_> {
+        records(self.as_bytes())
+    }
+
+    /// Throw the capture away and start again.
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.offset = 0;
+        self.last = None;
+    }
+}
+
+/// Read the records from a saved capture.
+pub fn records(bytes: &[u8]) -> Records<'_> {
+    Records { bytes }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Record<'a>;
+
+    fn next(&mut self) -> Option<Record<'a>> {
+        if self.bytes.len() < HEADER_LEN {
+            return None;
+        }
+        let direction = match self.bytes[0] {
+            0 => Direction::HostToDevice,
+            1 => Direction::DeviceToHost,
+            _ => return None,
+        };
+        let offset = LittleEndian::read_u32(&self.bytes[1..5]);
+        let len = LittleEndian::read_u16(&self.bytes[5..7]) as usize;
+        let data = self.bytes.get(HEADER_LEN..HEADER_LEN + len)?;
+        self.bytes = &self.bytes[HEADER_LEN + len..];
+        Some(Record {
+            direction,
+            offset,
+            data,
+        })
+    }
+}
+
+#[cfg(all(feature = "host", feature = "device"))]
+impl Replay {
+    /// Create a new `Replay`.
+    pub const fn new() -> Replay {
+        Replay {
+            commands: CommandDecoder::new(),
+            session: HostSession::new(),
+        }
+    }
+
+    /// Choose how `Info` replies are read. See `ResponseDecoder::set_info_mode`.
+    pub fn set_info_mode(&mut self, mode: InfoMode) {
+        self.session.set_info_mode(mode);
+    }
+
+    /// Feed in a record, passing everything found in it to `handler`.
+    ///
+    /// Each command decoded is handed to the `HostSession` as if we had
+    /// sent it, so the responses which follow can be checked against it.
+    pub fn feed<H>(&mut self, record: &Record, mut handler: H)
+    where
+        H: FnMut(Event),
+    {
+        for &ch in record.data {
+            match record.direction {
+                Direction::HostToDevice => match self.commands.receive(ch) {
+                    Ok(None) => {}
+                    Ok(Some(command)) => {
+                        if let Err(e) = self.session.send(&command) {
+                            handler(Event::Error(Direction::HostToDevice, e));
+                        }
+                        handler(Event::Command(command));
+                    }
+                    Err(e) => handler(Event::Error(Direction::HostToDevice, e)),
+                },
+                Direction::DeviceToHost => match self.session.receive(ch) {
+                    Ok(None) => {}
+                    Ok(Some(response)) => handler(Event::Response(response)),
+                    Err(e) => handler(Event::Error(Direction::DeviceToHost, e)),
+                },
+            }
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl Direction {
+    fn to_byte(self) -> u8 {
+        match self {
+            Direction::HostToDevice => 0,
+            Direction::DeviceToHost => 1,
+        }
+    }
+}
+
+impl<'a> Capture<'a> {
+    fn append(&mut self, bytes: &[u8]) {
+        self.buffer[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        self.offset = self.offset.wrapping_add(bytes.len() as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_capture() {
+        let mut buffer = [0u8; 32];
+        let mut c = Capture::new(&mut buffer);
+        c.record(Direction::HostToDevice, &[0xFC]).unwrap();
+        c.record(Direction::HostToDevice, &[0x01]).unwrap();
+        c.record(Direction::DeviceToHost, &[0xFC, 0x11]).unwrap();
+        assert_eq!(
+            c.as_bytes(),
+            &[0, 0, 0, 0, 0, 2, 0, 0xFC, 0x01, 1, 2, 0, 0, 0, 2, 0, 0xFC, 0x11]
+        );
+        assert_eq!(c.record(Direction::HostToDevice, &[0; 8]), Err(Error::BufferFull));
+        // Extending the last record needs less room
+        c.record(Direction::DeviceToHost, &[0; 8]).unwrap();
+
+        let mut r = c.records();
+        assert_eq!(
+            r.next(),
+            Some(Record {
+                direction: Direction::HostToDevice,
+                offset: 0,
+                data: &[0xFC, 0x01],
+            })
+        );
+        assert_eq!(r.next().map(|r| (r.offset, r.data.len())), Some((2, 10)));
+        assert_eq!(r.next(), None);
+
+        // A truncated file
+        assert_eq!(records(&c.as_bytes()[0..8]).count(), 0);
+    }
+
+    #[test]
+    #[cfg(all(feature = "host", feature = "device"))]
+    fn check_replay() {
+        let capture = [
+            0, 0, 0, 0, 0, 8, 0, 0x00, 0x01, 0, 0, 0x02, 0x00, 0xFC, 0x11,
+            1, 8, 0, 0, 0, 4, 0, 0xFC, 0x20, 0xAA, 0xBB,
+            1, 12, 0, 0, 0, 2, 0, 0xFC, 0x11,
+        ];
+        let mut replay = Replay::new();
+        let mut found = 0;
+        for record in records(&capture) {
+            replay.feed(&record, |event| {
+                let expected = match found {
+                    0 => Event::Command(Command::ReadRange {
+                        address: 0x100,
+                        length: 2,
+                    }),
+                    1 => Event::Response(Response::ReadRange { data: &[0xAA, 0xBB] }),
+                    _ => Event::Error(Direction::DeviceToHost, Error::MismatchedResponse),
+                };
+                assert_eq!(event, expected);
+                found += 1;
+            });
+        }
+        assert_eq!(found, 3);
+    }
+}
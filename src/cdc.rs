@@ -0,0 +1,213 @@
+//! Running the protocol over USB CDC.
+//!
+//! A USB serial port delivers data in packets (64 bytes at full speed)
+//! rather than a byte at a time, and a frame, or even an escape sequence,
+//! can be split across two packets. The decoders keep their state between
+//! bytes, so that's no problem as long as every byte is fed in order, which
+//! is what `CdcBootloader::push_packet` does. In the other direction, a
+//! `Packetizer` cuts an encoded frame into pieces no bigger than an
+//! endpoint's buffer.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::device::{BootloaderSession, FlashInterface};
+use super::Error;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// The max packet size of a full speed bulk endpoint.
+pub const MAX_PACKET_SIZE: usize = 64;
+
+/// Cuts a frame into packets.
+///
+/// If the last packet is completely full, an empty packet follows it, so
+/// the host knows the transfer has ended.
+pub struct Packetizer<'a, I> {
+    frame: I,
+    buffer: &'a mut [u8],
+    len: usize,
+    done: bool,
+}
+
+/// A bootloader fed with whole packets from a CDC endpoint.
+pub struct CdcBootloader<F> {
+    session: BootloaderSession<F>,
+    packet: [u8; MAX_PACKET_SIZE],
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<'a, I> Packetizer<'a, I>
+where
+    I: Iterator<Item = u8>,
+{
+    /// Packetize the bytes from `frame` (a `CommandEncoder` or
+    /// `ResponseEncoder`), using `buffer` for each packet. The buffer should
+    /// be the endpoint's max packet size.
+    pub fn new(frame: I, buffer: &'a mut [u8]) -> Packetizer<'a, I> {
+        Packetizer {
+            frame,
+            buffer,
+            len: 0,
+            done: false,
+        }
+    }
+
+    /// Get the next packet to send, or `None` when the frame is finished.
+    pub fn next_packet(&mut self) -> Option<&[u8]> {
+        if self.done {
+            return None;
+        }
+        let last_len = self.len;
+        self.len = 0;
+        while self.len < self.buffer.len() {
+            match self.frame.next() {
+                Some(b) => {
+                    self.buffer[self.len] = b;
+                    self.len += 1;
+                }
+                None => break,
+            }
+        }
+        if self.len < self.buffer.len() {
+            self.done = true;
+            // Nothing left, and the last packet was short (or there wasn't
+            // one), so it doesn't need terminating.
+            if self.len == 0 && last_len != self.buffer.len() {
+                return None;
+            }
+        }
+        Some(&self.buffer[0..self.len])
+    }
+}
+
+impl<F> CdcBootloader<F>
+where
+    F: FlashInterface,
+{
+    /// Create a new `CdcBootloader` driving the given flash.
+    #[inline(always)]
+    pub const fn new(flash: F) -> CdcBootloader<F> {
+        CdcBootloader {
+            session: BootloaderSession::new(flash),
+            packet: [0u8; MAX_PACKET_SIZE],
+        }
+    }
+
+    /// Wrap an existing session. This moves the whole session, which is
+    /// over a kilobyte, so on a small stack prefer `new`.
+    pub const fn from_session(session: BootloaderSession<F>) -> CdcBootloader<F> {
+        CdcBootloader {
+            session,
+            packet: [0u8; MAX_PACKET_SIZE],
+        }
+    }
+
+    /// Process a packet from the OUT endpoint.
+    ///
+    /// A packet can finish any number of commands. Each is carried out and
+    /// its response passed to `send` one packet at a time, for the IN
+    /// endpoint. If a response can't be encoded we stop there and return
+    /// the error; the rest of the packet is dropped.
+    pub fn push_packet<S>(&mut self, packet: &[u8], mut send: S) -> Result<(), Error>
+    where
+        S: FnMut(&[u8]),
+    {
+        for &ch in packet {
+            let format = self.session.reply_format();
+            if let Some(response) = self.session.receive(ch) {
+                let encoder = format.encoder(&response)?;
+                let mut packets = Packetizer::new(encoder, &mut self.packet);
+                while let Some(p) = packets.next_packet() {
+                    send(p);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the session.
+    pub fn session(&self) -> &BootloaderSession<F> {
+        &self.session
+    }
+
+    /// Get the session mutably, to reach the flash.
+    pub fn session_mut(&mut self) -> &mut BootloaderSession<F> {
+        &mut self.session
+    }
+
+    /// Get the session back.
+    pub fn release(self) -> BootloaderSession<F> {
+        self.session
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use super::super::mock::MemFlash;
+    use super::super::{Command, CommandEncoder};
+    use std::vec::Vec;
+
+    #[test]
+    fn check_packetizer() {
+        let mut buffer = [0u8; 4];
+        let mut p = Packetizer::new(0..6u8, &mut buffer);
+        assert_eq!(p.next_packet(), Some(&[0, 1, 2, 3][..]));
+        assert_eq!(p.next_packet(), Some(&[4, 5][..]));
+        assert_eq!(p.next_packet(), None);
+
+        // A full last packet gets a zero length packet after it
+        let mut p = Packetizer::new(0..4u8, &mut buffer);
+        assert_eq!(p.next_packet(), Some(&[0, 1, 2, 3][..]));
+        assert_eq!(p.next_packet(), Some(&[][..]));
+        assert_eq!(p.next_packet(), None);
+
+        let mut p = Packetizer::new(0..0u8, &mut buffer);
+        assert_eq!(p.next_packet(), None);
+    }
+
+    #[test]
+    fn check_split_packets() {
+        let mut flash = MemFlash::new(0x1000);
+        flash.data_mut()[0x10] = 0xFC;
+        let mut bl = CdcBootloader::new(flash);
+        let cmd = Command::ReadRange {
+            address: 0x10,
+            length: 100,
+        };
+        let mut rx: Vec<u8> = CommandEncoder::new(&Command::Ping).unwrap().collect();
+        rx.extend(CommandEncoder::new(&cmd).unwrap());
+
+        // Split right after the escape byte of the first command
+        let mut packets = Vec::new();
+        bl.push_packet(&rx[0..1], |p| packets.push(p.to_vec())).unwrap();
+        assert!(packets.is_empty());
+        bl.push_packet(&rx[1..], |p| packets.push(p.to_vec())).unwrap();
+
+        // A Pong, then 2 + 101 bytes (the 0xFC is doubled) of ReadRange
+        let lens: Vec<usize> = packets.iter().map(|p| p.len()).collect();
+        assert_eq!(lens, [2, 64, 39]);
+        assert_eq!(packets[0], [0xFC, 0x11]);
+        assert_eq!(&packets[1][0..4], &[0xFC, 0x20, 0xFC, 0xFC]);
+        assert!(packets[2].iter().all(|&b| b == 0xFF));
+    }
+}
@@ -0,0 +1,191 @@
+//! Timing out responses which never arrive.
+//!
+//! A `HostSession` has no idea how long it has been waiting unless it is
+//! given a `Clock`. With one (see `HostSession::with_clock`), `set_timeouts`
+//! says how long to wait for the first byte of a response, for each byte
+//! after that, and for the whole response. Call `poll` while waiting and it
+//! reports a `TimedOut`, along with whatever arrived, once any of them runs
+//! out:
+//!
+//! ```ignore
+//! let mut session = HostSession::with_clock(StdClock::new());
+//! session.set_timeouts(Timeouts {
+//!     first_byte_ms: Some(500),
+//!     inter_byte_ms: Some(50),
+//!     total_ms: Some(2000),
+//! });
+//! // send a command, then
+//! loop {
+//!     if let Some(ch) = uart.try_read() {
+//!         if let Some(response) = session.receive(ch)? { break; }
+//!     }
+//!     session.poll()?;
+//! }
+//! ```
+//!
+//! On a microcontroller, a `Clock` is usually a millisecond tick counter.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::fmt;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// A source of time.
+pub trait Clock {
+    /// The number of milliseconds since some fixed point. It's fine for this
+    /// to wrap around.
+    fn now_ms(&self) -> u32;
+}
+
+/// A `Clock` which never moves, so nothing ever times out. This is what a
+/// `HostSession` has unless it's given another.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoClock;
+
+/// A `Clock` which counts from when it was made, using `std::time::Instant`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy)]
+pub struct StdClock {
+    start: std::time::Instant,
+}
+
+/// How long a `HostSession` waits for a response. Each limit is in
+/// milliseconds, and `None` means wait forever.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Timeouts {
+    /// How long to wait after sending a command for the first byte of its
+    /// response.
+    pub first_byte_ms: Option<u32>,
+    /// How long to wait between one byte of a response and the next.
+    pub inter_byte_ms: Option<u32>,
+    /// How long to wait after sending a command for the whole response.
+    pub total_ms: Option<u32>,
+}
+
+/// Which of the `Timeouts` ran out.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TimeoutKind {
+    /// Nothing at all came back.
+    FirstByte,
+    /// The response stopped partway through.
+    InterByte,
+    /// The response took too long altogether.
+    Total,
+}
+
+/// A response which didn't arrive in time.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TimedOut<'a> {
+    /// Which limit ran out.
+    pub kind: TimeoutKind,
+    /// The part of the response which did arrive, with escapes removed,
+    /// starting with the response code.
+    pub partial: &'a [u8],
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl Clock for NoClock {
+    fn now_ms(&self) -> u32 {
+        0
+    }
+}
+
+#[cfg(feature = "std")]
+impl StdClock {
+    /// A clock which reads zero now.
+    pub fn new() -> StdClock {
+        StdClock {
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for StdClock {
+    fn default() -> StdClock {
+        StdClock::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    fn now_ms(&self) -> u32 {
+        self.start.elapsed().as_millis() as u32
+    }
+}
+
+impl Timeouts {
+    /// No timeouts at all.
+    pub const fn new() -> Timeouts {
+        Timeouts {
+            first_byte_ms: None,
+            inter_byte_ms: None,
+            total_ms: None,
+        }
+    }
+
+    /// Which limit, if any, has run out, given how long it's been since the
+    /// command was sent and (if anything has arrived) since the last byte.
+    pub(crate) fn check(&self, since_sent: u32, since_byte: Option<u32>) -> Option<TimeoutKind> {
+        let over = |limit: Option<u32>, elapsed: u32| limit.is_some_and(|l| elapsed >= l);
+        if over(self.total_ms, since_sent) {
+            return Some(TimeoutKind::Total);
+        }
+        match since_byte {
+            None if over(self.first_byte_ms, since_sent) => Some(TimeoutKind::FirstByte),
+            Some(elapsed) if over(self.inter_byte_ms, elapsed) => Some(TimeoutKind::InterByte),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            TimeoutKind::FirstByte => "no response",
+            TimeoutKind::InterByte => "response stopped partway",
+            TimeoutKind::Total => "response took too long",
+        })
+    }
+}
+
+impl<'a> fmt::Display for TimedOut<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "timed out: {} ({} bytes received)", self.kind, self.partial.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_timeouts() {
+        let t = Timeouts {
+            first_byte_ms: Some(100),
+            inter_byte_ms: Some(10),
+            total_ms: Some(500),
+        };
+        assert_eq!(t.check(99, None), None);
+        assert_eq!(t.check(100, None), Some(TimeoutKind::FirstByte));
+        assert_eq!(t.check(300, Some(9)), None);
+        assert_eq!(t.check(300, Some(10)), Some(TimeoutKind::InterByte));
+        assert_eq!(t.check(500, Some(0)), Some(TimeoutKind::Total));
+        assert_eq!(Timeouts::new().check(u32::MAX, Some(u32::MAX)), None);
+    }
+}
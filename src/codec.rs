@@ -0,0 +1,254 @@
+//! tokio-util codecs.
+//!
+//! With the `tokio-util` feature, an async host tool can wrap its serial
+//! port in `Framed::new(port, TockloaderCodec::new())` and get a
+//! `Sink<Command>` and a `Stream<OwnedResponse>`, with all the escaping
+//! handled. The `BootloaderCodec` is the other way round, for a bootloader
+//! (or a simulated one) running under tokio.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::convert::TryFrom;
+use std::fmt;
+use std::io;
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::device::ReplyFormat;
+use super::owned::{OwnedCommand, OwnedResponse};
+use super::session::HostSession;
+use super::{Command, CommandDecoder, Error, Response, MAX_CHUNK_LEN};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// The host side codec: encodes `Command`s and decodes `OwnedResponse`s.
+///
+/// It keeps track of the command in flight like a `HostSession`, so it knows
+/// how long a `ReadRange` reply will be and can reject replies which don't
+/// match.
+#[derive(Default)]
+pub struct TockloaderCodec {
+    session: HostSession,
+}
+
+/// The bootloader side codec: decodes `OwnedCommand`s and encodes
+/// `Response`s.
+#[derive(Default)]
+pub struct BootloaderCodec {
+    decoder: CommandDecoder,
+    format: ReplyFormat,
+}
+
+/// The ways a codec can fail.
+#[derive(Debug)]
+pub enum CodecError {
+    /// The underlying stream failed.
+    Io(io::Error),
+    /// A frame couldn't be encoded or decoded.
+    Protocol(Error),
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl TockloaderCodec {
+    /// Create a new `TockloaderCodec`.
+    pub const fn new() -> TockloaderCodec {
+        TockloaderCodec {
+            session: HostSession::new(),
+        }
+    }
+}
+
+impl<'a> Encoder<Command<'a>> for TockloaderCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: Command<'a>, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let mut encoder = self.session.send(&item).map_err(CodecError::Protocol)?;
+        while let Some(chunk) = encoder.next_chunk(MAX_CHUNK_LEN) {
+            dst.put_slice(chunk);
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for TockloaderCodec {
+    type Item = OwnedResponse;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<OwnedResponse>, CodecError> {
+        let mut used = 0;
+        let mut result = Ok(None);
+        for &ch in src.iter() {
+            used += 1;
+            result = match self.session.receive(ch) {
+                Ok(Some(ref response)) => OwnedResponse::try_from(response).map(Some),
+                Ok(None) => continue,
+                Err(e) => Err(e),
+            };
+            break;
+        }
+        src.advance(used);
+        result.map_err(CodecError::Protocol)
+    }
+}
+
+impl BootloaderCodec {
+    /// Create a new `BootloaderCodec`.
+    pub const fn new() -> BootloaderCodec {
+        BootloaderCodec {
+            decoder: CommandDecoder::new(),
+            format: ReplyFormat::new(),
+        }
+    }
+
+    /// Accept attribute values up to `len` bytes long, rather than
+    /// `MAX_ATTR_LEN`, and pad `GetAttr` replies out to `len`. See
+    /// `BootloaderSession::set_attr_len`.
+    #[cfg(feature = "attributes")]
+    pub fn set_attr_len(&mut self, len: usize) {
+        self.decoder.set_attr_len(len);
+        self.format.set_attr_len(len);
+    }
+
+    /// Pad `Id` replies out to `len` bytes, rather than `ID_LEN`. See
+    /// `BootloaderSession::set_id_len`.
+    pub fn set_id_len(&mut self, len: usize) {
+        self.format.set_id_len(len);
+    }
+}
+
+impl Decoder for BootloaderCodec {
+    type Item = OwnedCommand;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<OwnedCommand>, CodecError> {
+        let mut used = 0;
+        let mut result = Ok(None);
+        for &ch in src.iter() {
+            used += 1;
+            result = match self.decoder.receive(ch) {
+                Ok(Some(ref command)) => OwnedCommand::try_from(command).map(Some),
+                Ok(None) => continue,
+                Err(e) => Err(e),
+            };
+            break;
+        }
+        src.advance(used);
+        result.map_err(CodecError::Protocol)
+    }
+}
+
+impl<'a> Encoder<Response<'a>> for BootloaderCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: Response<'a>, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let mut encoder = self.format.encoder(&item).map_err(CodecError::Protocol)?;
+        while let Some(chunk) = encoder.next_chunk(MAX_CHUNK_LEN) {
+            dst.put_slice(chunk);
+        }
+        Ok(())
+    }
+}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> CodecError {
+        CodecError::Io(e)
+    }
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CodecError::Io(ref e) => write!(f, "I/O error: {}", e),
+            CodecError::Protocol(ref e) => write!(f, "protocol error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            CodecError::Io(ref e) => Some(e),
+            #[cfg(feature = "core-error")]
+            CodecError::Protocol(ref e) => Some(e),
+            #[cfg(not(feature = "core-error"))]
+            CodecError::Protocol(_) => None,
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_host_codec() {
+        let mut codec = TockloaderCodec::new();
+        let mut wire = BytesMut::new();
+        let cmd = Command::ReadRange {
+            address: 0x30000,
+            length: 3,
+        };
+        codec.encode(cmd, &mut wire).unwrap();
+        assert_eq!(&wire[..], &[0x00, 0x00, 0x03, 0x00, 0x03, 0x00, 0xFC, 0x11]);
+
+        // The reply arrives in pieces, with an escaped byte and a stray
+        // byte after it
+        let mut rx = BytesMut::from(&[0xFC, 0x20, 0x01][..]);
+        assert!(codec.decode(&mut rx).unwrap().is_none());
+        assert!(rx.is_empty());
+        rx.extend_from_slice(&[0xFC, 0xFC, 0x02, 0x99]);
+        let response = codec.decode(&mut rx).unwrap().unwrap();
+        assert_eq!(
+            response.as_ref(),
+            Response::ReadRange {
+                data: &[0x01, 0xFC, 0x02],
+            }
+        );
+        assert_eq!(&rx[..], &[0x99]);
+    }
+
+    #[test]
+    fn check_bootloader_codec() {
+        let mut codec = BootloaderCodec::new();
+        let mut rx = BytesMut::from(&[0xFC, 0x01, 0xFC][..]);
+        let command = codec.decode(&mut rx).unwrap().unwrap();
+        assert_eq!(command.as_ref(), Command::Ping);
+        assert!(codec.decode(&mut rx).unwrap().is_none());
+        assert!(rx.is_empty());
+
+        let mut wire = BytesMut::new();
+        codec.encode(Response::Pong, &mut wire).unwrap();
+        assert_eq!(&wire[..], &[0xFC, 0x11]);
+    }
+
+    #[test]
+    fn check_bootloader_codec_id_len() {
+        let mut codec = BootloaderCodec::new();
+        codec.set_id_len(12);
+        let mut wire = BytesMut::new();
+        codec.encode(Response::Id { id: &[0x01; 8] }, &mut wire).unwrap();
+        assert_eq!(&wire[..2], &[0xFC, 0x27]);
+        assert_eq!(&wire[2..10], &[0x01; 8]);
+        assert_eq!(wire.len(), 2 + 12);
+    }
+}
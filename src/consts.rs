@@ -0,0 +1,46 @@
+//! Constants from the protocol.
+//!
+//! These are the numbers the encoders and decoders use, so buffers sized
+//! from them will always be big enough.
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// Marks the end of a command, or the start of a response. A data byte
+/// with this value is sent twice.
+pub const ESCAPE_CHAR: u8 = 0xFC;
+
+/// The number of attribute slots. Indices run from 0 to `MAX_INDEX - 1`.
+pub const MAX_INDEX: u8 = 16;
+
+/// The length of an attribute key. Shorter keys are padded with nulls.
+pub const KEY_LEN: usize = 8;
+
+/// The longest an attribute value can be, as laid out in the spec. The
+/// codecs can be set up for bootloaders with longer ones; see
+/// `CommandDecoder::set_attr_len`.
+pub const MAX_ATTR_LEN: usize = 55;
+
+/// The longest a long attribute value can be, so that a slot of key, two
+/// byte length and value fills a 512 byte page. See `Command::SetLongAttr`.
+pub const MAX_LONG_ATTR_LEN: usize = 502;
+
+/// The size of an internal flash page, as sent with `WritePage`.
+pub const INT_PAGE_SIZE: usize = 512;
+
+/// The size of an external flash page, as sent with `WriteExPage`.
+pub const EXT_PAGE_SIZE: usize = 256;
+
+/// The length of the unique ID in an `Id` response, unless both ends are
+/// set up for another. See `ResponseDecoder::set_id_len`.
+pub const ID_LEN: usize = 8;
+
+/// The longest the string in an `Info` response can be.
+pub const MAX_INFO_LEN: usize = 192;
+
+/// The longest frame the decoders will hold, not counting escapes. Enough
+/// for a 4 byte address, a 512 byte page and a little spare.
+pub const MAX_FRAME_LEN: usize = 520;
@@ -0,0 +1,147 @@
+//! The CRC used by the `Crc*` commands.
+//!
+//! Tockloader checks the replies to `CrcRxBuffer`, `CrcIntFlash` and
+//! `CrcExtFlash` against the standard CRC-32 (the one used by zlib and
+//! Ethernet: reflected polynomial 0xEDB88320, initial value and final XOR of
+//! 0xFFFFFFFF). A bootloader can feed data into a `Crc32` as it arrives, so
+//! it never needs to hold the whole range in memory.
+//!
+//! By default a 1 KiB lookup table is used. Enable the `small-crc` feature to
+//! compute the CRC a bit at a time instead, which is slower but saves the
+//! table.
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// An incremental CRC-32 calculation.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Crc32 {
+    state: u32,
+}
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+#[cfg(not(feature = "small-crc"))]
+static TABLE: [u32; 256] = make_table();
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Calculate the CRC-32 of `data` in one go.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+impl Crc32 {
+    /// Start a new calculation.
+    pub const fn new() -> Crc32 {
+        Crc32 { state: 0xFFFF_FFFF }
+    }
+
+    /// Add some more data to the calculation.
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.state = step(self.state, byte);
+        }
+    }
+
+    /// Get the CRC of all the data supplied so far. You can carry on calling
+    /// `update` afterwards.
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+
+    /// Start again.
+    pub fn reset(&mut self) {
+        self.state = 0xFFFF_FFFF;
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32::new()
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(not(feature = "small-crc"))]
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut value = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            value = if value & 1 != 0 { (value >> 1) ^ POLYNOMIAL } else { value >> 1 };
+            bit += 1;
+        }
+        table[i] = value;
+        i += 1;
+    }
+    table
+}
+
+#[cfg(not(feature = "small-crc"))]
+fn step(state: u32, byte: u8) -> u32 {
+    TABLE[((state ^ u32::from(byte)) & 0xFF) as usize] ^ (state >> 8)
+}
+
+#[cfg(feature = "small-crc")]
+fn step(state: u32, byte: u8) -> u32 {
+    let mut state = state ^ u32::from(byte);
+    for _ in 0..8 {
+        // All ones if the bottom bit is set
+        let mask = (state & 1).wrapping_neg();
+        state = (state >> 1) ^ (POLYNOMIAL & mask);
+    }
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_vectors() {
+        assert_eq!(crc32(b""), 0x0000_0000);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        // An erased page, as tockloader sees when checking a blank region
+        assert_eq!(crc32(&[0xFF; 512]), 0xBD7B_C39F);
+        assert_eq!(crc32(&[0xFC; 64]), 0x8DA1_9D11);
+        let mut ramp = [0u8; 256];
+        for (i, b) in ramp.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        assert_eq!(crc32(&ramp), 0x2905_8C73);
+    }
+
+    #[test]
+    fn check_incremental() {
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+        crc.reset();
+        assert_eq!(crc.finish(), 0);
+    }
+}
@@ -0,0 +1,256 @@
+//! `Debug` for the protocol types.
+//!
+//! A derived `Debug` prints every byte of a page, which buries everything
+//! else in a log. Payloads are shown as their length and the bytes at each
+//! end instead, and addresses are shown in hex.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::fmt;
+
+use super::{Command, Response};
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+/// A payload, shown as its length and the bytes at each end.
+struct Payload<'a>(&'a [u8]);
+
+/// A number shown in hex.
+struct Hex(u32);
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+/// How many bytes to show from each end of a payload.
+const EDGE_LEN: usize = 4;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<'a> fmt::Debug for Command<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Command::Ping => f.write_str("Ping"),
+            Command::Info => f.write_str("Info"),
+            Command::Id => f.write_str("Id"),
+            Command::Reset => f.write_str("Reset"),
+            Command::ErasePage { address } => {
+                f.debug_struct("ErasePage").field("address", &Hex(address)).finish()
+            }
+            Command::WritePage { address, data } => f
+                .debug_struct("WritePage")
+                .field("address", &Hex(address))
+                .field("data", &Payload(data))
+                .finish(),
+            #[cfg(feature = "ext-flash")]
+            Command::EraseExBlock { address } => {
+                f.debug_struct("EraseExBlock").field("address", &Hex(address)).finish()
+            }
+            #[cfg(feature = "ext-flash")]
+            Command::WriteExPage { address, data } => f
+                .debug_struct("WriteExPage")
+                .field("address", &Hex(address))
+                .field("data", &Payload(data))
+                .finish(),
+            Command::CrcRxBuffer => f.write_str("CrcRxBuffer"),
+            Command::ReadRange { address, length } => f
+                .debug_struct("ReadRange")
+                .field("address", &Hex(address))
+                .field("length", &length)
+                .finish(),
+            #[cfg(feature = "ext-flash")]
+            Command::ExReadRange { address, length } => f
+                .debug_struct("ExReadRange")
+                .field("address", &Hex(address))
+                .field("length", &length)
+                .finish(),
+            #[cfg(feature = "attributes")]
+            Command::SetAttr { index, key, value } => f
+                .debug_struct("SetAttr")
+                .field("index", &index)
+                .field("key", &key)
+                .field("value", &Payload(value))
+                .finish(),
+            #[cfg(feature = "attributes")]
+            Command::GetAttr { index } => f.debug_struct("GetAttr").field("index", &index).finish(),
+            Command::CrcIntFlash { address, length } => f
+                .debug_struct("CrcIntFlash")
+                .field("address", &Hex(address))
+                .field("length", &length)
+                .finish(),
+            #[cfg(feature = "ext-flash")]
+            Command::CrcExtFlash { address, length } => f
+                .debug_struct("CrcExtFlash")
+                .field("address", &Hex(address))
+                .field("length", &length)
+                .finish(),
+            #[cfg(feature = "ext-flash")]
+            Command::EraseExPage { address } => {
+                f.debug_struct("EraseExPage").field("address", &Hex(address)).finish()
+            }
+            #[cfg(feature = "ext-flash")]
+            Command::ExtFlashInit => f.write_str("ExtFlashInit"),
+            Command::ClockOut => f.write_str("ClockOut"),
+            #[cfg(feature = "user-pages")]
+            Command::WriteFlashUserPages { page1, page2 } => f
+                .debug_struct("WriteFlashUserPages")
+                .field("page1", &Hex(page1))
+                .field("page2", &Hex(page2))
+                .finish(),
+            #[cfg(feature = "baud-change")]
+            Command::ChangeBaud { mode, baud } => f
+                .debug_struct("ChangeBaud")
+                .field("mode", &mode)
+                .field("baud", &baud)
+                .finish(),
+            #[cfg(feature = "multi-drop")]
+            Command::SetAddress { address } => {
+                f.debug_struct("SetAddress").field("address", &address).finish()
+            }
+            #[cfg(feature = "long-attributes")]
+            Command::SetLongAttr { index, key, value } => f
+                .debug_struct("SetLongAttr")
+                .field("index", &index)
+                .field("key", &key)
+                .field("value", &Payload(value))
+                .finish(),
+            #[cfg(feature = "long-attributes")]
+            Command::GetLongAttr { index } => {
+                f.debug_struct("GetLongAttr").field("index", &index).finish()
+            }
+            Command::Exit => f.write_str("Exit"),
+        }
+    }
+}
+
+impl<'a> fmt::Debug for Response<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Response::Overflow => f.write_str("Overflow"),
+            Response::Pong => f.write_str("Pong"),
+            Response::BadAddress => f.write_str("BadAddress"),
+            Response::InternalError => f.write_str("InternalError"),
+            Response::BadArguments => f.write_str("BadArguments"),
+            Response::Ok => f.write_str("Ok"),
+            Response::Unknown => f.write_str("Unknown"),
+            #[cfg(feature = "ext-flash")]
+            Response::ExtFlashTimeout => f.write_str("ExtFlashTimeout"),
+            #[cfg(feature = "ext-flash")]
+            Response::ExtFlashPageError => f.write_str("ExtFlashPageError"),
+            Response::CrcRxBuffer { length, crc } => f
+                .debug_struct("CrcRxBuffer")
+                .field("length", &length)
+                .field("crc", &Hex(crc))
+                .finish(),
+            Response::ReadRange { data } => {
+                f.debug_struct("ReadRange").field("data", &Payload(data)).finish()
+            }
+            #[cfg(feature = "ext-flash")]
+            Response::ExReadRange { data } => {
+                f.debug_struct("ExReadRange").field("data", &Payload(data)).finish()
+            }
+            #[cfg(feature = "attributes")]
+            Response::GetAttr { key, value } => f
+                .debug_struct("GetAttr")
+                .field("key", &key)
+                .field("value", &Payload(value))
+                .finish(),
+            Response::CrcIntFlash { crc } => {
+                f.debug_struct("CrcIntFlash").field("crc", &Hex(crc)).finish()
+            }
+            #[cfg(feature = "ext-flash")]
+            Response::CrcExtFlash { crc } => {
+                f.debug_struct("CrcExtFlash").field("crc", &Hex(crc)).finish()
+            }
+            Response::Info { info } => {
+                f.debug_struct("Info").field("info", &Payload(info)).finish()
+            }
+            #[cfg(feature = "baud-change")]
+            Response::ChangeBaudFail => f.write_str("ChangeBaudFail"),
+            Response::Id { id } => f.debug_struct("Id").field("id", &Payload(id)).finish(),
+            #[cfg(feature = "long-attributes")]
+            Response::GetLongAttr { key, value } => f
+                .debug_struct("GetLongAttr")
+                .field("key", &key)
+                .field("value", &Payload(value))
+                .finish(),
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<'a> fmt::Debug for Payload<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{} bytes", self.0.len())?;
+        if self.0.len() <= 2 * EDGE_LEN {
+            write_bytes(f, self.0, ":")?;
+        } else {
+            write_bytes(f, &self.0[0..EDGE_LEN], ":")?;
+            write_bytes(f, &self.0[self.0.len() - EDGE_LEN..], " ..")?;
+        }
+        f.write_str(">")
+    }
+}
+
+impl fmt::Debug for Hex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+/// Write `bytes` in hex, separated by spaces, after `prefix`.
+fn write_bytes(f: &mut fmt::Formatter, bytes: &[u8], prefix: &str) -> fmt::Result {
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    f.write_str(prefix)?;
+    for b in bytes {
+        write!(f, " {:02x}", b)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+
+    #[test]
+    fn check_debug() {
+        let mut page = [0u8; 512];
+        page[0] = 0xAA;
+        page[511] = 0xBB;
+        let cmd = Command::WritePage {
+            address: 0x30000,
+            data: &page,
+        };
+        assert_eq!(
+            format!("{:?}", cmd),
+            "WritePage { address: 0x30000, data: <512 bytes: aa 00 00 00 .. 00 00 00 bb> }"
+        );
+        let info = Response::Info { info: b"tock" };
+        assert_eq!(format!("{:?}", info), "Info { info: <4 bytes: 74 6f 63 6b> }");
+        let empty = Response::ReadRange { data: &[] };
+        assert_eq!(format!("{:?}", empty), "ReadRange { data: <0 bytes> }");
+        assert_eq!(format!("{:?}", Command::Ping), "Ping");
+    }
+}
@@ -0,0 +1,1561 @@
+//! The bootloader side of the protocol.
+//!
+//! A bootloader has to decode commands, perform them on its flash and send
+//! back the right response. The `BootloaderSession` does all of that, given
+//! an implementation of the `FlashInterface` trait for the hardware in
+//! question.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::address::{ExtFlashGeometry, IntFlashAddr};
+#[cfg(feature = "ext-flash")]
+use super::address::ExtFlashAddr;
+use super::crc::Crc32;
+use super::observer::Observer;
+use super::pages::FlashTarget;
+use super::policy::AccessPolicy;
+#[cfg(feature = "attributes")]
+use super::signature::{parse_signature, SignedImage, Verifier, SIGNATURE_KEY};
+#[cfg(feature = "baud-change")]
+use super::BaudMode;
+use super::{Command, CommandDecoder, Error, Response, ResponseEncoder};
+#[cfg(feature = "sequence")]
+use super::ResponseCode;
+use super::{ID_LEN, MAX_FRAME_LEN, MAX_INFO_LEN};
+#[cfg(feature = "attributes")]
+use super::{KEY_LEN, MAX_ATTR_LEN};
+#[cfg(feature = "long-attributes")]
+use super::MAX_LONG_ATTR_LEN;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// The ways in which a `FlashInterface` operation can fail. Each is reported
+/// to the host as a different `Response`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FlashError {
+    /// The address was out of range or not suitably aligned. Sent as
+    /// `Response::BadAddress`.
+    BadAddress,
+    /// The other arguments were invalid. Sent as `Response::BadArguments`.
+    BadArguments,
+    /// The hardware failed. Sent as `Response::InternalError`.
+    Internal,
+    /// This bootloader doesn't support the operation. Sent as
+    /// `Response::Unknown`.
+    Unsupported,
+}
+
+/// The hardware operations a bootloader needs to provide.
+///
+/// The internal flash and attribute operations are required. The external
+/// flash operations, the long attribute operations, `info` and `id` are
+/// optional and by default report `FlashError::Unsupported`.
+pub trait FlashInterface {
+    /// Fill `buffer` with the contents of internal flash at `address`.
+    fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), FlashError>;
+
+    /// Write a 512 byte page of internal flash.
+    fn write_page(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError>;
+
+    /// Erase a 512 byte page of internal flash.
+    fn erase_page(&mut self, address: u32) -> Result<(), FlashError>;
+
+    /// Read the attribute at `index`. The 8 byte key goes in `key` and the
+    /// value in `value` (which is `MAX_ATTR_LEN` bytes long, unless changed
+    /// with `BootloaderSession::set_attr_len`). Returns the length of the
+    /// value.
+    fn get_attr(&mut self, index: u8, key: &mut [u8], value: &mut [u8])
+        -> Result<usize, FlashError>;
+
+    /// Store an attribute at `index`. The key is 8 bytes, null padded.
+    fn set_attr(&mut self, index: u8, key: &[u8], value: &[u8]) -> Result<(), FlashError>;
+
+    /// Read the long attribute at `index`. The 8 byte key goes in `key` and
+    /// the value in `value`, which is `MAX_LONG_ATTR_LEN` bytes long.
+    /// Returns the length of the value.
+    #[cfg(feature = "long-attributes")]
+    fn get_long_attr(&mut self, _index: u8, _key: &mut [u8], _value: &mut [u8])
+        -> Result<usize, FlashError> {
+        Err(FlashError::Unsupported)
+    }
+
+    /// Store a long attribute at `index`. The key is 8 bytes, null padded.
+    #[cfg(feature = "long-attributes")]
+    fn set_long_attr(&mut self, _index: u8, _key: &[u8], _value: &[u8])
+        -> Result<(), FlashError> {
+        Err(FlashError::Unsupported)
+    }
+
+    /// Calculate the CRC32 of a range of internal flash.
+    fn crc_range(&mut self, address: u32, length: u32) -> Result<u32, FlashError>;
+
+    /// Fill `buffer` (which is 192 bytes long) with an information string
+    /// and return its length.
+    fn info(&mut self, _buffer: &mut [u8]) -> Result<usize, FlashError> {
+        Err(FlashError::Unsupported)
+    }
+
+    /// Fill `id` with the chip's unique ID. It's `ID_LEN` bytes long, unless
+    /// changed with `BootloaderSession::set_id_len`.
+    fn id(&mut self, _id: &mut [u8]) -> Result<(), FlashError> {
+        Err(FlashError::Unsupported)
+    }
+
+    /// Initialise the external flash chip.
+    fn ex_init(&mut self) -> Result<(), FlashError> {
+        Err(FlashError::Unsupported)
+    }
+
+    /// Fill `buffer` with the contents of external flash at `address`.
+    fn ex_read(&mut self, _address: u32, _buffer: &mut [u8]) -> Result<(), FlashError> {
+        Err(FlashError::Unsupported)
+    }
+
+    /// Write a 256 byte page of external flash.
+    fn ex_write_page(&mut self, _address: u32, _data: &[u8]) -> Result<(), FlashError> {
+        Err(FlashError::Unsupported)
+    }
+
+    /// Erase a page of external flash, 256 bytes unless the chip's
+    /// `ExtFlashGeometry` says otherwise.
+    fn ex_erase_page(&mut self, _address: u32) -> Result<(), FlashError> {
+        Err(FlashError::Unsupported)
+    }
+
+    /// Erase a block of external flash.
+    fn ex_erase_block(&mut self, _address: u32) -> Result<(), FlashError> {
+        Err(FlashError::Unsupported)
+    }
+
+    /// Calculate the CRC32 of a range of external flash.
+    fn ex_crc_range(&mut self, _address: u32, _length: u32) -> Result<u32, FlashError> {
+        Err(FlashError::Unsupported)
+    }
+}
+
+/// The `BootloaderSession` takes bytes from the host, carries out the
+/// decoded `Command`s on a `FlashInterface` and gives you the `Response` to
+/// send back.
+pub struct BootloaderSession<F> {
+    flash: F,
+    decoder: CommandDecoder,
+    buffer: [u8; MAX_FRAME_LEN],
+    check_alignment: bool,
+    ext_geometry: Option<ExtFlashGeometry>,
+    lengths: Lengths,
+    exit_command: bool,
+    exit: bool,
+    staging: Staging,
+    policy: Option<&'static dyn AccessPolicy>,
+    denied: FlashError,
+    #[cfg(feature = "attributes")]
+    verifier: Option<&'static dyn Verifier>,
+    #[cfg(feature = "attributes")]
+    verified: bool,
+    #[cfg(feature = "baud-change")]
+    baud: Option<BaudGuard>,
+    #[cfg(feature = "baud-change")]
+    baud_switch: Option<u32>,
+    #[cfg(feature = "multi-drop")]
+    new_address: Option<u8>,
+    #[cfg(feature = "sequence")]
+    last_sequence: Option<u8>,
+    #[cfg(feature = "sequence")]
+    last_reply: Option<ResponseCode>,
+}
+
+/// How a session's replies have to be encoded: the attribute and ID
+/// lengths it was set up with and, with the `multi-drop` feature, the
+/// address to answer from. Take it with `BootloaderSession::reply_format`
+/// before handing the session a byte, as the `Response` borrows the session.
+#[derive(Debug, Clone, Copy)]
+pub struct ReplyFormat {
+    lengths: Lengths,
+    #[cfg(feature = "multi-drop")]
+    address: Option<u8>,
+}
+
+/// Enforces the `ChangeBaud` handshake on the bootloader side.
+///
+/// A `ChangeBaud` with `BaudMode::Set` is answered at the old rate, then
+/// the UART moves to the new rate. The next command has to be a
+/// `ChangeBaud` with `BaudMode::Verify` and the same rate. Anything else
+/// gets `ChangeBaudFail` and the UART goes back to the old rate.
+///
+/// Pass each decoded command to `handle_command` before acting on it.
+#[cfg(feature = "baud-change")]
+#[derive(Debug, Clone)]
+pub struct BaudGuard {
+    baud: u32,
+    pending: Option<u32>,
+}
+
+/// What the caller of `BaudGuard::handle_command` should do with the
+/// command.
+#[cfg(feature = "baud-change")]
+#[derive(Debug, PartialEq)]
+pub enum BaudAction {
+    /// Nothing to do with the baud rate; carry out the command as usual.
+    Perform,
+    /// Send this response instead, then if there is a rate, switch the UART
+    /// to it.
+    Reply(Response<'static>, Option<u32>),
+}
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+/// Where a session is with staged writes.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Staging {
+    /// Writes happen straight away.
+    Off,
+    /// Nothing is staged.
+    Empty,
+    /// A page of this length, for this address, is waiting in the buffer.
+    Page(FlashTarget, u32, usize),
+}
+
+/// How long the variable length parts of some replies are.
+#[derive(Debug, Clone, Copy)]
+struct Lengths {
+    #[cfg(feature = "attributes")]
+    attr: usize,
+    id: usize,
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<F> BootloaderSession<F>
+where
+    F: FlashInterface,
+{
+    /// Create a new `BootloaderSession` driving the given flash.
+    #[inline(always)]
+    pub const fn new(flash: F) -> BootloaderSession<F> {
+        BootloaderSession {
+            flash,
+            decoder: CommandDecoder::new(),
+            buffer: [0u8; MAX_FRAME_LEN],
+            check_alignment: false,
+            ext_geometry: None,
+            lengths: Lengths::DEFAULT,
+            exit_command: false,
+            exit: false,
+            staging: Staging::Off,
+            policy: None,
+            denied: FlashError::BadAddress,
+            #[cfg(feature = "attributes")]
+            verifier: None,
+            #[cfg(feature = "attributes")]
+            verified: false,
+            #[cfg(feature = "baud-change")]
+            baud: None,
+            #[cfg(feature = "baud-change")]
+            baud_switch: None,
+            #[cfg(feature = "multi-drop")]
+            new_address: None,
+            #[cfg(feature = "sequence")]
+            last_sequence: None,
+            #[cfg(feature = "sequence")]
+            last_reply: None,
+        }
+    }
+
+    /// `new` followed by `set_rx_crc(true)`, as a `const fn`.
+    #[inline(always)]
+    pub(crate) const fn with_rx_crc(flash: F) -> BootloaderSession<F> {
+        let mut session = BootloaderSession::new(flash);
+        session.decoder.rx_crc = Some(Crc32::new());
+        session
+    }
+
+    /// Process incoming bytes.
+    ///
+    /// Returns `None` until a complete command has been received. The
+    /// command is then carried out and the `Response` to send to the host is
+    /// returned. Commands which have no reply (`Reset`) also return `None`.
+    /// A `Reset` drops whatever earlier commands left behind: a staged page,
+    /// a `ChangeBaud` waiting for its `Verify`, and the last sequence number.
+    ///
+    /// With the `multi-drop` feature, the response should be sent from
+    /// the address `address` gave before this was called. With the
+    /// `sequence` feature, a write or erase sent again with the same
+    /// sequence number gets the first reply again, and isn't carried out.
+    pub fn receive(&mut self, ch: u8) -> Option<Response<'_>> {
+        // The reply to `SetAddress` goes out from the old address
+        #[cfg(feature = "multi-drop")]
+        if let Some(address) = self.new_address.take() {
+            self.decoder.set_address(Some(address));
+        }
+        // A `CrcRxBuffer` doesn't change this
+        let rx_crc = self.decoder.rx_crc();
+        let observer = self.decoder.observer;
+        #[cfg(feature = "sequence")]
+        let sequence = self.decoder.sequence();
+        let command = match self.decoder.receive(ch) {
+            Ok(None) => return None,
+            Ok(Some(command)) => command,
+            Err(e) => return Some(Response::from(e)),
+        };
+        if self.policy.is_some_and(|p| !p.allow(&command)) {
+            return match command {
+                Command::Reset => None,
+                _ => Some(flash_error(self.denied)),
+            };
+        }
+        if let Command::Reset = command {
+            self.reset_state();
+            return None;
+        }
+        #[cfg(feature = "multi-drop")]
+        if let Command::SetAddress { address } = command {
+            self.new_address = Some(address);
+            return Some(Response::Ok);
+        }
+        // A retransmission is answered as before, without doing it again
+        #[cfg(feature = "sequence")]
+        {
+            if sequence.is_some() && sequence == self.last_sequence && changes_flash(&command) {
+                if let Some(reply) = self.last_reply.and_then(status_reply) {
+                    return Some(reply);
+                }
+            }
+            self.last_sequence = sequence;
+            self.last_reply = None;
+        }
+        #[cfg(feature = "attributes")]
+        if let Some(verifier) = self.verifier {
+            match command {
+                Command::SetAttr { key, value, .. } if key == &SIGNATURE_KEY[..] => {
+                    if let Staging::Page(..) = self.staging {
+                        self.staging = Staging::Empty;
+                    }
+                    let result =
+                        check_signature(&mut self.flash, &mut self.buffer, verifier, value);
+                    self.verified = result == Ok(true);
+                    return Some(match result {
+                        Ok(true) => Response::Ok,
+                        Ok(false) => Response::BadArguments,
+                        Err(e) => flash_error(e),
+                    });
+                }
+                // The image has to be signed again
+                ref command if changes_flash(command) => self.verified = false,
+                _ if matches!(self.staging, Staging::Page(..)) => self.verified = false,
+                _ => {}
+            }
+        }
+        if self.check_alignment && !is_aligned(&command, self.ext_geometry) {
+            return Some(Response::BadAddress);
+        }
+        #[cfg(feature = "baud-change")]
+        if let Some(guard) = self.baud.as_mut() {
+            if let BaudAction::Reply(response, switch) = guard.handle_command(&command) {
+                self.baud_switch = switch;
+                return Some(response);
+            }
+        }
+        if let (Command::Exit, true) = (command, self.exit_command) {
+            self.exit = self.may_exit();
+            return Some(if self.exit { Response::Ok } else { Response::BadArguments });
+        }
+        let response = perform(
+            &mut self.flash,
+            &mut self.buffer,
+            &mut self.staging,
+            &command,
+            rx_crc,
+            self.lengths,
+            observer,
+        );
+        #[cfg(feature = "sequence")]
+        if sequence.is_some() && changes_flash(&command) {
+            self.last_reply = response.as_ref().map(Response::kind);
+        }
+        response
+    }
+
+    /// Whether the host has sent `Exit` since the last call. Once its reply
+    /// has been sent, the bootloader should start the app, or reset the chip.
+    pub fn take_exit(&mut self) -> bool {
+        let exit = self.exit;
+        self.exit = false;
+        exit
+    }
+
+    /// Report every byte received, every command decoded, and the bytes
+    /// each read, write or CRC covered, to `observer`.
+    pub fn set_observer(&mut self, observer: &'static dyn Observer) {
+        self.decoder.set_observer(observer);
+    }
+
+    /// Answer `CrcRxBuffer` with the length and CRC of the arguments of
+    /// the command before it. See `CommandDecoder::set_rx_crc`. Otherwise
+    /// it gets `Unknown`.
+    pub fn set_rx_crc(&mut self, enabled: bool) {
+        self.decoder.set_rx_crc(enabled);
+    }
+
+    /// Accept attribute indices below `slots`, rather than `MAX_INDEX`. See
+    /// `CommandDecoder::set_attr_slots`.
+    #[cfg(feature = "attributes")]
+    pub fn set_attr_slots(&mut self, slots: u8) {
+        self.decoder.set_attr_slots(slots);
+    }
+
+    /// Accept attribute values up to `len` bytes long, rather than
+    /// `MAX_ATTR_LEN`, and give `get_attr` a buffer that long. See
+    /// `CommandDecoder::set_attr_len`. Replies have to be encoded with
+    /// the `ReplyFormat` from `reply_format`.
+    #[cfg(feature = "attributes")]
+    pub fn set_attr_len(&mut self, len: usize) {
+        self.decoder.set_attr_len(len);
+        self.lengths.attr = len;
+    }
+
+    /// Give `FlashInterface::id` a buffer `len` bytes long, rather than
+    /// `ID_LEN`, for chips with a longer unique ID. The host has to expect
+    /// it too, with `ResponseDecoder::set_id_len`.
+    pub fn set_id_len(&mut self, len: usize) {
+        self.lengths.id = len;
+    }
+
+    /// How replies from this session have to be encoded. Call this before
+    /// `receive` and build the encoder for its `Response` with
+    /// `ReplyFormat::encoder`.
+    pub fn reply_format(&self) -> ReplyFormat {
+        ReplyFormat {
+            lengths: self.lengths,
+            #[cfg(feature = "multi-drop")]
+            address: self.decoder.address(),
+        }
+    }
+
+    /// Answer `BadAddress` to any `ErasePage` or `WritePage` which isn't at
+    /// the start of an internal flash page, and any `EraseExBlock`,
+    /// `EraseExPage` or `WriteExPage` which isn't at the start of an
+    /// external flash page, without calling the flash. Checking a block is
+    /// aligned to the chip's erase block is still up to `ex_erase_block`,
+    /// unless `set_ext_geometry` has been called.
+    pub fn set_check_alignment(&mut self, enabled: bool) {
+        self.check_alignment = enabled;
+    }
+
+    /// Check `EraseExBlock` and `EraseExPage` against the blocks and pages
+    /// of `geometry` when `set_check_alignment` is on, rather than just
+    /// the 256 byte pages `WriteExPage` uses.
+    #[cfg(feature = "ext-flash")]
+    pub fn set_ext_geometry(&mut self, geometry: ExtFlashGeometry) {
+        self.ext_geometry = Some(geometry);
+    }
+
+    /// Answer `Exit` with `Ok`, and report it from `take_exit`. Otherwise it
+    /// gets `Unknown`, as in a bootloader which doesn't have it.
+    pub fn set_exit_command(&mut self, enabled: bool) {
+        self.exit_command = enabled;
+    }
+
+    /// Hold each `WritePage` or `WriteExPage` back until the host commits
+    /// it, for hosts using `StagedWrite`.
+    ///
+    /// A staged page is answered with `Ok` but not written. The host checks
+    /// it arrived intact with `CrcRxBuffer` (so `set_rx_crc` should be on
+    /// too), then commits it with a `CrcIntFlash` or `CrcExtFlash` covering
+    /// exactly that page, which writes it and answers with the CRC of what
+    /// is now in flash. Another write replaces the staged page, and any
+    /// other command drops it.
+    pub fn set_staged_writes(&mut self, enabled: bool) {
+        self.staging = if enabled { Staging::Empty } else { Staging::Off };
+    }
+
+    /// Check every command against `policy` before carrying it out. A
+    /// denied command is answered as if the flash had failed with the
+    /// error set with `set_denied_error`, except for `Reset`, which is
+    /// ignored.
+    pub fn set_access_policy(&mut self, policy: &'static dyn AccessPolicy) {
+        self.policy = Some(policy);
+    }
+
+    /// Answer commands the access policy denies with the response for
+    /// `error`. The default is `FlashError::BadAddress`.
+    pub fn set_denied_error(&mut self, error: FlashError) {
+        self.denied = error;
+    }
+
+    /// Only start images signed for `verifier`. See the `signature`
+    /// module. `Exit` gets `BadArguments` until the host has sent a
+    /// signature which `verifier` accepts, and any write or erase after
+    /// that means it has to send one again.
+    #[cfg(feature = "attributes")]
+    pub fn set_verifier(&mut self, verifier: &'static dyn Verifier) {
+        self.verifier = Some(verifier);
+        self.verified = false;
+    }
+
+    /// Accept `ChangeBaud`, with the UART currently at `baud`. See
+    /// `BaudGuard`. Otherwise it gets `Unknown`.
+    #[cfg(feature = "baud-change")]
+    pub fn set_baud(&mut self, baud: u32) {
+        self.baud = Some(BaudGuard::new(baud));
+    }
+
+    /// Call this once the response from `receive` has been sent. If it
+    /// returns a rate, switch the UART to it. A `Reset` in the middle of
+    /// the handshake puts the old rate back here too, with no response, so
+    /// it's simplest to call this after every `receive`.
+    #[cfg(feature = "baud-change")]
+    pub fn baud_switch(&mut self) -> Option<u32> {
+        self.baud_switch.take()
+    }
+
+    /// Only answer commands sent to `address` on a shared bus, or any
+    /// unaddressed command if it's `None`. See the `multidrop` module.
+    /// `SetAddress` changes it once its reply has been sent.
+    #[cfg(feature = "multi-drop")]
+    pub fn set_address(&mut self, address: Option<u8>) {
+        self.decoder.set_address(address);
+        self.new_address = None;
+    }
+
+    /// The address to send responses from. See `set_address`.
+    #[cfg(feature = "multi-drop")]
+    pub fn address(&self) -> Option<u8> {
+        self.decoder.address()
+    }
+
+    /// Get a reference to the flash.
+    pub fn flash(&self) -> &F {
+        &self.flash
+    }
+
+    /// Get a mutable reference to the flash.
+    pub fn flash_mut(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    /// Destroy the session and get the flash back.
+    pub fn release(self) -> F {
+        self.flash
+    }
+}
+
+impl ReplyFormat {
+    /// The format of a session with the default lengths, and no address,
+    /// for code which decodes commands itself.
+    pub const fn new() -> ReplyFormat {
+        ReplyFormat {
+            lengths: Lengths::DEFAULT,
+            #[cfg(feature = "multi-drop")]
+            address: None,
+        }
+    }
+
+    /// Pad `GetAttr` replies out to `len` bytes. See
+    /// `BootloaderSession::set_attr_len`.
+    #[cfg(feature = "attributes")]
+    pub fn set_attr_len(&mut self, len: usize) {
+        self.lengths.attr = len;
+    }
+
+    /// Pad `Id` replies out to `len` bytes. See
+    /// `BootloaderSession::set_id_len`.
+    pub fn set_id_len(&mut self, len: usize) {
+        self.lengths.id = len;
+    }
+
+    /// Create a `ResponseEncoder` for `response` in this format.
+    pub fn encoder<'a>(&self, response: &'a Response) -> Result<ResponseEncoder<'a>, Error> {
+        #[cfg(feature = "attributes")]
+        let mut encoder = ResponseEncoder::with_attr_len(response, self.lengths.attr)?;
+        #[cfg(not(feature = "attributes"))]
+        let mut encoder = ResponseEncoder::new(response)?;
+        encoder.set_id_len(self.lengths.id);
+        #[cfg(feature = "multi-drop")]
+        encoder.set_address(self.address);
+        Ok(encoder)
+    }
+}
+
+impl Default for ReplyFormat {
+    fn default() -> ReplyFormat {
+        ReplyFormat::new()
+    }
+}
+
+#[cfg(feature = "baud-change")]
+impl BaudGuard {
+    /// Create a new `BaudGuard`, with the UART at `baud`.
+    pub const fn new(baud: u32) -> BaudGuard {
+        BaudGuard {
+            baud,
+            pending: None,
+        }
+    }
+
+    /// The rate the UART should be at once a `Set` has been verified, or
+    /// undone.
+    pub fn baud(&self) -> u32 {
+        self.baud
+    }
+
+    /// Whether a `Set` is waiting for its `Verify`.
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Check `command` against the handshake.
+    pub fn handle_command(&mut self, command: &Command) -> BaudAction {
+        match (self.pending.take(), *command) {
+            (None, Command::ChangeBaud { mode: BaudMode::Set, baud }) => {
+                self.pending = Some(baud);
+                BaudAction::Reply(Response::Ok, Some(baud))
+            }
+            (Some(pending), Command::ChangeBaud { mode: BaudMode::Verify, baud })
+                if baud == pending =>
+            {
+                self.baud = baud;
+                BaudAction::Reply(Response::Ok, None)
+            }
+            (None, Command::ChangeBaud { .. }) => BaudAction::Reply(Response::ChangeBaudFail, None),
+            (Some(_), _) => BaudAction::Reply(Response::ChangeBaudFail, Some(self.baud)),
+            (None, _) => BaudAction::Perform,
+        }
+    }
+
+    /// No command arrived at the new rate in time. If a `Set` is pending,
+    /// returns the old rate to switch the UART back to.
+    pub fn timed_out(&mut self) -> Option<u32> {
+        self.pending.take().map(|_| self.baud)
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl Lengths {
+    /// The lengths the protocol specifies.
+    const DEFAULT: Lengths = Lengths {
+        #[cfg(feature = "attributes")]
+        attr: MAX_ATTR_LEN,
+        id: ID_LEN,
+    };
+}
+
+/// Whether a command which erases or writes a page (or block) starts on
+/// one, with external flash laid out as `ext_geometry` says if it's known.
+#[cfg_attr(not(feature = "ext-flash"), allow(unused_variables))]
+fn is_aligned(command: &Command, ext_geometry: Option<ExtFlashGeometry>) -> bool {
+    match *command {
+        Command::ErasePage { address } | Command::WritePage { address, .. } => {
+            IntFlashAddr::new(address).is_ok()
+        }
+        #[cfg(feature = "ext-flash")]
+        Command::EraseExBlock { address } => match ext_geometry {
+            Some(geometry) => geometry.is_block_aligned(address),
+            None => ExtFlashAddr::new(address).is_ok(),
+        },
+        #[cfg(feature = "ext-flash")]
+        Command::EraseExPage { address } => match ext_geometry {
+            Some(geometry) => geometry.is_page_aligned(address),
+            None => ExtFlashAddr::new(address).is_ok(),
+        },
+        #[cfg(feature = "ext-flash")]
+        Command::WriteExPage { address, .. } => ExtFlashAddr::new(address).is_ok(),
+        _ => true,
+    }
+}
+
+impl<F> BootloaderSession<F>
+where
+    F: FlashInterface,
+{
+    /// Whether to act on an `Exit`: always, unless there's a verifier which
+    /// hasn't accepted a signature.
+    fn may_exit(&self) -> bool {
+        #[cfg(feature = "attributes")]
+        if self.verifier.is_some() {
+            return self.verified;
+        }
+        true
+    }
+
+    /// Drop what earlier commands left behind, for a `Reset`.
+    fn reset_state(&mut self) {
+        if let Staging::Page(..) = self.staging {
+            self.staging = Staging::Empty;
+        }
+        #[cfg(feature = "baud-change")]
+        if let Some(baud) = self.baud.as_mut().and_then(BaudGuard::timed_out) {
+            self.baud_switch = Some(baud);
+        }
+        #[cfg(feature = "sequence")]
+        {
+            self.last_sequence = None;
+            self.last_reply = None;
+        }
+    }
+}
+
+/// Check the signature in the value of a signature attribute.
+#[cfg(feature = "attributes")]
+fn check_signature<F>(
+    flash: &mut F,
+    buffer: &mut [u8],
+    verifier: &dyn Verifier,
+    value: &[u8],
+) -> Result<bool, FlashError>
+where
+    F: FlashInterface,
+{
+    let (address, length, signature) = match parse_signature(value) {
+        Some(parsed) => parsed,
+        None => return Ok(false),
+    };
+    verifier.verify(&mut SignedImage::new(flash, buffer, address, length), signature)
+}
+
+/// Does `command` write to or erase flash?
+#[cfg(any(feature = "attributes", feature = "sequence"))]
+fn changes_flash(command: &Command) -> bool {
+    match *command {
+        Command::ErasePage { .. } | Command::WritePage { .. } => true,
+        #[cfg(feature = "user-pages")]
+        Command::WriteFlashUserPages { .. } => true,
+        #[cfg(feature = "ext-flash")]
+        Command::EraseExBlock { .. } |
+        Command::WriteExPage { .. } |
+        Command::EraseExPage { .. } => true,
+        _ => false,
+    }
+}
+
+/// Carry out `command`, staging or committing it if need be, and report
+/// any progress.
+fn perform<'b, F>(
+    flash: &mut F,
+    buffer: &'b mut [u8],
+    staging: &mut Staging,
+    command: &Command,
+    rx_crc: Option<(u16, u32)>,
+    lengths: Lengths,
+    observer: Option<&'static dyn Observer>,
+) -> Option<Response<'b>>
+where
+    F: FlashInterface,
+{
+    let response = match stage(flash, buffer, staging, command) {
+        // Staged, and not yet written, so not progress
+        Some(Response::Ok) => return Some(Response::Ok),
+        Some(response) => Some(response),
+        None => dispatch(flash, buffer, command, rx_crc, lengths),
+    };
+    report_progress(observer, command, &response);
+    response
+}
+
+/// Stage or commit `command`, if it's a write or the commit of the staged
+/// write. Anything else is left to `dispatch`.
+fn stage<F>(
+    flash: &mut F,
+    buffer: &mut [u8],
+    staging: &mut Staging,
+    command: &Command,
+) -> Option<Response<'static>>
+where
+    F: FlashInterface,
+{
+    let staged = match *staging {
+        Staging::Off => return None,
+        Staging::Empty => None,
+        Staging::Page(target, address, len) => Some((target, address, len)),
+    };
+    let (target, address, data) = match *command {
+        Command::WritePage { address, data } => (FlashTarget::Internal, address, data),
+        #[cfg(feature = "ext-flash")]
+        Command::WriteExPage { address, data } => (FlashTarget::External, address, data),
+        // Checking what arrived doesn't touch the staged page
+        Command::CrcRxBuffer => return None,
+        Command::CrcIntFlash { address, length }
+            if staged == Some((FlashTarget::Internal, address, length as usize)) =>
+        {
+            *staging = Staging::Empty;
+            let result = flash
+                .write_page(address, &buffer[0..length as usize])
+                .and_then(|_| flash.crc_range(address, length));
+            return Some(match result {
+                Ok(crc) => Response::CrcIntFlash { crc },
+                Err(e) => flash_error(e),
+            });
+        }
+        #[cfg(feature = "ext-flash")]
+        Command::CrcExtFlash { address, length }
+            if staged == Some((FlashTarget::External, address, length as usize)) =>
+        {
+            *staging = Staging::Empty;
+            let result = flash
+                .ex_write_page(address, &buffer[0..length as usize])
+                .and_then(|_| flash.ex_crc_range(address, length));
+            return Some(match result {
+                Ok(crc) => Response::CrcExtFlash { crc },
+                Err(e) => flash_error(e),
+            });
+        }
+        _ => {
+            *staging = Staging::Empty;
+            return None;
+        }
+    };
+    match buffer.get_mut(0..data.len()) {
+        Some(dest) => {
+            dest.copy_from_slice(data);
+            *staging = Staging::Page(target, address, data.len());
+            Some(Response::Ok)
+        }
+        None => {
+            *staging = Staging::Empty;
+            Some(Response::BadArguments)
+        }
+    }
+}
+
+fn dispatch<'b, F>(
+    flash: &mut F,
+    buffer: &'b mut [u8],
+    command: &Command,
+    rx_crc: Option<(u16, u32)>,
+    lengths: Lengths,
+) -> Option<Response<'b>>
+where
+    F: FlashInterface,
+{
+    let result = match *command {
+        Command::Ping => Ok(Response::Pong),
+        Command::Reset => return None,
+        // Handled by the session, if it's turned on
+        Command::Exit => Err(FlashError::Unsupported),
+        Command::Info => {
+            let info = &mut buffer[0..MAX_INFO_LEN];
+            match flash.info(info) {
+                Ok(len) if len <= MAX_INFO_LEN => Ok(Response::Info { info: &info[0..len] }),
+                Ok(_) => Err(FlashError::Internal),
+                Err(e) => Err(e),
+            }
+        }
+        Command::ErasePage { address } => flash.erase_page(address).map(|_| Response::Ok),
+        Command::WritePage { address, data } => {
+            flash.write_page(address, data).map(|_| Response::Ok)
+        }
+        Command::ReadRange { address, length } => {
+            let length = length as usize;
+            if length > buffer.len() {
+                Err(FlashError::BadArguments)
+            } else {
+                let data = &mut buffer[0..length];
+                flash.read(address, data).map(move |_| Response::ReadRange { data })
+            }
+        }
+        #[cfg(feature = "attributes")]
+        Command::SetAttr { index, key, value } => {
+            flash.set_attr(index, key, value).map(|_| Response::Ok)
+        }
+        #[cfg(feature = "attributes")]
+        Command::GetAttr { index } => {
+            let (key, rest) = buffer.split_at_mut(KEY_LEN);
+            let value = match rest.get_mut(0..lengths.attr) {
+                Some(value) => value,
+                None => return Some(Response::InternalError),
+            };
+            match flash.get_attr(index, key, value) {
+                Ok(len) if len <= lengths.attr => {
+                    Ok(Response::GetAttr {
+                        key,
+                        value: &value[0..len],
+                    })
+                }
+                Ok(_) => Err(FlashError::Internal),
+                Err(e) => Err(e),
+            }
+        }
+        Command::CrcIntFlash { address, length } => {
+            flash.crc_range(address, length).map(|crc| Response::CrcIntFlash { crc })
+        }
+        #[cfg(feature = "ext-flash")]
+        Command::ExtFlashInit => flash.ex_init().map(|_| Response::Ok),
+        #[cfg(feature = "ext-flash")]
+        Command::EraseExBlock { address } => flash.ex_erase_block(address).map(|_| Response::Ok),
+        #[cfg(feature = "ext-flash")]
+        Command::EraseExPage { address } => flash.ex_erase_page(address).map(|_| Response::Ok),
+        #[cfg(feature = "ext-flash")]
+        Command::WriteExPage { address, data } => {
+            flash.ex_write_page(address, data).map(|_| Response::Ok)
+        }
+        #[cfg(feature = "ext-flash")]
+        Command::ExReadRange { address, length } => {
+            let length = length as usize;
+            if length > buffer.len() {
+                Err(FlashError::BadArguments)
+            } else {
+                let data = &mut buffer[0..length];
+                flash.ex_read(address, data).map(move |_| Response::ExReadRange { data })
+            }
+        }
+        #[cfg(feature = "ext-flash")]
+        Command::CrcExtFlash { address, length } => {
+            flash.ex_crc_range(address, length).map(|crc| Response::CrcExtFlash { crc })
+        }
+        Command::CrcRxBuffer => match rx_crc {
+            Some((length, crc)) => Ok(Response::CrcRxBuffer { length, crc }),
+            None => Err(FlashError::Unsupported),
+        },
+        Command::Id => match buffer.get_mut(0..lengths.id) {
+            Some(id) => flash.id(id).map(move |_| Response::Id { id }),
+            None => return Some(Response::InternalError),
+        },
+        // This is chip specific
+        Command::ClockOut => Err(FlashError::Unsupported),
+        #[cfg(feature = "user-pages")]
+        Command::WriteFlashUserPages { .. } => Err(FlashError::Unsupported),
+        #[cfg(feature = "baud-change")]
+        Command::ChangeBaud { .. } => Err(FlashError::Unsupported),
+        #[cfg(feature = "multi-drop")]
+        Command::SetAddress { .. } => Err(FlashError::Unsupported),
+        #[cfg(feature = "long-attributes")]
+        Command::SetLongAttr { index, key, value } => {
+            flash.set_long_attr(index, key, value).map(|_| Response::Ok)
+        }
+        #[cfg(feature = "long-attributes")]
+        Command::GetLongAttr { index } => {
+            let (key, rest) = buffer.split_at_mut(KEY_LEN);
+            let value = match rest.get_mut(0..MAX_LONG_ATTR_LEN) {
+                Some(value) => value,
+                None => return Some(Response::InternalError),
+            };
+            match flash.get_long_attr(index, key, value) {
+                Ok(len) if len <= MAX_LONG_ATTR_LEN => {
+                    Ok(Response::GetLongAttr {
+                        key,
+                        value: &value[0..len],
+                    })
+                }
+                Ok(_) => Err(FlashError::Internal),
+                Err(e) => Err(e),
+            }
+        }
+    };
+    Some(match result {
+        Ok(response) => response,
+        Err(e) => flash_error(e),
+    })
+}
+
+/// The response with code `code`, to answer a retransmission with, if it
+/// has no payload. The replies to commands which change flash never have
+/// one.
+#[cfg(feature = "sequence")]
+fn status_reply(code: ResponseCode) -> Option<Response<'static>> {
+    match code {
+        ResponseCode::Ok => Some(Response::Ok),
+        ResponseCode::Overflow => Some(Response::Overflow),
+        ResponseCode::BadAddress => Some(Response::BadAddress),
+        ResponseCode::InternalError => Some(Response::InternalError),
+        ResponseCode::BadArguments => Some(Response::BadArguments),
+        ResponseCode::Unknown => Some(Response::Unknown),
+        #[cfg(feature = "ext-flash")]
+        ResponseCode::ExtFlashTimeout => Some(Response::ExtFlashTimeout),
+        #[cfg(feature = "ext-flash")]
+        ResponseCode::ExtFlashPageError => Some(Response::ExtFlashPageError),
+        _ => None,
+    }
+}
+
+/// The response to send for a `FlashError`.
+fn flash_error(error: FlashError) -> Response<'static> {
+    match error {
+        FlashError::BadAddress => Response::BadAddress,
+        FlashError::BadArguments => Response::BadArguments,
+        FlashError::Internal => Response::InternalError,
+        FlashError::Unsupported => Response::Unknown,
+    }
+}
+
+/// Tell `observer` how many bytes `command` wrote, read or checked, if it
+/// worked.
+fn report_progress(
+    observer: Option<&'static dyn Observer>,
+    command: &Command,
+    response: &Option<Response>,
+) {
+    let bytes = match (command, response) {
+        (&Command::WritePage { data, .. }, Some(Response::Ok)) => data.len(),
+        (&Command::ReadRange { length, .. }, Some(Response::ReadRange { .. })) => length as usize,
+        (&Command::CrcIntFlash { length, .. }, Some(Response::CrcIntFlash { .. })) => {
+            length as usize
+        }
+        #[cfg(feature = "ext-flash")]
+        (&Command::WriteExPage { data, .. }, Some(Response::Ok)) => data.len(),
+        #[cfg(feature = "ext-flash")]
+        (&Command::ExReadRange { length, .. }, Some(Response::ExReadRange { .. })) => {
+            length as usize
+        }
+        #[cfg(feature = "ext-flash")]
+        (&Command::CrcExtFlash { length, .. }, Some(Response::CrcExtFlash { .. })) => {
+            length as usize
+        }
+        _ => return,
+    };
+    if let Some(o) = observer {
+        o.on_progress(bytes);
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use super::super::{CommandEncoder, Crc32, ResponseDecoder, ResponseEncoder};
+    use super::super::{INT_PAGE_SIZE, KEY_LEN, MAX_ATTR_LEN};
+
+    const BASE: u32 = 0x30000;
+
+    struct RamFlash {
+        mem: [u8; INT_PAGE_SIZE * 2],
+        attrs: [([u8; KEY_LEN], [u8; MAX_ATTR_LEN], usize); 2],
+    }
+
+    impl RamFlash {
+        fn new() -> RamFlash {
+            RamFlash {
+                mem: [0xFF; INT_PAGE_SIZE * 2],
+                attrs: [([0; KEY_LEN], [0; MAX_ATTR_LEN], 0); 2],
+            }
+        }
+
+        fn range(&mut self, address: u32, len: usize) -> Result<&mut [u8], FlashError> {
+            let start = address.checked_sub(BASE).ok_or(FlashError::BadAddress)? as usize;
+            self.mem.get_mut(start..start + len).ok_or(FlashError::BadAddress)
+        }
+    }
+
+    impl FlashInterface for RamFlash {
+        fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), FlashError> {
+            buffer.copy_from_slice(self.range(address, buffer.len())?);
+            Ok(())
+        }
+
+        fn write_page(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+            if address & (INT_PAGE_SIZE as u32 - 1) != 0 {
+                return Err(FlashError::BadAddress);
+            }
+            self.range(address, data.len())?.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn erase_page(&mut self, address: u32) -> Result<(), FlashError> {
+            for b in self.range(address, INT_PAGE_SIZE)?.iter_mut() {
+                *b = 0xFF;
+            }
+            Ok(())
+        }
+
+        fn get_attr(&mut self, index: u8, key: &mut [u8], value: &mut [u8])
+            -> Result<usize, FlashError> {
+            let attr = self.attrs.get(index as usize).ok_or(FlashError::BadArguments)?;
+            key.copy_from_slice(&attr.0);
+            value.copy_from_slice(&attr.1);
+            Ok(attr.2)
+        }
+
+        fn set_attr(&mut self, index: u8, key: &[u8], value: &[u8]) -> Result<(), FlashError> {
+            let attr = self.attrs.get_mut(index as usize).ok_or(FlashError::BadArguments)?;
+            attr.0.copy_from_slice(key);
+            attr.1[0..value.len()].copy_from_slice(value);
+            attr.2 = value.len();
+            Ok(())
+        }
+
+        fn crc_range(&mut self, _address: u32, length: u32) -> Result<u32, FlashError> {
+            Ok(length)
+        }
+
+        fn id(&mut self, id: &mut [u8]) -> Result<(), FlashError> {
+            for (i, b) in id.iter_mut().enumerate() {
+                *b = 0xA0 + i as u8;
+            }
+            Ok(())
+        }
+    }
+
+    /// Send a command to the session and check the response that comes out.
+    fn check(s: &mut BootloaderSession<RamFlash>, cmd: &Command, expected: Option<Response>) {
+        let mut result = None;
+        let mut count = 0;
+        for byte in CommandEncoder::new(cmd).unwrap() {
+            if let Some(r) = s.receive(byte) {
+                // Round-trip it to check it can be sent
+                let mut d = ResponseDecoder::new();
+                if let Response::ReadRange { data } = r {
+                    d.set_payload_len(data.len()).unwrap();
+                }
+                let mut decoded = None;
+                for b in ResponseEncoder::new(&r).unwrap() {
+                    if let Some(x) = d.receive(b).unwrap() {
+                        decoded = Some(x == r);
+                    }
+                }
+                assert_eq!(decoded, Some(true));
+                result = Some(expected.as_ref() == Some(&r));
+                count += 1;
+            }
+        }
+        match expected {
+            Some(_) => assert_eq!((count, result), (1, Some(true))),
+            None => assert_eq!(count, 0),
+        }
+    }
+
+    #[test]
+    fn check_ping_reset_and_exit() {
+        let mut s = BootloaderSession::new(RamFlash::new());
+        check(&mut s, &Command::Ping, Some(Response::Pong));
+        check(&mut s, &Command::Reset, None);
+        assert!(!s.take_exit());
+        check(&mut s, &Command::Exit, Some(Response::Unknown));
+        assert!(!s.take_exit());
+        s.set_exit_command(true);
+        check(&mut s, &Command::Reset, None);
+        assert!(!s.take_exit());
+        check(&mut s, &Command::Exit, Some(Response::Ok));
+        assert!(s.take_exit());
+        assert!(!s.take_exit());
+    }
+
+    #[test]
+    fn check_write_and_read() {
+        let mut s = BootloaderSession::new(RamFlash::new());
+        let mut page = [0u8; INT_PAGE_SIZE];
+        for (i, b) in page.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let cmd = Command::WritePage {
+            address: BASE + INT_PAGE_SIZE as u32,
+            data: &page,
+        };
+        check(&mut s, &cmd, Some(Response::Ok));
+        let cmd = Command::ReadRange {
+            address: BASE + INT_PAGE_SIZE as u32 + 250,
+            length: 8,
+        };
+        let expected = [250, 251, 252, 253, 254, 255, 0, 1];
+        check(&mut s, &cmd, Some(Response::ReadRange { data: &expected }));
+        let cmd = Command::ErasePage { address: BASE + INT_PAGE_SIZE as u32 };
+        check(&mut s, &cmd, Some(Response::Ok));
+        assert!(s.flash().mem.iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn check_progress() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        struct Progress(AtomicUsize);
+
+        impl Observer for Progress {
+            fn on_progress(&self, bytes: usize) {
+                self.0.fetch_add(bytes, Ordering::Relaxed);
+            }
+        }
+
+        static PROGRESS: Progress = Progress(AtomicUsize::new(0));
+        let mut s = BootloaderSession::new(RamFlash::new());
+        s.set_observer(&PROGRESS);
+        let page = [0u8; INT_PAGE_SIZE];
+        let write = |address| Command::WritePage {
+            address,
+            data: &page,
+        };
+        check(&mut s, &write(BASE), Some(Response::Ok));
+        // A failed write doesn't count
+        check(&mut s, &write(BASE + 0x1000), Some(Response::BadAddress));
+        let cmd = Command::ReadRange {
+            address: BASE,
+            length: 16,
+        };
+        check(&mut s, &cmd, Some(Response::ReadRange { data: &[0; 16] }));
+        let cmd = Command::CrcIntFlash {
+            address: BASE,
+            length: 1024,
+        };
+        check(&mut s, &cmd, Some(Response::CrcIntFlash { crc: 1024 }));
+        check(&mut s, &Command::Ping, Some(Response::Pong));
+        assert_eq!(PROGRESS.0.load(Ordering::Relaxed), INT_PAGE_SIZE + 16 + 1024);
+    }
+
+    #[test]
+    fn check_rx_crc() {
+        let mut s = BootloaderSession::new(RamFlash::new());
+        check(&mut s, &Command::CrcRxBuffer, Some(Response::Unknown));
+        s.set_rx_crc(true);
+        let page = [0xFC; INT_PAGE_SIZE];
+        let address = BASE + INT_PAGE_SIZE as u32;
+        let cmd = Command::WritePage {
+            address,
+            data: &page,
+        };
+        check(&mut s, &cmd, Some(Response::Ok));
+        let mut crc = Crc32::new();
+        crc.update(&address.to_le_bytes());
+        crc.update(&page);
+        let expected = Response::CrcRxBuffer {
+            length: 4 + INT_PAGE_SIZE as u16,
+            crc: crc.finish(),
+        };
+        check(&mut s, &Command::CrcRxBuffer, Some(expected));
+        // Asking again gets the same answer
+        check(&mut s, &Command::CrcRxBuffer, Some(expected));
+    }
+
+    #[test]
+    fn check_staged_writes() {
+        let mut s = BootloaderSession::new(RamFlash::new());
+        s.set_rx_crc(true);
+        s.set_staged_writes(true);
+        let page = [0xA5; INT_PAGE_SIZE];
+        let write = Command::WritePage {
+            address: BASE,
+            data: &page,
+        };
+        let read = Command::ReadRange {
+            address: BASE,
+            length: 4,
+        };
+        let commit = Command::CrcIntFlash {
+            address: BASE,
+            length: INT_PAGE_SIZE as u32,
+        };
+        // Staged, but not written, and any other command drops it
+        check(&mut s, &write, Some(Response::Ok));
+        check(&mut s, &read, Some(Response::ReadRange { data: &[0xFF; 4] }));
+        check(&mut s, &commit, Some(Response::CrcIntFlash { crc: INT_PAGE_SIZE as u32 }));
+        check(&mut s, &read, Some(Response::ReadRange { data: &[0xFF; 4] }));
+
+        // Checking what arrived keeps it, and the commit writes it
+        check(&mut s, &write, Some(Response::Ok));
+        let mut crc = Crc32::new();
+        crc.update(&BASE.to_le_bytes());
+        crc.update(&page);
+        let received = Response::CrcRxBuffer {
+            length: 4 + INT_PAGE_SIZE as u16,
+            crc: crc.finish(),
+        };
+        check(&mut s, &Command::CrcRxBuffer, Some(received));
+        check(&mut s, &commit, Some(Response::CrcIntFlash { crc: INT_PAGE_SIZE as u32 }));
+        check(&mut s, &read, Some(Response::ReadRange { data: &[0xA5; 4] }));
+
+        // As does a Reset
+        let page = [0x5A; INT_PAGE_SIZE];
+        let write = Command::WritePage {
+            address: BASE,
+            data: &page,
+        };
+        check(&mut s, &write, Some(Response::Ok));
+        check(&mut s, &Command::Reset, None);
+        check(&mut s, &commit, Some(Response::CrcIntFlash { crc: INT_PAGE_SIZE as u32 }));
+        check(&mut s, &read, Some(Response::ReadRange { data: &[0xA5; 4] }));
+    }
+
+    #[test]
+    fn check_access_policy() {
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        struct Lockdown(AtomicBool);
+
+        impl AccessPolicy for Lockdown {
+            fn allow(&self, command: &Command) -> bool {
+                match *command {
+                    Command::ReadRange { address, .. } => address >= BASE + 0x100,
+                    Command::ErasePage { .. } | Command::Exit => {
+                        !self.0.load(Ordering::Relaxed)
+                    }
+                    _ => true,
+                }
+            }
+        }
+
+        static POLICY: Lockdown = Lockdown(AtomicBool::new(false));
+        let mut s = BootloaderSession::new(RamFlash::new());
+        s.set_access_policy(&POLICY);
+        s.set_exit_command(true);
+        let read = |address| Command::ReadRange { address, length: 4 };
+        check(&mut s, &read(BASE), Some(Response::BadAddress));
+        check(&mut s, &read(BASE + 0x100), Some(Response::ReadRange { data: &[0xFF; 4] }));
+        let erase = Command::ErasePage { address: BASE };
+        check(&mut s, &erase, Some(Response::Ok));
+        check(&mut s, &Command::Exit, Some(Response::Ok));
+        assert!(s.take_exit());
+
+        POLICY.0.store(true, Ordering::Relaxed);
+        s.set_denied_error(FlashError::Internal);
+        check(&mut s, &erase, Some(Response::InternalError));
+        check(&mut s, &Command::Exit, Some(Response::InternalError));
+        assert!(!s.take_exit());
+        check(&mut s, &Command::Ping, Some(Response::Pong));
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_verifier() {
+        use super::super::signature::{signature_command, SignedImage, Verifier};
+        use super::super::workflow::SYNC_BYTES;
+
+        /// Takes the CRC of the image as its signature.
+        struct CrcVerifier;
+
+        impl Verifier for CrcVerifier {
+            fn verify(&self, image: &mut SignedImage, signature: &[u8])
+                -> Result<bool, FlashError> {
+                let mut crc = Crc32::new();
+                image.read(|chunk| crc.update(chunk))?;
+                Ok(signature == crc.finish().to_le_bytes())
+            }
+        }
+
+        static VERIFIER: CrcVerifier = CrcVerifier;
+        let mut s = BootloaderSession::new(RamFlash::new());
+        s.set_verifier(&VERIFIER);
+        s.set_exit_command(true);
+        let image = [0x5A; INT_PAGE_SIZE];
+        let write = Command::WritePage {
+            address: BASE,
+            data: &image,
+        };
+        check(&mut s, &write, Some(Response::Ok));
+        check(&mut s, &Command::Exit, Some(Response::BadArguments));
+        assert!(!s.take_exit());
+        // A Reset still clears out half a command before signing
+        for &b in [0x12, 0x34].iter().chain(&SYNC_BYTES) {
+            assert_eq!(s.receive(b), None);
+        }
+        check(&mut s, &Command::Ping, Some(Response::Pong));
+
+        let mut buffer = [0u8; MAX_ATTR_LEN];
+        let crc = crate::crc32(&image).to_le_bytes();
+        let length = INT_PAGE_SIZE as u32;
+        let sign = signature_command(BASE, length, &[0; 4], &mut buffer).unwrap();
+        check(&mut s, &sign, Some(Response::BadArguments));
+        let sign = signature_command(BASE + 0x1000, length, &crc, &mut buffer).unwrap();
+        check(&mut s, &sign, Some(Response::BadAddress));
+        let sign = signature_command(BASE, length, &crc, &mut buffer).unwrap();
+        check(&mut s, &sign, Some(Response::Ok));
+        // A sync doesn't start the signed image, only an Exit does
+        for &b in &SYNC_BYTES {
+            assert_eq!(s.receive(b), None);
+        }
+        assert!(!s.take_exit());
+        // Reading is fine, but writing means signing again
+        check(&mut s, &Command::Ping, Some(Response::Pong));
+        check(&mut s, &Command::Exit, Some(Response::Ok));
+        assert!(s.take_exit());
+        check(&mut s, &write, Some(Response::Ok));
+        check(&mut s, &Command::Exit, Some(Response::BadArguments));
+        assert!(!s.take_exit());
+
+        // Other attributes are stored as usual
+        let cmd = Command::SetAttr {
+            index: 0,
+            key: b"board\0\0\0",
+            value: b"hail",
+        };
+        check(&mut s, &cmd, Some(Response::Ok));
+    }
+
+    #[test]
+    #[cfg(feature = "ext-flash")]
+    fn check_errors() {
+        let mut s = BootloaderSession::new(RamFlash::new());
+        let page = [0u8; INT_PAGE_SIZE];
+        let cmd = Command::WritePage {
+            address: BASE + 1,
+            data: &page,
+        };
+        check(&mut s, &cmd, Some(Response::BadAddress));
+        let cmd = Command::ReadRange {
+            address: BASE,
+            length: 0xFFFF,
+        };
+        check(&mut s, &cmd, Some(Response::BadArguments));
+        check(&mut s, &Command::ExtFlashInit, Some(Response::Unknown));
+        check(&mut s, &Command::Info, Some(Response::Unknown));
+    }
+
+    #[test]
+    fn check_id() {
+        let mut s = BootloaderSession::new(RamFlash::new());
+        let id = [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7];
+        check(&mut s, &Command::Id, Some(Response::Id { id: &id }));
+        // A 16 byte ID needs the host to expect it
+        s.set_id_len(16);
+        let mut d = ResponseDecoder::new();
+        d.set_id_len(16);
+        let mut decoded = None;
+        for byte in CommandEncoder::new(&Command::Id).unwrap() {
+            if let Some(r) = s.receive(byte) {
+                for b in ResponseEncoder::new(&r).unwrap() {
+                    if let Some(Response::Id { id }) = d.receive(b).unwrap() {
+                        decoded = Some(id.len());
+                        assert_eq!(id[15], 0xAF);
+                    }
+                }
+            }
+        }
+        assert_eq!(decoded, Some(16));
+    }
+
+    #[test]
+    fn check_alignment() {
+        let mut s = BootloaderSession::new(RamFlash::new());
+        let cmd = Command::ErasePage { address: BASE + 4 };
+        check(&mut s, &cmd, Some(Response::Ok));
+        s.set_check_alignment(true);
+        check(&mut s, &cmd, Some(Response::BadAddress));
+        let page = [0u8; INT_PAGE_SIZE];
+        let cmd = Command::WritePage {
+            address: BASE + INT_PAGE_SIZE as u32,
+            data: &page,
+        };
+        check(&mut s, &cmd, Some(Response::Ok));
+    }
+
+    #[test]
+    #[cfg(feature = "ext-flash")]
+    fn check_ex_alignment() {
+        let mut s = BootloaderSession::new(RamFlash::new());
+        s.set_check_alignment(true);
+        let cmd = Command::EraseExBlock { address: 0x80 };
+        check(&mut s, &cmd, Some(Response::BadAddress));
+        // Aligned, so it gets as far as the flash, which doesn't have any
+        let cmd = Command::EraseExBlock { address: 0x1000 };
+        check(&mut s, &cmd, Some(Response::Unknown));
+        // Page aligned isn't enough once the blocks are known
+        s.set_ext_geometry(ExtFlashGeometry::new(0x10000, 0x1000).unwrap());
+        check(&mut s, &cmd, Some(Response::BadAddress));
+        let cmd = Command::EraseExPage { address: 0x1100 };
+        check(&mut s, &cmd, Some(Response::BadAddress));
+        let cmd = Command::EraseExPage { address: 0x2000 };
+        check(&mut s, &cmd, Some(Response::Unknown));
+    }
+
+    #[test]
+    #[cfg(feature = "baud-change")]
+    fn check_baud_guard() {
+        let set = Command::ChangeBaud {
+            mode: BaudMode::Set,
+            baud: 115200,
+        };
+        let verify = Command::ChangeBaud {
+            mode: BaudMode::Verify,
+            baud: 115200,
+        };
+        let mut g = BaudGuard::new(9600);
+        assert_eq!(g.handle_command(&Command::Ping), BaudAction::Perform);
+        let fail = BaudAction::Reply(Response::ChangeBaudFail, None);
+        assert_eq!(g.handle_command(&verify), fail);
+        let action = g.handle_command(&set);
+        assert_eq!(action, BaudAction::Reply(Response::Ok, Some(115200)));
+        assert!(g.is_pending());
+        assert_eq!(g.handle_command(&verify), BaudAction::Reply(Response::Ok, None));
+        assert_eq!(g.baud(), 115200);
+        assert!(!g.is_pending());
+        assert_eq!(g.timed_out(), None);
+        // Verify at the wrong rate goes back to where we were
+        g.handle_command(&Command::ChangeBaud {
+            mode: BaudMode::Set,
+            baud: 9600,
+        });
+        let fail = BaudAction::Reply(Response::ChangeBaudFail, Some(115200));
+        assert_eq!(g.handle_command(&verify), fail);
+        g.handle_command(&set);
+        assert_eq!(g.timed_out(), Some(115200));
+        assert_eq!(g.baud(), 115200);
+    }
+
+    #[test]
+    #[cfg(feature = "baud-change")]
+    fn check_session_baud() {
+        let set = Command::ChangeBaud {
+            mode: BaudMode::Set,
+            baud: 115200,
+        };
+        let verify = Command::ChangeBaud {
+            mode: BaudMode::Verify,
+            baud: 115200,
+        };
+        let mut s = BootloaderSession::new(RamFlash::new());
+        check(&mut s, &set, Some(Response::Unknown));
+        s.set_baud(9600);
+        check(&mut s, &set, Some(Response::Ok));
+        assert_eq!(s.baud_switch(), Some(115200));
+        check(&mut s, &verify, Some(Response::Ok));
+        assert_eq!(s.baud_switch(), None);
+        check(&mut s, &Command::Ping, Some(Response::Pong));
+        // Anything other than the Verify undoes the change
+        let set = Command::ChangeBaud {
+            mode: BaudMode::Set,
+            baud: 57600,
+        };
+        check(&mut s, &set, Some(Response::Ok));
+        assert_eq!(s.baud_switch(), Some(57600));
+        check(&mut s, &Command::Ping, Some(Response::ChangeBaudFail));
+        assert_eq!(s.baud_switch(), Some(115200));
+        check(&mut s, &Command::Ping, Some(Response::Pong));
+        // As does a Reset, with no reply
+        check(&mut s, &set, Some(Response::Ok));
+        assert_eq!(s.baud_switch(), Some(57600));
+        check(&mut s, &Command::Reset, None);
+        assert_eq!(s.baud_switch(), Some(115200));
+        check(&mut s, &Command::Ping, Some(Response::Pong));
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_attributes() {
+        let mut s = BootloaderSession::new(RamFlash::new());
+        let cmd = Command::SetAttr {
+            index: 1,
+            key: b"board\0\0\0",
+            value: b"hail",
+        };
+        check(&mut s, &cmd, Some(Response::Ok));
+        let expected = Response::GetAttr {
+            key: b"board\0\0\0",
+            value: b"hail",
+        };
+        check(&mut s, &Command::GetAttr { index: 1 }, Some(expected));
+        check(&mut s, &Command::GetAttr { index: 5 }, Some(Response::BadArguments));
+        let cmd = Command::CrcIntFlash {
+            address: BASE,
+            length: 16,
+        };
+        check(&mut s, &cmd, Some(Response::CrcIntFlash { crc: 16 }));
+        let mut flash = s.release();
+        let mut key = [0u8; KEY_LEN];
+        let mut value = [0u8; MAX_ATTR_LEN];
+        assert_eq!(flash.get_attr(1, &mut key, &mut value), Ok(4));
+    }
+}
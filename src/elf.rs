@@ -0,0 +1,219 @@
+//! Loading images to flash from ELF files.
+//!
+//! The linker's output already says where everything goes: each `PT_LOAD`
+//! segment of an ELF file has a physical address and the bytes to put
+//! there. `Image::from_elf` reads those into an `Image`, which
+//! `Image::plan` can then split between internal and external flash.
+//!
+//! Only little endian files are read, 32 or 64 bit. The physical (load)
+//! address of each segment is used, so initial values for `.data` go to
+//! flash, not RAM. Segments with nothing in the file, like `.bss`, are
+//! skipped.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use byteorder::{ByteOrder, LittleEndian};
+use core::convert::TryFrom;
+
+use super::image::{Image, ImageError};
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const ELF_MAGIC: &[u8; 4] = b"\x7FELF";
+const CLASS_32: u8 = 1;
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const PT_LOAD: u32 = 1;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl Image {
+    /// Read the `PT_LOAD` segments of an ELF file. Returns
+    /// `ImageError::BadElf` if it isn't a little endian ELF file or a
+    /// segment runs past the end of it, `ImageError::OutOfRange` if a
+    /// segment doesn't fit in 32-bit addresses, and `ImageError::Overlap`
+    /// if two segments overlap.
+    pub fn from_elf(elf: &[u8]) -> Result<Image, ImageError> {
+        if elf.get(0..4) != Some(&ELF_MAGIC[..]) || elf.get(5) != Some(&DATA_LITTLE_ENDIAN) {
+            return Err(ImageError::BadElf);
+        }
+        let class = elf.get(4).cloned();
+        // Where the program headers are, how long each is and how many
+        let (phoff, phentsize, phnum) = match class {
+            Some(CLASS_32) => (
+                read_u32(elf, 0x1C)? as u64,
+                read_u16(elf, 0x2A)?,
+                read_u16(elf, 0x2C)?,
+            ),
+            Some(CLASS_64) => (
+                read_u64(elf, 0x20)?,
+                read_u16(elf, 0x36)?,
+                read_u16(elf, 0x38)?,
+            ),
+            _ => return Err(ImageError::BadElf),
+        };
+        let mut image = Image::new();
+        for n in 0..phnum as u64 {
+            let header = phoff
+                .checked_add(n * phentsize as u64)
+                .and_then(|h| usize::try_from(h).ok())
+                .ok_or(ImageError::BadElf)?;
+            let header = field(elf, header, phentsize as usize)?;
+            if read_u32(header, 0)? != PT_LOAD {
+                continue;
+            }
+            let (offset, address, length) = if class == Some(CLASS_32) {
+                (
+                    read_u32(header, 4)? as u64,
+                    read_u32(header, 12)? as u64,
+                    read_u32(header, 16)? as u64,
+                )
+            } else {
+                (
+                    read_u64(header, 8)?,
+                    read_u64(header, 24)?,
+                    read_u64(header, 32)?,
+                )
+            };
+            if length == 0 {
+                continue;
+            }
+            let address = u32::try_from(address).map_err(|_| ImageError::OutOfRange)?;
+            let offset = usize::try_from(offset).map_err(|_| ImageError::BadElf)?;
+            let length = usize::try_from(length).map_err(|_| ImageError::BadElf)?;
+            let data = field(elf, offset, length)?;
+            image.add(address, data)?;
+        }
+        Ok(image)
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// The `len` bytes at `offset`, or `ImageError::BadElf` if the file isn't
+/// that long.
+fn field(elf: &[u8], offset: usize, len: usize) -> Result<&[u8], ImageError> {
+    offset
+        .checked_add(len)
+        .and_then(|end| elf.get(offset..end))
+        .ok_or(ImageError::BadElf)
+}
+
+fn read_u16(elf: &[u8], offset: usize) -> Result<u16, ImageError> {
+    field(elf, offset, 2).map(LittleEndian::read_u16)
+}
+
+fn read_u32(elf: &[u8], offset: usize) -> Result<u32, ImageError> {
+    field(elf, offset, 4).map(LittleEndian::read_u32)
+}
+
+fn read_u64(elf: &[u8], offset: usize) -> Result<u64, ImageError> {
+    field(elf, offset, 8).map(LittleEndian::read_u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::image::Segment;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// A 32-bit ELF file with the given program headers, as type, physical
+    /// address and data, each segment's data following the headers.
+    fn make_elf(segments: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let mut elf = vec![0u8; 0x34];
+        elf[0..4].copy_from_slice(ELF_MAGIC);
+        elf[4] = CLASS_32;
+        elf[5] = DATA_LITTLE_ENDIAN;
+        LittleEndian::write_u32(&mut elf[0x1C..0x20], 0x34);
+        LittleEndian::write_u16(&mut elf[0x2A..0x2C], 0x20);
+        LittleEndian::write_u16(&mut elf[0x2C..0x2E], segments.len() as u16);
+        let mut offset = 0x34 + 0x20 * segments.len();
+        for &(kind, address, data) in segments {
+            let mut header = [0u8; 0x20];
+            LittleEndian::write_u32(&mut header[0..4], kind);
+            LittleEndian::write_u32(&mut header[4..8], offset as u32);
+            // A different virtual address, which should be ignored
+            LittleEndian::write_u32(&mut header[8..12], 0x2000_0000);
+            LittleEndian::write_u32(&mut header[12..16], address);
+            LittleEndian::write_u32(&mut header[16..20], data.len() as u32);
+            elf.extend_from_slice(&header);
+            offset += data.len();
+        }
+        for &(_, _, data) in segments {
+            elf.extend_from_slice(data);
+        }
+        elf
+    }
+
+    #[test]
+    fn check_from_elf() {
+        let elf = make_elf(&[
+            (PT_LOAD, 0x30000, &[1, 2, 3, 4]),
+            (6, 0x1234, &[9; 4]),
+            (PT_LOAD, 0x20001000, &[]),
+            (PT_LOAD, 0x9000_0000, &[5; 300]),
+            (PT_LOAD, 0x30004, &[6, 7]),
+        ]);
+        let image = Image::from_elf(&elf).unwrap();
+        assert_eq!(
+            image.segments()[0],
+            Segment {
+                address: 0x30000,
+                data: vec![1, 2, 3, 4, 6, 7],
+            }
+        );
+        assert_eq!(image.segments()[1].address, 0x9000_0000);
+        assert_eq!(image.segments().len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "ext-flash")]
+    fn check_elf_plan() {
+        use super::super::image::FlashRegion;
+        use super::super::pages::FlashTarget;
+
+        let elf = make_elf(&[
+            (PT_LOAD, 0x30000, &[1, 2, 3, 4]),
+            (PT_LOAD, 0x9000_0000, &[5; 300]),
+        ]);
+        let image = Image::from_elf(&elf).unwrap();
+        let regions = [
+            FlashRegion::internal(0x30000, 0x10000),
+            FlashRegion::external(0x9000_0000, 0x100_0000, 0),
+        ];
+        let plan = image.plan(&regions).unwrap();
+        assert_eq!(plan.len(), 2);
+        assert_eq!(plan[0].target(), FlashTarget::Internal);
+        assert_eq!(plan[0].len(), 1);
+        assert_eq!(plan[1].target(), FlashTarget::External);
+        let addresses: Vec<u32> = plan[1].pages().iter().map(|p| p.address).collect();
+        assert_eq!(addresses, [0, 256]);
+    }
+
+    #[test]
+    fn check_bad_elf() {
+        let elf = make_elf(&[(PT_LOAD, 0x30000, &[1, 2, 3, 4])]);
+        assert_eq!(Image::from_elf(&elf[0..elf.len() - 1]), Err(ImageError::BadElf));
+        assert_eq!(Image::from_elf(b"\x7FELF"), Err(ImageError::BadElf));
+        let mut big_endian = elf.clone();
+        big_endian[5] = 2;
+        assert_eq!(Image::from_elf(&big_endian), Err(ImageError::BadElf));
+    }
+}
@@ -0,0 +1,158 @@
+//! `defmt::Format` for the protocol types.
+//!
+//! Logging a 512 byte page over RTT every time one is written would swamp
+//! the link, so payloads are summarised as their length and first few
+//! bytes.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use defmt::{write, Format, Formatter};
+
+use super::{Command, Response};
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+/// A payload, shown as its length and first few bytes.
+struct Summary<'a>(&'a [u8]);
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+/// How many bytes of a payload to show.
+const SUMMARY_LEN: usize = 4;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<'a> Format for Command<'a> {
+    fn format(&self, f: Formatter) {
+        match *self {
+            Command::Ping => write!(f, "Ping"),
+            Command::Info => write!(f, "Info"),
+            Command::Id => write!(f, "Id"),
+            Command::Reset => write!(f, "Reset"),
+            Command::ErasePage { address } => write!(f, "ErasePage({=u32:#x})", address),
+            Command::WritePage { address, data } => {
+                write!(f, "WritePage({=u32:#x}, {})", address, Summary(data))
+            }
+            #[cfg(feature = "ext-flash")]
+            Command::EraseExBlock { address } => write!(f, "EraseExBlock({=u32:#x})", address),
+            #[cfg(feature = "ext-flash")]
+            Command::WriteExPage { address, data } => {
+                write!(f, "WriteExPage({=u32:#x}, {})", address, Summary(data))
+            }
+            Command::CrcRxBuffer => write!(f, "CrcRxBuffer"),
+            Command::ReadRange { address, length } => {
+                write!(f, "ReadRange({=u32:#x}, {=u16})", address, length)
+            }
+            #[cfg(feature = "ext-flash")]
+            Command::ExReadRange { address, length } => {
+                write!(f, "ExReadRange({=u32:#x}, {=u16})", address, length)
+            }
+            #[cfg(feature = "attributes")]
+            Command::SetAttr { index, key, value } => {
+                write!(f, "SetAttr({=u8}, {=[u8]:a}, {})", index, key, Summary(value))
+            }
+            #[cfg(feature = "attributes")]
+            Command::GetAttr { index } => write!(f, "GetAttr({=u8})", index),
+            Command::CrcIntFlash { address, length } => {
+                write!(f, "CrcIntFlash({=u32:#x}, {=u32})", address, length)
+            }
+            #[cfg(feature = "ext-flash")]
+            Command::CrcExtFlash { address, length } => {
+                write!(f, "CrcExtFlash({=u32:#x}, {=u32})", address, length)
+            }
+            #[cfg(feature = "ext-flash")]
+            Command::EraseExPage { address } => write!(f, "EraseExPage({=u32:#x})", address),
+            #[cfg(feature = "ext-flash")]
+            Command::ExtFlashInit => write!(f, "ExtFlashInit"),
+            Command::ClockOut => write!(f, "ClockOut"),
+            #[cfg(feature = "user-pages")]
+            Command::WriteFlashUserPages { page1, page2 } => {
+                write!(f, "WriteFlashUserPages({=u32:#x}, {=u32:#x})", page1, page2)
+            }
+            #[cfg(feature = "baud-change")]
+            Command::ChangeBaud { mode, baud } => {
+                write!(f, "ChangeBaud({}, {=u32})", mode, baud)
+            }
+            #[cfg(feature = "multi-drop")]
+            Command::SetAddress { address } => write!(f, "SetAddress({=u8})", address),
+            #[cfg(feature = "long-attributes")]
+            Command::SetLongAttr { index, key, value } => {
+                write!(f, "SetLongAttr({=u8}, {=[u8]:a}, {})", index, key, Summary(value))
+            }
+            #[cfg(feature = "long-attributes")]
+            Command::GetLongAttr { index } => write!(f, "GetLongAttr({=u8})", index),
+            Command::Exit => write!(f, "Exit"),
+        }
+    }
+}
+
+impl<'a> Format for Response<'a> {
+    fn format(&self, f: Formatter) {
+        match *self {
+            Response::Overflow => write!(f, "Overflow"),
+            Response::Pong => write!(f, "Pong"),
+            Response::BadAddress => write!(f, "BadAddress"),
+            Response::InternalError => write!(f, "InternalError"),
+            Response::BadArguments => write!(f, "BadArguments"),
+            Response::Ok => write!(f, "Ok"),
+            Response::Unknown => write!(f, "Unknown"),
+            #[cfg(feature = "ext-flash")]
+            Response::ExtFlashTimeout => write!(f, "ExtFlashTimeout"),
+            #[cfg(feature = "ext-flash")]
+            Response::ExtFlashPageError => write!(f, "ExtFlashPageError"),
+            Response::CrcRxBuffer { length, crc } => {
+                write!(f, "CrcRxBuffer({=u16}, {=u32:#010x})", length, crc)
+            }
+            Response::ReadRange { data } => write!(f, "ReadRange({})", Summary(data)),
+            #[cfg(feature = "ext-flash")]
+            Response::ExReadRange { data } => write!(f, "ExReadRange({})", Summary(data)),
+            #[cfg(feature = "attributes")]
+            Response::GetAttr { key, value } => {
+                write!(f, "GetAttr({=[u8]:a}, {})", key, Summary(value))
+            }
+            Response::CrcIntFlash { crc } => write!(f, "CrcIntFlash({=u32:#010x})", crc),
+            #[cfg(feature = "ext-flash")]
+            Response::CrcExtFlash { crc } => write!(f, "CrcExtFlash({=u32:#010x})", crc),
+            Response::Info { info } => write!(f, "Info({})", Summary(info)),
+            #[cfg(feature = "baud-change")]
+            Response::ChangeBaudFail => write!(f, "ChangeBaudFail"),
+            Response::Id { id } => write!(f, "Id({=[u8]:x})", id),
+            #[cfg(feature = "long-attributes")]
+            Response::GetLongAttr { key, value } => {
+                write!(f, "GetLongAttr({=[u8]:a}, {})", key, Summary(value))
+            }
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<'a> Format for Summary<'a> {
+    fn format(&self, f: Formatter) {
+        if self.0.len() > SUMMARY_LEN {
+            write!(f, "{=usize} bytes {=[u8]:02x}..", self.0.len(), &self.0[0..SUMMARY_LEN])
+        } else {
+            write!(f, "{=usize} bytes {=[u8]:02x}", self.0.len(), self.0)
+        }
+    }
+}
@@ -0,0 +1,286 @@
+//! `arbitrary::Arbitrary` for the protocol types.
+//!
+//! Only values the encoders accept are generated: pages are exactly a page
+//! long, keys are `KEY_LEN` bytes, values and info strings are within
+//! their limits and attribute indices are in range. That way a fuzz target
+//! can assert that anything it encodes decodes back to the same thing,
+//! rather than spending its time on arguments the encoder rejects.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use super::consts::{ID_LEN, MAX_FRAME_LEN};
+use super::{BaudMode, Command, Response};
+use super::{INT_PAGE_SIZE, MAX_INFO_LEN};
+#[cfg(feature = "ext-flash")]
+use super::EXT_PAGE_SIZE;
+#[cfg(feature = "attributes")]
+use super::{KEY_LEN, MAX_ATTR_LEN, MAX_INDEX};
+#[cfg(feature = "long-attributes")]
+use super::MAX_LONG_ATTR_LEN;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<'a> Arbitrary<'a> for BaudMode {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<BaudMode> {
+        Ok(if u.arbitrary()? { BaudMode::Set } else { BaudMode::Verify })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Command<'a> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Command<'a>> {
+        // Rather than number the commands which are left, pick again
+        loop {
+            return Ok(match u.choose_index(24)? {
+                0 => Command::Ping,
+                1 => Command::Info,
+                2 => Command::Id,
+                3 => Command::Reset,
+                4 => Command::ErasePage {
+                    address: u.arbitrary()?,
+                },
+                5 => Command::WritePage {
+                    address: u.arbitrary()?,
+                    data: u.bytes(INT_PAGE_SIZE)?,
+                },
+                #[cfg(feature = "ext-flash")]
+                6 => Command::EraseExBlock {
+                    address: u.arbitrary()?,
+                },
+                #[cfg(feature = "ext-flash")]
+                7 => Command::WriteExPage {
+                    address: u.arbitrary()?,
+                    data: u.bytes(EXT_PAGE_SIZE)?,
+                },
+                8 => Command::CrcRxBuffer,
+                9 => Command::ReadRange {
+                    address: u.arbitrary()?,
+                    length: u.arbitrary()?,
+                },
+                #[cfg(feature = "ext-flash")]
+                10 => Command::ExReadRange {
+                    address: u.arbitrary()?,
+                    length: u.arbitrary()?,
+                },
+                #[cfg(feature = "attributes")]
+                11 => Command::SetAttr {
+                    index: u.int_in_range(0..=MAX_INDEX - 1)?,
+                    key: u.bytes(KEY_LEN)?,
+                    value: arbitrary_slice(u, MAX_ATTR_LEN)?,
+                },
+                #[cfg(feature = "attributes")]
+                12 => Command::GetAttr {
+                    index: u.int_in_range(0..=MAX_INDEX - 1)?,
+                },
+                13 => Command::CrcIntFlash {
+                    address: u.arbitrary()?,
+                    length: u.arbitrary()?,
+                },
+                #[cfg(feature = "ext-flash")]
+                14 => Command::CrcExtFlash {
+                    address: u.arbitrary()?,
+                    length: u.arbitrary()?,
+                },
+                #[cfg(feature = "ext-flash")]
+                15 => Command::EraseExPage {
+                    address: u.arbitrary()?,
+                },
+                #[cfg(feature = "ext-flash")]
+                16 => Command::ExtFlashInit,
+                17 => Command::ClockOut,
+                #[cfg(feature = "user-pages")]
+                18 => Command::WriteFlashUserPages {
+                    page1: u.arbitrary()?,
+                    page2: u.arbitrary()?,
+                },
+                #[cfg(feature = "baud-change")]
+                19 => Command::ChangeBaud {
+                    mode: u.arbitrary()?,
+                    baud: u.arbitrary()?,
+                },
+                #[cfg(feature = "multi-drop")]
+                20 => Command::SetAddress {
+                    address: u.arbitrary()?,
+                },
+                #[cfg(feature = "long-attributes")]
+                21 => Command::SetLongAttr {
+                    index: u.int_in_range(0..=MAX_INDEX - 1)?,
+                    key: u.bytes(KEY_LEN)?,
+                    value: arbitrary_slice(u, MAX_LONG_ATTR_LEN)?,
+                },
+                #[cfg(feature = "long-attributes")]
+                22 => Command::GetLongAttr {
+                    index: u.int_in_range(0..=MAX_INDEX - 1)?,
+                },
+                23 => Command::Exit,
+                // Left out by a command group feature
+                _ => continue,
+            });
+        }
+    }
+}
+
+impl<'a> Arbitrary<'a> for Response<'a> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Response<'a>> {
+        loop {
+            return Ok(match u.choose_index(19)? {
+                0 => Response::Overflow,
+                1 => Response::Pong,
+                2 => Response::BadAddress,
+                3 => Response::InternalError,
+                4 => Response::BadArguments,
+                5 => Response::Ok,
+                6 => Response::Unknown,
+                #[cfg(feature = "ext-flash")]
+                7 => Response::ExtFlashTimeout,
+                #[cfg(feature = "ext-flash")]
+                8 => Response::ExtFlashPageError,
+                9 => Response::CrcRxBuffer {
+                    length: u.arbitrary()?,
+                    crc: u.arbitrary()?,
+                },
+                10 => Response::ReadRange {
+                    data: arbitrary_slice(u, MAX_FRAME_LEN - 1)?,
+                },
+                #[cfg(feature = "ext-flash")]
+                11 => Response::ExReadRange {
+                    data: arbitrary_slice(u, MAX_FRAME_LEN - 1)?,
+                },
+                #[cfg(feature = "attributes")]
+                12 => Response::GetAttr {
+                    key: u.bytes(KEY_LEN)?,
+                    value: arbitrary_slice(u, MAX_ATTR_LEN)?,
+                },
+                13 => Response::CrcIntFlash {
+                    crc: u.arbitrary()?,
+                },
+                #[cfg(feature = "ext-flash")]
+                14 => Response::CrcExtFlash {
+                    crc: u.arbitrary()?,
+                },
+                15 => Response::Info {
+                    info: arbitrary_slice(u, MAX_INFO_LEN)?,
+                },
+                #[cfg(feature = "baud-change")]
+                16 => Response::ChangeBaudFail,
+                17 => Response::Id {
+                    id: u.bytes(ID_LEN)?,
+                },
+                #[cfg(feature = "long-attributes")]
+                18 => Response::GetLongAttr {
+                    key: u.bytes(KEY_LEN)?,
+                    value: arbitrary_slice(u, MAX_LONG_ATTR_LEN)?,
+                },
+                // Left out by a command group feature
+                _ => continue,
+            });
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Take up to `max_len` bytes.
+fn arbitrary_slice<'a>(u: &mut Unstructured<'a>, max_len: usize) -> Result<&'a [u8]> {
+    let len = u.int_in_range(0..=max_len)?;
+    u.bytes(len)
+}
+
+#[cfg(all(test, feature = "host", feature = "device"))]
+mod tests {
+    use super::*;
+    use super::super::{CommandDecoder, CommandEncoder, ResponseEncoder, ESCAPE_CHAR};
+    use super::super::roundtrip::{roundtrip_check, roundtrip_check_response};
+    use std::vec::Vec;
+
+    /// Bytes from a simple LCG, so the test is repeatable.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x1234_5678u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn check_commands_roundtrip() {
+        let data = noise(64 * 1024);
+        let mut u = Unstructured::new(&data);
+        let mut decoder = CommandDecoder::new();
+        let mut count = 0;
+        while let Ok(cmd) = Command::arbitrary(&mut u) {
+            let mut decoded = None;
+            for b in CommandEncoder::new(&cmd).unwrap() {
+                if let Some(c) = decoder.receive(b).unwrap() {
+                    decoded = Some(c == cmd);
+                }
+            }
+            assert_eq!(decoded, Some(true));
+            count += 1;
+            if u.is_empty() {
+                break;
+            }
+        }
+        assert!(count > 100);
+    }
+
+    /// Like `noise`, but with every other byte on average an escape.
+    fn escapes(len: usize) -> Vec<u8> {
+        let mut noise = noise(2 * len);
+        let flips = noise.split_off(len);
+        noise
+            .into_iter()
+            .zip(flips)
+            .map(|(b, flip)| if flip & 1 == 0 { ESCAPE_CHAR } else { b })
+            .collect()
+    }
+
+    #[test]
+    fn check_escapes_roundtrip() {
+        let data = escapes(64 * 1024);
+        let mut u = Unstructured::new(&data);
+        let mut count = 0;
+        while let Ok(cmd) = Command::arbitrary(&mut u) {
+            assert_eq!(roundtrip_check(&cmd), Ok(true), "{:?}", cmd);
+            count += 1;
+            if u.is_empty() {
+                break;
+            }
+        }
+        assert!(count > 100);
+        let mut u = Unstructured::new(&data);
+        while let Ok(r) = Response::arbitrary(&mut u) {
+            assert_eq!(roundtrip_check_response(&r), Ok(true), "{:?}", r);
+            if u.is_empty() {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn check_responses_encode() {
+        let data = noise(64 * 1024);
+        let mut u = Unstructured::new(&data);
+        while let Ok(r) = Response::arbitrary(&mut u) {
+            assert!(ResponseEncoder::new(&r).is_ok());
+            if u.is_empty() {
+                break;
+            }
+        }
+    }
+}
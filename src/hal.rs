@@ -0,0 +1,159 @@
+//! Using an embedded-hal serial port as a `Transport`.
+//!
+//! Wrap your HAL's UART in a `SerialTransport` and hand it to
+//! `serve_bootloader`:
+//!
+//! ```ignore
+//! let mut uart = SerialTransport::new(uart);
+//! let mut session = BootloaderSession::new(flash);
+//! serve_bootloader(&mut uart, &mut session);
+//! ```
+//!
+//! Every operation blocks, spinning on `WouldBlock`.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::transport::Transport;
+use embedded_hal::serial::{Read, Write};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// A `Transport` over an embedded-hal 0.2 serial port.
+pub struct SerialTransport<S> {
+    serial: S,
+}
+
+/// An error from either half of the serial port.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SerialError<R, W> {
+    /// Reading failed.
+    Read(R),
+    /// Writing or flushing failed.
+    Write(W),
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<S> SerialTransport<S>
+where
+    S: Read<u8> + Write<u8>,
+{
+    /// Wrap a serial port.
+    pub fn new(serial: S) -> SerialTransport<S> {
+        SerialTransport { serial }
+    }
+
+    /// Get the serial port back.
+    pub fn release(self) -> S {
+        self.serial
+    }
+}
+
+impl<S> Transport for SerialTransport<S>
+where
+    S: Read<u8> + Write<u8>,
+{
+    type Error = SerialError<<S as Read<u8>>::Error, <S as Write<u8>>::Error>;
+
+    fn read_byte(&mut self) -> Result<u8, Self::Error> {
+        nb::block!(self.serial.read()).map_err(SerialError::Read)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        for &b in bytes {
+            nb::block!(self.serial.write(b)).map_err(SerialError::Write)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        nb::block!(self.serial.flush()).map_err(SerialError::Write)
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use super::super::session::HostSession;
+    use super::super::transport::run_host_command;
+    use super::super::{Command, Response};
+
+    /// Says it's busy on every other call.
+    struct Uart {
+        rx: [u8; 2],
+        rx_pos: usize,
+        tx: [u8; 2],
+        tx_pos: usize,
+        busy: bool,
+    }
+
+    impl Read<u8> for Uart {
+        type Error = ();
+
+        fn read(&mut self) -> nb::Result<u8, ()> {
+            self.busy = !self.busy;
+            if self.busy {
+                return Err(nb::Error::WouldBlock);
+            }
+            let b = *self.rx.get(self.rx_pos).ok_or(nb::Error::Other(()))?;
+            self.rx_pos += 1;
+            Ok(b)
+        }
+    }
+
+    impl Write<u8> for Uart {
+        type Error = ();
+
+        fn write(&mut self, word: u8) -> nb::Result<(), ()> {
+            self.busy = !self.busy;
+            if self.busy {
+                return Err(nb::Error::WouldBlock);
+            }
+            self.tx[self.tx_pos] = word;
+            self.tx_pos += 1;
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn check_ping() {
+        let mut t = SerialTransport::new(Uart {
+            rx: [0xFC, 0x11],
+            rx_pos: 0,
+            tx: [0; 2],
+            tx_pos: 0,
+            busy: false,
+        });
+        let mut s = HostSession::new();
+        let result = run_host_command(&mut t, &mut s, &Command::Ping, |r| r == Response::Pong);
+        assert_eq!(result, Ok(Some(true)));
+        let uart = t.release();
+        assert_eq!(uart.tx, [0xFC, 0x01]);
+        assert_eq!(
+            SerialTransport::new(uart).read_byte(),
+            Err(SerialError::Read(()))
+        );
+    }
+}
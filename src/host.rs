@@ -0,0 +1,827 @@
+//! A ready-made flash tool, for hosts with `std`.
+//!
+//! The `Host` opens a serial port (using the `serialport` crate), makes sure
+//! the bootloader is listening, and offers the operations a flash tool
+//! actually wants - reading memory, writing an image, reading attributes -
+//! built on top of a `HostSession`.
+//!
+//! ```ignore
+//! let mut host = Host::open("/dev/ttyUSB0", 115200)?;
+//! host.write_image(0x30000, &app)?;
+//! ```
+//!
+//! Writes which don't check as they go, such as `write_paged`, can be
+//! followed by `verify_image`, which reports the ranges to write again:
+//!
+//! ```ignore
+//! for m in host.verify_image(0x30000, &app)?.mismatches {
+//!     let from = (m.address - 0x30000) as usize;
+//!     host.write_image(m.address, &app[from..from + m.length as usize])?;
+//! }
+//! ```
+//!
+//! Long operations can be stopped from another thread with a
+//! `CancelToken`. The `Host` notices between commands, gets the bootloader
+//! back in step, and returns `HostError::Cancelled`:
+//!
+//! ```ignore
+//! let cancel = CancelToken::new();
+//! host.set_cancel(cancel.clone());
+//! // In the UI thread
+//! cancel.cancel();
+//! ```
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::boxed::Box;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+use super::attributes::{AttributeStore, TABLE_ADDRESS, TABLE_LEN};
+use super::image::PagedImage;
+use super::observer::Observer;
+use super::pages::{FlashTarget, PadPolicy, PageWriter};
+use super::session::HostSession;
+use super::transport::{run_host_command, RunError, Transport};
+#[cfg(feature = "attributes")]
+use super::workflow::FindAttr;
+use super::workflow::{Mismatch, PageDiff, ReadPaginator, SyncStep, SyncUp, VerifyImage};
+use super::{Command, Error, Response, INT_PAGE_SIZE};
+#[cfg(feature = "attributes")]
+use super::{Field, KEY_LEN};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// A connection to a bootloader over any `std::io` stream, usually a
+/// serial port.
+pub struct Host<T = Box<dyn serialport::SerialPort>> {
+    stream: Stream<T>,
+    session: HostSession,
+    on_progress: Option<ProgressFn>,
+    cancel: Option<CancelToken>,
+    pad_policy: PadPolicy,
+}
+
+/// A way to stop a `Host` partway through an operation, from another
+/// thread. Clones share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+/// What `Host::verify_image` found.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct VerifyReport {
+    /// The ranges of flash which don't match the image, in address order.
+    /// Neighbouring pages which both fail are joined into one range.
+    pub mismatches: Vec<Mismatch>,
+    /// How many bytes were checked.
+    pub bytes_checked: usize,
+}
+
+/// How far a `read_range` or `write_image` has got.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Progress {
+    /// How many pages (or reads, for `read_range`) are done.
+    pub pages_done: usize,
+    /// How many pages there are altogether.
+    pub pages_total: usize,
+    /// How many bytes have been written or read so far.
+    pub bytes_done: usize,
+    /// How long since the operation started.
+    pub elapsed: Duration,
+}
+
+/// An attribute read back by a `Host`.
+#[cfg(feature = "attributes")]
+#[derive(Debug, PartialEq, Clone)]
+pub struct HostAttribute {
+    /// The key, without the null padding.
+    pub key: Vec<u8>,
+    /// The value.
+    pub value: Vec<u8>,
+}
+
+/// The ways a `Host` operation can fail.
+#[derive(Debug)]
+pub enum HostError {
+    /// The serial port couldn't be opened.
+    Serial(serialport::Error),
+    /// Reading or writing failed, or timed out.
+    Io(io::Error),
+    /// The bootloader's reply was wrong, or was an error. Error responses
+    /// are reported as `Error::Refused`.
+    Protocol(Error),
+    /// The operation was stopped with a `CancelToken`. The bootloader has
+    /// been synced again, so the `Host` can carry on.
+    Cancelled,
+}
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+struct Stream<T>(T);
+
+/// What `set_progress` was given.
+type ProgressFn = Box<dyn FnMut(&Progress) + Send>;
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+/// How long to wait for each byte from the bootloader.
+const READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl Host {
+    /// Open a serial port at the given baud rate and sync with the
+    /// bootloader.
+    pub fn open(path: &str, baud: u32) -> Result<Host, HostError> {
+        let port = serialport::new(path, baud)
+            .timeout(READ_TIMEOUT)
+            .open()
+            .map_err(HostError::Serial)?;
+        let mut host = Host::new(port);
+        host.sync()?;
+        Ok(host)
+    }
+}
+
+impl<T> Host<T>
+where
+    T: io::Read + io::Write,
+{
+    /// Use an already open stream. Reads should time out rather than block
+    /// forever. Call `sync` before anything else.
+    pub fn new(stream: T) -> Host<T> {
+        Host {
+            stream: Stream(stream),
+            session: HostSession::new(),
+            on_progress: None,
+            cancel: None,
+            pad_policy: PadPolicy::Fill(0xFF),
+        }
+    }
+
+    /// Get the stream back.
+    pub fn release(self) -> T {
+        self.stream.0
+    }
+
+    /// Report the traffic to `observer`, and each page read or written by
+    /// `read_range` and `write_image` to `Observer::on_progress`.
+    pub fn set_observer(&mut self, observer: &'static dyn Observer) {
+        self.session.set_observer(observer);
+    }
+
+    /// Call `on_progress` after each page read or written by `read_range`
+    /// and `write_image`, for drawing a progress bar.
+    pub fn set_progress<F>(&mut self, on_progress: F)
+    where
+        F: FnMut(&Progress) + Send + 'static,
+    {
+        self.on_progress = Some(Box::new(on_progress));
+    }
+
+    /// Check `cancel` between the commands of `read_range`, `write_image`
+    /// and the other operations which take many.
+    pub fn set_cancel(&mut self, cancel: CancelToken) {
+        self.cancel = Some(cancel);
+    }
+
+    /// How `write_image` and `write_changed` pad partial pages. The default
+    /// is `PadPolicy::Fill(0xFF)`, which leaves them looking erased.
+    pub fn set_pad_policy(&mut self, policy: PadPolicy) {
+        self.pad_policy = policy;
+    }
+
+    /// Get the bootloader's attention, as tockloader does.
+    ///
+    /// Anything the bootloader has half received is flushed out with a
+    /// `Reset`, then we ping until it answers.
+    pub fn sync(&mut self) -> Result<(), HostError> {
+        let mut sync = SyncUp::new();
+        loop {
+            match sync.next_step() {
+                SyncStep::Flush(bytes) => {
+                    self.stream.write_bytes(bytes)?;
+                    self.stream.flush()?;
+                    self.session.reset();
+                }
+                SyncStep::Send(cmd) => {
+                    // Garbage on the line counts as a failed attempt too
+                    match self.command(&cmd, |r| Ok(sync.handle_response(&r))) {
+                        Ok(_) => {}
+                        Err(HostError::Io(e)) if e.kind() != io::ErrorKind::TimedOut => {
+                            return Err(HostError::Io(e))
+                        }
+                        Err(_) => sync.timed_out(),
+                    }
+                }
+                SyncStep::Finished(result) => return result.map_err(HostError::Protocol),
+            }
+        }
+    }
+
+    /// Check the bootloader is there.
+    pub fn ping(&mut self) -> Result<(), HostError> {
+        self.command(&Command::Ping, |r| match r {
+            Response::Pong => Ok(()),
+            r => Err(unexpected(&r)),
+        })
+    }
+
+    /// Tell the bootloader to start the app, with `Command::Exit`. Only
+    /// bootloaders which have turned the command on understand it; others
+    /// answer `Unknown`, which comes back as `Error::Refused`.
+    pub fn exit(&mut self) -> Result<(), HostError> {
+        self.command(&Command::Exit, |r| match r {
+            Response::Ok => Ok(()),
+            r => Err(unexpected(&r)),
+        })
+    }
+
+    /// Fill `buffer` from internal flash at `address`, in as many reads as it
+    /// takes. Reads which fail or time out are tried again, as a
+    /// `ReadPaginator` does.
+    pub fn read_range(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), HostError> {
+        self.read(FlashTarget::Internal, address, buffer)
+    }
+
+    /// Fill `buffer` from external flash at `address`, like `read_range`.
+    #[cfg(feature = "ext-flash")]
+    pub fn read_ext_range(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), HostError> {
+        self.read(FlashTarget::External, address, buffer)
+    }
+
+    /// Write `data` to internal flash at `address`, checking the CRC of each
+    /// page as it goes. Partial pages are padded as `set_pad_policy` says,
+    /// with 0xFF by default.
+    pub fn write_image(&mut self, address: u32, data: &[u8]) -> Result<(), HostError> {
+        let mut writer =
+            PageWriter::with_pad_policy(FlashTarget::Internal, address, data, self.pad_policy)
+                .map_err(HostError::Protocol)?;
+        writer.set_verify(true);
+        let mut progress = Progress::new(writer.num_pages());
+        let start = Instant::now();
+        while let Some(cmd) = writer.next_command() {
+            self.check_cancel()?;
+            let written = match cmd {
+                Command::WritePage { data, .. } => data.len(),
+                _ => 0,
+            };
+            let crc = self.command(&cmd, |r| match r {
+                Response::Ok => Ok(None),
+                Response::CrcIntFlash { crc } => Ok(Some(crc)),
+                r => Err(unexpected(&r)),
+            })?;
+            if crc.is_some() && crc != writer.expected_crc() {
+                return Err(HostError::Protocol(Error::CrcMismatch));
+            }
+            if written > 0 {
+                self.progress(&mut progress, start, written);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the pages of `data` which differ from what is already in
+    /// internal flash at `address`, using a `PageDiff`. Returns how many
+    /// pages were written. Progress counts every page, but only the bytes
+    /// actually written.
+    pub fn write_changed(&mut self, address: u32, data: &[u8]) -> Result<usize, HostError> {
+        let mut diff =
+            PageDiff::with_pad_policy(FlashTarget::Internal, address, data, self.pad_policy)
+                .map_err(HostError::Protocol)?;
+        let mut progress = Progress::new(diff.num_pages());
+        let start = Instant::now();
+        while let Some(cmd) = diff.next_command() {
+            self.check_cancel()?;
+            let response = self.command(&cmd, |r| match r {
+                Response::Ok => Ok(Response::Ok),
+                Response::CrcIntFlash { crc } => Ok(Response::CrcIntFlash { crc }),
+                r => Err(unexpected(&r)),
+            })?;
+            let (written, skipped) = (diff.pages_written(), diff.pages_skipped());
+            diff.handle_response(&response).map_err(HostError::Protocol)?;
+            if diff.pages_written() > written {
+                self.progress(&mut progress, start, INT_PAGE_SIZE);
+            } else if diff.pages_skipped() > skipped {
+                self.progress(&mut progress, start, 0);
+            }
+        }
+        Ok(diff.pages_written())
+    }
+
+    /// Check that internal flash holds `data` at `address`, using a
+    /// `VerifyImage`, and report the ranges which don't. Progress counts
+    /// each page checked.
+    pub fn verify_image(&mut self, address: u32, data: &[u8]) -> Result<VerifyReport, HostError> {
+        let mut verify = VerifyImage::new(FlashTarget::Internal, address, data)
+            .map_err(HostError::Protocol)?;
+        let mut report = VerifyReport::default();
+        let mut progress = Progress::new(verify.num_pages());
+        let start = Instant::now();
+        while let Some(cmd) = verify.next_command() {
+            self.check_cancel()?;
+            let length = match cmd {
+                Command::CrcIntFlash { length, .. } => length as usize,
+                _ => 0,
+            };
+            let crc = self.command(&cmd, |r| match r {
+                Response::CrcIntFlash { crc } => Ok(crc),
+                r => Err(unexpected(&r)),
+            })?;
+            let mismatch = verify
+                .handle_response(&Response::CrcIntFlash { crc })
+                .map_err(HostError::Protocol)?;
+            if let Some(m) = mismatch {
+                match report.mismatches.last_mut() {
+                    Some(last) if last.end() == m.address as u64 => last.length += m.length,
+                    _ => report.mismatches.push(m),
+                }
+            }
+            report.bytes_checked += length;
+            self.progress(&mut progress, start, length);
+        }
+        Ok(report)
+    }
+
+    /// Write the pages of a `PagedImage`, to whichever flash it is for.
+    /// Unlike `write_image`, the pages aren't read back; follow up with
+    /// `write_changed` or a CRC check if that matters.
+    pub fn write_paged(&mut self, image: &PagedImage) -> Result<(), HostError> {
+        let mut progress = Progress::new(image.len());
+        let start = Instant::now();
+        for cmd in image.commands(false) {
+            self.check_cancel()?;
+            self.command(&cmd, |r| match r {
+                Response::Ok => Ok(()),
+                r => Err(unexpected(&r)),
+            })?;
+            self.progress(&mut progress, start, image.target().page_size());
+        }
+        Ok(())
+    }
+
+    /// Read the attribute at `index`, or `None` if the slot is empty.
+    #[cfg(feature = "attributes")]
+    pub fn get_attribute(&mut self, index: u8) -> Result<Option<HostAttribute>, HostError> {
+        let result = self.command(&Command::GetAttr { index }, |r| match r {
+            Response::GetAttr { key, value } => {
+                let len = key.iter().position(|&b| b == 0x00).unwrap_or(KEY_LEN);
+                Ok(HostAttribute {
+                    key: key[0..len].to_vec(),
+                    value: value.to_vec(),
+                })
+            }
+            r => Err(unexpected(&r)),
+        });
+        match result {
+            Ok(ref attr) if attr.value.is_empty() => Ok(None),
+            Ok(attr) => Ok(Some(attr)),
+            // The decoder rejects the length byte of an erased slot
+            Err(HostError::Protocol(Error::InvalidValue {
+                field: Field::AttrLength,
+                ..
+            })) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Find the attribute with `key`, using a `FindAttr`, returning its
+    /// index and value, or `None` if no slot has that key.
+    #[cfg(feature = "attributes")]
+    pub fn find_attribute(&mut self, key: &[u8]) -> Result<Option<(u8, Vec<u8>)>, HostError> {
+        let mut find = FindAttr::new(key).map_err(HostError::Protocol)?;
+        while let Some(cmd) = find.next_command() {
+            let result = self.command(&cmd, |r| {
+                let found = find.handle_response(&r)?;
+                Ok(found.map(|a| (a.index, a.value.to_vec())))
+            });
+            match result {
+                Ok(Some(found)) => return Ok(Some(found)),
+                Ok(None) => {}
+                // The decoder rejects the length byte of an erased slot
+                Err(HostError::Protocol(Error::InvalidValue {
+                    field: Field::AttrLength,
+                    ..
+                })) => find.slot_empty(),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Fill `buffer` from `target` at `address`.
+    fn read(
+        &mut self,
+        target: FlashTarget,
+        address: u32,
+        buffer: &mut [u8],
+    ) -> Result<(), HostError> {
+        let mut reader =
+            ReadPaginator::new(target, address, buffer).map_err(HostError::Protocol)?;
+        let mut progress = Progress::new(reader.num_chunks());
+        let start = Instant::now();
+        while let Some(cmd) = reader.next_command() {
+            self.check_cancel()?;
+            let before = reader.bytes_read();
+            match self.command(&cmd, |r| Ok(reader.handle_response(&r))) {
+                Ok(result) => result.map_err(HostError::Protocol)?,
+                Err(HostError::Io(e)) if e.kind() != io::ErrorKind::TimedOut => {
+                    return Err(HostError::Io(e));
+                }
+                // No reply, or one too garbled to decode
+                Err(e) => {
+                    if reader.timed_out().is_err() {
+                        return Err(e);
+                    }
+                }
+            }
+            if reader.bytes_read() > before {
+                self.progress(&mut progress, start, reader.bytes_read() - before);
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the whole attribute table from flash, rather than a slot at a
+    /// time with `GetAttr`.
+    pub fn read_attributes(&mut self) -> Result<AttributeStore, HostError> {
+        let mut table = [0u8; TABLE_LEN];
+        self.read_range(TABLE_ADDRESS, &mut table)?;
+        AttributeStore::from_bytes(&table).map_err(HostError::Protocol)
+    }
+
+    /// Write `store` to the attribute table in flash, as `write_changed`
+    /// does, so only the pages which differ are written. Returns how many
+    /// were.
+    pub fn write_attributes(&mut self, store: &AttributeStore) -> Result<usize, HostError> {
+        self.write_changed(TABLE_ADDRESS, store.as_bytes())
+    }
+
+    /// If the `CancelToken` has been used, clear it, sync up again so the
+    /// bootloader isn't left halfway through anything, and return
+    /// `HostError::Cancelled`.
+    fn check_cancel(&mut self) -> Result<(), HostError> {
+        match self.cancel {
+            Some(ref cancel) if cancel.0.swap(false, Ordering::SeqCst) => {
+                self.sync()?;
+                Err(HostError::Cancelled)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Count another page of `bytes` done, and report it.
+    fn progress(&mut self, progress: &mut Progress, start: Instant, bytes: usize) {
+        if let Some(o) = self.session.observer() {
+            o.on_progress(bytes);
+        }
+        progress.pages_done += 1;
+        progress.bytes_done += bytes;
+        progress.elapsed = start.elapsed();
+        if let Some(f) = self.on_progress.as_mut() {
+            f(progress);
+        }
+    }
+
+    /// Send a command and hand the reply to `handler`.
+    fn command<R, F>(&mut self, command: &Command, handler: F) -> Result<R, HostError>
+    where
+        F: FnOnce(Response) -> Result<R, Error>,
+    {
+        match run_host_command(&mut self.stream, &mut self.session, command, handler) {
+            Ok(Some(result)) => result.map_err(HostError::Protocol),
+            Ok(None) => Err(HostError::Protocol(Error::MismatchedResponse)),
+            Err(RunError::Transport(e)) => Err(HostError::Io(e)),
+            Err(RunError::Protocol(e)) => Err(HostError::Protocol(e)),
+        }
+    }
+}
+
+impl CancelToken {
+    /// A token which hasn't been used.
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Stop whatever operation the `Host` is in the middle of.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Has `cancel` been called, and not yet noticed?
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl VerifyReport {
+    /// Did everything match?
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl Progress {
+    /// Nothing done yet, out of `pages_total`.
+    fn new(pages_total: usize) -> Progress {
+        Progress {
+            pages_done: 0,
+            pages_total,
+            bytes_done: 0,
+            elapsed: Duration::from_secs(0),
+        }
+    }
+
+    /// The average rate so far, in bytes per second. This is zero until
+    /// some time has passed.
+    pub fn bytes_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes_done as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Whether every page is done.
+    pub fn is_done(&self) -> bool {
+        self.pages_done >= self.pages_total
+    }
+}
+
+impl From<io::Error> for HostError {
+    fn from(e: io::Error) -> HostError {
+        HostError::Io(e)
+    }
+}
+
+impl fmt::Display for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HostError::Serial(ref e) => write!(f, "couldn't open serial port: {}", e),
+            HostError::Io(ref e) => write!(f, "I/O error: {}", e),
+            HostError::Protocol(ref e) => write!(f, "protocol error: {}", e),
+            HostError::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for HostError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            HostError::Serial(ref e) => Some(e),
+            HostError::Io(ref e) => Some(e),
+            #[cfg(feature = "core-error")]
+            HostError::Protocol(ref e) => Some(e),
+            #[cfg(not(feature = "core-error"))]
+            HostError::Protocol(_) => None,
+            HostError::Cancelled => None,
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<T> Transport for Stream<T>
+where
+    T: io::Read + io::Write,
+{
+    type Error = io::Error;
+
+    fn read_byte(&mut self) -> Result<u8, io::Error> {
+        let mut buf = [0u8; 1];
+        self.0.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), io::Error> {
+        self.0.write_all(bytes)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.0.flush()
+    }
+}
+
+/// The error for a reply we didn't want.
+fn unexpected(response: &Response) -> Error {
+    if response.is_error() {
+        Error::Refused
+    } else {
+        Error::MismatchedResponse
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::image::Image;
+    use super::super::mock::{Loopback, MemFlash};
+    use std::io::Write;
+    use std::vec;
+    use std::vec::Vec;
+
+    fn make_host() -> Host<Loopback<MemFlash>> {
+        let mut flash = MemFlash::new(0x1000);
+        flash.attributes_mut().set(b"board", b"hail").unwrap();
+        Host::new(Loopback::new(flash))
+    }
+
+    #[test]
+    fn check_sync() {
+        let mut host = make_host();
+        // Half a command left over from before
+        host.stream.0.write_all(&[0x12, 0x34]).unwrap();
+        host.sync().unwrap();
+        host.ping().unwrap();
+    }
+
+    #[test]
+    fn check_write_and_read() {
+        let mut host = make_host();
+        let image: Vec<u8> = (0..700u32).map(|i| i as u8).collect();
+        host.write_image(0x200, &image).unwrap();
+        let mut readback = vec![0u8; 1024];
+        host.read_range(0x200, &mut readback).unwrap();
+        assert_eq!(&readback[0..700], &image[..]);
+        assert!(readback[700..].iter().all(|&b| b == 0xFF));
+
+        match host.write_image(0x10000, &image) {
+            Err(HostError::Protocol(Error::Refused)) => {}
+            x => panic!("Unexpected {:?}", x),
+        }
+
+        host.set_pad_policy(PadPolicy::Reject);
+        match host.write_image(0x200, &image) {
+            Err(HostError::Protocol(Error::BadArguments)) => {}
+            x => panic!("Unexpected {:?}", x),
+        }
+        host.write_image(0x200, &image[0..512]).unwrap();
+    }
+
+    #[test]
+    fn check_write_changed() {
+        let mut host = make_host();
+        let mut image = vec![0x33u8; 1200];
+        assert_eq!(host.write_changed(0x200, &image).unwrap(), 3);
+        image[600] = 0x44;
+        assert_eq!(host.write_changed(0x200, &image).unwrap(), 1);
+        assert_eq!(host.write_changed(0x200, &image).unwrap(), 0);
+        let mut readback = vec![0u8; 1200];
+        host.read_range(0x200, &mut readback).unwrap();
+        assert_eq!(readback, image);
+    }
+
+    #[test]
+    fn check_verify_image() {
+        let mut host = make_host();
+        let mut image = vec![0x77u8; 1500];
+        host.write_image(0x200, &image).unwrap();
+        let report = host.verify_image(0x200, &image).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.bytes_checked, 1500);
+        // In the first and last pages
+        image[10] = 0;
+        image[1400] = 0;
+        let report = host.verify_image(0x200, &image).unwrap();
+        let ranges: Vec<_> = report.mismatches.iter().map(|m| (m.address, m.length)).collect();
+        assert_eq!(ranges, [(0x200, 512), (0x600, 476)]);
+        // And now the one in between
+        image[600] = 0;
+        let report = host.verify_image(0x200, &image).unwrap();
+        assert_eq!(report.mismatches.len(), 1);
+        assert_eq!(report.mismatches[0].length, 1500);
+    }
+
+    #[test]
+    fn check_attribute_table() {
+        let mut host = make_host();
+        let mut store = host.read_attributes().unwrap();
+        assert_eq!(store.iter().count(), 0);
+        store.set(b"board", b"imix").unwrap();
+        assert_eq!(host.write_attributes(&store).unwrap(), 1);
+        assert_eq!(host.write_attributes(&store).unwrap(), 0);
+        let store = host.read_attributes().unwrap();
+        assert_eq!(store.find(b"board").map(|a| a.value), Some(&b"imix"[..]));
+    }
+
+    #[test]
+    fn check_write_paged() {
+        let mut host = make_host();
+        let image = Image::from_binary(0x400, &[0x66; 600]).unwrap();
+        host.write_paged(&image.pages(FlashTarget::Internal)).unwrap();
+        let mut readback = vec![0u8; 1024];
+        host.read_range(0x400, &mut readback).unwrap();
+        assert!(readback[0..600].iter().all(|&b| b == 0x66));
+        assert!(readback[600..].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn check_progress() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        struct Pages(AtomicUsize);
+
+        impl Observer for Pages {
+            fn on_progress(&self, _bytes: usize) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        static PAGES: Pages = Pages(AtomicUsize::new(0));
+        let mut host = make_host();
+        host.set_observer(&PAGES);
+        host.write_image(0x200, &[0x55; 1200]).unwrap();
+        assert_eq!(PAGES.0.load(Ordering::Relaxed), 3);
+        host.read_range(0x200, &mut [0u8; 1200]).unwrap();
+        assert_eq!(PAGES.0.load(Ordering::Relaxed), 6);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        host.set_progress(move |p| tx.send(*p).unwrap());
+        host.write_image(0x200, &[0x55; 1200]).unwrap();
+        let seen: Vec<Progress> = rx.try_iter().collect();
+        let done: Vec<_> = seen.iter().map(|p| (p.pages_done, p.bytes_done)).collect();
+        assert_eq!(done, [(1, 512), (2, 1024), (3, 1536)]);
+        assert!(seen.iter().all(|p| p.pages_total == 3));
+        assert!(seen[2].is_done() && !seen[1].is_done());
+        host.read_range(0x200, &mut [0u8; 100]).unwrap();
+        let seen: Vec<Progress> = rx.try_iter().collect();
+        assert_eq!(seen.len(), 1);
+        assert_eq!((seen[0].pages_total, seen[0].bytes_done), (1, 100));
+    }
+
+    #[test]
+    fn check_cancel() {
+        let mut host = make_host();
+        let cancel = CancelToken::new();
+        host.set_cancel(cancel.clone());
+        let token = cancel.clone();
+        host.set_progress(move |p| {
+            if p.pages_done == 1 {
+                token.cancel();
+            }
+        });
+        match host.write_image(0x200, &[0x55; 1200]) {
+            Err(HostError::Cancelled) => {}
+            r => panic!("{:?}", r),
+        }
+        assert!(!cancel.is_cancelled());
+        // Only the first page went, and the bootloader still answers
+        host.ping().unwrap();
+        host.set_progress(|_| {});
+        let mut buffer = [0u8; 1024];
+        host.read_range(0x200, &mut buffer).unwrap();
+        assert_eq!(&buffer[0..512], &[0x55; 512][..]);
+        assert_ne!(&buffer[512..1024], &[0x55; 512][..]);
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_get_attribute() {
+        let mut host = make_host();
+        assert_eq!(
+            host.get_attribute(0).unwrap(),
+            Some(HostAttribute {
+                key: b"board".to_vec(),
+                value: b"hail".to_vec(),
+            })
+        );
+        assert_eq!(host.get_attribute(1).unwrap(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_find_attribute() {
+        let mut host = make_host();
+        assert_eq!(host.find_attribute(b"board").unwrap(), Some((0, b"hail".to_vec())));
+        assert_eq!(host.find_attribute(b"arch").unwrap(), None);
+    }
+}
@@ -0,0 +1,616 @@
+//! Loading images to flash from Intel HEX files or raw binaries.
+//!
+//! A build usually produces an Intel HEX file, or a raw binary which goes
+//! at a known address. Either way, an `Image` holds the bytes as a sorted
+//! list of `Segment`s, with any gaps between them left alone. `pages` then
+//! lays the segments out in flash pages, padding partial pages with 0xFF,
+//! and `PagedImage::commands` gives the commands which write them.
+//!
+//! ```ignore
+//! let image = Image::from_ihex(&std::fs::read_to_string("kernel.hex")?)?;
+//! for command in image.pages(FlashTarget::Internal).commands(false) {
+//!     // send it, and check the reply
+//! }
+//! ```
+//!
+//! An image which spans both internal and external flash, such as one read
+//! from an ELF file, can be split between them with `plan`, given the
+//! `FlashRegion`s which the linker script put each kind of flash at.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::pages::FlashTarget;
+use super::Command;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Some bytes to go at an address.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Segment {
+    /// Where the first byte goes.
+    pub address: u32,
+    /// The bytes.
+    pub data: Vec<u8>,
+}
+
+/// The contents of an image file, as segments sorted by address. Segments
+/// never overlap, and ones which touch are joined together.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Image {
+    segments: Vec<Segment>,
+}
+
+/// An image laid out in whole flash pages.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PagedImage {
+    target: FlashTarget,
+    pages: Vec<Segment>,
+}
+
+/// A range of addresses in an image, and the flash they go to.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FlashRegion {
+    start: u32,
+    length: u32,
+    target: FlashTarget,
+    base: u32,
+}
+
+/// The commands which write a `PagedImage`. See `PagedImage::commands`.
+#[derive(Debug, Clone)]
+pub struct ImageCommands<'a> {
+    image: &'a PagedImage,
+    index: usize,
+    erase: bool,
+    erased: bool,
+}
+
+/// The ways loading an image can fail.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ImageError {
+    /// A line of an Intel HEX file isn't a valid record.
+    Syntax {
+        /// The line number, counting from one.
+        line: usize,
+    },
+    /// A record's checksum is wrong.
+    Checksum {
+        /// The line number, counting from one.
+        line: usize,
+    },
+    /// A record is of a type this loader doesn't know.
+    UnknownRecord {
+        /// The line number, counting from one.
+        line: usize,
+        /// The record type.
+        kind: u8,
+    },
+    /// Two pieces of data were given for the same address.
+    Overlap {
+        /// The first address given twice.
+        address: u32,
+    },
+    /// Some data runs past the end of the 32-bit address space.
+    OutOfRange,
+    /// Some data isn't in any of the regions given to `Image::plan`.
+    Unmapped {
+        /// The first address which isn't in a region.
+        address: u32,
+    },
+    /// A file isn't a little endian ELF file, or is cut short.
+    BadElf,
+}
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const RECORD_DATA: u8 = 0x00;
+const RECORD_EOF: u8 = 0x01;
+const RECORD_SEGMENT: u8 = 0x02;
+const RECORD_START_SEGMENT: u8 = 0x03;
+const RECORD_LINEAR: u8 = 0x04;
+const RECORD_START_LINEAR: u8 = 0x05;
+
+/// The longest record: 255 bytes of data, plus the length, address, type
+/// and checksum.
+const MAX_RECORD_LEN: usize = 255 + 5;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl Segment {
+    /// The address after the last byte, which may be 2^32.
+    pub fn end(&self) -> u64 {
+        self.address as u64 + self.data.len() as u64
+    }
+}
+
+impl FlashRegion {
+    /// The `length` bytes of internal flash at `start`. Image addresses and
+    /// flash addresses are the same.
+    pub const fn internal(start: u32, length: u32) -> FlashRegion {
+        FlashRegion {
+            start,
+            length,
+            target: FlashTarget::Internal,
+            base: start,
+        }
+    }
+
+    /// `length` bytes of external flash, which the image puts at `start`
+    /// (usually where it is memory mapped) but which go at `base` in the
+    /// external flash itself.
+    #[cfg(feature = "ext-flash")]
+    pub const fn external(start: u32, length: u32, base: u32) -> FlashRegion {
+        FlashRegion {
+            start,
+            length,
+            target: FlashTarget::External,
+            base,
+        }
+    }
+
+    /// The first image address in the region.
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    /// The address after the last one in the region, which may be 2^32.
+    pub fn end(&self) -> u64 {
+        self.start as u64 + self.length as u64
+    }
+
+    /// Which flash the region is in.
+    pub fn target(&self) -> FlashTarget {
+        self.target
+    }
+
+    /// Whether `address` is in the region.
+    pub fn contains(&self, address: u32) -> bool {
+        address >= self.start && (address as u64) < self.end()
+    }
+}
+
+impl Image {
+    /// An empty image.
+    pub fn new() -> Image {
+        Image::default()
+    }
+
+    /// A raw binary, which goes at `base`. Returns `ImageError::OutOfRange`
+    /// if it runs past the end of the address space.
+    pub fn from_binary(base: u32, data: &[u8]) -> Result<Image, ImageError> {
+        let mut image = Image::new();
+        image.add(base, data)?;
+        Ok(image)
+    }
+
+    /// Parse an Intel HEX file.
+    ///
+    /// Data records, extended segment and extended linear address records,
+    /// and the end of file record are understood. Start address records
+    /// are ignored, as is anything after the end of file record. Blank
+    /// lines are skipped.
+    pub fn from_ihex(text: &str) -> Result<Image, ImageError> {
+        let mut image = Image::new();
+        let mut base: u32 = 0;
+        let mut record = [0u8; MAX_RECORD_LEN];
+        for (n, line) in text.lines().enumerate() {
+            let line_no = n + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let record = parse_record(line, &mut record).ok_or(ImageError::Syntax {
+                line: line_no,
+            })?;
+            if record.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+                return Err(ImageError::Checksum { line: line_no });
+            }
+            let offset = u16::from_be_bytes([record[1], record[2]]);
+            let kind = record[3];
+            let data = &record[4..record.len() - 1];
+            match kind {
+                RECORD_DATA => image.add(base.wrapping_add(offset as u32), data)?,
+                RECORD_EOF => break,
+                RECORD_SEGMENT | RECORD_LINEAR if data.len() == 2 => {
+                    let value = u16::from_be_bytes([data[0], data[1]]) as u32;
+                    base = if kind == RECORD_SEGMENT {
+                        value << 4
+                    } else {
+                        value << 16
+                    };
+                }
+                RECORD_START_SEGMENT | RECORD_START_LINEAR => {}
+                RECORD_SEGMENT | RECORD_LINEAR => {
+                    return Err(ImageError::Syntax { line: line_no });
+                }
+                kind => {
+                    return Err(ImageError::UnknownRecord {
+                        line: line_no,
+                        kind,
+                    })
+                }
+            }
+        }
+        Ok(image)
+    }
+
+    /// Add `data` at `address`. Returns `ImageError::Overlap` if any of it
+    /// is already in the image, and `ImageError::OutOfRange` if it runs past
+    /// the end of the address space.
+    pub fn add(&mut self, address: u32, data: &[u8]) -> Result<(), ImageError> {
+        let end = address as u64 + data.len() as u64;
+        if end > 1 << 32 {
+            return Err(ImageError::OutOfRange);
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        // The segments before this one start at or below it
+        let i = self.segments.partition_point(|s| s.address <= address);
+        if let Some(prev) = i.checked_sub(1).map(|p| &self.segments[p]) {
+            if prev.end() > address as u64 {
+                return Err(ImageError::Overlap { address });
+            }
+        }
+        if let Some(next) = self.segments.get(i) {
+            if (next.address as u64) < end {
+                return Err(ImageError::Overlap {
+                    address: next.address,
+                });
+            }
+        }
+        let joins_prev = i > 0 && self.segments[i - 1].end() == address as u64;
+        let joins_next = self.segments.get(i).is_some_and(|s| s.address as u64 == end);
+        match (joins_prev, joins_next) {
+            (true, true) => {
+                let next = self.segments.remove(i);
+                let prev = &mut self.segments[i - 1];
+                prev.data.extend_from_slice(data);
+                prev.data.extend_from_slice(&next.data);
+            }
+            (true, false) => self.segments[i - 1].data.extend_from_slice(data),
+            (false, true) => {
+                let next = &mut self.segments[i];
+                next.data.splice(0..0, data.iter().cloned());
+                next.address = address;
+            }
+            (false, false) => self.segments.insert(
+                i,
+                Segment {
+                    address,
+                    data: data.to_vec(),
+                },
+            ),
+        }
+        Ok(())
+    }
+
+    /// The segments, in address order.
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Whether there is no data at all.
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Lay the image out in pages of `target`. Only pages with some data in
+    /// are included, and the rest of each of those is filled with 0xFF, so
+    /// a gap in the middle of a page is erased when it's written.
+    pub fn pages(&self, target: FlashTarget) -> PagedImage {
+        let size = target.page_size() as u64;
+        let mut pages: Vec<Segment> = Vec::new();
+        for segment in &self.segments {
+            let mut address = segment.address as u64;
+            while address < segment.end() {
+                let page_start = address & !(size - 1);
+                let chunk_end = segment.end().min(page_start + size);
+                if pages.last().map(|p| p.address as u64) != Some(page_start) {
+                    pages.push(Segment {
+                        address: page_start as u32,
+                        data: vec![0xFF; size as usize],
+                    });
+                }
+                if let Some(page) = pages.last_mut() {
+                    let from = (address - segment.address as u64) as usize;
+                    let to = (chunk_end - segment.address as u64) as usize;
+                    let at = (address - page_start) as usize;
+                    page.data[at..at + to - from].copy_from_slice(&segment.data[from..to]);
+                }
+                address = chunk_end;
+            }
+        }
+        PagedImage { target, pages }
+    }
+
+    /// Split the image between `regions`, moving each part to its address
+    /// in that region's flash, and lay each part out in pages. There is a
+    /// `PagedImage` for each region with some data in, in the order the
+    /// regions are given. Returns `ImageError::Unmapped` if any data isn't
+    /// in one of the regions.
+    ///
+    /// The regions shouldn't overlap; data in two of them would be written
+    /// to both.
+    pub fn plan(&self, regions: &[FlashRegion]) -> Result<Vec<PagedImage>, ImageError> {
+        for segment in &self.segments {
+            let mut address = segment.address as u64;
+            while address < segment.end() {
+                let region = regions
+                    .iter()
+                    .find(|r| r.contains(address as u32))
+                    .ok_or(ImageError::Unmapped {
+                        address: address as u32,
+                    })?;
+                address = region.end();
+            }
+        }
+        let mut plan = Vec::new();
+        for region in regions {
+            let mut part = Image::new();
+            for segment in &self.segments {
+                let start = (segment.address as u64).max(region.start as u64);
+                let end = segment.end().min(region.end());
+                if start >= end {
+                    continue;
+                }
+                let from = (start - segment.address as u64) as usize;
+                let to = (end - segment.address as u64) as usize;
+                let address = (start as u32 - region.start)
+                    .checked_add(region.base)
+                    .ok_or(ImageError::OutOfRange)?;
+                part.add(address, &segment.data[from..to])?;
+            }
+            if !part.is_empty() {
+                plan.push(part.pages(region.target));
+            }
+        }
+        Ok(plan)
+    }
+}
+
+impl PagedImage {
+    /// The pages, in address order, each a whole page long.
+    pub fn pages(&self) -> &[Segment] {
+        &self.pages
+    }
+
+    /// Which flash the pages are for.
+    pub fn target(&self) -> FlashTarget {
+        self.target
+    }
+
+    /// The number of pages.
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Whether there are no pages.
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// The commands which write the image: a `WritePage` (or `WriteExPage`)
+    /// for each page, each after an `ErasePage` (or `EraseExPage`) if
+    /// `erase` is set. The bootloader erases as part of a write anyway, so
+    /// `erase` is only needed for ones which don't.
+    pub fn commands(&self, erase: bool) -> ImageCommands<'_> {
+        ImageCommands {
+            image: self,
+            index: 0,
+            erase,
+            erased: false,
+        }
+    }
+}
+
+impl<'a> Iterator for ImageCommands<'a> {
+    type Item = Command<'a>;
+
+    fn next(&mut self) -> Option<Command<'a>> {
+        let page = self.image.pages.get(self.index)?;
+        let address = page.address;
+        if self.erase && !self.erased {
+            self.erased = true;
+            return Some(match self.image.target {
+                FlashTarget::Internal => Command::ErasePage { address },
+                #[cfg(feature = "ext-flash")]
+                FlashTarget::External => Command::EraseExPage { address },
+            });
+        }
+        self.erased = false;
+        self.index += 1;
+        let data = &page.data[..];
+        Some(match self.image.target {
+            FlashTarget::Internal => Command::WritePage { address, data },
+            #[cfg(feature = "ext-flash")]
+            FlashTarget::External => Command::WriteExPage { address, data },
+        })
+    }
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImageError::Syntax { line } => write!(f, "line {}: not a valid record", line),
+            ImageError::Checksum { line } => write!(f, "line {}: bad checksum", line),
+            ImageError::UnknownRecord { line, kind } => {
+                write!(f, "line {}: unknown record type {:#04x}", line, kind)
+            }
+            ImageError::Overlap { address } => write!(f, "{:#010x} given twice", address),
+            ImageError::OutOfRange => write!(f, "data past the end of the address space"),
+            ImageError::Unmapped { address } => {
+                write!(f, "{:#010x} isn't in any flash region", address)
+            }
+            ImageError::BadElf => write!(f, "not a valid little endian ELF file"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ImageError {}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Turn `:llaaaatt...cc` into bytes in `buffer`. Returns `None` unless the
+/// line is well formed and its length byte matches.
+fn parse_record<'b>(line: &str, buffer: &'b mut [u8; MAX_RECORD_LEN]) -> Option<&'b [u8]> {
+    let hex = line.strip_prefix(':')?.as_bytes();
+    if hex.len() % 2 != 0 || hex.len() / 2 < 5 || hex.len() / 2 > MAX_RECORD_LEN {
+        return None;
+    }
+    let len = hex.len() / 2;
+    for (byte, pair) in buffer.iter_mut().zip(hex.chunks(2)) {
+        let digits = core::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(digits, 16).ok()?;
+    }
+    let record = &buffer[0..len];
+    if record[0] as usize + 5 != len {
+        return None;
+    }
+    Some(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_ihex() {
+        let text = "\
+:020000040003F7
+:10000000000102030405060708090A0B0C0D0E0F78
+:0400100010111213A6
+
+:0400000500030000F4
+:0200000200F00C
+:02000000AABB99
+:00000001FF
+:02000000CCDD55
+";
+        let image = Image::from_ihex(text).unwrap();
+        let expected: Vec<u8> = (0..0x14).collect();
+        assert_eq!(
+            image.segments(),
+            [
+                Segment {
+                    address: 0xF00,
+                    data: vec![0xAA, 0xBB],
+                },
+                Segment {
+                    address: 0x30000,
+                    data: expected,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn check_ihex_errors() {
+        let bad = |text| Image::from_ihex(text).unwrap_err();
+        assert_eq!(bad("\n:02000000AABB98"), ImageError::Checksum { line: 2 });
+        assert_eq!(bad(":02000000AABB"), ImageError::Syntax { line: 1 });
+        assert_eq!(bad("02000000AABB99"), ImageError::Syntax { line: 1 });
+        assert_eq!(bad(":0200000GAABB99"), ImageError::Syntax { line: 1 });
+        assert_eq!(bad(":00000006FA"), ImageError::UnknownRecord { line: 1, kind: 6 });
+        assert_eq!(
+            bad(":02000000AABB99\n:0100010011ED"),
+            ImageError::Overlap { address: 1 }
+        );
+    }
+
+    #[test]
+    fn check_add() {
+        let mut image = Image::new();
+        image.add(0x10, &[3, 4]).unwrap();
+        image.add(0x00, &[0]).unwrap();
+        image.add(0x0E, &[1, 2]).unwrap();
+        image.add(0x01, &[]).unwrap();
+        assert_eq!(image.segments().len(), 2);
+        assert_eq!(image.segments()[1].data, [1, 2, 3, 4]);
+        image.add(0x01, &[0; 0x0D]).unwrap();
+        assert_eq!(image.segments().len(), 1);
+        assert_eq!(image.segments()[0].end(), 0x12);
+        assert_eq!(image.add(0x11, &[0]), Err(ImageError::Overlap { address: 0x11 }));
+        assert_eq!(image.add(0xFFFF_FFFF, &[0, 0]), Err(ImageError::OutOfRange));
+        assert!(Image::from_binary(0xFFFF_FFFF, &[0]).is_ok());
+    }
+
+    #[test]
+    fn check_pages() {
+        // Ends partway through one page, with a gap, then a few bytes in
+        // the same page and a few more two pages on
+        let mut image = Image::from_binary(0x30100, &[0x11; 0x200]).unwrap();
+        image.add(0x30380, &[0x22; 4]).unwrap();
+        image.add(0x30800, &[0x33; 4]).unwrap();
+        let paged = image.pages(FlashTarget::Internal);
+        let addresses: Vec<u32> = paged.pages().iter().map(|p| p.address).collect();
+        assert_eq!(addresses, [0x30000, 0x30200, 0x30800]);
+        let page = &paged.pages()[1].data;
+        assert_eq!(page.len(), 512);
+        assert!(page[0..0x100].iter().all(|&b| b == 0x11));
+        assert!(page[0x100..0x180].iter().all(|&b| b == 0xFF));
+        assert_eq!(page[0x180..0x185], [0x22, 0x22, 0x22, 0x22, 0xFF]);
+
+        let commands: Vec<Command> = paged.commands(true).collect();
+        assert_eq!(commands.len(), 6);
+        assert_eq!(commands[2], Command::ErasePage { address: 0x30200 });
+        assert_eq!(
+            commands[3],
+            Command::WritePage {
+                address: 0x30200,
+                data: page,
+            }
+        );
+        assert_eq!(paged.commands(false).count(), 3);
+    }
+
+    #[test]
+    fn check_plan() {
+        let mut image = Image::from_binary(0x30000, &[0x11; 0x10]).unwrap();
+        image.add(0x40000, &[0x22; 0x10]).unwrap();
+        let regions = [
+            FlashRegion::internal(0x40000, 0x10000),
+            FlashRegion::internal(0x30000, 0x10000),
+        ];
+        let plan = image.plan(&regions).unwrap();
+        assert_eq!(plan.len(), 2);
+        assert_eq!(plan[0].pages()[0].address, 0x40000);
+        assert_eq!(plan[1].pages()[0].address, 0x30000);
+        assert_eq!(
+            image.plan(&regions[0..1]),
+            Err(ImageError::Unmapped { address: 0x30000 })
+        );
+        // A region which ends partway through a segment
+        let regions = [FlashRegion::internal(0x30000, 8)];
+        assert_eq!(
+            image.plan(&regions),
+            Err(ImageError::Unmapped { address: 0x30008 })
+        );
+        assert_eq!(Image::new().plan(&[]), Ok(Vec::new()));
+    }
+}
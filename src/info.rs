@@ -0,0 +1,215 @@
+//! Making sense of the reply to `Info`.
+//!
+//! The spec says the reply is a length byte, then a string, then zeroes up
+//! to 192 bytes. What the string says is up to the bootloader. The Tock
+//! bootloader sends a small JSON object:
+//!
+//! ```text
+//! {"version":"1.1.3", "name":"Tock Bootloader"}
+//! ```
+//!
+//! and some others send plain text, such as `Tock Bootloader 1.0.1`.
+//! `BootloaderInfo::parse` understands both, and gives back the version as
+//! a `SemVer`, so a host can decide what to send:
+//!
+//! ```ignore
+//! let info = BootloaderInfo::from_response(&response).ok_or(Error::MismatchedResponse)?;
+//! if info.version >= Some(SemVer::new(1, 1, 0)) {
+//!     // Safe to send ChangeBaud
+//! }
+//! ```
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::version::SemVer;
+use super::Response;
+use super::MAX_INFO_LEN;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// What a bootloader says about itself in reply to `Info`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct BootloaderInfo<'a> {
+    /// The bootloader's version, if it gave one which parses.
+    pub version: Option<SemVer>,
+    /// The bootloader's name. For a plain text string, this is the whole
+    /// string apart from the version.
+    pub name: &'a [u8],
+    /// The `flags` member of a JSON string, or empty if there isn't one.
+    /// What it means is up to the bootloader.
+    pub flags: &'a [u8],
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<'a> BootloaderInfo<'a> {
+    /// Parse the payload of an `Info` reply, with or without the length
+    /// byte and padding. Returns `None` if there's no string at all.
+    ///
+    /// JSON strings are read simply: only string members are understood,
+    /// and escapes aren't undone.
+    pub fn parse(payload: &'a [u8]) -> Option<BootloaderInfo<'a>> {
+        let text = trim(info_string(payload));
+        if text.is_empty() {
+            return None;
+        }
+        if text.starts_with(b"{") {
+            return Some(BootloaderInfo {
+                version: json_member(text, b"version").and_then(SemVer::parse),
+                name: json_member(text, b"name").unwrap_or(&[]),
+                flags: json_member(text, b"flags").unwrap_or(&[]),
+            });
+        }
+        // Plain text, perhaps with the version as the last word
+        let split = text.iter().rposition(|&b| b == b' ');
+        let (name, version) = match split {
+            Some(at) => (trim(&text[0..at]), &text[at + 1..]),
+            None => (&text[0..0], text),
+        };
+        match SemVer::parse(version) {
+            Some(version) => Some(BootloaderInfo {
+                version: Some(version),
+                name,
+                flags: &[],
+            }),
+            None => Some(BootloaderInfo {
+                version: None,
+                name: text,
+                flags: &[],
+            }),
+        }
+    }
+
+    /// Parse the info in an `Info` reply. Returns `None` for any other
+    /// reply, or if there's no string.
+    pub fn from_response(response: &Response<'a>) -> Option<BootloaderInfo<'a>> {
+        match *response {
+            Response::Info { info } => BootloaderInfo::parse(info),
+            _ => None,
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Find the string in an `Info` payload. The `ResponseDecoder` leaves the
+/// length byte there with `InfoMode::Fixed`, so a first byte is taken as
+/// the length if it's a control character (which can't be part of the
+/// string), or if it's followed by exactly that much string and then only
+/// padding.
+fn info_string(payload: &[u8]) -> &[u8] {
+    let string = match payload.split_first() {
+        Some((&len, rest)) if len < b' ' => &rest[0..rest.len().min(len as usize)],
+        Some((&len, rest)) if is_prefixed(len as usize, rest) => &rest[0..len as usize],
+        _ => payload,
+    };
+    let string = &string[0..string.len().min(MAX_INFO_LEN)];
+    let end = string.iter().position(|&b| b == 0).unwrap_or(string.len());
+    &string[0..end]
+}
+
+/// Is `rest` a string of `len` bytes, then nulls?
+fn is_prefixed(len: usize, rest: &[u8]) -> bool {
+    len <= rest.len() && !rest[0..len].contains(&0) && rest[len..].iter().all(|&b| b == 0)
+}
+
+/// Strip whitespace from both ends.
+fn trim(text: &[u8]) -> &[u8] {
+    let start = text.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(text.len());
+    let end = text.iter().rposition(|b| !b.is_ascii_whitespace()).map_or(start, |p| p + 1);
+    &text[start..end]
+}
+
+/// The value of the string member `key` of a JSON object, without its
+/// quotes.
+fn json_member<'t>(text: &'t [u8], key: &[u8]) -> Option<&'t [u8]> {
+    let mut rest = text;
+    while let Some(at) = rest.iter().position(|&b| b == b'"') {
+        let after = &rest[at + 1..];
+        let close = after.iter().position(|&b| b == b'"')?;
+        let (name, after) = (&after[0..close], &after[close + 1..]);
+        let after = trim(after);
+        match after.split_first() {
+            // A member name, so look at its value
+            Some((b':', value)) => {
+                let value = trim(value);
+                let value = match value.strip_prefix(b"\"") {
+                    Some(value) => value,
+                    // Not a string, so skip it
+                    None => {
+                        rest = value;
+                        continue;
+                    }
+                };
+                let end = value.iter().position(|&b| b == b'"')?;
+                if name == key {
+                    return Some(&value[0..end]);
+                }
+                rest = &value[end + 1..];
+            }
+            _ => rest = after,
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_json() {
+        let info = b"{\"version\":\"1.1.3\", \"name\":\"Tock Bootloader\", \"flags\": \"x\"}";
+        assert_eq!(
+            BootloaderInfo::parse(info),
+            Some(BootloaderInfo {
+                version: Some(SemVer::new(1, 1, 3)),
+                name: b"Tock Bootloader",
+                flags: b"x",
+            })
+        );
+        // Length prefixed and padded, as sent with PaddingMode::Spec
+        let mut payload = [0u8; 1 + MAX_INFO_LEN];
+        let json = b"{\"name\":\"tb\", \"version\":\"1.0.0\"}";
+        payload[0] = json.len() as u8;
+        payload[1..1 + json.len()].copy_from_slice(json);
+        let info = BootloaderInfo::parse(&payload).unwrap();
+        assert_eq!(info.version, Some(SemVer::new(1, 0, 0)));
+        assert_eq!((info.name, info.flags), (&b"tb"[..], &b""[..]));
+        // Not a string member
+        let info = BootloaderInfo::parse(b"{\"version\":2, \"name\":\"tb\"}").unwrap();
+        assert_eq!((info.version, info.name), (None, &b"tb"[..]));
+    }
+
+    #[test]
+    fn check_plain_text() {
+        let info = BootloaderInfo::parse(b"Tock Bootloader v1.0.1\0\0\0").unwrap();
+        assert_eq!(info.version, Some(SemVer::new(1, 0, 1)));
+        assert_eq!(info.name, b"Tock Bootloader");
+        let info = BootloaderInfo::parse(b"\x051.1.0").unwrap();
+        assert_eq!((info.version, info.name), (Some(SemVer::new(1, 1, 0)), &b""[..]));
+        let info = BootloaderInfo::parse(b"tockboot").unwrap();
+        assert_eq!((info.version, info.name), (None, &b"tockboot"[..]));
+        // Cut short by InfoMode::Fixed
+        let info = BootloaderInfo::parse(b"\x15Tock Bo").unwrap();
+        assert_eq!(info.name, b"Tock Bo");
+        assert_eq!(BootloaderInfo::parse(b"\0\0\0\0"), None);
+        assert_eq!(BootloaderInfo::from_response(&Response::Pong), None);
+    }
+}
@@ -0,0 +1,387 @@
+//! Using embedded-io streams.
+//!
+//! With the `embedded-io` feature, any `embedded_io::Read + Write` stream can
+//! be wrapped in an `IoTransport` and used with the blocking run loops. With
+//! the `embedded-io-async` feature, `send_command` does the same job as
+//! `run_host_command` for async streams, so it can be used from Embassy or
+//! RTIC firmware, or an async host tool. `serve` is the async version of
+//! `serve_bootloader`, taking the separate receive and transmit halves that
+//! Embassy's UART and USB drivers give you.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+#[cfg(all(feature = "embedded-io-async", feature = "device"))]
+use super::device::{BootloaderSession, FlashInterface};
+#[cfg(all(feature = "embedded-io-async", feature = "host"))]
+use super::session::HostSession;
+#[cfg(feature = "embedded-io")]
+use super::transport::Transport;
+#[cfg(all(feature = "embedded-io-async", feature = "host"))]
+use super::transport::RunError;
+#[cfg(all(feature = "embedded-io-async", feature = "host"))]
+use super::{Command, Response};
+#[cfg(all(feature = "embedded-io-async", feature = "device"))]
+use super::Error;
+#[cfg(all(feature = "embedded-io-async", any(feature = "host", feature = "device")))]
+use super::MAX_CHUNK_LEN;
+#[cfg(all(feature = "embedded-io-async", feature = "device"))]
+use core::future::Future;
+#[cfg(all(feature = "embedded-io-async", feature = "device"))]
+use core::pin::Pin;
+#[cfg(all(feature = "embedded-io-async", feature = "device"))]
+use core::task::{Context, Poll};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// A `Transport` over a blocking embedded-io stream.
+#[cfg(feature = "embedded-io")]
+pub struct IoTransport<T> {
+    io: T,
+}
+
+/// An error from an embedded-io stream.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum IoError<E> {
+    /// The stream reported an error.
+    Io(E),
+    /// The stream ended before a complete frame was read.
+    EndOfStream,
+}
+
+/// Why `serve` stopped.
+#[cfg(all(feature = "embedded-io-async", feature = "device"))]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ServeError<R, W> {
+    /// The receive half failed.
+    Read(R),
+    /// The receive half ran out of bytes.
+    EndOfStream,
+    /// The transmit half failed.
+    Write(W),
+    /// A response couldn't be encoded.
+    Protocol(Error),
+}
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+/// Returns `Pending` once, so other tasks get a turn.
+#[cfg(all(feature = "embedded-io-async", feature = "device"))]
+struct YieldNow {
+    yielded: bool,
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(feature = "embedded-io")]
+impl<T> IoTransport<T>
+where
+    T: embedded_io::Read + embedded_io::Write,
+{
+    /// Wrap a stream.
+    pub fn new(io: T) -> IoTransport<T> {
+        IoTransport { io }
+    }
+
+    /// Get the stream back.
+    pub fn release(self) -> T {
+        self.io
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<T> Transport for IoTransport<T>
+where
+    T: embedded_io::Read + embedded_io::Write,
+{
+    type Error = IoError<T::Error>;
+
+    fn read_byte(&mut self) -> Result<u8, Self::Error> {
+        let mut buf = [0u8; 1];
+        match self.io.read(&mut buf) {
+            Ok(0) => Err(IoError::EndOfStream),
+            Ok(_) => Ok(buf[0]),
+            Err(e) => Err(IoError::Io(e)),
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.io.write_all(bytes).map_err(IoError::Io)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.io.flush().map_err(IoError::Io)
+    }
+}
+
+/// Send `command` over an async stream and wait for the reply, which is
+/// passed to `handler`. This works like `run_host_command`.
+#[cfg(all(feature = "embedded-io-async", feature = "host"))]
+pub async fn send_command<T, F, R>(
+    io: &mut T,
+    session: &mut HostSession,
+    command: &Command<'_>,
+    handler: F,
+) -> Result<Option<R>, RunError<IoError<T::Error>>>
+where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    F: FnOnce(Response) -> R,
+{
+    let mut encoder = session.send(command).map_err(RunError::Protocol)?;
+    while let Some(chunk) = encoder.next_chunk(MAX_CHUNK_LEN) {
+        io.write_all(chunk).await.map_err(|e| RunError::Transport(IoError::Io(e)))?;
+    }
+    io.flush().await.map_err(|e| RunError::Transport(IoError::Io(e)))?;
+    if !session.in_flight() {
+        return Ok(None);
+    }
+    loop {
+        let mut buf = [0u8; 1];
+        match io.read(&mut buf).await {
+            Ok(0) => return Err(RunError::Transport(IoError::EndOfStream)),
+            Ok(_) => {}
+            Err(e) => return Err(RunError::Transport(IoError::Io(e))),
+        }
+        if let Some(response) = session.receive(buf[0]).map_err(RunError::Protocol)? {
+            return Ok(Some(handler(response)));
+        }
+    }
+}
+
+/// Run a bootloader on an async stream: read commands from `rx`, perform
+/// them with `session` and write the responses to `tx`.
+///
+/// The flash operations themselves are blocking, so after each one we yield
+/// to let other tasks (such as the USB stack) run. This only returns if
+/// something goes wrong.
+#[cfg(all(feature = "embedded-io-async", feature = "device"))]
+pub async fn serve<R, W, F>(
+    rx: &mut R,
+    tx: &mut W,
+    session: &mut BootloaderSession<F>,
+) -> ServeError<R::Error, W::Error>
+where
+    R: embedded_io_async::Read,
+    W: embedded_io_async::Write,
+    F: FlashInterface,
+{
+    let mut buf = [0u8; MAX_CHUNK_LEN];
+    loop {
+        let len = match rx.read(&mut buf).await {
+            Ok(0) => return ServeError::EndOfStream,
+            Ok(len) => len,
+            Err(e) => return ServeError::Read(e),
+        };
+        for &ch in &buf[0..len] {
+            let format = session.reply_format();
+            if let Some(response) = session.receive(ch) {
+                let mut encoder = match format.encoder(&response) {
+                    Ok(encoder) => encoder,
+                    Err(e) => return ServeError::Protocol(e),
+                };
+                while let Some(chunk) = encoder.next_chunk(MAX_CHUNK_LEN) {
+                    if let Err(e) = tx.write_all(chunk).await {
+                        return ServeError::Write(e);
+                    }
+                }
+                if let Err(e) = tx.flush().await {
+                    return ServeError::Write(e);
+                }
+                YieldNow { yielded: false }.await;
+            }
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(all(feature = "embedded-io-async", feature = "device"))]
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(all(test, feature = "host", feature = "device"))]
+mod tests {
+    use super::*;
+
+    /// Reads from one slice and writes into another.
+    struct Pipe<'a> {
+        rx: &'a [u8],
+        tx: [u8; 16],
+        tx_len: usize,
+    }
+
+    impl<'a> embedded_io::ErrorType for Pipe<'a> {
+        type Error = core::convert::Infallible;
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl<'a> embedded_io::Read for Pipe<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            embedded_io::Read::read(&mut self.rx, buf)
+        }
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl<'a> embedded_io::Write for Pipe<'a> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.tx[self.tx_len..self.tx_len + buf.len()].copy_from_slice(buf);
+            self.tx_len += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "embedded-io-async")]
+    impl<'a> embedded_io_async::Read for Pipe<'a> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            embedded_io_async::Read::read(&mut self.rx, buf).await
+        }
+    }
+
+    #[cfg(feature = "embedded-io-async")]
+    impl<'a> embedded_io_async::Write for Pipe<'a> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.tx[self.tx_len..self.tx_len + buf.len()].copy_from_slice(buf);
+            self.tx_len += buf.len();
+            Ok(buf.len())
+        }
+    }
+
+    #[cfg(feature = "embedded-io")]
+    #[test]
+    fn check_blocking() {
+        use super::super::session::HostSession;
+        use super::super::transport::{run_host_command, RunError};
+        use super::super::{Command, Response};
+
+        let mut t = IoTransport::new(Pipe {
+            rx: &[0xFC, 0x11],
+            tx: [0; 16],
+            tx_len: 0,
+        });
+        let mut s = HostSession::new();
+        let result = run_host_command(&mut t, &mut s, &Command::Ping, |r| r == Response::Pong);
+        assert_eq!(result, Ok(Some(true)));
+        let result = run_host_command(&mut t, &mut s, &Command::Ping, |_| ());
+        assert_eq!(result, Err(RunError::Transport(IoError::EndOfStream)));
+        let pipe = t.release();
+        assert_eq!(&pipe.tx[0..pipe.tx_len], &[0xFC, 0x01, 0xFC, 0x01]);
+    }
+
+    #[cfg(feature = "embedded-io-async")]
+    #[test]
+    fn check_async() {
+        use core::task::Waker;
+
+        let mut pipe = Pipe {
+            rx: &[0xFC, 0x23, 0x78, 0x56, 0x34, 0x12],
+            tx: [0; 16],
+            tx_len: 0,
+        };
+        let mut s = HostSession::new();
+        let cmd = Command::CrcIntFlash {
+            address: 0,
+            length: 4,
+        };
+        let mut future = core::pin::pin!(send_command(&mut pipe, &mut s, &cmd, |r| {
+            r == Response::CrcIntFlash { crc: 0x1234_5678 }
+        }));
+        // Nothing in the pipe ever has to wait
+        let mut cx = Context::from_waker(Waker::noop());
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(result) => assert_eq!(result, Ok(Some(true))),
+            Poll::Pending => panic!("Future didn't complete"),
+        }
+    }
+
+    #[cfg(feature = "embedded-io-async")]
+    #[test]
+    fn check_serve() {
+        use super::super::device::FlashError;
+        use core::task::Waker;
+
+        struct Blank;
+
+        impl FlashInterface for Blank {
+            fn read(&mut self, _: u32, _: &mut [u8]) -> Result<(), FlashError> {
+                Err(FlashError::Unsupported)
+            }
+
+            fn write_page(&mut self, _: u32, _: &[u8]) -> Result<(), FlashError> {
+                Ok(())
+            }
+
+            fn erase_page(&mut self, _: u32) -> Result<(), FlashError> {
+                Ok(())
+            }
+
+            fn get_attr(&mut self, _: u8, _: &mut [u8], _: &mut [u8]) -> Result<usize, FlashError> {
+                Err(FlashError::Unsupported)
+            }
+
+            fn set_attr(&mut self, _: u8, _: &[u8], _: &[u8]) -> Result<(), FlashError> {
+                Err(FlashError::Unsupported)
+            }
+
+            fn crc_range(&mut self, _: u32, _: u32) -> Result<u32, FlashError> {
+                Err(FlashError::Unsupported)
+            }
+        }
+
+        let mut rx: &[u8] = &[0xFC, 0x01, 0x00, 0x02, 0x00, 0x00, 0xFC, 0x06];
+        let mut pipe = Pipe {
+            rx: &[],
+            tx: [0; 16],
+            tx_len: 0,
+        };
+        let mut session = BootloaderSession::new(Blank);
+        let mut polls = 0;
+        let result = {
+            let mut future = core::pin::pin!(serve(&mut rx, &mut pipe, &mut session));
+            let mut cx = Context::from_waker(Waker::noop());
+            loop {
+                polls += 1;
+                if let Poll::Ready(result) = future.as_mut().poll(&mut cx) {
+                    break result;
+                }
+            }
+        };
+        assert_eq!(result, ServeError::EndOfStream);
+        // Yielded once after each command
+        assert_eq!(polls, 3);
+        assert_eq!(&pipe.tx[0..pipe.tx_len], &[0xFC, 0x11, 0xFC, 0x15]);
+    }
+}
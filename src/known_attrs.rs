@@ -0,0 +1,232 @@
+//! Typed access to the attributes tockloader knows about.
+//!
+//! Attributes are just an 8 byte key and up to 55 bytes of value, but a few
+//! keys have a meaning to tockloader. This module gives each of them a type
+//! which knows its key and how its value is written, so you don't have to
+//! build null padded key arrays or format addresses by hand.
+//!
+//! Read an attribute by sending `GetAttr` for each index and passing the
+//! replies to `from_response`, which ignores replies for other keys. Write
+//! one with `set_command`.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::{AttrKey, Command, Response};
+use super::{KEY_LEN, MAX_ATTR_LEN};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// An attribute with a well-known key.
+pub trait KnownAttr<'a>: Sized {
+    /// The key, null padded to 8 bytes.
+    const KEY: &'static [u8; KEY_LEN];
+
+    /// Write the value into `buffer`, returning its length.
+    fn encode(&self, buffer: &mut [u8; MAX_ATTR_LEN]) -> usize;
+
+    /// Parse a value. Returns `None` if it isn't valid for this attribute.
+    fn decode(value: &'a [u8]) -> Option<Self>;
+
+    /// Build the `SetAttr` command which stores this attribute at `index`.
+    /// The value is written into `buffer`.
+    fn set_command<'b>(&self, index: u8, buffer: &'b mut [u8; MAX_ATTR_LEN]) -> Command<'b> {
+        let len = self.encode(buffer);
+        Command::SetAttr {
+            index,
+            key: Self::KEY,
+            value: &buffer[0..len],
+        }
+    }
+
+    /// Get this attribute from the reply to a `GetAttr` command. Returns
+    /// `None` if the reply is for some other key, or isn't a `GetAttr`
+    /// reply at all.
+    fn from_response(response: &Response<'a>) -> Option<Self> {
+        match *response {
+            #[cfg(feature = "attributes")]
+            Response::GetAttr { key, value } if AttrKey::from_padded(*Self::KEY).matches(key) => {
+                Self::decode(value)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The name of the board, e.g. `hail`. Stored under the `board` key.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct BoardName<'a>(pub &'a [u8]);
+
+/// The CPU architecture, e.g. `cortex-m4`. Stored under the `arch` key.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Arch<'a>(pub &'a [u8]);
+
+/// Where apps start in flash. Stored under the `appaddr` key as text, e.g.
+/// `0x30000`, which is how tockloader reads it.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct AppAddress(pub u32);
+
+/// The version of the bootloader, e.g. `1.1.0`. Stored under the `bootver`
+/// key.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct BootloaderVersion<'a>(pub &'a [u8]);
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<'a> KnownAttr<'a> for BoardName<'a> {
+    const KEY: &'static [u8; KEY_LEN] = b"board\0\0\0";
+
+    fn encode(&self, buffer: &mut [u8; MAX_ATTR_LEN]) -> usize {
+        encode_text(self.0, buffer)
+    }
+
+    fn decode(value: &'a [u8]) -> Option<BoardName<'a>> {
+        Some(BoardName(value))
+    }
+}
+
+impl<'a> KnownAttr<'a> for Arch<'a> {
+    const KEY: &'static [u8; KEY_LEN] = b"arch\0\0\0\0";
+
+    fn encode(&self, buffer: &mut [u8; MAX_ATTR_LEN]) -> usize {
+        encode_text(self.0, buffer)
+    }
+
+    fn decode(value: &'a [u8]) -> Option<Arch<'a>> {
+        Some(Arch(value))
+    }
+}
+
+impl<'a> KnownAttr<'a> for BootloaderVersion<'a> {
+    const KEY: &'static [u8; KEY_LEN] = b"bootver\0";
+
+    fn encode(&self, buffer: &mut [u8; MAX_ATTR_LEN]) -> usize {
+        encode_text(self.0, buffer)
+    }
+
+    fn decode(value: &'a [u8]) -> Option<BootloaderVersion<'a>> {
+        Some(BootloaderVersion(value))
+    }
+}
+
+impl<'a> KnownAttr<'a> for AppAddress {
+    const KEY: &'static [u8; KEY_LEN] = b"appaddr\0";
+
+    fn encode(&self, buffer: &mut [u8; MAX_ATTR_LEN]) -> usize {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        buffer[0] = b'0';
+        buffer[1] = b'x';
+        let mut len = 2;
+        let mut started = false;
+        for shift in (0..8).rev() {
+            let nibble = (self.0 >> (shift * 4)) & 0xF;
+            if nibble != 0 || started || shift == 0 {
+                started = true;
+                buffer[len] = DIGITS[nibble as usize];
+                len += 1;
+            }
+        }
+        len
+    }
+
+    /// Accepts hex with a `0x` prefix, or decimal, with any trailing nulls
+    /// ignored.
+    fn decode(value: &'a [u8]) -> Option<AppAddress> {
+        let value = trim_key(value);
+        let (digits, radix) = match value {
+            [b'0', b'x', rest @ ..] | [b'0', b'X', rest @ ..] => (rest, 16),
+            _ => (value, 10),
+        };
+        if digits.is_empty() {
+            return None;
+        }
+        let mut result: u32 = 0;
+        for &d in digits {
+            let digit = (d as char).to_digit(radix)?;
+            result = result.checked_mul(radix)?.checked_add(digit)?;
+        }
+        Some(AppAddress(result))
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Strip trailing nulls.
+fn trim_key(key: &[u8]) -> &[u8] {
+    let len = key.iter().rposition(|&b| b != 0x00).map_or(0, |p| p + 1);
+    &key[0..len]
+}
+
+/// Copy as much of `text` as fits.
+fn encode_text(text: &[u8], buffer: &mut [u8; MAX_ATTR_LEN]) -> usize {
+    let len = text.len().min(MAX_ATTR_LEN);
+    buffer[0..len].copy_from_slice(&text[0..len]);
+    len
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use super::super::CommandEncoder;
+
+    #[test]
+    fn check_set_board() {
+        let mut buffer = [0u8; MAX_ATTR_LEN];
+        let cmd = BoardName(b"hail").set_command(0, &mut buffer);
+        assert_eq!(
+            cmd,
+            Command::SetAttr {
+                index: 0,
+                key: b"board\0\0\0",
+                value: b"hail",
+            }
+        );
+        assert!(CommandEncoder::new(&cmd).is_ok());
+    }
+
+    #[test]
+    fn check_app_address() {
+        let mut buffer = [0u8; MAX_ATTR_LEN];
+        for &addr in &[0u32, 0x30000, 0xFFFF_FFFF] {
+            let len = AppAddress(addr).encode(&mut buffer);
+            assert_eq!(AppAddress::decode(&buffer[0..len]), Some(AppAddress(addr)));
+        }
+        let len = AppAddress(0x40000).encode(&mut buffer);
+        assert_eq!(&buffer[0..len], b"0x40000");
+        assert_eq!(AppAddress::decode(b"196608"), Some(AppAddress(0x30000)));
+        assert_eq!(AppAddress::decode(b"0x3000g"), None);
+        assert_eq!(AppAddress::decode(b"0x"), None);
+        assert_eq!(AppAddress::decode(b"0x100000000"), None);
+    }
+
+    #[test]
+    fn check_from_response() {
+        let r = Response::GetAttr {
+            key: b"appaddr\0",
+            value: b"0x30000",
+        };
+        assert_eq!(AppAddress::from_response(&r), Some(AppAddress(0x30000)));
+        assert_eq!(BoardName::from_response(&r), None);
+        let r = Response::GetAttr {
+            key: b"arch\0\0\0\0",
+            value: b"cortex-m0",
+        };
+        assert_eq!(Arch::from_response(&r), Some(Arch(b"cortex-m0")));
+        assert_eq!(Arch::from_response(&Response::Pong), None);
+    }
+}
@@ -5,9 +5,36 @@
 //! protocol. This crate implements that protocol so
 //! that you can write future tockloader compatible bootloaders
 //! in Rust!
+//!
+//! # Static allocation
+//!
+//! Nothing here uses the heap, apart from the image loader behind the
+//! `alloc` feature. The decoders carry their frame buffer inline, so a
+//! `CommandDecoder` or `ResponseDecoder` is a little over 512 bytes and a
+//! `BootloaderSession` twice that. Built in `main`, they would
+//! be put together on the stack and then copied into place, which a small
+//! chip may not have room for. Their constructors are all `const fn`, so
+//! they can be built into a `static` at compile time instead, and end up in
+//! `.bss` or `.data`. For example, with the `static_cell` crate:
+//!
+//! ```ignore
+//! static SESSION: ConstStaticCell<BootloaderSession<MyFlash>> =
+//!     ConstStaticCell::new(BootloaderSession::new(MyFlash::new()));
+//!
+//! let session: &'static mut BootloaderSession<MyFlash> = SESSION.take();
+//! ```
+//!
+//! A `SharedDecoder` or `RxRing` can be a plain `static`. Otherwise, give a
+//! decoder a buffer that lives elsewhere with `with_buffer`.
 
 #![no_std]
 
+#[cfg(any(test, feature = "mock", feature = "std", feature = "tokio-util"))]
+extern crate std;
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 // ****************************************************************************
 //
 // Imports
@@ -15,8 +42,43 @@
 // ****************************************************************************
 
 extern crate byteorder;
+#[cfg(feature = "embedded-hal")]
+extern crate embedded_hal;
+#[cfg(feature = "heapless")]
+extern crate heapless;
+#[cfg(feature = "embedded-io")]
+extern crate embedded_io;
+#[cfg(feature = "embedded-io-async")]
+extern crate embedded_io_async;
+#[cfg(feature = "embedded-hal")]
+extern crate nb;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "defmt")]
+extern crate defmt;
+#[cfg(feature = "arbitrary")]
+extern crate arbitrary;
+#[cfg(feature = "no-panic")]
+extern crate no_panic;
+#[cfg(feature = "ufmt")]
+extern crate ufmt;
+#[cfg(feature = "critical-section")]
+extern crate critical_section;
 
+#[cfg(any(feature = "host", feature = "device"))]
 use byteorder::{LittleEndian, ByteOrder};
+use consts::{ESCAPE_CHAR, EXT_PAGE_SIZE, INT_PAGE_SIZE, KEY_LEN, MAX_ATTR_LEN, MAX_INFO_LEN};
+#[cfg(any(feature = "host", feature = "device"))]
+use consts::ID_LEN;
+#[cfg(any(feature = "host", feature = "device"))]
+use consts::MAX_FRAME_LEN;
+use consts::MAX_LONG_ATTR_LEN;
+#[cfg(feature = "attributes")]
+use consts::MAX_INDEX;
+#[cfg(any(feature = "host", feature = "device"))]
+use core::cmp;
+use core::convert::TryFrom;
+use core::fmt;
 
 // ****************************************************************************
 //
@@ -25,17 +87,30 @@ use byteorder::{LittleEndian, ByteOrder};
 // ****************************************************************************
 
 /// Commands supported by the protocol. A bootloader will decode these and a
-/// flash tool will encode them.
-#[derive(Debug, PartialEq)]
+/// flash tool will encode them. The `device` feature builds the decoding side
+/// and the `host` feature the encoding side; both are on by default.
+///
+/// The external flash, attribute, baud rate and user page commands can be
+/// left out by turning off the `ext-flash`, `attributes`, `baud-change` and
+/// `user-pages` features. Their opcodes then decode as unknown commands.
+/// `SetAddress` is only there with the `multi-drop` feature, and
+/// `SetLongAttr` and `GetLongAttr` with the `long-attributes` feature.
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum Command<'a> {
     /// Send a PING to the bootloader. It will drop its hp buffer and send
     /// back a PONG.
     Ping,
     /// Get info about the bootloader. The result is one byte of length, plus
-    /// length bytes of string, followed by 192-length zeroes.
+    /// length bytes of string, followed by 192-length zeroes. The
+    /// `ResponseDecoder` only reads it that way with
+    /// `InfoMode::LengthPrefixed`.
     Info,
-    /// Get the Unique ID. Result is 8 bytes of unique ID (but I'm not sure
-    /// what the result code should be).
+    /// Get the Unique ID. The reply is `Response::Id`, with `ID_LEN` bytes
+    /// of ID unless the chip has a longer one and both ends have been told
+    /// (see `ResponseDecoder::set_id_len`). Some older bootloaders answer
+    /// with `Response::Ok` instead.
     Id,
     /// Reset all TX and RX buffers.
     Reset,
@@ -48,11 +123,13 @@ pub enum Command<'a> {
     /// byte address of the start of the page, followed by 512 bytes of page.
     WritePage { address: u32, data: &'a [u8] },
     /// Erase a block of pages in ex flash. The RX buffer should contain the
-    /// address of the start of the block. Each block is 8 pages, so 2048
-    /// bytes.
+    /// address of the start of the block. How big a block is depends on the
+    /// chip; the spec assumes 8 pages, so 2048 bytes. See `ExtFlashGeometry`.
+    #[cfg(feature = "ext-flash")]
     EraseExBlock { address: u32 },
     /// Write a page to ex flash. The RX buffer should contain the address of
     /// the start of the 256 byte page, followed by 256 bytes of page.
+    #[cfg(feature = "ext-flash")]
     WriteExPage { address: u32, data: &'a [u8] },
     /// Get the length and CRC of the RX buffer. The response is two bytes of
     /// little endian length, followed by 4 bytes of crc32.
@@ -64,6 +141,7 @@ pub enum Command<'a> {
     /// Read a range from external flash. The RX buffer should contain a 4
     /// byte address followed by 2 bytes of length. The response will be
     /// length bytes long.
+    #[cfg(feature = "ext-flash")]
     ExReadRange { address: u32, length: u16 },
     /// Write a payload attribute. The RX buffer should contain a one byte
     /// index, 8 bytes of key (null padded), one byte of value length, and
@@ -71,6 +149,7 @@ pub enum Command<'a> {
     /// The value may contain nulls.
     ///
     /// The attribute index must be less than 16.
+    #[cfg(feature = "attributes")]
     SetAttr {
         index: u8,
         key: &'a [u8],
@@ -80,6 +159,7 @@ pub enum Command<'a> {
     /// The result is 8 bytes of key, 1 byte of value length, and 55 bytes of
     /// potential value. You must discard 55-valuelength bytes from the end
     /// yourself.
+    #[cfg(feature = "attributes")]
     GetAttr { index: u8 },
     /// Get the CRC of a range of internal flash. The RX buffer should contain
     /// a four byte address and a four byte range. The result will be a four
@@ -88,17 +168,21 @@ pub enum Command<'a> {
     /// Get the CRC of a range of external flash. The RX buffer should contain
     /// a four byte address and a four byte range. The result will be a four
     /// byte crc32.
+    #[cfg(feature = "ext-flash")]
     CrcExtFlash { address: u32, length: u32 },
     /// Erase a page in external flash. The RX buffer should contain a 4 byte
     /// address pointing to the start of the 256 byte page.
+    #[cfg(feature = "ext-flash")]
     EraseExPage { address: u32 },
     /// Initialise the external flash chip. This sets the page size to 256b.
+    #[cfg(feature = "ext-flash")]
     ExtFlashInit,
     /// Go into an infinite loop with the 32khz clock present on pin PA19
     /// (GP6) this is used for clock calibration.
     ClockOut,
     /// Write the flash user pages (first 4 bytes is first page, second 4
     /// bytes is second page, little endian).
+    #[cfg(feature = "user-pages")]
     WriteFlashUserPages { page1: u32, page2: u32 },
     /// Change the baud rate of the bootloader. The first byte is 0x01 to set
     /// a new baud rate. The next 4 bytes are the new baud rate. To allow the
@@ -106,12 +190,44 @@ pub enum Command<'a> {
     /// this command again with the first byte of 0x02 and the next 4 bytes of
     /// the new baud rate. If the next command does not match this, the
     /// bootloader will revert to the old baud rate.
+    #[cfg(feature = "baud-change")]
     ChangeBaud { mode: BaudMode, baud: u32 },
+    /// Answer only to frames addressed to `address` from now on, and
+    /// address replies the same way. The reply to this command is sent as
+    /// the command was, addressed or not. See the `multidrop` module.
+    #[cfg(feature = "multi-drop")]
+    SetAddress { address: u8 },
+    /// Write a long attribute, for values too big for `SetAttr`. The RX
+    /// buffer should contain a one byte index, 8 bytes of key (null padded),
+    /// two bytes of little endian value length, and valuelength value bytes.
+    /// valuelength must be less than or equal to `MAX_LONG_ATTR_LEN`.
+    ///
+    /// Long attributes have their own slots, apart from the ones `SetAttr`
+    /// uses, but the index is checked against the same limit.
+    #[cfg(feature = "long-attributes")]
+    SetLongAttr {
+        index: u8,
+        key: &'a [u8],
+        value: &'a [u8],
+    },
+    /// Get a long attribute. The RX buffer should contain a 1 byte index.
+    /// The result is 8 bytes of key, 2 bytes of little endian value length,
+    /// and `MAX_LONG_ATTR_LEN` bytes of potential value.
+    #[cfg(feature = "long-attributes")]
+    GetLongAttr { index: u8 },
+    /// Leave the bootloader and start the app. The bootloader answers `Ok`
+    /// first. This isn't part of the protocol but an extension of this
+    /// crate's, which a bootloader only acts on if its port has turned it
+    /// on (see `BootloaderSession::set_exit_command`); otherwise it gets
+    /// `Unknown`.
+    Exit,
 }
 
 /// Reponses supported by the protocol. A bootloader will encode these
 /// and a flash tool will decode them.
-#[derive(Debug, PartialEq)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum Response<'a> {
     Overflow, // RES_OVERFLOW
     Pong, // RES_PONG
@@ -120,74 +236,291 @@ pub enum Response<'a> {
     BadArguments, // RES_BADARGS
     Ok, // RES_OK
     Unknown, // RES_UNKNOWN
+    #[cfg(feature = "ext-flash")]
     ExtFlashTimeout, // RES_XFTIMEOUT
+    #[cfg(feature = "ext-flash")]
     ExtFlashPageError, // RES_XFEPE ??
     CrcRxBuffer { length: u16, crc: u32 }, // RES_CRCRX
     ReadRange { data: &'a [u8] }, // RES_RRANGE
+    #[cfg(feature = "ext-flash")]
     ExReadRange { data: &'a [u8] }, // RES_XRRANGE
+    #[cfg(feature = "attributes")]
     GetAttr { key: &'a [u8], value: &'a [u8] }, // RES_GATTR
     CrcIntFlash { crc: u32 }, // RES_CRCIF
+    #[cfg(feature = "ext-flash")]
     CrcExtFlash { crc: u32 }, // RES_CRCXF
     Info { info: &'a [u8] }, // RES_INFO
+    #[cfg(feature = "baud-change")]
     ChangeBaudFail, // RES_CHANGE_BAUD_FAIL
+    Id { id: &'a [u8] }, // RES_ID
+    #[cfg(feature = "long-attributes")]
+    GetLongAttr { key: &'a [u8], value: &'a [u8] }, // RES_GLATTR
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
 pub enum Error {
     /// We got a command we didn't understand.
     UnknownCommand,
-    /// We didn't like the arguments given with a command.
+    /// We didn't like the arguments given to an encoder or constructor.
     BadArguments,
+    /// A frame had the wrong number of bytes after (for a command) or
+    /// before (for a response) its opcode.
+    WrongLength {
+        /// The opcode, or response code.
+        opcode: u8,
+        /// How many bytes it should have had. For responses this is the
+        /// least it should have had.
+        expected: usize,
+        /// How many bytes it had.
+        got: usize,
+    },
+    /// A field in a frame held a value that isn't allowed.
+    InvalidValue {
+        /// Which field.
+        field: Field,
+        /// The value it held.
+        got: u32,
+    },
     /// The user didn't call `set_payload_len` yet we
     /// got a response of unbounded length.
     UnsetLength,
     /// The user called `set_payload_len` yet we
     /// got a response of bounded length.
     SetLength,
+    /// There wasn't enough room in the output buffer.
+    BufferFull,
+    /// We got a response that doesn't go with the command we sent.
+    MismatchedResponse,
+    /// The bootloader sent back an error response.
+    Refused,
+    /// The CRC the bootloader calculated doesn't match ours.
+    CrcMismatch,
+    /// More arguments arrived than fit in the decoder's buffer, so the
+    /// frame was dropped, or bytes were lost before they reached the
+    /// decoder. The bootloader should answer with `Response::Overflow`,
+    /// which is what `Response::from` gives.
+    Overflow,
+}
+
+/// A field of a frame, for `Error::InvalidValue`.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum Field {
+    /// The mode byte of a `ChangeBaud` command.
+    BaudMode,
+    /// The value length of a `GetAttr` or `GetLongAttr` response.
+    AttrLength,
+    /// The index of a `SetAttr` or `GetAttr` command.
+    AttrIndex,
+    /// The length byte of an `Info` response.
+    InfoLength,
 }
 
 /// The `ComandDecoder` takes bytes and gives you `Command`s.
-pub struct CommandDecoder {
+#[cfg(feature = "device")]
+pub struct CommandDecoder<B = [u8; MAX_FRAME_LEN]> {
     state: DecoderState,
-    buffer: [u8; 520],
+    buffer: B,
     count: usize,
+    observer: Option<&'static dyn Observer>,
+    rx_crc: Option<Crc32>,
+    last_rx: Option<(u16, u32)>,
+    overflow: bool,
+    #[cfg(feature = "attributes")]
+    attr_slots: u8,
+    #[cfg(feature = "attributes")]
+    attr_len: usize,
+    #[cfg(feature = "multi-drop")]
+    filter: Option<AddressFilter>,
+    #[cfg(feature = "sequence")]
+    sequence_next: bool,
+    #[cfg(feature = "sequence")]
+    sequence: Option<u8>,
 }
 
 /// The `ResponseDecoder` takes bytes and gives you `Responses`s.
-pub struct ResponseDecoder {
+#[cfg(feature = "host")]
+pub struct ResponseDecoder<B = [u8; MAX_FRAME_LEN]> {
     state: DecoderState,
-    buffer: [u8; 520],
+    buffer: B,
     count: usize,
     needed: Option<usize>,
+    observer: Option<&'static dyn Observer>,
+    info_mode: InfoMode,
+    id_len: usize,
+    #[cfg(feature = "attributes")]
+    attr_len: usize,
+    #[cfg(feature = "multi-drop")]
+    filter: Option<AddressFilter>,
 }
 
 /// The `CommandEncoder` takes a `Command` and gives you bytes.
+#[cfg(feature = "host")]
+#[derive(Clone)]
 pub struct CommandEncoder<'a> {
-    command: &'a Command<'a>,
+    frame: Frame<'a>,
     count: usize,
     sent_escape: bool,
+    staging: [u8; MAX_CHUNK_LEN],
+    staged: usize,
+    staged_len: usize,
+    #[cfg(any(feature = "multi-drop", feature = "sequence"))]
+    header: Header,
 }
 
 /// The `ResponseEncoder` takes a `Response` and gives you bytes.
+#[cfg(feature = "device")]
+#[derive(Clone)]
 pub struct ResponseEncoder<'a> {
     response: &'a Response<'a>,
     count: usize,
     sent_escape: bool,
+    staging: [u8; MAX_CHUNK_LEN],
+    padding: PaddingMode,
+    pad_byte: u8,
+    id_len: usize,
+    #[cfg(feature = "attributes")]
+    attr_len: usize,
+    #[cfg(feature = "multi-drop")]
+    header: Header,
 }
 
+/// Controls how the `ResponseEncoder` lays out variable length responses.
 #[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PaddingMode {
+    /// Emit only the bytes given in the `Response`. This matches what the
+    /// `ResponseDecoder` in this crate has historically expected.
+    Raw,
+    /// Emit the layout given in the spec. For `Response::Info` this is one
+    /// byte of length, then the string, then padding up to 192 bytes. This is
+    /// what stock tockloader expects.
+    Spec,
+}
+
+/// Controls how the `ResponseDecoder` reads `Response::Info`.
+#[cfg(feature = "host")]
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InfoMode {
+    /// Take the first 8 bytes after the response code, length byte and
+    /// all. This is what the `ResponseDecoder` has historically done, for
+    /// bootloaders which send an 8 byte string with `PaddingMode::Raw`.
+    Fixed,
+    /// Read the length byte and the 192 bytes after it, as laid out in the
+    /// spec, and give back just the string. This is what the C bootloader
+    /// sends.
+    LengthPrefixed,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum BaudMode {
     Set, // 0x01
     Verify, // 0x02
 }
 
+/// A baud rate for `Command::ChangeBaud`. The named rates are the ones the
+/// Tock bootloader is known to work at; anything else is `Custom`.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Baud {
+    B115200,
+    B230400,
+    B460800,
+    B500000,
+    B921600,
+    B1000000,
+    Custom(u32),
+}
+
+/// The opcode of each `Command`, as sent on the wire after the escape byte.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+#[non_exhaustive]
+pub enum Opcode {
+    Ping = CMD_PING,
+    Info = CMD_INFO,
+    Id = CMD_ID,
+    Reset = CMD_RESET,
+    ErasePage = CMD_EPAGE,
+    WritePage = CMD_WPAGE,
+    EraseExBlock = CMD_XEBLOCK,
+    WriteExPage = CMD_XWPAGE,
+    CrcRxBuffer = CMD_CRCRX,
+    ReadRange = CMD_RRANGE,
+    ExReadRange = CMD_XRRANGE,
+    SetAttr = CMD_SATTR,
+    GetAttr = CMD_GATTR,
+    CrcIntFlash = CMD_CRCIF,
+    CrcExtFlash = CMD_CRCEF,
+    EraseExPage = CMD_XEPAGE,
+    ExtFlashInit = CMD_XFINIT,
+    ClockOut = CMD_CLKOUT,
+    WriteFlashUserPages = CMD_WUSER,
+    ChangeBaud = CMD_CHANGE_BAUD,
+    SetAddress = CMD_SET_ADDRESS,
+    SetLongAttr = CMD_SLATTR,
+    GetLongAttr = CMD_GLATTR,
+    Exit = CMD_EXIT,
+}
+
+/// How many bytes of arguments come before a command's opcode.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ArgLen {
+    /// The command takes no arguments.
+    None,
+    /// The command always takes this many bytes.
+    Fixed(usize),
+    /// The command takes between `min` and `max` bytes, inclusive. Where in
+    /// that range depends on a length byte in the arguments.
+    Variable { min: usize, max: usize },
+}
+
+/// The code of each `Response`, as sent on the wire after the escape byte.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+#[non_exhaustive]
+pub enum ResponseCode {
+    Overflow = RES_OVERFLOW,
+    Pong = RES_PONG,
+    BadAddress = RES_BADADDR,
+    InternalError = RES_INTERROR,
+    BadArguments = RES_BADARGS,
+    Ok = RES_OK,
+    Unknown = RES_UNKNOWN,
+    ExtFlashTimeout = RES_XFTIMEOUT,
+    ExtFlashPageError = RES_XFEPE,
+    CrcRxBuffer = RES_CRCRX,
+    ReadRange = RES_RRANGE,
+    ExReadRange = RES_XRRANGE,
+    GetAttr = RES_GATTR,
+    CrcIntFlash = RES_CRCIF,
+    CrcExtFlash = RES_CRCXF,
+    Info = RES_INFO,
+    ChangeBaudFail = RES_CHANGE_BAUD_FAIL,
+    Id = RES_ID,
+    GetLongAttr = RES_GLATTR,
+}
+
 // ****************************************************************************
 //
 // Public Data
 //
 // ****************************************************************************
 
-// None
+/// The most bytes that `next_chunk` will return in one call.
+pub const MAX_CHUNK_LEN: usize = 64;
 
 // ****************************************************************************
 //
@@ -195,18 +528,343 @@ pub enum BaudMode {
 //
 // ****************************************************************************
 
+#[cfg(any(feature = "host", feature = "device"))]
 enum DecoderState {
     Loading,
     Escape,
 }
 
+/// The multi-drop and sequence headers an encoder sends before the frame,
+/// and how much of them has gone.
+#[cfg(any(
+    all(feature = "multi-drop", any(feature = "host", feature = "device")),
+    all(feature = "sequence", feature = "host")
+))]
+#[derive(Clone, Copy)]
+struct Header {
+    address: Option<[u8; 3]>,
+    sequence: Option<[u8; 3]>,
+    sent: usize,
+}
+
+/// How to encode and decode one command. `COMMANDS` has one of these for
+/// each opcode.
+#[cfg(any(feature = "host", feature = "device"))]
+struct CommandDesc {
+    kind: Opcode,
+    /// Lays out the arguments of a command of this kind.
+    #[cfg(feature = "host")]
+    encode: for<'a> fn(&Command<'a>, &mut Frame<'a>),
+    /// Builds the command once the arguments have been checked against
+    /// `kind.arg_len()`.
+    #[cfg(feature = "device")]
+    decode: for<'a> fn(&'a [u8]) -> Result<Command<'a>, Error>,
+}
+
+/// A command laid out as it goes on the wire, before escaping: a few bytes
+/// of arguments, then any page or value borrowed from the command, then
+/// the opcode.
+#[cfg(feature = "host")]
+#[derive(Clone)]
+struct Frame<'a> {
+    head: [u8; MAX_HEAD_LEN],
+    head_len: usize,
+    data: &'a [u8],
+    opcode: u8,
+}
+
 // ****************************************************************************
 //
 // Private Data
 //
 // ****************************************************************************
 
-const ESCAPE_CHAR: u8 = 0xFC;
+/// Every command, in opcode order, with how to lay it out and read it
+/// back. Adding a command is one row here, plus its `Opcode`.
+#[cfg(any(feature = "host", feature = "device"))]
+const COMMANDS: &[CommandDesc] = &[
+    CommandDesc {
+        kind: Opcode::Ping,
+        #[cfg(feature = "host")]
+        encode: encode_none,
+        #[cfg(feature = "device")]
+        decode: |_| Ok(Command::Ping),
+    },
+    CommandDesc {
+        kind: Opcode::Info,
+        #[cfg(feature = "host")]
+        encode: encode_none,
+        #[cfg(feature = "device")]
+        decode: |_| Ok(Command::Info),
+    },
+    CommandDesc {
+        kind: Opcode::Id,
+        #[cfg(feature = "host")]
+        encode: encode_none,
+        #[cfg(feature = "device")]
+        decode: |_| Ok(Command::Id),
+    },
+    CommandDesc {
+        kind: Opcode::Reset,
+        #[cfg(feature = "host")]
+        encode: encode_none,
+        #[cfg(feature = "device")]
+        decode: |_| Ok(Command::Reset),
+    },
+    CommandDesc {
+        kind: Opcode::ErasePage,
+        #[cfg(feature = "host")]
+        encode: encode_address,
+        #[cfg(feature = "device")]
+        decode: |args| {
+            Ok(Command::ErasePage {
+                address: read_u32(args, 0)?,
+            })
+        },
+    },
+    CommandDesc {
+        kind: Opcode::WritePage,
+        #[cfg(feature = "host")]
+        encode: encode_page,
+        #[cfg(feature = "device")]
+        decode: |args| {
+            Ok(Command::WritePage {
+                address: read_u32(args, 0)?,
+                data: read_slice(args, 4, INT_PAGE_SIZE)?,
+            })
+        },
+    },
+    #[cfg(feature = "ext-flash")]
+    CommandDesc {
+        kind: Opcode::EraseExBlock,
+        #[cfg(feature = "host")]
+        encode: encode_address,
+        #[cfg(feature = "device")]
+        decode: |args| {
+            Ok(Command::EraseExBlock {
+                address: read_u32(args, 0)?,
+            })
+        },
+    },
+    #[cfg(feature = "ext-flash")]
+    CommandDesc {
+        kind: Opcode::WriteExPage,
+        #[cfg(feature = "host")]
+        encode: encode_page,
+        #[cfg(feature = "device")]
+        decode: |args| {
+            Ok(Command::WriteExPage {
+                address: read_u32(args, 0)?,
+                data: read_slice(args, 4, EXT_PAGE_SIZE)?,
+            })
+        },
+    },
+    CommandDesc {
+        kind: Opcode::CrcRxBuffer,
+        #[cfg(feature = "host")]
+        encode: encode_none,
+        #[cfg(feature = "device")]
+        decode: |_| Ok(Command::CrcRxBuffer),
+    },
+    CommandDesc {
+        kind: Opcode::ReadRange,
+        #[cfg(feature = "host")]
+        encode: encode_range,
+        #[cfg(feature = "device")]
+        decode: |args| {
+            Ok(Command::ReadRange {
+                address: read_u32(args, 0)?,
+                length: read_u16(args, 4)?,
+            })
+        },
+    },
+    #[cfg(feature = "ext-flash")]
+    CommandDesc {
+        kind: Opcode::ExReadRange,
+        #[cfg(feature = "host")]
+        encode: encode_range,
+        #[cfg(feature = "device")]
+        decode: |args| {
+            Ok(Command::ExReadRange {
+                address: read_u32(args, 0)?,
+                length: read_u16(args, 4)?,
+            })
+        },
+    },
+    #[cfg(feature = "attributes")]
+    CommandDesc {
+        kind: Opcode::SetAttr,
+        #[cfg(feature = "host")]
+        encode: encode_set_attr,
+        #[cfg(feature = "device")]
+        decode: |args| {
+            let length = read_u8(args, KEY_LEN + 1)? as usize;
+            check_len(CMD_SATTR, args, KEY_LEN + 2 + length)?;
+            Ok(Command::SetAttr {
+                index: read_u8(args, 0)?,
+                key: read_slice(args, 1, KEY_LEN)?,
+                value: read_slice(args, KEY_LEN + 2, length)?,
+            })
+        },
+    },
+    #[cfg(feature = "attributes")]
+    CommandDesc {
+        kind: Opcode::GetAttr,
+        #[cfg(feature = "host")]
+        encode: encode_index,
+        #[cfg(feature = "device")]
+        decode: |args| {
+            Ok(Command::GetAttr {
+                index: read_u8(args, 0)?,
+            })
+        },
+    },
+    CommandDesc {
+        kind: Opcode::CrcIntFlash,
+        #[cfg(feature = "host")]
+        encode: encode_words,
+        #[cfg(feature = "device")]
+        decode: |args| {
+            Ok(Command::CrcIntFlash {
+                address: read_u32(args, 0)?,
+                length: read_u32(args, 4)?,
+            })
+        },
+    },
+    #[cfg(feature = "ext-flash")]
+    CommandDesc {
+        kind: Opcode::CrcExtFlash,
+        #[cfg(feature = "host")]
+        encode: encode_words,
+        #[cfg(feature = "device")]
+        decode: |args| {
+            Ok(Command::CrcExtFlash {
+                address: read_u32(args, 0)?,
+                length: read_u32(args, 4)?,
+            })
+        },
+    },
+    #[cfg(feature = "ext-flash")]
+    CommandDesc {
+        kind: Opcode::EraseExPage,
+        #[cfg(feature = "host")]
+        encode: encode_address,
+        #[cfg(feature = "device")]
+        decode: |args| {
+            Ok(Command::EraseExPage {
+                address: read_u32(args, 0)?,
+            })
+        },
+    },
+    #[cfg(feature = "ext-flash")]
+    CommandDesc {
+        kind: Opcode::ExtFlashInit,
+        #[cfg(feature = "host")]
+        encode: encode_none,
+        #[cfg(feature = "device")]
+        decode: |_| Ok(Command::ExtFlashInit),
+    },
+    CommandDesc {
+        kind: Opcode::ClockOut,
+        #[cfg(feature = "host")]
+        encode: encode_none,
+        #[cfg(feature = "device")]
+        decode: |_| Ok(Command::ClockOut),
+    },
+    #[cfg(feature = "user-pages")]
+    CommandDesc {
+        kind: Opcode::WriteFlashUserPages,
+        #[cfg(feature = "host")]
+        encode: encode_words,
+        #[cfg(feature = "device")]
+        decode: |args| {
+            Ok(Command::WriteFlashUserPages {
+                page1: read_u32(args, 0)?,
+                page2: read_u32(args, 4)?,
+            })
+        },
+    },
+    #[cfg(feature = "baud-change")]
+    CommandDesc {
+        kind: Opcode::ChangeBaud,
+        #[cfg(feature = "host")]
+        encode: encode_change_baud,
+        #[cfg(feature = "device")]
+        decode: |args| {
+            let mode = match read_u8(args, 0)? {
+                0x01 => BaudMode::Set,
+                0x02 => BaudMode::Verify,
+                got => {
+                    return Err(Error::InvalidValue {
+                        field: Field::BaudMode,
+                        got: u32::from(got),
+                    })
+                }
+            };
+            Ok(Command::ChangeBaud {
+                mode,
+                baud: read_u32(args, 1)?,
+            })
+        },
+    },
+    #[cfg(feature = "multi-drop")]
+    CommandDesc {
+        kind: Opcode::SetAddress,
+        #[cfg(feature = "host")]
+        encode: encode_index,
+        #[cfg(feature = "device")]
+        decode: |args| {
+            Ok(Command::SetAddress {
+                address: read_u8(args, 0)?,
+            })
+        },
+    },
+    #[cfg(feature = "long-attributes")]
+    CommandDesc {
+        kind: Opcode::SetLongAttr,
+        #[cfg(feature = "host")]
+        encode: encode_set_attr,
+        #[cfg(feature = "device")]
+        decode: |args| {
+            let length = read_u16(args, KEY_LEN + 1)? as usize;
+            check_len(CMD_SLATTR, args, KEY_LEN + 3 + length)?;
+            Ok(Command::SetLongAttr {
+                index: read_u8(args, 0)?,
+                key: read_slice(args, 1, KEY_LEN)?,
+                value: read_slice(args, KEY_LEN + 3, length)?,
+            })
+        },
+    },
+    #[cfg(feature = "long-attributes")]
+    CommandDesc {
+        kind: Opcode::GetLongAttr,
+        #[cfg(feature = "host")]
+        encode: encode_index,
+        #[cfg(feature = "device")]
+        decode: |args| {
+            Ok(Command::GetLongAttr {
+                index: read_u8(args, 0)?,
+            })
+        },
+    },
+    CommandDesc {
+        kind: Opcode::Exit,
+        #[cfg(feature = "host")]
+        encode: encode_none,
+        #[cfg(feature = "device")]
+        decode: |_| Ok(Command::Exit),
+    },
+];
+
+/// The longest run of arguments before a command's data, which is
+/// `SetAttr`'s index, key and length.
+#[cfg(all(feature = "host", not(feature = "long-attributes")))]
+const MAX_HEAD_LEN: usize = 10;
+
+/// The longest run of arguments before a command's data, which is
+/// `SetLongAttr`'s index, key and two byte length.
+#[cfg(all(feature = "host", feature = "long-attributes"))]
+const MAX_HEAD_LEN: usize = 11;
 
 const CMD_PING: u8 = 0x01;
 const CMD_INFO: u8 = 0x03;
@@ -228,6 +886,10 @@ const CMD_XFINIT: u8 = 0x18;
 const CMD_CLKOUT: u8 = 0x19;
 const CMD_WUSER: u8 = 0x20;
 const CMD_CHANGE_BAUD: u8 = 0x21;
+const CMD_SET_ADDRESS: u8 = 0x22;
+const CMD_SLATTR: u8 = 0x23;
+const CMD_GLATTR: u8 = 0x24;
+const CMD_EXIT: u8 = 0x25;
 
 const RES_OVERFLOW: u8 = 0x10;
 const RES_PONG: u8 = 0x11;
@@ -246,13 +908,11 @@ const RES_CRCIF: u8 = 0x23;
 const RES_CRCXF: u8 = 0x24;
 const RES_INFO: u8 = 0x25;
 const RES_CHANGE_BAUD_FAIL: u8 = 0x26;
+const RES_ID: u8 = 0x27;
+const RES_GLATTR: u8 = 0x28;
 
-const MAX_INDEX: u8 = 16;
-const KEY_LEN: usize = 8;
-const MAX_ATTR_LEN: usize = 55;
-const INT_PAGE_SIZE: usize = 512;
-const EXT_PAGE_SIZE: usize = 256;
-const MAX_INFO_LEN: usize = 192;
+#[cfg(feature = "device")]
+const RESPONSE_PAD_BYTE: u8 = 0x00;
 
 // ****************************************************************************
 //
@@ -260,324 +920,1221 @@ const MAX_INFO_LEN: usize = 192;
 //
 // ****************************************************************************
 
-impl CommandDecoder {
-    /// Create a new `CommandDecoder`.
-    ///
-    /// The decoder is fed bytes with the `receive` method.
-    pub fn new() -> CommandDecoder {
-        CommandDecoder {
-            state: DecoderState::Loading,
-            buffer: [0u8; 520],
-            count: 0,
+#[cfg(feature = "attributes")]
+pub mod ab_update;
+pub mod address;
+#[cfg(feature = "std")]
+pub mod analyze;
+pub mod attr_key;
+pub mod attributes;
+#[cfg(feature = "host")]
+pub mod batch;
+#[cfg(feature = "device")]
+pub mod bootloader;
+pub mod capture;
+#[cfg(feature = "device")]
+pub mod cdc;
+#[cfg(feature = "host")]
+pub mod clock;
+#[cfg(feature = "tokio-util")]
+pub mod codec;
+pub mod consts;
+pub mod crc;
+mod debug;
+#[cfg(feature = "device")]
+pub mod device;
+#[cfg(feature = "elf")]
+mod elf;
+#[cfg(feature = "defmt")]
+mod format;
+#[cfg(feature = "arbitrary")]
+mod fuzz;
+#[cfg(feature = "embedded-hal")]
+pub mod hal;
+#[cfg(feature = "std")]
+pub mod host;
+#[cfg(feature = "alloc")]
+pub mod image;
+#[cfg(feature = "host")]
+pub mod info;
+#[cfg(any(feature = "embedded-io", feature = "embedded-io-async"))]
+pub mod io;
+#[cfg(feature = "attributes")]
+pub mod known_attrs;
+#[cfg(all(any(test, feature = "mock"), feature = "device"))]
+pub mod mock;
+#[cfg(feature = "multi-drop")]
+pub mod multidrop;
+pub mod observer;
+#[cfg(feature = "heapless")]
+pub mod owned;
+pub mod pages;
+#[cfg(feature = "device")]
+pub mod policy;
+#[cfg(feature = "host")]
+pub mod retry;
+#[cfg(feature = "device")]
+pub mod ring;
+#[cfg(all(feature = "host", feature = "device"))]
+pub mod roundtrip;
+#[cfg(feature = "sequence")]
+pub mod sequence;
+#[cfg(feature = "host")]
+pub mod session;
+#[cfg(feature = "critical-section")]
+pub mod shared;
+#[cfg(feature = "attributes")]
+pub mod signature;
+pub mod tbf;
+#[cfg(feature = "std")]
+pub mod tcp;
+pub mod test_vectors;
+#[cfg(all(test, feature = "host", feature = "device"))]
+mod traces;
+#[cfg(feature = "host")]
+pub mod transaction;
+pub mod transport;
+#[cfg(feature = "ufmt")]
+mod uformat;
+#[cfg(feature = "host")]
+pub mod vectored;
+pub mod version;
+pub mod workflow;
+
+#[cfg(feature = "attributes")]
+pub use ab_update::{AbUpdate, PendingSwap, Slot, SlotLayout};
+pub use address::{ExtFlashAddr, ExtFlashGeometry, IntFlashAddr};
+#[cfg(feature = "std")]
+pub use analyze::analyze;
+pub use attr_key::AttrKey;
+pub use attributes::AttributeStore;
+#[cfg(feature = "host")]
+pub use batch::BatchEncoder;
+#[cfg(feature = "device")]
+pub use bootloader::{Attributes, Bootloader, Flash, NoAttributes};
+pub use capture::{Capture, Direction};
+#[cfg(all(feature = "host", feature = "device"))]
+pub use capture::Replay;
+#[cfg(feature = "device")]
+pub use cdc::{CdcBootloader, Packetizer};
+#[cfg(feature = "host")]
+pub use clock::{Clock, NoClock, TimedOut, TimeoutKind, Timeouts};
+#[cfg(feature = "std")]
+pub use clock::StdClock;
+#[cfg(feature = "tokio-util")]
+pub use codec::{BootloaderCodec, TockloaderCodec};
+pub use crc::{crc32, Crc32};
+#[cfg(feature = "device")]
+pub use device::{BootloaderSession, FlashError, FlashInterface, ReplyFormat};
+#[cfg(all(feature = "device", feature = "baud-change"))]
+pub use device::{BaudAction, BaudGuard};
+#[cfg(feature = "embedded-hal")]
+pub use hal::SerialTransport;
+#[cfg(feature = "std")]
+pub use host::{CancelToken, Host, HostError, Progress, VerifyReport};
+#[cfg(all(feature = "std", feature = "attributes"))]
+pub use host::HostAttribute;
+#[cfg(feature = "alloc")]
+pub use image::{FlashRegion, Image, ImageCommands, ImageError, PagedImage, Segment};
+#[cfg(feature = "host")]
+pub use info::BootloaderInfo;
+#[cfg(feature = "embedded-io")]
+pub use io::IoTransport;
+#[cfg(all(feature = "embedded-io-async", feature = "host"))]
+pub use io::send_command;
+#[cfg(all(feature = "embedded-io-async", feature = "device"))]
+pub use io::serve;
+#[cfg(feature = "attributes")]
+pub use known_attrs::KnownAttr;
+#[cfg(all(any(test, feature = "mock"), feature = "device"))]
+pub use mock::{Loopback, MemFlash};
+#[cfg(feature = "multi-drop")]
+pub use multidrop::{AddressFilter, Filtered};
+pub use observer::Observer;
+#[cfg(feature = "heapless")]
+pub use owned::{OwnedCommand, OwnedResponse};
+pub use pages::{FlashTarget, PadPolicy, PageWriter};
+#[cfg(feature = "device")]
+pub use policy::AccessPolicy;
+#[cfg(feature = "host")]
+pub use retry::RetryingSession;
+#[cfg(feature = "device")]
+pub use ring::RxRing;
+#[cfg(all(feature = "host", feature = "device"))]
+pub use roundtrip::{roundtrip_check, roundtrip_check_response};
+#[cfg(feature = "test-utils")]
+pub use roundtrip::{assert_command_roundtrip, assert_response_roundtrip};
+#[cfg(feature = "host")]
+pub use session::{matches, HostSession};
+#[cfg(feature = "critical-section")]
+pub use shared::SharedDecoder;
+#[cfg(feature = "attributes")]
+pub use signature::signature_command;
+#[cfg(all(feature = "device", feature = "attributes"))]
+pub use signature::{SignedImage, Verifier};
+pub use tbf::TbfHeader;
+#[cfg(feature = "std")]
+pub use tcp::TcpTransport;
+#[cfg(feature = "host")]
+pub use transaction::{Transaction, TransactionSession};
+#[cfg(feature = "host")]
+pub use transport::run_host_command;
+#[cfg(feature = "device")]
+pub use transport::serve_bootloader;
+pub use transport::{RunError, Transport};
+#[cfg(feature = "host")]
+pub use vectored::VectoredEncoder;
+pub use version::{ProtocolVersion, SemVer};
+pub use workflow::{
+    install_app, InstallApp, Mismatch, PageDiff, ReadPaginator, StagedWrite, SyncStep, SyncUp,
+    VerifyImage,
+};
+#[cfg(feature = "baud-change")]
+pub use workflow::{BaudChange, BaudStep};
+#[cfg(feature = "attributes")]
+pub use workflow::FindAttr;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::WrongLength {
+                opcode,
+                expected,
+                got,
+            } => write!(
+                f,
+                "frame {:#04x} should have {} bytes of arguments, not {}",
+                opcode, expected, got
+            ),
+            Error::InvalidValue { field, got } => write!(f, "{} can't be {}", field.name(), got),
+            e => f.write_str(e.message()),
         }
     }
+}
 
-    /// Empty the RX buffer.
-    pub fn reset(&mut self) {
-        self.count = 0;
+#[cfg(feature = "core-error")]
+impl core::error::Error for Error {}
+
+impl<'a> Command<'a> {
+    /// The opcode this command is sent with.
+    pub fn opcode(&self) -> u8 {
+        self.kind() as u8
     }
 
-    /// Process incoming bytes.
-    ///
-    /// The decoder is fed bytes with the `receive` method. If not enough
-    /// bytes have been seen, this function returns `None`. Once enough bytes
-    /// have been seen, it returns `Ok(Some(Command))` containing the decoded
-    /// Command. It returns `Err` if it doesn't like the byte received.
-    pub fn receive(&mut self, ch: u8) -> Result<Option<Command>, Error> {
-        match self.state {
-            DecoderState::Loading => self.handle_loading(ch),
-            DecoderState::Escape => self.handle_escape(ch),
+    /// Which command this is, without its arguments.
+    pub fn kind(&self) -> Opcode {
+        match *self {
+            Command::Ping => Opcode::Ping,
+            Command::Info => Opcode::Info,
+            Command::Id => Opcode::Id,
+            Command::Reset => Opcode::Reset,
+            Command::ErasePage { .. } => Opcode::ErasePage,
+            Command::WritePage { .. } => Opcode::WritePage,
+            #[cfg(feature = "ext-flash")]
+            Command::EraseExBlock { .. } => Opcode::EraseExBlock,
+            #[cfg(feature = "ext-flash")]
+            Command::WriteExPage { .. } => Opcode::WriteExPage,
+            Command::CrcRxBuffer => Opcode::CrcRxBuffer,
+            Command::ReadRange { .. } => Opcode::ReadRange,
+            #[cfg(feature = "ext-flash")]
+            Command::ExReadRange { .. } => Opcode::ExReadRange,
+            #[cfg(feature = "attributes")]
+            Command::SetAttr { .. } => Opcode::SetAttr,
+            #[cfg(feature = "attributes")]
+            Command::GetAttr { .. } => Opcode::GetAttr,
+            Command::CrcIntFlash { .. } => Opcode::CrcIntFlash,
+            #[cfg(feature = "ext-flash")]
+            Command::CrcExtFlash { .. } => Opcode::CrcExtFlash,
+            #[cfg(feature = "ext-flash")]
+            Command::EraseExPage { .. } => Opcode::EraseExPage,
+            #[cfg(feature = "ext-flash")]
+            Command::ExtFlashInit => Opcode::ExtFlashInit,
+            Command::ClockOut => Opcode::ClockOut,
+            #[cfg(feature = "user-pages")]
+            Command::WriteFlashUserPages { .. } => Opcode::WriteFlashUserPages,
+            #[cfg(feature = "baud-change")]
+            Command::ChangeBaud { .. } => Opcode::ChangeBaud,
+            #[cfg(feature = "multi-drop")]
+            Command::SetAddress { .. } => Opcode::SetAddress,
+            #[cfg(feature = "long-attributes")]
+            Command::SetLongAttr { .. } => Opcode::SetLongAttr,
+            #[cfg(feature = "long-attributes")]
+            Command::GetLongAttr { .. } => Opcode::GetLongAttr,
+            Command::Exit => Opcode::Exit,
         }
     }
 
-    fn load_char(&mut self, ch: u8) {
-        if self.count < self.buffer.len() {
-            self.buffer[self.count] = ch;
-            self.count = self.count + 1;
+    /// Build an `ErasePage`, checking `address` is the start of a page.
+    pub fn erase_page(address: u32) -> Result<Command<'a>, Error> {
+        Ok(IntFlashAddr::new(address)?.erase())
+    }
+
+    /// Build a `WritePage`, checking `address` is the start of a page and
+    /// `data` is exactly one page long.
+    pub fn write_page(address: u32, data: &'a [u8]) -> Result<Command<'a>, Error> {
+        IntFlashAddr::new(address)?;
+        expect_len(data, INT_PAGE_SIZE)?;
+        Ok(Command::WritePage { address, data })
+    }
+
+    /// Build a `WriteExPage`, checking `address` is the start of an external
+    /// page and `data` is exactly one external page long.
+    #[cfg(feature = "ext-flash")]
+    pub fn write_ex_page(address: u32, data: &'a [u8]) -> Result<Command<'a>, Error> {
+        ExtFlashAddr::new(address)?;
+        expect_len(data, EXT_PAGE_SIZE)?;
+        Ok(Command::WriteExPage { address, data })
+    }
+
+    /// Build a `SetAttr`, checking `index` is in range and `value` is no
+    /// more than `MAX_ATTR_LEN` bytes. The key is sent with its padding.
+    #[cfg(feature = "attributes")]
+    pub fn set_attr(index: u8, key: &'a AttrKey, value: &'a [u8]) -> Result<Command<'a>, Error> {
+        if index >= MAX_INDEX || value.len() > MAX_ATTR_LEN {
+            return Err(Error::BadArguments);
         }
+        Ok(Command::SetAttr {
+            index,
+            key: key.as_padded(),
+            value,
+        })
     }
 
-    fn handle_loading(&mut self, ch: u8) -> Result<Option<Command>, Error> {
-        if ch == ESCAPE_CHAR {
-            self.state = DecoderState::Escape;
-        } else {
-            self.load_char(ch);
+    /// Build a `ChangeBaud`. Only a `Custom` rate of zero is rejected.
+    #[cfg(feature = "baud-change")]
+    pub fn change_baud(mode: BaudMode, baud: Baud) -> Result<Command<'a>, Error> {
+        match u32::from(baud) {
+            0 => Err(Error::BadArguments),
+            baud => Ok(Command::ChangeBaud { mode, baud }),
         }
-        Ok(None)
     }
 
-    fn handle_escape(&mut self, ch: u8) -> Result<Option<Command>, Error> {
-        self.state = DecoderState::Loading;
-        let result: Result<Option<Command>, Error> = match ch {
-            ESCAPE_CHAR => {
-                // Double escape means just load an escape
-                self.load_char(ch);
-                Ok(None)
-            }
-            CMD_PING => Ok(Some(Command::Ping)),
-            CMD_INFO => Ok(Some(Command::Info)),
-            CMD_ID => Ok(Some(Command::Id)),
-            CMD_RESET => Ok(Some(Command::Reset)),
-            CMD_EPAGE => {
-                let num_expected_bytes: usize = 4;
-                if self.count == num_expected_bytes {
-                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
-                    Ok(Some(Command::ErasePage { address }))
-                } else {
-                    Err(Error::BadArguments)
-                }
-            }
-            CMD_WPAGE => {
-                let num_expected_bytes: usize = INT_PAGE_SIZE + 4;
-                if self.count == num_expected_bytes {
-                    let payload = &self.buffer[0..num_expected_bytes];
-                    let address = LittleEndian::read_u32(&payload[0..4]);
-                    Ok(Some(Command::WritePage {
-                        address,
-                        data: &payload[4..num_expected_bytes],
-                    }))
-                } else {
-                    Err(Error::BadArguments)
-                }
-            }
-            CMD_XEBLOCK => {
-                let num_expected_bytes: usize = 4;
-                if self.count == num_expected_bytes {
-                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
-                    Ok(Some(Command::EraseExBlock { address }))
-                } else {
-                    Err(Error::BadArguments)
-                }
-            }
-            CMD_XWPAGE => {
-                let num_expected_bytes: usize = EXT_PAGE_SIZE + 4;
-                if self.count == num_expected_bytes {
-                    let payload = &self.buffer[0..num_expected_bytes];
-                    let address = LittleEndian::read_u32(&payload[0..4]);
-                    Ok(Some(Command::WriteExPage {
-                        address,
-                        data: &payload[4..num_expected_bytes],
-                    }))
-                } else {
-                    Err(Error::BadArguments)
-                }
-            }
-            CMD_CRCRX => Ok(Some(Command::CrcRxBuffer)),
-            CMD_RRANGE => {
-                let num_expected_bytes: usize = 6;
-                if self.count == num_expected_bytes {
-                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
-                    let length = LittleEndian::read_u16(&self.buffer[4..6]);
-                    Ok(Some(Command::ReadRange { address, length }))
-                } else {
-                    Err(Error::BadArguments)
-                }
-            }
-            CMD_XRRANGE => {
-                let num_expected_bytes: usize = 6;
-                if self.count == num_expected_bytes {
-                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
-                    let length = LittleEndian::read_u16(&self.buffer[4..6]);
-                    Ok(Some(Command::ExReadRange { address, length }))
-                } else {
-                    Err(Error::BadArguments)
-                }
-            }
-            CMD_SATTR => {
-                let num_expected_bytes: usize = 10;
-                if self.count >= num_expected_bytes {
-                    let index = self.buffer[0];
-                    let key = &self.buffer[1..9];
-                    let length = self.buffer[9] as usize;
-                    if self.count > (num_expected_bytes + length) {
-                        let value = &self.buffer[10..10 + length];
-                        Ok(Some(Command::SetAttr { index, key, value }))
-                    } else {
-                        Err(Error::BadArguments)
-                    }
-                } else {
-                    Err(Error::BadArguments)
-                }
-            }
-            CMD_GATTR => {
-                let num_expected_bytes: usize = 1;
-                if self.count == num_expected_bytes {
-                    let index = self.buffer[0];
-                    Ok(Some(Command::GetAttr { index }))
-                } else {
-                    Err(Error::BadArguments)
-                }
-            }
-            CMD_CRCIF => {
-                let num_expected_bytes: usize = 8;
-                if self.count == num_expected_bytes {
-                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
-                    let length = LittleEndian::read_u32(&self.buffer[4..8]);
-                    Ok(Some(Command::CrcIntFlash { address, length }))
-                } else {
-                    Err(Error::BadArguments)
-                }
-            }
-            CMD_CRCEF => {
-                let num_expected_bytes: usize = 8;
-                if self.count == num_expected_bytes {
-                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
-                    let length = LittleEndian::read_u32(&self.buffer[4..8]);
-                    Ok(Some(Command::CrcExtFlash { address, length }))
-                } else {
-                    Err(Error::BadArguments)
-                }
-            }
-            CMD_XEPAGE => {
-                let num_expected_bytes: usize = 4;
-                if self.count == num_expected_bytes {
-                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
-                    Ok(Some(Command::EraseExPage { address }))
-                } else {
-                    Err(Error::BadArguments)
-                }
-            }
-            CMD_XFINIT => Ok(Some(Command::ExtFlashInit)),
-            CMD_CLKOUT => Ok(Some(Command::ClockOut)),
-            CMD_WUSER => {
-                let num_expected_bytes: usize = 8;
-                if self.count == num_expected_bytes {
-                    let page1 = LittleEndian::read_u32(&self.buffer[0..4]);
-                    let page2 = LittleEndian::read_u32(&self.buffer[4..8]);
-                    Ok(Some(Command::WriteFlashUserPages { page1, page2 }))
-                } else {
-                    Err(Error::BadArguments)
-                }
+    /// Build a `GetAttr`, checking `index` is in range.
+    #[cfg(feature = "attributes")]
+    pub fn get_attr(index: u8) -> Result<Command<'a>, Error> {
+        if index >= MAX_INDEX {
+            return Err(Error::BadArguments);
+        }
+        Ok(Command::GetAttr { index })
+    }
+}
+
+impl<'a> Response<'a> {
+    /// The code this response is sent with.
+    pub fn code(&self) -> u8 {
+        self.kind() as u8
+    }
+
+    /// Which response this is, without its payload.
+    pub fn kind(&self) -> ResponseCode {
+        match *self {
+            Response::Overflow => ResponseCode::Overflow,
+            Response::Pong => ResponseCode::Pong,
+            Response::BadAddress => ResponseCode::BadAddress,
+            Response::InternalError => ResponseCode::InternalError,
+            Response::BadArguments => ResponseCode::BadArguments,
+            Response::Ok => ResponseCode::Ok,
+            Response::Unknown => ResponseCode::Unknown,
+            #[cfg(feature = "ext-flash")]
+            Response::ExtFlashTimeout => ResponseCode::ExtFlashTimeout,
+            #[cfg(feature = "ext-flash")]
+            Response::ExtFlashPageError => ResponseCode::ExtFlashPageError,
+            Response::CrcRxBuffer { .. } => ResponseCode::CrcRxBuffer,
+            Response::ReadRange { .. } => ResponseCode::ReadRange,
+            #[cfg(feature = "ext-flash")]
+            Response::ExReadRange { .. } => ResponseCode::ExReadRange,
+            #[cfg(feature = "attributes")]
+            Response::GetAttr { .. } => ResponseCode::GetAttr,
+            Response::CrcIntFlash { .. } => ResponseCode::CrcIntFlash,
+            #[cfg(feature = "ext-flash")]
+            Response::CrcExtFlash { .. } => ResponseCode::CrcExtFlash,
+            Response::Info { .. } => ResponseCode::Info,
+            #[cfg(feature = "baud-change")]
+            Response::ChangeBaudFail => ResponseCode::ChangeBaudFail,
+            Response::Id { .. } => ResponseCode::Id,
+            #[cfg(feature = "long-attributes")]
+            Response::GetLongAttr { .. } => ResponseCode::GetLongAttr,
+        }
+    }
+}
+
+impl<'a> Response<'a> {
+    /// The key in a `GetAttr` or `GetLongAttr` reply, or `None` for any
+    /// other response.
+    pub fn attr_key(&self) -> Option<AttrKey> {
+        match *self {
+            #[cfg(feature = "attributes")]
+            Response::GetAttr { key, .. } => AttrKey::new(key).ok(),
+            #[cfg(feature = "long-attributes")]
+            Response::GetLongAttr { key, .. } => AttrKey::new(key).ok(),
+            _ => None,
+        }
+    }
+
+    /// Whether this response reports that the command failed.
+    pub fn is_error(&self) -> bool {
+        match *self {
+            Response::Overflow
+            | Response::BadAddress
+            | Response::InternalError
+            | Response::BadArguments
+            | Response::Unknown => true,
+            #[cfg(feature = "ext-flash")]
+            Response::ExtFlashTimeout | Response::ExtFlashPageError => true,
+            #[cfg(feature = "baud-change")]
+            Response::ChangeBaudFail => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this response is final, in that sending the same command
+    /// again would get the same answer. `Overflow` and `InternalError` are
+    /// the only ones that aren't, and are worth a retry.
+    pub fn is_terminal(&self) -> bool {
+        !matches!(*self, Response::Overflow | Response::InternalError)
+    }
+}
+
+/// The response a bootloader should send when decoding a command fails.
+impl From<Error> for Response<'static> {
+    fn from(error: Error) -> Response<'static> {
+        match error {
+            Error::UnknownCommand => Response::Unknown,
+            Error::BadArguments | Error::WrongLength { .. } | Error::InvalidValue { .. } => {
+                Response::BadArguments
             }
-            CMD_CHANGE_BAUD => {
-                let num_expected_bytes: usize = 5;
-                if self.count == num_expected_bytes {
-                    let mode = self.buffer[0];
-                    let baud = LittleEndian::read_u32(&self.buffer[1..5]);
-                    match mode {
-                        0x01 => Ok(Some(Command::ChangeBaud {
-                            mode: BaudMode::Set,
-                            baud,
-                        })),
-                        0x02 => Ok(Some(Command::ChangeBaud {
-                            mode: BaudMode::Verify,
-                            baud,
-                        })),
-                        _ => Err(Error::BadArguments),
+            Error::BufferFull | Error::Overflow => Response::Overflow,
+            _ => Response::InternalError,
+        }
+    }
+}
 
-                    }
-                } else {
-                    Err(Error::BadArguments)
-                }
+impl Baud {
+    /// Whether this is one of the named rates.
+    pub fn is_standard(self) -> bool {
+        !matches!(Baud::from(u32::from(self)), Baud::Custom(_))
+    }
+}
+
+/// Named rates come back as their variant, not as `Custom`.
+impl From<u32> for Baud {
+    fn from(baud: u32) -> Baud {
+        match baud {
+            115_200 => Baud::B115200,
+            230_400 => Baud::B230400,
+            460_800 => Baud::B460800,
+            500_000 => Baud::B500000,
+            921_600 => Baud::B921600,
+            1_000_000 => Baud::B1000000,
+            baud => Baud::Custom(baud),
+        }
+    }
+}
+
+impl From<Baud> for u32 {
+    fn from(baud: Baud) -> u32 {
+        match baud {
+            Baud::B115200 => 115_200,
+            Baud::B230400 => 230_400,
+            Baud::B460800 => 460_800,
+            Baud::B500000 => 500_000,
+            Baud::B921600 => 921_600,
+            Baud::B1000000 => 1_000_000,
+            Baud::Custom(baud) => baud,
+        }
+    }
+}
+
+impl Opcode {
+    /// How many bytes of arguments this command takes.
+    pub const fn arg_len(self) -> ArgLen {
+        match self {
+            Opcode::Ping
+            | Opcode::Info
+            | Opcode::Id
+            | Opcode::Reset
+            | Opcode::CrcRxBuffer
+            | Opcode::ExtFlashInit
+            | Opcode::ClockOut
+            | Opcode::Exit => ArgLen::None,
+            Opcode::ErasePage | Opcode::EraseExBlock | Opcode::EraseExPage => ArgLen::Fixed(4),
+            Opcode::WritePage => ArgLen::Fixed(4 + INT_PAGE_SIZE),
+            Opcode::WriteExPage => ArgLen::Fixed(4 + EXT_PAGE_SIZE),
+            Opcode::ReadRange | Opcode::ExReadRange => ArgLen::Fixed(6),
+            Opcode::SetAttr => ArgLen::Variable {
+                min: 2 + KEY_LEN,
+                max: 2 + KEY_LEN + MAX_ATTR_LEN,
+            },
+            Opcode::SetLongAttr => ArgLen::Variable {
+                min: 3 + KEY_LEN,
+                max: 3 + KEY_LEN + MAX_LONG_ATTR_LEN,
+            },
+            Opcode::GetAttr | Opcode::SetAddress | Opcode::GetLongAttr => ArgLen::Fixed(1),
+            Opcode::CrcIntFlash | Opcode::CrcExtFlash | Opcode::WriteFlashUserPages => {
+                ArgLen::Fixed(8)
             }
-            _ => Ok(None),
-        };
-        // A command or error signifies the end of the buffer
-        if let Ok(Some(_)) = result {
-            self.count = 0;
-        } else if let Err(_) = result {
-            self.count = 0;
+            Opcode::ChangeBaud => ArgLen::Fixed(5),
         }
-        result
     }
 }
 
-impl ResponseDecoder {
-    /// Create a new `ResponseDecoder`.
+impl ArgLen {
+    /// Whether `len` bytes of arguments could be right.
+    pub const fn accepts(self, len: usize) -> bool {
+        match self {
+            ArgLen::None => len == 0,
+            ArgLen::Fixed(n) => len == n,
+            ArgLen::Variable { min, max } => len >= min && len <= max,
+        }
+    }
+
+    /// The most bytes of arguments there could be.
+    pub const fn max(self) -> usize {
+        match self {
+            ArgLen::None => 0,
+            ArgLen::Fixed(n) => n,
+            ArgLen::Variable { max, .. } => max,
+        }
+    }
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = Error;
+
+    /// Returns `Error::UnknownCommand` if `value` isn't an opcode.
+    fn try_from(value: u8) -> Result<Opcode, Error> {
+        Ok(match value {
+            CMD_PING => Opcode::Ping,
+            CMD_INFO => Opcode::Info,
+            CMD_ID => Opcode::Id,
+            CMD_RESET => Opcode::Reset,
+            CMD_EPAGE => Opcode::ErasePage,
+            CMD_WPAGE => Opcode::WritePage,
+            CMD_XEBLOCK => Opcode::EraseExBlock,
+            CMD_XWPAGE => Opcode::WriteExPage,
+            CMD_CRCRX => Opcode::CrcRxBuffer,
+            CMD_RRANGE => Opcode::ReadRange,
+            CMD_XRRANGE => Opcode::ExReadRange,
+            CMD_SATTR => Opcode::SetAttr,
+            CMD_GATTR => Opcode::GetAttr,
+            CMD_CRCIF => Opcode::CrcIntFlash,
+            CMD_CRCEF => Opcode::CrcExtFlash,
+            CMD_XEPAGE => Opcode::EraseExPage,
+            CMD_XFINIT => Opcode::ExtFlashInit,
+            CMD_CLKOUT => Opcode::ClockOut,
+            CMD_WUSER => Opcode::WriteFlashUserPages,
+            CMD_CHANGE_BAUD => Opcode::ChangeBaud,
+            CMD_SET_ADDRESS => Opcode::SetAddress,
+            CMD_SLATTR => Opcode::SetLongAttr,
+            CMD_GLATTR => Opcode::GetLongAttr,
+            CMD_EXIT => Opcode::Exit,
+            _ => return Err(Error::UnknownCommand),
+        })
+    }
+}
+
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> u8 {
+        opcode as u8
+    }
+}
+
+impl TryFrom<u8> for ResponseCode {
+    type Error = Error;
+
+    /// Returns `Error::UnknownCommand` if `value` isn't a response code.
+    fn try_from(value: u8) -> Result<ResponseCode, Error> {
+        Ok(match value {
+            RES_OVERFLOW => ResponseCode::Overflow,
+            RES_PONG => ResponseCode::Pong,
+            RES_BADADDR => ResponseCode::BadAddress,
+            RES_INTERROR => ResponseCode::InternalError,
+            RES_BADARGS => ResponseCode::BadArguments,
+            RES_OK => ResponseCode::Ok,
+            RES_UNKNOWN => ResponseCode::Unknown,
+            RES_XFTIMEOUT => ResponseCode::ExtFlashTimeout,
+            RES_XFEPE => ResponseCode::ExtFlashPageError,
+            RES_CRCRX => ResponseCode::CrcRxBuffer,
+            RES_RRANGE => ResponseCode::ReadRange,
+            RES_XRRANGE => ResponseCode::ExReadRange,
+            RES_GATTR => ResponseCode::GetAttr,
+            RES_CRCIF => ResponseCode::CrcIntFlash,
+            RES_CRCXF => ResponseCode::CrcExtFlash,
+            RES_INFO => ResponseCode::Info,
+            RES_CHANGE_BAUD_FAIL => ResponseCode::ChangeBaudFail,
+            RES_ID => ResponseCode::Id,
+            RES_GLATTR => ResponseCode::GetLongAttr,
+            _ => return Err(Error::UnknownCommand),
+        })
+    }
+}
+
+impl From<ResponseCode> for u8 {
+    fn from(code: ResponseCode) -> u8 {
+        code as u8
+    }
+}
+
+#[cfg(feature = "device")]
+impl CommandDecoder {
+    /// Create a new `CommandDecoder`.
     ///
-    /// The decoder is fed bytes with the `receive` method.
-    pub fn new() -> ResponseDecoder {
-        ResponseDecoder {
+    /// The decoder is fed bytes with the `receive` method. This is a `const
+    /// fn`, so a decoder can be built straight into a `static`.
+    #[inline(always)]
+    pub const fn new() -> CommandDecoder {
+        CommandDecoder {
             state: DecoderState::Loading,
-            buffer: [0u8; 520],
+            buffer: [0u8; MAX_FRAME_LEN],
             count: 0,
-            needed: None,
+            observer: None,
+            rx_crc: None,
+            last_rx: None,
+            overflow: false,
+            #[cfg(feature = "attributes")]
+            attr_slots: MAX_INDEX,
+            #[cfg(feature = "attributes")]
+            attr_len: MAX_ATTR_LEN,
+            #[cfg(feature = "multi-drop")]
+            filter: None,
+            #[cfg(feature = "sequence")]
+            sequence_next: false,
+            #[cfg(feature = "sequence")]
+            sequence: None,
         }
     }
+}
 
-    /// Empty the RX buffer.
+#[cfg(feature = "device")]
+impl<B> CommandDecoder<B> {
+    /// Create a new `CommandDecoder` which uses `buffer`, rather than one
+    /// of its own. The buffer has to hold the longest command you expect,
+    /// not counting escapes, which for `WritePage` is `MAX_FRAME_LEN`. Any
+    /// more is dropped, and the command gets `Error::Overflow`.
+    #[inline(always)]
+    pub const fn with_buffer(buffer: B) -> CommandDecoder<B> {
+        CommandDecoder {
+            state: DecoderState::Loading,
+            buffer,
+            count: 0,
+            observer: None,
+            rx_crc: None,
+            last_rx: None,
+            overflow: false,
+            #[cfg(feature = "attributes")]
+            attr_slots: MAX_INDEX,
+            #[cfg(feature = "attributes")]
+            attr_len: MAX_ATTR_LEN,
+            #[cfg(feature = "multi-drop")]
+            filter: None,
+            #[cfg(feature = "sequence")]
+            sequence_next: false,
+            #[cfg(feature = "sequence")]
+            sequence: None,
+        }
+    }
+}
+
+#[cfg(feature = "device")]
+impl<B> CommandDecoder<B>
+where
+    B: AsMut<[u8]>,
+{
+    /// Empty the RX buffer, and forget any escape half way through.
     pub fn reset(&mut self) {
-        self.count = 0;
+        self.clear();
+        #[cfg(feature = "multi-drop")]
+        if let Some(filter) = self.filter.as_mut() {
+            filter.reset();
+        }
     }
 
     /// Process incoming bytes.
     ///
     /// The decoder is fed bytes with the `receive` method. If not enough
     /// bytes have been seen, this function returns `None`. Once enough bytes
-    /// have been seen, it returns `Some(Response)` containing the
-    /// decoded Response.
-    pub fn receive(&mut self, ch: u8) -> Result<Option<Response>, Error> {
-        match self.state {
-            DecoderState::Loading => self.handle_loading(ch),
-            DecoderState::Escape => self.handle_escape(ch),
+    /// have been seen, it returns `Ok(Some(Command))` containing the decoded
+    /// Command. It returns `Err` if it doesn't like the byte received.
+    pub fn receive(&mut self, ch: u8) -> Result<Option<Command<'_>>, Error> {
+        #[cfg(feature = "multi-drop")]
+        if let Some(filter) = self.filter.as_mut() {
+            let mut pass = [0u8; 2];
+            let len = match filter.receive(ch) {
+                Filtered::Drop => return Ok(None),
+                Filtered::Start => {
+                    self.clear();
+                    return Ok(None);
+                }
+                Filtered::Pass(bytes) => {
+                    pass[0..bytes.len()].copy_from_slice(bytes);
+                    bytes.len()
+                }
+            };
+            // Only ever an escape, so it can't finish a frame
+            if len == 2 {
+                let _ = self.receive_byte(pass[0]);
+            }
+            return self.receive_byte(pass[len - 1]);
         }
+        self.receive_byte(ch)
     }
 
-    /// Set the expected length of an unbounded message. This
-    /// depends entirely on the last command you sent.
-    pub fn set_payload_len(&mut self, length: usize) -> Result<(), Error> {
-        match self.needed {
-            Some(_) => Err(Error::SetLength),
-            None => {
-                self.needed = Some(length + 1);
-                Ok(())
-            }
+    /// Answer only to commands sent to `address`, as described in the
+    /// `multidrop` module, or to any unaddressed command if it's `None`.
+    /// Any command half way through is dropped.
+    #[cfg(feature = "multi-drop")]
+    pub fn set_address(&mut self, address: Option<u8>) {
+        self.filter = address.map(AddressFilter::commands);
+        self.clear();
+    }
+
+    /// The address given to `set_address`.
+    #[cfg(feature = "multi-drop")]
+    pub fn address(&self) -> Option<u8> {
+        self.filter.map(|f| f.address())
+    }
+
+    /// The sequence number from the header of the command being received,
+    /// as described in the `sequence` module, or `None` if it didn't have
+    /// one. This is forgotten once the command has been decoded, so look
+    /// before passing in the byte which finishes it.
+    #[cfg(feature = "sequence")]
+    pub fn sequence(&self) -> Option<u8> {
+        self.sequence
+    }
+
+    fn clear(&mut self) {
+        self.state = DecoderState::Loading;
+        self.count = 0;
+        self.overflow = false;
+        if let Some(crc) = self.rx_crc.as_mut() {
+            crc.reset();
+        }
+        #[cfg(feature = "sequence")]
+        {
+            self.sequence_next = false;
+            self.sequence = None;
         }
     }
 
-    fn load_char(&mut self, ch: u8) -> Result<Option<Response>, Error> {
-        if self.count < self.buffer.len() {
-            self.buffer[self.count] = ch;
-            self.count = self.count + 1;
+    /// Does `push_bytes` have to go one byte at a time?
+    fn bytewise(&self) -> bool {
+        #[cfg(feature = "multi-drop")]
+        if self.filter.is_some() {
+            return true;
         }
-        if self.needed == Some(self.count) {
-            let result = match self.buffer[0] {
-                RES_CRCRX => {
-                    let length = LittleEndian::read_u16(&self.buffer[1..3]);
-                    let crc = LittleEndian::read_u32(&self.buffer[3..7]);
-                    Ok(Some(Response::CrcRxBuffer { length, crc }))
-                }
-                RES_RRANGE => {
-                    let data = &self.buffer[1..self.count];
-                    Ok(Some(Response::ReadRange { data }))
-                }
-                RES_XRRANGE => {
-                    let data = &self.buffer[1..self.count];
-                    Ok(Some(Response::ExReadRange { data }))
-                }
-                RES_GATTR => {
-                    let key = &self.buffer[1..9];
-                    let length = self.buffer[9] as usize;
-                    if (9 + length) <= self.count {
-                        let value = &self.buffer[10..(10 + length)];
-                        Ok(Some(Response::GetAttr { key, value }))
-                    } else {
-                        Err(Error::BadArguments)
-                    }
-                }
-                RES_CRCIF => {
-                    let crc = LittleEndian::read_u32(&self.buffer[1..5]);
-                    Ok(Some(Response::CrcIntFlash { crc }))
+        #[cfg(feature = "sequence")]
+        if self.sequence_next {
+            return true;
+        }
+        false
+    }
+
+    fn receive_byte(&mut self, ch: u8) -> Result<Option<Command<'_>>, Error> {
+        // The sequence number is sent as it is, as it's never an escape
+        #[cfg(feature = "sequence")]
+        if self.sequence_next {
+            self.sequence_next = false;
+            if ch != ESCAPE_CHAR {
+                self.sequence = Some(ch);
+                return Ok(None);
+            }
+        }
+        let observer = self.observer;
+        if let Some(o) = observer {
+            o.on_bytes(&[ch]);
+            if self.count == 0 && matches!(self.state, DecoderState::Loading) {
+                o.on_frame_start();
+            }
+        }
+        let result = match self.state {
+            DecoderState::Loading => self.handle_loading(ch),
+            DecoderState::Escape => self.handle_escape(ch),
+        };
+        if let Some(o) = observer {
+            match result {
+                Ok(Some(ref command)) => o.on_command_decoded(command),
+                Err(e) => o.on_error(e),
+                Ok(None) => {}
+            }
+        }
+        result
+    }
+
+    /// Process a run of incoming bytes.
+    ///
+    /// This gives the same results as passing each byte to `receive`, but
+    /// bytes between escapes are copied into the buffer in one go. It
+    /// returns how many bytes were used, along with the result of the last
+    /// one. It stops early at the end of a frame, and may also stop early
+    /// after an escape, so pass any remaining bytes in again. With an
+    /// address set, or in a sequence header, it takes one byte at a time.
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> (usize, Result<Option<Command<'_>>, Error>) {
+        if self.bytewise() {
+            return match bytes.first() {
+                Some(&ch) => (1, self.receive(ch)),
+                None => (0, Ok(None)),
+            };
+        }
+        let mut used = 0;
+        while let Some(&ch) = bytes.get(used) {
+            match self.state {
+                DecoderState::Loading if ch != ESCAPE_CHAR => {
+                    let run = bytes.get(used..).unwrap_or(&[]);
+                    let run = run.get(0..escape_free_len(run)).unwrap_or(&[]);
+                    self.load_run(run);
+                    used += run.len();
+                }
+                // Neither of these can end a frame
+                DecoderState::Loading | DecoderState::Escape if ch == ESCAPE_CHAR => {
+                    let _ = self.receive(ch);
+                    used += 1;
+                }
+                DecoderState::Loading | DecoderState::Escape => break,
+            }
+        }
+        match bytes.get(used) {
+            Some(&ch) => (used + 1, self.receive(ch)),
+            None => (used, Ok(None)),
+        }
+    }
+
+    /// Process one half of a circular DMA receive buffer, passing each
+    /// command, or error, to `handler`.
+    ///
+    /// Call this from the half-transfer interrupt with the first half of
+    /// the buffer, and from the transfer-complete interrupt with the second.
+    /// Frames, and escape sequences, can straddle the two halves, as the
+    /// decoder picks up where it left off.
+    pub fn process_dma_half<F>(&mut self, half: &[u8], mut handler: F)
+    where
+        F: FnMut(Result<Command<'_>, Error>),
+    {
+        let mut rest = half;
+        while !rest.is_empty() {
+            let (used, result) = self.push_bytes(rest);
+            match result {
+                Ok(Some(command)) => handler(Ok(command)),
+                Ok(None) => {}
+                Err(e) => handler(Err(e)),
+            }
+            rest = rest.get(used..).unwrap_or(&[]);
+        }
+    }
+
+    /// Report everything this decoder sees to `observer`.
+    pub fn set_observer(&mut self, observer: &'static dyn Observer) {
+        self.observer = Some(observer);
+    }
+
+    /// Keep a CRC of each command's arguments as they arrive, so that a
+    /// `CrcRxBuffer` can be answered from `rx_crc` without going over the
+    /// buffer again. Change this between commands.
+    pub fn set_rx_crc(&mut self, enabled: bool) {
+        self.rx_crc = if enabled { Some(Crc32::new()) } else { None };
+        self.last_rx = None;
+    }
+
+    /// Accept `SetAttr` and `GetAttr` for indices below `slots`, rather
+    /// than `MAX_INDEX`, for bootloaders with a bigger attribute table.
+    /// Others get `Error::InvalidValue`.
+    #[cfg(feature = "attributes")]
+    pub fn set_attr_slots(&mut self, slots: u8) {
+        self.attr_slots = slots;
+    }
+
+    /// Accept `SetAttr` values up to `len` bytes long, rather than
+    /// `MAX_ATTR_LEN`, for bootloaders with bigger attribute slots. Longer
+    /// ones get `Error::InvalidValue`.
+    #[cfg(feature = "attributes")]
+    pub fn set_attr_len(&mut self, len: usize) {
+        self.attr_len = len;
+    }
+
+    /// The length and CRC of the arguments of the last command, other than
+    /// `CrcRxBuffer` itself, for the reply to a `CrcRxBuffer`. Returns
+    /// `None` if `set_rx_crc` hasn't been called, or no command has arrived
+    /// since.
+    pub fn rx_crc(&self) -> Option<(u16, u32)> {
+        self.last_rx
+    }
+
+    fn load_char(&mut self, ch: u8) {
+        if let Some(slot) = self.buffer.as_mut().get_mut(self.count) {
+            *slot = ch;
+            self.count += 1;
+            if let Some(crc) = self.rx_crc.as_mut() {
+                crc.update(&[ch]);
+            }
+        } else {
+            self.overflow = true;
+        }
+    }
+
+    fn load_run(&mut self, run: &[u8]) {
+        if let Some(o) = self.observer {
+            o.on_bytes(run);
+            if self.count == 0 {
+                o.on_frame_start();
+            }
+        }
+        let count = copy_run(self.buffer.as_mut(), self.count, run);
+        if let Some(crc) = self.rx_crc.as_mut() {
+            crc.update(run.get(0..count - self.count).unwrap_or(&[]));
+        }
+        if count - self.count < run.len() {
+            self.overflow = true;
+        }
+        self.count = count;
+    }
+
+    #[cfg_attr(feature = "no-panic", no_panic::no_panic)]
+    fn handle_loading(&mut self, ch: u8) -> Result<Option<Command<'_>>, Error> {
+        if ch == ESCAPE_CHAR {
+            self.state = DecoderState::Escape;
+        } else {
+            self.load_char(ch);
+        }
+        Ok(None)
+    }
+
+    #[cfg_attr(feature = "no-panic", no_panic::no_panic)]
+    fn handle_escape(&mut self, ch: u8) -> Result<Option<Command<'_>>, Error> {
+        self.state = DecoderState::Loading;
+        if ch == ESCAPE_CHAR {
+            // Double escape means just load an escape
+            self.load_char(ch);
+            return Ok(None);
+        }
+        // A sequence header starts a command, so drop anything before it
+        #[cfg(feature = "sequence")]
+        if ch == sequence::SEQUENCE_MARK {
+            self.clear();
+            self.sequence_next = true;
+            return Ok(None);
+        }
+        let payload = self.buffer.as_mut().get(0..self.count).unwrap_or(&[]);
+        let result = if self.overflow {
+            Err(Error::Overflow)
+        } else {
+            decode_command(ch, payload)
+        };
+        #[cfg(feature = "attributes")]
+        let result = match result {
+            Ok(Some(command)) => {
+                check_attr(&command, self.attr_slots, self.attr_len).map(|_| Some(command))
+            }
+            other => other,
+        };
+        // A command or error signifies the end of the buffer
+        if !matches!(result, Ok(None)) {
+            if let Some(crc) = self.rx_crc.as_mut() {
+                if ch != CMD_CRCRX {
+                    self.last_rx = Some((self.count as u16, crc.finish()));
                 }
-                RES_CRCXF => {
-                    let crc = LittleEndian::read_u32(&self.buffer[1..5]);
-                    Ok(Some(Response::CrcExtFlash { crc }))
+                crc.reset();
+            }
+            self.count = 0;
+            self.overflow = false;
+            #[cfg(feature = "sequence")]
+            {
+                self.sequence = None;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(feature = "device")]
+impl Default for CommandDecoder {
+    fn default() -> CommandDecoder {
+        CommandDecoder::new()
+    }
+}
+
+#[cfg(feature = "host")]
+impl ResponseDecoder {
+    /// Create a new `ResponseDecoder`.
+    ///
+    /// The decoder is fed bytes with the `receive` method. This is a `const
+    /// fn`, so a decoder can be built straight into a `static`.
+    #[inline(always)]
+    pub const fn new() -> ResponseDecoder {
+        ResponseDecoder {
+            state: DecoderState::Loading,
+            buffer: [0u8; MAX_FRAME_LEN],
+            count: 0,
+            needed: None,
+            observer: None,
+            info_mode: InfoMode::Fixed,
+            id_len: ID_LEN,
+            #[cfg(feature = "attributes")]
+            attr_len: MAX_ATTR_LEN,
+            #[cfg(feature = "multi-drop")]
+            filter: None,
+        }
+    }
+}
+
+#[cfg(feature = "host")]
+impl<B> ResponseDecoder<B> {
+    /// Create a new `ResponseDecoder` which uses `buffer`, rather than one
+    /// of its own. The buffer has to hold the longest response you expect,
+    /// not counting escapes. Anything longer gets `Error::BufferFull`.
+    ///
+    /// A host which keeps its reads short can save a lot of RAM this way:
+    /// with a 64 byte buffer it can read up to 63 bytes at a time, and
+    /// decode everything but `Info` and `GetAttr` replies.
+    #[inline(always)]
+    pub const fn with_buffer(buffer: B) -> ResponseDecoder<B> {
+        ResponseDecoder {
+            state: DecoderState::Loading,
+            buffer,
+            count: 0,
+            needed: None,
+            observer: None,
+            info_mode: InfoMode::Fixed,
+            id_len: ID_LEN,
+            #[cfg(feature = "attributes")]
+            attr_len: MAX_ATTR_LEN,
+            #[cfg(feature = "multi-drop")]
+            filter: None,
+        }
+    }
+}
+
+#[cfg(feature = "host")]
+impl<B> ResponseDecoder<B>
+where
+    B: AsMut<[u8]>,
+{
+    /// Empty the RX buffer.
+    pub fn reset(&mut self) {
+        self.count = 0;
+    }
+
+    /// Process incoming bytes.
+    ///
+    /// The decoder is fed bytes with the `receive` method. If not enough
+    /// bytes have been seen, this function returns `None`. Once enough bytes
+    /// have been seen, it returns `Some(Response)` containing the
+    /// decoded Response.
+    pub fn receive(&mut self, ch: u8) -> Result<Option<Response<'_>>, Error> {
+        #[cfg(feature = "multi-drop")]
+        if let Some(filter) = self.filter.as_mut() {
+            let mut pass = [0u8; 2];
+            let len = match filter.receive(ch) {
+                Filtered::Drop => return Ok(None),
+                // Keep the length of any read we're waiting for
+                Filtered::Start => {
+                    self.state = DecoderState::Loading;
+                    self.count = 0;
+                    return Ok(None);
                 }
-                RES_INFO => {
-                    let info = &self.buffer[1..self.count];
-                    Ok(Some(Response::Info { info }))
+                Filtered::Pass(bytes) => {
+                    pass[0..bytes.len()].copy_from_slice(bytes);
+                    bytes.len()
                 }
-                _ => Err(Error::UnknownCommand),
             };
+            // Only ever an escape, so it can't finish a frame
+            if len == 2 {
+                let _ = self.receive_byte(pass[0]);
+            }
+            return self.receive_byte(pass[len - 1]);
+        }
+        self.receive_byte(ch)
+    }
+
+    /// Only take responses from the bootloader at `address`, as described
+    /// in the `multidrop` module, or unaddressed ones if it's `None`.
+    #[cfg(feature = "multi-drop")]
+    pub fn set_address(&mut self, address: Option<u8>) {
+        self.filter = address.map(AddressFilter::responses);
+    }
+
+    /// The address given to `set_address`.
+    #[cfg(feature = "multi-drop")]
+    pub fn address(&self) -> Option<u8> {
+        self.filter.map(|f| f.address())
+    }
+
+    fn receive_byte(&mut self, ch: u8) -> Result<Option<Response<'_>>, Error> {
+        let observer = self.observer;
+        if let Some(o) = observer {
+            o.on_bytes(&[ch]);
+            if self.count == 0 && matches!(self.state, DecoderState::Loading) {
+                o.on_frame_start();
+            }
+        }
+        let result = match self.state {
+            DecoderState::Loading => self.handle_loading(ch),
+            DecoderState::Escape => self.handle_escape(ch),
+        };
+        if let Some(o) = observer {
+            match result {
+                Ok(Some(ref response)) => o.on_response_decoded(response),
+                Err(e) => o.on_error(e),
+                Ok(None) => {}
+            }
+        }
+        result
+    }
+
+    /// Process a run of incoming bytes.
+    ///
+    /// This gives the same results as passing each byte to `receive`, but
+    /// bytes between escapes are copied into the buffer in one go. It
+    /// returns how many bytes were used, along with the result of the last
+    /// one. It stops early at the end of a frame, and may also stop early
+    /// after an escape, so pass any remaining bytes in again. With an
+    /// address set, it takes one byte at a time.
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> (usize, Result<Option<Response<'_>>, Error>) {
+        #[cfg(feature = "multi-drop")]
+        if self.filter.is_some() {
+            return match bytes.first() {
+                Some(&ch) => (1, self.receive(ch)),
+                None => (0, Ok(None)),
+            };
+        }
+        let mut used = 0;
+        while let Some(&ch) = bytes.get(used) {
+            // The byte which completes a payload goes through `receive`
+            let room = match self.needed {
+                Some(needed) => needed.saturating_sub(self.count + 1),
+                None => usize::MAX,
+            };
+            match self.state {
+                DecoderState::Loading if ch != ESCAPE_CHAR && room > 0 => {
+                    let run = bytes.get(used..).unwrap_or(&[]);
+                    let run = run.get(0..escape_free_len(run).min(room)).unwrap_or(&[]);
+                    self.load_run(run);
+                    used += run.len();
+                }
+                // Neither of these can end a frame
+                DecoderState::Loading if ch == ESCAPE_CHAR => {
+                    let _ = self.receive(ch);
+                    used += 1;
+                }
+                DecoderState::Escape if ch == ESCAPE_CHAR && room > 0 => {
+                    let _ = self.receive(ch);
+                    used += 1;
+                }
+                DecoderState::Loading | DecoderState::Escape => break,
+            }
+        }
+        match bytes.get(used) {
+            Some(&ch) => (used + 1, self.receive(ch)),
+            None => (used, Ok(None)),
+        }
+    }
+
+    /// Process one half of a circular DMA receive buffer, passing each
+    /// response, or error, to `handler`. This works in the same way as
+    /// `CommandDecoder::process_dma_half`.
+    ///
+    /// The handler can't call `set_payload_len`, so if a `ReadRange` reply
+    /// might follow another response in the same half, feed the decoder
+    /// with `push_bytes` instead.
+    pub fn process_dma_half<F>(&mut self, half: &[u8], mut handler: F)
+    where
+        F: FnMut(Result<Response<'_>, Error>),
+    {
+        let mut rest = half;
+        while !rest.is_empty() {
+            let (used, result) = self.push_bytes(rest);
+            match result {
+                Ok(Some(response)) => handler(Ok(response)),
+                Ok(None) => {}
+                Err(e) => handler(Err(e)),
+            }
+            rest = rest.get(used..).unwrap_or(&[]);
+        }
+    }
+
+    /// Report everything this decoder sees to `observer`.
+    pub fn set_observer(&mut self, observer: &'static dyn Observer) {
+        self.observer = Some(observer);
+    }
+
+    /// Choose how `Info` replies are read. The default is `InfoMode::Fixed`.
+    pub fn set_info_mode(&mut self, mode: InfoMode) {
+        self.info_mode = mode;
+    }
+
+    /// Expect `GetAttr` replies with values padded to `len` bytes, rather
+    /// than `MAX_ATTR_LEN`, for bootloaders with bigger attribute slots.
+    #[cfg(feature = "attributes")]
+    pub fn set_attr_len(&mut self, len: usize) {
+        self.attr_len = len;
+    }
+
+    /// Expect `Id` replies with `len` bytes of ID, rather than `ID_LEN`,
+    /// for chips with longer unique IDs, such as 12 or 16 bytes.
+    pub fn set_id_len(&mut self, len: usize) {
+        self.id_len = len;
+    }
+
+    /// The part of a response received so far, with escapes removed.
+    #[cfg(feature = "host")]
+    pub(crate) fn partial(&mut self) -> &[u8] {
+        let count = self.count;
+        self.buffer.as_mut().get(0..count).unwrap_or(&[])
+    }
+
+    /// Drop any partially received response, and the expected length.
+    fn restart(&mut self) {
+        self.state = DecoderState::Loading;
+        self.count = 0;
+        self.needed = None;
+        #[cfg(feature = "multi-drop")]
+        if let Some(filter) = self.filter.as_mut() {
+            filter.reset();
+        }
+    }
+
+    /// Set the expected length of an unbounded message. This
+    /// depends entirely on the last command you sent.
+    pub fn set_payload_len(&mut self, length: usize) -> Result<(), Error> {
+        match self.needed {
+            Some(_) => Err(Error::SetLength),
+            // It has to fit in the buffer along with the response byte
+            None if length < self.buffer.as_mut().len() => {
+                self.needed = Some(length + 1);
+                Ok(())
+            }
+            None => Err(Error::BufferFull),
+        }
+    }
+
+    /// Never completes a response, so leave at least one byte of the
+    /// payload for `load_char`.
+    fn load_run(&mut self, run: &[u8]) {
+        if let Some(o) = self.observer {
+            o.on_bytes(run);
+            if self.count == 0 {
+                o.on_frame_start();
+            }
+        }
+        self.count = copy_run(self.buffer.as_mut(), self.count, run);
+    }
+
+    fn load_char(&mut self, ch: u8) -> Result<Option<Response<'_>>, Error> {
+        if let Some(slot) = self.buffer.as_mut().get_mut(self.count) {
+            *slot = ch;
+            self.count += 1;
+        }
+        if self.needed == Some(self.count) {
+            let payload = self.buffer.as_mut().get(0..self.count).unwrap_or(&[]);
             self.needed = None;
             self.count = 0;
-            result
+            decode_response(payload, self.info_mode)
         } else {
             Ok(None)
         }
     }
 
-    fn handle_loading(&mut self, ch: u8) -> Result<Option<Response>, Error> {
+    #[cfg_attr(feature = "no-panic", no_panic::no_panic)]
+    fn handle_loading(&mut self, ch: u8) -> Result<Option<Response<'_>>, Error> {
         if ch == ESCAPE_CHAR {
             self.state = DecoderState::Escape;
             Ok(None)
@@ -586,7 +2143,8 @@ impl ResponseDecoder {
         }
     }
 
-    fn handle_escape(&mut self, ch: u8) -> Result<Option<Response>, Error> {
+    #[cfg_attr(feature = "no-panic", no_panic::no_panic)]
+    fn handle_escape(&mut self, ch: u8) -> Result<Option<Response<'_>>, Error> {
         self.state = DecoderState::Loading;
         match ch {
             ESCAPE_CHAR => {
@@ -628,16 +2186,19 @@ impl ResponseDecoder {
                 self.needed = None;
                 Ok(Some(Response::Unknown))
             }
+            #[cfg(feature = "ext-flash")]
             RES_XFTIMEOUT => {
                 self.count = 0;
                 self.needed = None;
                 Ok(Some(Response::ExtFlashTimeout))
             }
+            #[cfg(feature = "ext-flash")]
             RES_XFEPE => {
                 self.count = 0;
                 self.needed = None;
                 Ok(Some(Response::ExtFlashPageError))
             }
+            #[cfg(feature = "baud-change")]
             RES_CHANGE_BAUD_FAIL => {
                 self.count = 0;
                 self.needed = None;
@@ -652,20 +2213,23 @@ impl ResponseDecoder {
                 if self.needed.is_none() {
                     Err(Error::UnsetLength)
                 } else {
-                    self.load_char(ch)?;
-                    Ok(None)
+                    // An empty payload is complete already
+                    self.load_char(ch)
                 }
             }
             RES_XRRANGE => {
                 if self.needed.is_none() {
                     Err(Error::UnsetLength)
                 } else {
-                    self.load_char(ch)?;
-                    Ok(None)
+                    self.load_char(ch)
                 }
             }
             RES_GATTR => {
-                self.set_payload_len(1 + 8 + 55)?;
+                #[cfg(feature = "attributes")]
+                let attr_len = self.attr_len;
+                #[cfg(not(feature = "attributes"))]
+                let attr_len = MAX_ATTR_LEN;
+                self.set_payload_len(KEY_LEN + 1 + attr_len)?;
                 self.load_char(ch)?;
                 Ok(None)
             }
@@ -680,7 +2244,21 @@ impl ResponseDecoder {
                 Ok(None)
             }
             RES_INFO => {
-                self.set_payload_len(8)?;
+                self.set_payload_len(match self.info_mode {
+                    InfoMode::Fixed => 8,
+                    InfoMode::LengthPrefixed => 1 + MAX_INFO_LEN,
+                })?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
+            RES_ID => {
+                self.set_payload_len(self.id_len)?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
+            #[cfg(feature = "long-attributes")]
+            RES_GLATTR => {
+                self.set_payload_len(KEY_LEN + 2 + MAX_LONG_ATTR_LEN)?;
                 self.load_char(ch)?;
                 Ok(None)
             }
@@ -689,440 +2267,1110 @@ impl ResponseDecoder {
     }
 }
 
+#[cfg(feature = "host")]
+impl Default for ResponseDecoder {
+    fn default() -> ResponseDecoder {
+        ResponseDecoder::new()
+    }
+}
+
+#[cfg(feature = "host")]
 impl<'a> CommandEncoder<'a> {
     /// Create a new `CommandEncoder`.
     ///
     /// The encoder takes a reference to a `Command` to encode. The `next` method
     /// will then supply the encoded bytes one at a time.
     pub fn new(command: &'a Command) -> Result<CommandEncoder<'a>, Error> {
-        // We have to accept slices rather than arrays, so bounds check them
-        // all now to save surprises later.
-        match command {
-            &Command::WritePage { address: _, data } => {
-                if data.len() != INT_PAGE_SIZE {
-                    return Err(Error::BadArguments);
-                }
+        #[cfg(feature = "attributes")]
+        check_attr(command, MAX_INDEX, MAX_ATTR_LEN).map_err(|_| Error::BadArguments)?;
+        Ok(CommandEncoder::from_frame(Frame::new(command)?))
+    }
+
+    /// Create a new `CommandEncoder` for a bootloader with `slots`
+    /// attribute slots, rather than `MAX_INDEX`.
+    #[cfg(feature = "attributes")]
+    pub fn with_attr_slots(command: &'a Command, slots: u8) -> Result<CommandEncoder<'a>, Error> {
+        CommandEncoder::with_attr_limits(command, slots, MAX_ATTR_LEN)
+    }
+
+    /// Create a new `CommandEncoder` for a bootloader with `slots`
+    /// attribute slots, rather than `MAX_INDEX`, holding values of up to
+    /// `len` bytes, rather than `MAX_ATTR_LEN`. The length is sent in one
+    /// byte, so `len` can be at most 255.
+    #[cfg(feature = "attributes")]
+    pub fn with_attr_limits(
+        command: &'a Command,
+        slots: u8,
+        len: usize,
+    ) -> Result<CommandEncoder<'a>, Error> {
+        check_attr(command, slots, len).map_err(|_| Error::BadArguments)?;
+        Ok(CommandEncoder::from_frame(Frame::new(command)?))
+    }
+
+    fn from_frame(frame: Frame<'a>) -> CommandEncoder<'a> {
+        CommandEncoder {
+            frame,
+            count: 0,
+            sent_escape: false,
+            staging: [0u8; MAX_CHUNK_LEN],
+            staged: 0,
+            staged_len: 0,
+            #[cfg(any(feature = "multi-drop", feature = "sequence"))]
+            header: Header::new(),
+        }
+    }
+
+    /// Send the command to the bootloader at `address`, starting the frame
+    /// with the header described in the `multidrop` module. This must be
+    /// called before the first byte is taken from the encoder.
+    #[cfg(feature = "multi-drop")]
+    pub fn set_address(&mut self, address: Option<u8>) {
+        self.header.address = address.map(multidrop::command_header);
+    }
+
+    /// Give the command a sequence number, with the header described in
+    /// the `sequence` module. This must be called before the first byte is
+    /// taken from the encoder.
+    #[cfg(feature = "sequence")]
+    pub fn set_sequence(&mut self, sequence: Option<u8>) {
+        self.header.sequence = sequence.map(sequence::sequence_header);
+    }
+
+    /// Rewind the encoder so the same frame can be sent again, for example
+    /// after a NACK. Any settings made on the encoder are kept.
+    pub fn reset(&mut self) {
+        self.count = 0;
+        self.sent_escape = false;
+        self.staged = 0;
+        self.staged_len = 0;
+        #[cfg(any(feature = "multi-drop", feature = "sequence"))]
+        {
+            self.header.sent = 0;
+        }
+    }
+
+    /// Supply up to `max_len` encoded bytes as one contiguous slice.
+    ///
+    /// This is for drivers that hand blocks of bytes to a DMA engine rather
+    /// than taking an interrupt per byte. The bytes are staged in a small
+    /// window inside the encoder, so at most `MAX_CHUNK_LEN` bytes are
+    /// returned per call. A `max_len` of zero is treated as one, so `None`
+    /// always means every byte has been emitted.
+    ///
+    /// Runs of arguments without an escape are copied as slices, as
+    /// `encode_all` does. The iterator takes its bytes from the same window.
+    pub fn next_chunk(&mut self, max_len: usize) -> Option<&[u8]> {
+        let limit = max_len.clamp(1, MAX_CHUNK_LEN);
+        // Anything left in the window by the iterator goes first
+        if self.staged == self.staged_len {
+            self.staged = 0;
+            self.staged_len = self.fill(limit);
+        }
+        let start = self.staged;
+        let end = cmp::min(self.staged_len, start + limit);
+        self.staged = end;
+        if start == end {
+            None
+        } else {
+            Some(&self.staging[start..end])
+        }
+    }
+
+    /// Fill the window with up to `limit` encoded bytes, returning how many
+    /// were written.
+    fn fill(&mut self, limit: usize) -> usize {
+        let mut len = 0;
+        while len < limit {
+            let rest = self.frame.args_from(self.count);
+            let rest = &rest[0..cmp::min(rest.len(), limit - len)];
+            // Any header goes out first, and an escape a byte at a time
+            let run = if self.header_sent() && !self.sent_escape {
+                escape_free_len(rest)
+            } else {
+                0
+            };
+            if run > 0 {
+                self.staging[len..len + run].copy_from_slice(&rest[0..run]);
+                self.count += run;
+                len += run;
+                continue;
             }
-            &Command::WriteExPage { address: _, data } => {
-                if data.len() != EXT_PAGE_SIZE {
-                    return Err(Error::BadArguments);
+            match self.step() {
+                Some(byte) => {
+                    self.staging[len] = byte;
+                    len += 1;
                 }
+                None => break,
             }
-            &Command::SetAttr { index, key, value } => {
-                if index > MAX_INDEX {
-                    return Err(Error::BadArguments);
-                }
+        }
+        len
+    }
+
+    /// Encode the next byte on its own, for the header, escapes and the
+    /// end of the frame.
+    fn step(&mut self) -> Option<u8> {
+        #[cfg(any(feature = "multi-drop", feature = "sequence"))]
+        if let Some(byte) = self.header.next() {
+            return Some(byte);
+        }
+        let args_len = self.frame.args_len();
+        let byte = if self.count < args_len {
+            self.frame.arg(self.count)
+        } else if self.count == args_len {
+            ESCAPE_CHAR
+        } else if self.count == args_len + 1 {
+            self.frame.opcode
+        } else {
+            return None;
+        };
+        // Escapes in the arguments are sent twice
+        if byte == ESCAPE_CHAR && self.count < args_len && !self.sent_escape {
+            self.sent_escape = true;
+        } else {
+            self.sent_escape = false;
+            self.count += 1;
+        }
+        Some(byte)
+    }
+
+    /// Whether all of the multi-drop and sequence header has gone.
+    #[cfg(any(feature = "multi-drop", feature = "sequence"))]
+    fn header_sent(&self) -> bool {
+        self.header.is_done()
+    }
+
+    #[cfg(not(any(feature = "multi-drop", feature = "sequence")))]
+    fn header_sent(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "host")]
+impl<'a> Iterator for CommandEncoder<'a> {
+    type Item = u8;
+
+    /// Supply the next encoded byte. Once all the bytes have been emitted, it
+    /// returns `None` forevermore.
+    ///
+    /// The bytes come from the window `next_chunk` fills, so runs without
+    /// an escape are still copied as slices.
+    fn next(&mut self) -> Option<u8> {
+        if self.staged == self.staged_len {
+            self.staged = 0;
+            self.staged_len = self.fill(MAX_CHUNK_LEN);
+        }
+        let byte = *self.staging[..self.staged_len].get(self.staged)?;
+        self.staged += 1;
+        Some(byte)
+    }
+}
+
+/// Encode `command` into `buffer` in one go, returning the length of the
+/// frame.
+///
+/// This gives the same bytes as a `CommandEncoder`, but copies the runs of
+/// bytes between escapes as slices rather than one byte at a time, which is
+/// much quicker for pages. The command is checked in the same way as
+/// `CommandEncoder::new`. If the frame doesn't fit, `Error::BufferFull` is
+/// returned and the buffer is left partly written. There's no multi-drop
+/// or sequence header; put `multidrop::command_header` or
+/// `sequence::sequence_header` in front of the frame yourself.
+#[cfg(feature = "host")]
+pub fn encode_all(command: &Command, buffer: &mut [u8]) -> Result<usize, Error> {
+    #[cfg(feature = "attributes")]
+    check_attr(command, MAX_INDEX, MAX_ATTR_LEN).map_err(|_| Error::BadArguments)?;
+    let frame = Frame::new(command)?;
+    let len = escape_into(buffer, 0, frame.head())?;
+    let len = escape_into(buffer, len, frame.data)?;
+    copy_into(buffer, len, &[ESCAPE_CHAR, frame.opcode])
+}
+
+/// Split one buffer between a `CommandDecoder` and a `ResponseDecoder`,
+/// giving the first `command_len` bytes to the command decoder.
+///
+/// This is for devices which watch traffic in both directions, such as a
+/// bridge, and can't spare a full size buffer for each. Each decoder only
+/// needs room for the longest frame it will see, not counting escapes. That
+/// is `MAX_FRAME_LEN` for a command decoder which sees page writes, but a
+/// response decoder only needs one more than the longest read, or the
+/// longest `Info` or `GetAttr` reply if those are bigger.
+#[cfg(all(feature = "host", feature = "device"))]
+pub fn split_decoders(
+    buffer: &mut [u8],
+    command_len: usize,
+) -> (CommandDecoder<&mut [u8]>, ResponseDecoder<&mut [u8]>) {
+    let (commands, responses) = buffer.split_at_mut(cmp::min(command_len, buffer.len()));
+    (CommandDecoder::with_buffer(commands), ResponseDecoder::with_buffer(responses))
+}
+
+#[cfg(feature = "device")]
+impl<'a> ResponseEncoder<'a> {
+    /// Create a new `ResponseEncoder`.
+    ///
+    /// The encoder takes a reference to a `Command` to encode. The `next` method
+    /// will then supply the encoded bytes one at a time.
+    pub fn new(response: &'a Response) -> Result<ResponseEncoder<'a>, Error> {
+        ResponseEncoder::build(response, MAX_ATTR_LEN)
+    }
+
+    /// Create a new `ResponseEncoder` for a bootloader with attribute
+    /// values of up to `len` bytes, rather than `MAX_ATTR_LEN`. `GetAttr`
+    /// values are padded out to `len`. The length is sent in one byte, so
+    /// `len` can be at most 255.
+    #[cfg(feature = "attributes")]
+    pub fn with_attr_len(response: &'a Response, len: usize) -> Result<ResponseEncoder<'a>, Error> {
+        ResponseEncoder::build(response, len)
+    }
+
+    fn build(response: &'a Response, len: usize) -> Result<ResponseEncoder<'a>, Error> {
+        if len > usize::from(u8::MAX) {
+            return Err(Error::BadArguments);
+        }
+        match *response {
+            #[cfg(feature = "attributes")]
+            Response::GetAttr { key, value } => {
                 if key.len() != KEY_LEN {
                     return Err(Error::BadArguments);
                 }
-                if value.len() > MAX_ATTR_LEN {
+                if value.len() > len {
                     return Err(Error::BadArguments);
                 }
             }
+            #[cfg(feature = "long-attributes")]
+            Response::GetLongAttr { key, value }
+                if key.len() != KEY_LEN || value.len() > MAX_LONG_ATTR_LEN =>
+            {
+                return Err(Error::BadArguments);
+            }
+            Response::Info { info } if info.len() > MAX_INFO_LEN => {
+                return Err(Error::BadArguments);
+            }
             _ => {}
-        };
-        Ok(CommandEncoder {
-            command: command,
+        }
+        Ok(ResponseEncoder {
+            response,
             count: 0,
             sent_escape: false,
+            staging: [0u8; MAX_CHUNK_LEN],
+            padding: PaddingMode::Raw,
+            pad_byte: RESPONSE_PAD_BYTE,
+            id_len: ID_LEN,
+            #[cfg(feature = "attributes")]
+            attr_len: len,
+            #[cfg(feature = "multi-drop")]
+            header: Header::new(),
         })
     }
 
-    fn render_byte(&mut self, byte: u8) -> (usize, Option<u8>) {
-        if byte == ESCAPE_CHAR {
-            if self.sent_escape {
-                self.sent_escape = false;
-                (1, Some(ESCAPE_CHAR))
-            } else {
-                self.sent_escape = true;
-                (0, Some(ESCAPE_CHAR))
+    /// Say the response comes from the bootloader at `address`, starting
+    /// the frame with the header described in the `multidrop` module. This
+    /// must be called before the first byte is taken from the encoder.
+    #[cfg(feature = "multi-drop")]
+    pub fn set_address(&mut self, address: Option<u8>) {
+        self.header.address = address.map(multidrop::response_header);
+    }
+
+    /// Choose how variable length responses are laid out. The default is
+    /// `PaddingMode::Raw`. This must be called before the first byte is
+    /// taken from the encoder.
+    pub fn set_padding_mode(&mut self, padding: PaddingMode) {
+        self.padding = padding;
+    }
+
+    /// Choose the byte used to fill unused space in `GetAttr` values and
+    /// padded `Info` strings. The default is 0x00, which matches the
+    /// reference C bootloader.
+    pub fn set_pad_byte(&mut self, pad_byte: u8) {
+        self.pad_byte = pad_byte;
+    }
+
+    /// Choose how unused space is filled, as `set_pad_byte` does, or with
+    /// `PadPolicy::Reject`, refuse a response which would need padding: a
+    /// `GetAttr` value shorter than the attribute length, an `Id` shorter
+    /// than the ID length, or an `Info` string shorter than 192 bytes in
+    /// `PaddingMode::Spec`. Returns `Error::BadArguments` if it would. The
+    /// check uses the settings made so far, so call this after
+    /// `set_padding_mode` and `set_id_len`.
+    pub fn set_pad_policy(&mut self, policy: PadPolicy) -> Result<(), Error> {
+        match policy {
+            PadPolicy::Fill(pad_byte) => {
+                self.pad_byte = pad_byte;
+                Ok(())
             }
-        } else {
-            self.sent_escape = false;
-            (1, Some(byte))
+            PadPolicy::Reject if self.needs_padding() => Err(Error::BadArguments),
+            PadPolicy::Reject => Ok(()),
         }
     }
 
-    fn render_u16(&mut self, idx: usize, value: u16) -> (usize, Option<u8>) {
-        match idx {
-            0 => self.render_byte(value as u8),
-            1 => self.render_byte((value >> 8) as u8),
-            _ => (0, None),
-        }
+    /// Pad `Id` replies out to `len` bytes, rather than `ID_LEN`. A longer
+    /// ID is sent whole. This must be called before the first byte is taken
+    /// from the encoder.
+    pub fn set_id_len(&mut self, len: usize) {
+        self.id_len = len;
     }
 
-    fn render_u32(&mut self, idx: usize, value: u32) -> (usize, Option<u8>) {
-        match idx {
-            0 => self.render_byte(value as u8),
-            1 => self.render_byte((value >> 8) as u8),
-            2 => self.render_byte((value >> 16) as u8),
-            3 => self.render_byte((value >> 24) as u8),
-            _ => (0, None),
+    /// Rewind the encoder so the same frame can be sent again, for example
+    /// after a NACK. Any settings made on the encoder are kept.
+    pub fn reset(&mut self) {
+        self.count = 0;
+        self.sent_escape = false;
+        #[cfg(feature = "multi-drop")]
+        {
+            self.header.sent = 0;
         }
     }
 
-    fn render_buffer(&mut self, idx: usize, page_size: usize, data: &[u8]) -> (usize, Option<u8>) {
-        if (idx < data.len()) && (idx < page_size) {
-            self.render_byte(data[idx])
-        } else if idx < page_size {
-            self.render_byte(0xFF) // pad short data with 0xFFs
+    /// Supply up to `max_len` encoded bytes as one contiguous slice.
+    ///
+    /// This is for drivers that hand blocks of bytes to a DMA engine rather
+    /// than taking an interrupt per byte. The bytes are staged in a small
+    /// window inside the encoder, so at most `MAX_CHUNK_LEN` bytes are
+    /// returned per call. A `max_len` of zero is treated as one, so `None`
+    /// always means every byte has been emitted.
+    pub fn next_chunk(&mut self, max_len: usize) -> Option<&[u8]> {
+        let limit = max_len.clamp(1, MAX_CHUNK_LEN);
+        let mut len = 0;
+        while len < limit {
+            match self.next() {
+                Some(byte) => {
+                    self.staging[len] = byte;
+                    len += 1;
+                }
+                None => break,
+            }
+        }
+        if len == 0 {
+            None
         } else {
-            (0, None)
+            Some(&self.staging[0..len])
         }
     }
 
-    fn render_basic_cmd(&mut self, count: usize, cmd: u8) -> (usize, Option<u8>) {
-        match count {
-            0 => (1, Some(ESCAPE_CHAR)), // Escape
-            1 => (1, Some(cmd)), // Command
-            _ => (0, None),
+    /// Whether any of the response is padding.
+    fn needs_padding(&self) -> bool {
+        match *self.response {
+            #[cfg(feature = "attributes")]
+            Response::GetAttr { value, .. } => value.len() < self.attr_len,
+            Response::Info { info } => {
+                self.padding == PaddingMode::Spec && info.len() < MAX_INFO_LEN
+            }
+            Response::Id { id } => id.len() < self.id_len,
+            _ => false,
         }
     }
 
-    fn render_erasepage_cmd(&mut self, address: u32) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...3 => self.render_u32(count, address),
-            _ => self.render_basic_cmd(count - 4, CMD_EPAGE),
+    /// Escapes in the payload are sent twice, as in `CommandEncoder`.
+    fn render_byte(&mut self, byte: u8) -> (usize, Option<u8>) {
+        if byte == ESCAPE_CHAR && !self.sent_escape {
+            self.sent_escape = true;
+            (0, Some(byte))
+        } else {
+            self.sent_escape = false;
+            (1, Some(byte))
         }
     }
 
-    fn render_writepage_cmd(&mut self, address: u32, data: &[u8]) -> (usize, Option<u8>) {
+    fn render_crc_rx_buffer(&mut self, length: u16, crc: u32) -> (usize, Option<u8>) {
         let count = self.count;
         match count {
-            0...3 => self.render_u32(count, address),
-            4...515 => self.render_buffer(count - 4, INT_PAGE_SIZE, data),
-            _ => self.render_basic_cmd(count - 516, CMD_WPAGE),
+            0..=1 => self.render_header(count, RES_CRCRX),
+            2..=3 => self.render_u16(count - 2, length),
+            4..=7 => self.render_u32(count - 4, crc),
+            _ => (0, None),
         }
     }
 
-    fn render_eraseexblock(&mut self, address: u32) -> (usize, Option<u8>) {
+    fn render_read_range(&mut self, data: &[u8]) -> (usize, Option<u8>) {
         let count = self.count;
         match count {
-            0...3 => self.render_u32(count, address),
-            _ => self.render_basic_cmd(count - 4, CMD_XEBLOCK),
+            0..=1 => self.render_header(count, RES_RRANGE),
+            x if x < data.len() + 2 => self.render_byte(data[x - 2]),
+            _ => (0, None),
         }
     }
 
-    fn render_writeexpage(&mut self, address: u32, data: &[u8]) -> (usize, Option<u8>) {
+    #[cfg(feature = "ext-flash")]
+    fn render_ex_read_range(&mut self, data: &[u8]) -> (usize, Option<u8>) {
         let count = self.count;
         match count {
-            0...3 => self.render_u32(count, address),
-            4...259 => self.render_buffer(count - 4, EXT_PAGE_SIZE, data),
-            _ => self.render_basic_cmd(count - (EXT_PAGE_SIZE + 4), CMD_XWPAGE),
+            0..=1 => self.render_header(count, RES_XRRANGE),
+            x if x - 2 < data.len() => self.render_byte(data[x - 2]),
+            _ => (0, None),
         }
     }
 
-    fn render_readrange(&mut self, address: u32, length: u16) -> (usize, Option<u8>) {
+    #[cfg(feature = "attributes")]
+    fn render_get_attr(&mut self, key: &[u8], value: &[u8]) -> (usize, Option<u8>) {
         let count = self.count;
         match count {
-            0...3 => self.render_u32(count, address),
-            4...5 => self.render_u16(count - 4, length),
-            _ => self.render_basic_cmd(count - 6, CMD_RRANGE),
+            0..=1 => self.render_header(count, RES_GATTR),
+            2..=9 => self.render_buffer(count - 2, 8, key),
+            10 => self.render_byte(value.len() as u8),
+            _ => self.render_buffer(count - 11, self.attr_len, value),
         }
     }
 
-    fn render_exreadrange(&mut self, address: u32, length: u16) -> (usize, Option<u8>) {
+    fn render_crc_int_flash(&mut self, crc: u32) -> (usize, Option<u8>) {
         let count = self.count;
         match count {
-            0...3 => self.render_u32(count, address),
-            4...5 => self.render_u16(count - 4, length),
-            _ => self.render_basic_cmd(count - 6, CMD_XRRANGE),
+            0..=1 => self.render_header(count, RES_CRCIF),
+            _ => self.render_u32(count - 2, crc),
         }
     }
 
-    fn render_setattr(&mut self, index: u8, key: &[u8], value: &[u8]) -> (usize, Option<u8>) {
+    #[cfg(feature = "ext-flash")]
+    fn render_crc_ex_flash(&mut self, crc: u32) -> (usize, Option<u8>) {
         let count = self.count;
-        let max_len = if value.len() > MAX_ATTR_LEN {
-            MAX_ATTR_LEN
-        } else {
-            value.len()
-        };
         match count {
-            0 => self.render_byte(index),
-            1...9 => self.render_buffer(count - 1, KEY_LEN, key),
-            10 => self.render_byte(max_len as u8),
-            x if (max_len > 0) && (x < max_len + 11) => {
-                self.render_buffer(count - 11, max_len, value)
-            }
-            _ => self.render_basic_cmd(count - (11 + max_len), CMD_SATTR),
+            0..=1 => self.render_header(count, RES_CRCXF),
+            _ => self.render_u32(count - 2, crc),
         }
     }
 
-    fn render_getattr(&mut self, index: u8) -> (usize, Option<u8>) {
+    fn render_info(&mut self, info: &[u8]) -> (usize, Option<u8>) {
         let count = self.count;
-        match count {
-            0 => self.render_byte(index),
-            _ => self.render_basic_cmd(count - 1, CMD_GATTR),
+        match (self.padding, count) {
+            (_, 0..=1) => self.render_header(count, RES_INFO),
+            (PaddingMode::Raw, _) => self.render_buffer(count - 2, info.len(), info),
+            (PaddingMode::Spec, 2) => self.render_byte(info.len() as u8),
+            (PaddingMode::Spec, _) => self.render_buffer(count - 3, MAX_INFO_LEN, info),
         }
     }
 
-    fn render_crcintflash(&mut self, address: u32, length: u32) -> (usize, Option<u8>) {
+    fn render_id(&mut self, id: &[u8]) -> (usize, Option<u8>) {
         let count = self.count;
         match count {
-            0...3 => self.render_u32(count, address),
-            4...7 => self.render_u32(count - 4, length),
-            _ => self.render_basic_cmd(count - 8, CMD_CRCIF),
+            0..=1 => self.render_header(count, RES_ID),
+            _ => self.render_buffer(count - 2, cmp::max(id.len(), self.id_len), id),
         }
     }
 
-    fn render_crcextflash(&mut self, address: u32, length: u32) -> (usize, Option<u8>) {
+    #[cfg(feature = "long-attributes")]
+    fn render_get_long_attr(&mut self, key: &[u8], value: &[u8]) -> (usize, Option<u8>) {
         let count = self.count;
         match count {
-            0...3 => self.render_u32(count, address),
-            4...7 => self.render_u32(count - 4, length),
-            _ => self.render_basic_cmd(count - 8, CMD_CRCEF),
+            0..=1 => self.render_header(count, RES_GLATTR),
+            2..=9 => self.render_buffer(count - 2, 8, key),
+            10..=11 => self.render_u16(count - 10, value.len() as u16),
+            _ => self.render_buffer(count - 12, MAX_LONG_ATTR_LEN, value),
         }
     }
 
-    fn render_eraseexpage(&mut self, address: u32) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...3 => self.render_u32(count, address),
-            _ => self.render_basic_cmd(count - 4, CMD_XEPAGE),
+    fn render_u16(&mut self, idx: usize, value: u16) -> (usize, Option<u8>) {
+        match idx {
+            0 => self.render_byte(value as u8),
+            1 => self.render_byte((value >> 8) as u8),
+            _ => (0, None),
         }
     }
 
-    fn render_writeflashuserpages(&mut self, page1: u32, page2: u32) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...3 => self.render_u32(count, page1),
-            4...7 => self.render_u32(count - 4, page2),
-            _ => self.render_basic_cmd(count - 8, CMD_WUSER),
+    fn render_u32(&mut self, idx: usize, value: u32) -> (usize, Option<u8>) {
+        match idx {
+            0 => self.render_byte(value as u8),
+            1 => self.render_byte((value >> 8) as u8),
+            2 => self.render_byte((value >> 16) as u8),
+            3 => self.render_byte((value >> 24) as u8),
+            _ => (0, None),
         }
     }
 
-    fn render_changebaud(&mut self, mode: BaudMode, baud: u32) -> (usize, Option<u8>) {
-        let count = self.count;
+    fn render_buffer(&mut self, idx: usize, page_size: usize, data: &[u8]) -> (usize, Option<u8>) {
+        if (idx < data.len()) && (idx < page_size) {
+            self.render_byte(data[idx])
+        } else if idx < page_size {
+            self.render_byte(self.pad_byte)
+        } else {
+            (0, None)
+        }
+    }
+
+    fn render_header(&mut self, count: usize, cmd: u8) -> (usize, Option<u8>) {
         match count {
-            0 => {
-                self.render_byte(match mode {
-                    BaudMode::Set => 0x01,
-                    BaudMode::Verify => 0x02,
-                })
-            }
-            1...3 => self.render_u32(count - 1, baud),
-            _ => self.render_basic_cmd(count - 8, CMD_WUSER),
+            0 => (1, Some(ESCAPE_CHAR)), // Escape
+            1 => (1, Some(cmd)), // Command
+            _ => (0, None),
         }
     }
 }
 
-impl<'a> Iterator for CommandEncoder<'a> {
+#[cfg(feature = "device")]
+impl<'a> Iterator for ResponseEncoder<'a> {
     type Item = u8;
 
     /// Supply the next encoded byte. Once all the bytes have been emitted, it
     /// returns `None` forevermore.
     fn next(&mut self) -> Option<u8> {
+        #[cfg(feature = "multi-drop")]
+        if let Some(byte) = self.header.next() {
+            return Some(byte);
+        }
         let count = self.count;
-        let (inc, result) = match self.command {
-            &Command::Ping => self.render_basic_cmd(count, CMD_PING),
-            &Command::Info => self.render_basic_cmd(count, CMD_INFO),
-            &Command::Id => self.render_basic_cmd(count, CMD_ID),
-            &Command::Reset => self.render_basic_cmd(count, CMD_RESET),
-            &Command::ErasePage { address } => self.render_erasepage_cmd(address),
-            &Command::WritePage { address, data } => self.render_writepage_cmd(address, data),
-            &Command::EraseExBlock { address } => self.render_eraseexblock(address),
-            &Command::WriteExPage { address, data } => self.render_writeexpage(address, data),
-            &Command::CrcRxBuffer => self.render_basic_cmd(count, CMD_CRCRX),
-            &Command::ReadRange { address, length } => self.render_readrange(address, length),
-            &Command::ExReadRange { address, length } => self.render_exreadrange(address, length),
-            &Command::SetAttr { index, key, value } => self.render_setattr(index, key, value),
-            &Command::GetAttr { index } => self.render_getattr(index),
-            &Command::CrcIntFlash { address, length } => self.render_crcintflash(address, length),
-            &Command::CrcExtFlash { address, length } => self.render_crcextflash(address, length),
-            &Command::EraseExPage { address } => self.render_eraseexpage(address),
-            &Command::ExtFlashInit => self.render_basic_cmd(count, CMD_XFINIT),
-            &Command::ClockOut => self.render_basic_cmd(count, CMD_CLKOUT),
-            &Command::WriteFlashUserPages { page1, page2 } => {
-                self.render_writeflashuserpages(page1, page2)
-            }
-            &Command::ChangeBaud { mode, baud } => self.render_changebaud(mode, baud),
+        let (inc, result) = match *self.response {
+            Response::Overflow => self.render_header(count, RES_OVERFLOW),
+            Response::Pong => self.render_header(count, RES_PONG),
+            Response::BadAddress => self.render_header(count, RES_BADADDR),
+            Response::InternalError => self.render_header(count, RES_INTERROR),
+            Response::BadArguments => self.render_header(count, RES_BADARGS),
+            Response::Ok => self.render_header(count, RES_OK),
+            Response::Unknown => self.render_header(count, RES_UNKNOWN),
+            #[cfg(feature = "ext-flash")]
+            Response::ExtFlashTimeout => self.render_header(count, RES_XFTIMEOUT),
+            #[cfg(feature = "ext-flash")]
+            Response::ExtFlashPageError => self.render_header(count, RES_XFEPE),
+            Response::CrcRxBuffer { length, crc } => self.render_crc_rx_buffer(length, crc),
+            Response::ReadRange { data } => self.render_read_range(data),
+            #[cfg(feature = "ext-flash")]
+            Response::ExReadRange { data } => self.render_ex_read_range(data),
+            #[cfg(feature = "attributes")]
+            Response::GetAttr { key, value } => self.render_get_attr(key, value),
+            Response::CrcIntFlash { crc } => self.render_crc_int_flash(crc),
+            #[cfg(feature = "ext-flash")]
+            Response::CrcExtFlash { crc } => self.render_crc_ex_flash(crc),
+            Response::Info { info } => self.render_info(info),
+            #[cfg(feature = "baud-change")]
+            Response::ChangeBaudFail => self.render_header(count, RES_CHANGE_BAUD_FAIL),
+            Response::Id { id } => self.render_id(id),
+            #[cfg(feature = "long-attributes")]
+            Response::GetLongAttr { key, value } => self.render_get_long_attr(key, value),
         };
-        self.count = self.count + inc;
+        self.count += inc;
         result
     }
 }
 
-impl<'a> ResponseEncoder<'a> {
-    /// Create a new `ResponseEncoder`.
-    ///
-    /// The encoder takes a reference to a `Command` to encode. The `next` method
-    /// will then supply the encoded bytes one at a time.
-    pub fn new(response: &'a Response) -> Result<ResponseEncoder<'a>, Error> {
-        match response {
-            &Response::GetAttr { key, value } => {
-                if key.len() != KEY_LEN {
-                    return Err(Error::BadArguments);
-                }
-                if value.len() > MAX_ATTR_LEN {
-                    return Err(Error::BadArguments);
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Decode the arguments of a command. `payload` is everything received
+/// before the escape and `opcode`.
+#[cfg(feature = "device")]
+#[cfg_attr(feature = "no-panic", no_panic::no_panic)]
+fn decode_command(opcode: u8, payload: &[u8]) -> Result<Option<Command<'_>>, Error> {
+    let desc = match COMMANDS.iter().find(|desc| desc.kind as u8 == opcode) {
+        Some(desc) => desc,
+        None => return Ok(None),
+    };
+    match desc.kind.arg_len() {
+        // Anything before a command without arguments is dropped
+        ArgLen::None => {}
+        ArgLen::Fixed(len) => check_len(opcode, payload, len)?,
+        ArgLen::Variable { min, .. } => check_min_len(opcode, payload, min)?,
+    }
+    (desc.decode)(payload).map(Some)
+}
+
+/// Decode a response with a payload. `frame` starts with the response
+/// byte.
+#[cfg(feature = "host")]
+#[cfg_attr(feature = "no-panic", no_panic::no_panic)]
+fn decode_response(frame: &[u8], info_mode: InfoMode) -> Result<Option<Response<'_>>, Error> {
+    let code = read_u8(frame, 0)?;
+    let payload = frame.get(1..).unwrap_or(&[]);
+    let response = match code {
+        RES_CRCRX => {
+            check_min_len(code, payload, 6)?;
+            Response::CrcRxBuffer {
+                length: read_u16(payload, 0)?,
+                crc: read_u32(payload, 2)?,
+            }
+        }
+        RES_RRANGE => Response::ReadRange { data: payload },
+        #[cfg(feature = "ext-flash")]
+        RES_XRRANGE => Response::ExReadRange { data: payload },
+        #[cfg(feature = "attributes")]
+        RES_GATTR => {
+            check_min_len(code, payload, KEY_LEN + 1)?;
+            let length = read_u8(payload, KEY_LEN)?;
+            let value = read_slice(payload, KEY_LEN + 1, length as usize).map_err(|_| {
+                Error::InvalidValue {
+                    field: Field::AttrLength,
+                    got: u32::from(length),
                 }
+            })?;
+            Response::GetAttr {
+                key: read_slice(payload, 0, KEY_LEN)?,
+                value,
             }
-            &Response::Info { info } => {
-                if info.len() > MAX_INFO_LEN {
-                    return Err(Error::BadArguments);
+        }
+        RES_CRCIF => {
+            check_min_len(code, payload, 4)?;
+            Response::CrcIntFlash {
+                crc: read_u32(payload, 0)?,
+            }
+        }
+        #[cfg(feature = "ext-flash")]
+        RES_CRCXF => {
+            check_min_len(code, payload, 4)?;
+            Response::CrcExtFlash {
+                crc: read_u32(payload, 0)?,
+            }
+        }
+        RES_INFO if info_mode == InfoMode::LengthPrefixed => {
+            let length = read_u8(payload, 0)?;
+            let info = read_slice(payload, 1, length as usize).map_err(|_| {
+                Error::InvalidValue {
+                    field: Field::InfoLength,
+                    got: u32::from(length),
+                }
+            })?;
+            Response::Info { info }
+        }
+        RES_INFO => Response::Info { info: payload },
+        RES_ID => Response::Id { id: payload },
+        #[cfg(feature = "long-attributes")]
+        RES_GLATTR => {
+            check_min_len(code, payload, KEY_LEN + 2)?;
+            let length = read_u16(payload, KEY_LEN)?;
+            let value = read_slice(payload, KEY_LEN + 2, length as usize).map_err(|_| {
+                Error::InvalidValue {
+                    field: Field::AttrLength,
+                    got: u32::from(length),
                 }
+            })?;
+            Response::GetLongAttr {
+                key: read_slice(payload, 0, KEY_LEN)?,
+                value,
             }
-            _ => {}
         }
-        Ok(ResponseEncoder {
-            response: response,
-            count: 0,
-            sent_escape: false,
-        })
-    }
+        _ => return Err(Error::UnknownCommand),
+    };
+    Ok(Some(response))
+}
 
-    fn render_byte(&mut self, byte: u8) -> (usize, Option<u8>) {
-        if byte == ESCAPE_CHAR {
-            if self.sent_escape {
-                self.sent_escape = false;
-                (1, Some(ESCAPE_CHAR))
-            } else {
-                self.sent_escape = true;
-                (0, Some(ESCAPE_CHAR))
+impl Error {
+    /// What went wrong, for `Display`.
+    fn message(self) -> &'static str {
+        match self {
+            Error::UnknownCommand => "unrecognised command or response code",
+            Error::BadArguments => "arguments were the wrong length or out of range",
+            Error::WrongLength { .. } => "a frame was the wrong length",
+            Error::InvalidValue { .. } => "a field in a frame held a value that isn't allowed",
+            Error::UnsetLength => {
+                "got a variable length response without a length set (call set_payload_len)"
             }
+            Error::SetLength => "a payload length was set, but the response has a fixed length",
+            Error::BufferFull => "not enough room in the buffer",
+            Error::MismatchedResponse => "the response doesn't go with the command sent",
+            Error::Refused => "the bootloader sent back an error response",
+            Error::CrcMismatch => "the CRC of flash doesn't match the data written",
+            Error::Overflow => "a frame was too long for the buffer",
+        }
+    }
+}
+
+impl Field {
+    /// What the field is called, for `Display`.
+    fn name(self) -> &'static str {
+        match self {
+            Field::BaudMode => "the baud mode",
+            Field::AttrLength => "the attribute length",
+            Field::AttrIndex => "the attribute index",
+            Field::InfoLength => "the info length",
+        }
+    }
+}
+
+#[cfg(feature = "host")]
+impl<'a> Frame<'a> {
+    /// Lay out `command`, checking it will encode the way the bootloader
+    /// expects.
+    fn new(command: &Command<'a>) -> Result<Frame<'a>, Error> {
+        check_command(command)?;
+        let kind = command.kind();
+        let mut frame = Frame {
+            head: [0u8; MAX_HEAD_LEN],
+            head_len: 0,
+            data: &[],
+            opcode: kind as u8,
+        };
+        // Every `Opcode` has a row
+        if let Some(desc) = COMMANDS.iter().find(|desc| desc.kind == kind) {
+            (desc.encode)(command, &mut frame);
+        }
+        let accepted = match *command {
+            // How long a value can be is up to the caller; see `check_attr`
+            #[cfg(feature = "attributes")]
+            Command::SetAttr { value, .. } => value.len() <= usize::from(u8::MAX),
+            _ => kind.arg_len().accepts(frame.args_len()),
+        };
+        if accepted {
+            Ok(frame)
         } else {
-            (1, Some(byte))
+            Err(Error::BadArguments)
         }
     }
 
-    fn render_crc_rx_buffer(&mut self, length: u16, crc: u32) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...1 => self.render_header(count, RES_CRCRX),
-            2...3 => self.render_u16(count - 2, length),
-            4...7 => self.render_u32(count - 4, crc),
-            _ => (0, None),
+    /// Anything past `MAX_HEAD_LEN` is dropped, but `check_command` makes
+    /// sure there never is any.
+    fn push(&mut self, bytes: &[u8]) {
+        let end = cmp::min(self.head_len + bytes.len(), MAX_HEAD_LEN);
+        if let Some(dest) = self.head.get_mut(self.head_len..end) {
+            dest.copy_from_slice(&bytes[0..dest.len()]);
+            self.head_len = end;
         }
     }
 
-    fn render_read_range(&mut self, data: &[u8]) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...1 => self.render_header(count, RES_RRANGE),
-            x if x < data.len() + 2 => self.render_byte(data[x - 2]),
-            _ => (0, None),
+    fn head(&self) -> &[u8] {
+        &self.head[0..self.head_len]
+    }
+
+    /// The number of argument bytes, before escaping.
+    fn args_len(&self) -> usize {
+        self.head_len + self.data.len()
+    }
+
+    fn arg(&self, idx: usize) -> u8 {
+        match idx.checked_sub(self.head_len) {
+            None => self.head[idx],
+            Some(idx) => self.data[idx],
         }
     }
 
-    fn render_ex_read_range(&mut self, data: &[u8]) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...1 => self.render_header(count, RES_XRRANGE),
-            x if x - 2 < data.len() => self.render_byte(data[x - 2]),
-            _ => (0, None),
+    /// The argument bytes from `idx` to the end of the head, or of the data
+    /// if `idx` is past the head.
+    fn args_from(&self, idx: usize) -> &[u8] {
+        match idx.checked_sub(self.head_len) {
+            None => &self.head[idx..self.head_len],
+            Some(idx) => self.data.get(idx..).unwrap_or(&[]),
         }
     }
+}
 
-    fn render_get_attr(&mut self, key: &[u8], value: &[u8]) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...1 => self.render_header(count, RES_GATTR),
-            2...9 => self.render_buffer(count - 2, 8, key),
-            10 => self.render_byte(value.len() as u8),
-            _ => self.render_buffer(count - 11, MAX_ATTR_LEN, value),
+/// Lay out a command with no arguments.
+#[cfg(feature = "host")]
+fn encode_none<'a>(_: &Command<'a>, _: &mut Frame<'a>) {}
+
+/// Lay out a command whose only argument is an address.
+#[cfg(feature = "host")]
+fn encode_address<'a>(command: &Command<'a>, frame: &mut Frame<'a>) {
+    match *command {
+        Command::ErasePage { address } => frame.push(&address.to_le_bytes()),
+        #[cfg(feature = "ext-flash")]
+        Command::EraseExBlock { address } | Command::EraseExPage { address } => {
+            frame.push(&address.to_le_bytes())
         }
+        _ => {}
     }
+}
 
-    fn render_crc_int_flash(&mut self, crc: u32) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...1 => self.render_header(count, RES_CRCIF),
-            _ => self.render_u32(count - 2, crc),
+/// Lay out a page write: the address, then the page.
+#[cfg(feature = "host")]
+fn encode_page<'a>(command: &Command<'a>, frame: &mut Frame<'a>) {
+    match *command {
+        Command::WritePage { address, data } => {
+            frame.push(&address.to_le_bytes());
+            frame.data = data;
         }
+        #[cfg(feature = "ext-flash")]
+        Command::WriteExPage { address, data } => {
+            frame.push(&address.to_le_bytes());
+            frame.data = data;
+        }
+        _ => {}
     }
+}
 
-    fn render_crc_ex_flash(&mut self, crc: u32) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...1 => self.render_header(count, RES_CRCXF),
-            _ => self.render_u32(count - 2, crc),
+/// Lay out a read: the address, then a two byte length.
+#[cfg(feature = "host")]
+fn encode_range<'a>(command: &Command<'a>, frame: &mut Frame<'a>) {
+    match *command {
+        Command::ReadRange { address, length } => {
+            frame.push(&address.to_le_bytes());
+            frame.push(&length.to_le_bytes());
         }
+        #[cfg(feature = "ext-flash")]
+        Command::ExReadRange { address, length } => {
+            frame.push(&address.to_le_bytes());
+            frame.push(&length.to_le_bytes());
+        }
+        _ => {}
     }
+}
 
-    fn render_info(&mut self, info: &[u8]) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...1 => self.render_header(count, RES_INFO),
-            _ => self.render_buffer(count - 2, info.len(), info),
+/// Lay out a command with two four byte arguments.
+#[cfg(feature = "host")]
+fn encode_words<'a>(command: &Command<'a>, frame: &mut Frame<'a>) {
+    let (first, second) = match *command {
+        Command::CrcIntFlash { address, length } => (address, length),
+        #[cfg(feature = "ext-flash")]
+        Command::CrcExtFlash { address, length } => (address, length),
+        #[cfg(feature = "user-pages")]
+        Command::WriteFlashUserPages { page1, page2 } => (page1, page2),
+        _ => return,
+    };
+    frame.push(&first.to_le_bytes());
+    frame.push(&second.to_le_bytes());
+}
+
+/// Lay out a command whose only argument is one byte.
+#[cfg(all(feature = "host", any(feature = "attributes", feature = "multi-drop")))]
+fn encode_index<'a>(command: &Command<'a>, frame: &mut Frame<'a>) {
+    let index = match *command {
+        #[cfg(feature = "attributes")]
+        Command::GetAttr { index } => index,
+        #[cfg(feature = "long-attributes")]
+        Command::GetLongAttr { index } => index,
+        #[cfg(feature = "multi-drop")]
+        Command::SetAddress { address } => address,
+        _ => return,
+    };
+    frame.push(&[index]);
+}
+
+/// Lay out an attribute write: the index, key and length, then the value.
+#[cfg(all(feature = "host", feature = "attributes"))]
+fn encode_set_attr<'a>(command: &Command<'a>, frame: &mut Frame<'a>) {
+    match *command {
+        Command::SetAttr { index, key, value } => {
+            frame.push(&[index]);
+            frame.push(key);
+            frame.push(&[value.len() as u8]);
+            frame.data = value;
+        }
+        #[cfg(feature = "long-attributes")]
+        Command::SetLongAttr { index, key, value } => {
+            frame.push(&[index]);
+            frame.push(key);
+            frame.push(&(value.len() as u16).to_le_bytes());
+            frame.data = value;
+        }
+        _ => {}
+    }
+}
+
+/// Lay out a baud rate change: the mode, then the rate.
+#[cfg(all(feature = "host", feature = "baud-change"))]
+fn encode_change_baud<'a>(command: &Command<'a>, frame: &mut Frame<'a>) {
+    if let Command::ChangeBaud { mode, baud } = *command {
+        frame.push(&[match mode {
+            BaudMode::Set => 0x01,
+            BaudMode::Verify => 0x02,
+        }]);
+        frame.push(&baud.to_le_bytes());
+    }
+}
+
+/// Check a command will encode the way the bootloader expects. We have to
+/// accept slices rather than arrays, so they are bounds checked to save
+/// surprises later: the key here, and everything else against
+/// `Opcode::arg_len` once the frame is laid out.
+#[cfg(feature = "host")]
+fn check_command(command: &Command) -> Result<(), Error> {
+    match *command {
+        #[cfg(feature = "attributes")]
+        Command::SetAttr { key, .. } if key.len() != KEY_LEN => Err(Error::BadArguments),
+        #[cfg(feature = "long-attributes")]
+        Command::SetLongAttr { key, .. } if key.len() != KEY_LEN => Err(Error::BadArguments),
+        _ => Ok(()),
+    }
+}
+
+/// Check the index of a `SetAttr` or `GetAttr` is below `slots`, and the
+/// value of a `SetAttr` is no more than `len` bytes. Long attributes have
+/// the same slot limit, but their values can be up to `MAX_LONG_ATTR_LEN`
+/// bytes.
+#[cfg(all(feature = "attributes", any(feature = "host", feature = "device")))]
+fn check_attr(command: &Command, slots: u8, len: usize) -> Result<(), Error> {
+    match *command {
+        Command::SetAttr { index, .. } | Command::GetAttr { index } if index >= slots => {
+            Err(Error::InvalidValue {
+                field: Field::AttrIndex,
+                got: u32::from(index),
+            })
+        }
+        Command::SetAttr { value, .. } if value.len() > len => Err(Error::InvalidValue {
+            field: Field::AttrLength,
+            got: value.len() as u32,
+        }),
+        #[cfg(feature = "long-attributes")]
+        Command::SetLongAttr { index, .. } | Command::GetLongAttr { index } if index >= slots => {
+            Err(Error::InvalidValue {
+                field: Field::AttrIndex,
+                got: u32::from(index),
+            })
+        }
+        #[cfg(feature = "long-attributes")]
+        Command::SetLongAttr { value, .. } if value.len() > MAX_LONG_ATTR_LEN => {
+            Err(Error::InvalidValue {
+                field: Field::AttrLength,
+                got: value.len() as u32,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Copy `bytes` into `buffer` after the first `len` bytes, sending each
+/// escape twice. Returns the new length.
+#[cfg(feature = "host")]
+fn escape_into(buffer: &mut [u8], mut len: usize, mut bytes: &[u8]) -> Result<usize, Error> {
+    while !bytes.is_empty() {
+        let run = escape_free_len(bytes);
+        len = copy_into(buffer, len, &bytes[0..run])?;
+        if run < bytes.len() {
+            len = copy_into(buffer, len, &[ESCAPE_CHAR, ESCAPE_CHAR])?;
+            bytes = &bytes[run + 1..];
+        } else {
+            bytes = &[];
+        }
+    }
+    Ok(len)
+}
+
+/// Copy `bytes` into `buffer` after the first `len` bytes. Returns the new
+/// length.
+#[cfg(feature = "host")]
+fn copy_into(buffer: &mut [u8], len: usize, bytes: &[u8]) -> Result<usize, Error> {
+    let end = len + bytes.len();
+    let dest = buffer.get_mut(len..end).ok_or(Error::BufferFull)?;
+    dest.copy_from_slice(bytes);
+    Ok(end)
+}
+
+/// How many bytes there are before the first escape.
+#[cfg(all(any(feature = "host", feature = "device"), not(feature = "fast-scan")))]
+fn escape_free_len(bytes: &[u8]) -> usize {
+    bytes.iter().position(|&b| b == ESCAPE_CHAR).unwrap_or(bytes.len())
+}
+
+/// How many bytes there are before the first escape, checking a word at a
+/// time once the bytes are aligned.
+#[cfg(all(any(feature = "host", feature = "device"), feature = "fast-scan"))]
+fn escape_free_len(bytes: &[u8]) -> usize {
+    const ONES: u32 = 0x0101_0101;
+    const HIGHS: u32 = 0x8080_8080;
+    const ESCAPES: u32 = ONES * ESCAPE_CHAR as u32;
+    let head = cmp::min(bytes.as_ptr().align_offset(4), bytes.len());
+    let (start, words) = bytes.split_at(head);
+    if let Some(len) = start.iter().position(|&b| b == ESCAPE_CHAR) {
+        return len;
+    }
+    let mut len = head;
+    for word in words.chunks_exact(4) {
+        // A byte of `w` is zero where there was an escape, and subtracting
+        // one from a zero byte sets its top bit.
+        let w = u32::from_ne_bytes([word[0], word[1], word[2], word[3]]) ^ ESCAPES;
+        if w.wrapping_sub(ONES) & !w & HIGHS != 0 {
+            break;
+        }
+        len += 4;
+    }
+    let rest = bytes.get(len..).unwrap_or(&[]);
+    len + rest.iter().position(|&b| b == ESCAPE_CHAR).unwrap_or(rest.len())
+}
+
+/// Copy as much of `run` as fits into `buffer` after the first `count`
+/// bytes, dropping the rest as `load_char` would. Returns the new count.
+#[cfg(any(feature = "host", feature = "device"))]
+fn copy_run(buffer: &mut [u8], count: usize, run: &[u8]) -> usize {
+    let len = run.len().min(buffer.len().saturating_sub(count));
+    match (buffer.get_mut(count..count + len), run.get(0..len)) {
+        (Some(dest), Some(src)) => {
+            dest.copy_from_slice(src);
+            count + len
         }
+        _ => count,
     }
+}
 
-    fn render_u16(&mut self, idx: usize, value: u16) -> (usize, Option<u8>) {
-        match idx {
-            0 => self.render_byte(value as u8),
-            1 => self.render_byte((value >> 8) as u8),
-            _ => (0, None),
-        }
+#[cfg(feature = "device")]
+fn check_len(opcode: u8, payload: &[u8], len: usize) -> Result<(), Error> {
+    if payload.len() == len {
+        Ok(())
+    } else {
+        Err(wrong_length(opcode, len, payload.len()))
     }
+}
 
-    fn render_u32(&mut self, idx: usize, value: u32) -> (usize, Option<u8>) {
-        match idx {
-            0 => self.render_byte(value as u8),
-            1 => self.render_byte((value >> 8) as u8),
-            2 => self.render_byte((value >> 16) as u8),
-            3 => self.render_byte((value >> 24) as u8),
-            _ => (0, None),
+#[cfg(any(
+    all(feature = "multi-drop", any(feature = "host", feature = "device")),
+    all(feature = "sequence", feature = "host")
+))]
+impl Header {
+    const fn new() -> Header {
+        Header {
+            address: None,
+            sequence: None,
+            sent: 0,
         }
     }
 
-    fn render_buffer(&mut self, idx: usize, page_size: usize, data: &[u8]) -> (usize, Option<u8>) {
-        if (idx < data.len()) && (idx < page_size) {
-            self.render_byte(data[idx])
-        } else if idx < page_size {
-            self.render_byte(0xFF) // pad short data with 0xFFs
-        } else {
-            (0, None)
-        }
+    /// The next header byte, or `None` once it has all gone. The address
+    /// goes first, so a board can tell straight away if it's listening.
+    fn next(&mut self) -> Option<u8> {
+        let mut bytes = self.address.iter().chain(self.sequence.iter()).flatten();
+        let byte = bytes.nth(self.sent).cloned()?;
+        self.sent += 1;
+        Some(byte)
     }
 
-    fn render_header(&mut self, count: usize, cmd: u8) -> (usize, Option<u8>) {
-        match count {
-            0 => (1, Some(ESCAPE_CHAR)), // Escape
-            1 => (1, Some(cmd)), // Command
-            _ => (0, None),
-        }
+    #[cfg(feature = "host")]
+    fn is_done(&self) -> bool {
+        self.sent >= 3 * (self.address.iter().count() + self.sequence.iter().count())
     }
 }
 
-impl<'a> Iterator for ResponseEncoder<'a> {
-    type Item = u8;
+/// Check a frame has at least `len` bytes of payload.
+#[cfg(any(feature = "host", feature = "device"))]
+fn check_min_len(opcode: u8, payload: &[u8], len: usize) -> Result<(), Error> {
+    if payload.len() >= len {
+        Ok(())
+    } else {
+        Err(wrong_length(opcode, len, payload.len()))
+    }
+}
 
-    /// Supply the next encoded byte. Once all the bytes have been emitted, it
-    /// returns `None` forevermore.
-    fn next(&mut self) -> Option<u8> {
-        let count = self.count;
-        let (inc, result) = match self.response {
-            &Response::Overflow => self.render_header(count, RES_OVERFLOW),
-            &Response::Pong => self.render_header(count, RES_PONG),
-            &Response::BadAddress => self.render_header(count, RES_BADADDR),
-            &Response::InternalError => self.render_header(count, RES_INTERROR),
-            &Response::BadArguments => self.render_header(count, RES_BADARGS),
-            &Response::Ok => self.render_header(count, RES_OK),
-            &Response::Unknown => self.render_header(count, RES_UNKNOWN),
-            &Response::ExtFlashTimeout => self.render_header(count, RES_XFTIMEOUT),
-            &Response::ExtFlashPageError => self.render_header(count, RES_XFEPE),
-            &Response::CrcRxBuffer { length, crc } => self.render_crc_rx_buffer(length, crc),
-            &Response::ReadRange { data } => self.render_read_range(data),
-            &Response::ExReadRange { data } => self.render_ex_read_range(data),
-            &Response::GetAttr { key, value } => self.render_get_attr(key, value),
-            &Response::CrcIntFlash { crc } => self.render_crc_int_flash(crc),
-            &Response::CrcExtFlash { crc } => self.render_crc_ex_flash(crc),
-            &Response::Info { info } => self.render_info(info),
-            &Response::ChangeBaudFail => self.render_header(count, RES_CHANGE_BAUD_FAIL),
-        };
-        self.count = self.count + inc;
-        result
+#[cfg(any(feature = "host", feature = "device"))]
+fn wrong_length(opcode: u8, expected: usize, got: usize) -> Error {
+    Error::WrongLength {
+        opcode,
+        expected,
+        got,
     }
 }
 
-// ****************************************************************************
-//
-// Private Impl/Functions/Modules
-//
-// ****************************************************************************
+/// Check some arguments are exactly `len` bytes.
+fn expect_len(payload: &[u8], len: usize) -> Result<(), Error> {
+    if payload.len() == len {
+        Ok(())
+    } else {
+        Err(Error::BadArguments)
+    }
+}
+
+/// `len` bytes from `offset`, or `Error::BadArguments` if there aren't
+/// that many.
+#[cfg(any(feature = "host", feature = "device"))]
+fn read_slice(buffer: &[u8], offset: usize, len: usize) -> Result<&[u8], Error> {
+    let end = offset.checked_add(len).ok_or(Error::BadArguments)?;
+    buffer.get(offset..end).ok_or(Error::BadArguments)
+}
+
+#[cfg(any(
+    feature = "host",
+    all(
+        feature = "device",
+        any(feature = "attributes", feature = "baud-change", feature = "multi-drop")
+    )
+))]
+fn read_u8(buffer: &[u8], offset: usize) -> Result<u8, Error> {
+    buffer.get(offset).cloned().ok_or(Error::BadArguments)
+}
+
+#[cfg(any(feature = "host", feature = "device"))]
+fn read_u16(buffer: &[u8], offset: usize) -> Result<u16, Error> {
+    read_slice(buffer, offset, 2).map(LittleEndian::read_u16)
+}
+
+#[cfg(any(feature = "host", feature = "device"))]
+fn read_u32(buffer: &[u8], offset: usize) -> Result<u32, Error> {
+    read_slice(buffer, offset, 4).map(LittleEndian::read_u32)
+}
 
-#[cfg(test)]
+#[cfg(all(test, feature = "host", feature = "device"))]
 mod tests {
     use super::*;
 
@@ -1255,13 +3503,12 @@ mod tests {
         match p.receive(CMD_WPAGE) {
             Ok(Some(Command::WritePage {
                         address,
-                        data: ref page,
+                        data: page,
                     })) => {
                 assert_eq!(address, 0xDEADBEEF);
                 assert_eq!(page.len(), INT_PAGE_SIZE);
-                for i in 0..INT_PAGE_SIZE {
-                    let datum = i as u8;
-                    assert_eq!(datum, page[i as usize]);
+                for (i, datum) in page.iter().enumerate() {
+                    assert_eq!(i as u8, *datum);
                 }
             }
             e => panic!("Did not expect: {:?}", e),
@@ -1297,6 +3544,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "ext-flash")]
     fn check_cmd_erase_block_decode() {
         let mut p = CommandDecoder::new();
         assert_eq!(p.receive(0xEF), Ok(None));
@@ -1313,91 +3561,545 @@ mod tests {
     }
 
     #[test]
-    fn check_cmd_erase_block_encode() {
-        let cmd = Command::EraseExBlock { address: 0xDEADBEEF };
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        // 4 byte address, little-endian
-        assert_eq!(e.next(), Some(0xEF));
-        assert_eq!(e.next(), Some(0xBE));
-        assert_eq!(e.next(), Some(0xAD));
-        assert_eq!(e.next(), Some(0xDE));
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_XEBLOCK));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+    #[cfg(feature = "ext-flash")]
+    fn check_cmd_erase_block_encode() {
+        let cmd = Command::EraseExBlock { address: 0xDEADBEEF };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        // 4 byte address, little-endian
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_XEBLOCK));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "ext-flash")]
+    fn check_cmd_write_ex_page_decode() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0xAD), Ok(None));
+        assert_eq!(p.receive(0xDE), Ok(None));
+        for i in 0..EXT_PAGE_SIZE {
+            let datum = i as u8;
+            assert_eq!(p.receive(datum), Ok(None));
+            if datum == ESCAPE_CHAR {
+                assert_eq!(p.receive(datum), Ok(None));
+            }
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_XWPAGE) {
+            Ok(Some(Command::WriteExPage {
+                        address,
+                        data: page,
+                    })) => {
+                assert_eq!(address, 0xDEADBEEF);
+                assert_eq!(page.len(), EXT_PAGE_SIZE);
+                for (i, datum) in page.iter().enumerate() {
+                    assert_eq!(i as u8, *datum);
+                }
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "ext-flash")]
+    fn check_cmd_write_ex_page_encode() {
+        let mut buffer = [0xBBu8; EXT_PAGE_SIZE];
+        buffer[0] = 0xAA;
+        buffer[EXT_PAGE_SIZE - 1] = 0xCC;
+        let cmd = Command::WriteExPage {
+            address: 0xDEADBEEF,
+            data: &buffer,
+        };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        // 4 byte address, little-endian
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        // first byte of data
+        assert_eq!(e.next(), Some(0xAA));
+        for _ in 1..(EXT_PAGE_SIZE - 1) {
+            assert_eq!(e.next(), Some(0xBB));
+        }
+        // last byte of data
+        assert_eq!(e.next(), Some(0xCC));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_XWPAGE));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn check_cmd_next_chunk() {
+        let mut buffer = [0xBBu8; INT_PAGE_SIZE];
+        buffer[10] = ESCAPE_CHAR;
+        buffer[INT_PAGE_SIZE - 1] = ESCAPE_CHAR;
+        let cmd = Command::WritePage {
+            address: 0xDEADBEEF,
+            data: &buffer,
+        };
+        let mut expected = CommandEncoder::new(&cmd).unwrap();
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        let mut total = 0;
+        while let Some(chunk) = e.next_chunk(48) {
+            assert!(!chunk.is_empty() && chunk.len() <= 48);
+            for byte in chunk {
+                assert_eq!(expected.next(), Some(*byte));
+            }
+            total += chunk.len();
+        }
+        assert_eq!(expected.next(), None);
+        // Address, page, two doubled escapes, escape and command byte
+        assert_eq!(total, 4 + INT_PAGE_SIZE + 2 + 2);
+        assert_eq!(e.next_chunk(48), None);
+    }
+
+    #[test]
+    fn check_cmd_reset_and_clone() {
+        let cmd = Command::ReadRange {
+            address: 0x0000_FCFC,
+            length: 0x00FC,
+        };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        let mut first = [0u8; 16];
+        let mut len = 0;
+        for byte in e.by_ref() {
+            first[len] = byte;
+            len += 1;
+        }
+        e.reset();
+        assert!(e.clone().eq(first[0..len].iter().cloned()));
+        // Rewind part way through an escape sequence
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        e.reset();
+        assert!(e.eq(first[0..len].iter().cloned()));
+    }
+
+    #[test]
+    fn check_cmd_next_chunk_limit() {
+        let buffer = [0x00u8; INT_PAGE_SIZE];
+        let cmd = Command::WritePage {
+            address: 0,
+            data: &buffer,
+        };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        assert_eq!(e.next_chunk(0).map(|c| c.len()), Some(1));
+        assert_eq!(e.next_chunk(1000).map(|c| c.len()), Some(MAX_CHUNK_LEN));
+    }
+
+    #[test]
+    fn check_cmd_next_chunk_runs() {
+        let mut buffer = [0x11u8; INT_PAGE_SIZE];
+        buffer[0] = ESCAPE_CHAR;
+        buffer[100] = ESCAPE_CHAR;
+        buffer[101] = ESCAPE_CHAR;
+        let cmd = Command::WritePage {
+            address: 0x0003_FC00,
+            data: &buffer,
+        };
+        for max_len in 1..8 {
+            let mut expected = CommandEncoder::new(&cmd).unwrap();
+            let mut e = CommandEncoder::new(&cmd).unwrap();
+            #[cfg(feature = "sequence")]
+            {
+                expected.set_sequence(Some(ESCAPE_CHAR));
+                e.set_sequence(Some(ESCAPE_CHAR));
+            }
+            while let Some(chunk) = e.next_chunk(max_len) {
+                for byte in chunk {
+                    assert_eq!(expected.next(), Some(*byte));
+                }
+            }
+            assert_eq!(expected.next(), None);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "host")]
+    fn check_cmd_next_and_next_chunk() {
+        let mut page = [0x11u8; INT_PAGE_SIZE];
+        page[3] = ESCAPE_CHAR;
+        page[200] = ESCAPE_CHAR;
+        let cmd = Command::WritePage {
+            address: 0x0003_FC00,
+            data: &page,
+        };
+        let mut buffer = [0u8; 2 * MAX_FRAME_LEN];
+        let len = encode_all(&cmd, &mut buffer).unwrap();
+        // Bytes from the iterator and from chunks can be mixed freely
+        for max_len in 1..8 {
+            let mut e = CommandEncoder::new(&cmd).unwrap();
+            let mut offset = 0;
+            while let Some(byte) = e.next() {
+                assert_eq!(byte, buffer[offset]);
+                offset += 1;
+                if let Some(chunk) = e.next_chunk(max_len) {
+                    assert_eq!(chunk, &buffer[offset..offset + chunk.len()]);
+                    offset += chunk.len();
+                }
+            }
+            assert_eq!(offset, len);
+            assert_eq!(e.next_chunk(max_len), None);
+        }
+        // A reset drops whatever was left in the window
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        e.nth(10);
+        e.reset();
+        assert!(buffer[0..len].iter().cloned().eq(e));
+    }
+
+    // Test CMD_CRCRX here
+    // Test CMD_RRANGE here
+    // Test CMD_XRRANGE here
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_cmd_set_attr_decode() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0x03), Ok(None));
+        for &b in b"board\0\0\0" {
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        assert_eq!(p.receive(0x04), Ok(None));
+        for &b in b"hail" {
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(
+            p.receive(CMD_SATTR),
+            Ok(Some(Command::SetAttr {
+                index: 3,
+                key: b"board\0\0\0",
+                value: b"hail",
+            }))
+        );
+        // Length byte doesn't match the number of value bytes
+        for &b in &[0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0x04, 0xAA, ESCAPE_CHAR] {
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        assert_eq!(
+            p.receive(CMD_SATTR),
+            Err(Error::WrongLength {
+                opcode: CMD_SATTR,
+                expected: 14,
+                got: 11,
+            })
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_cmd_set_attr_encode() {
+        let cmd = Command::SetAttr {
+            index: 3,
+            key: b"board\0\0\0",
+            value: b"hail",
+        };
+        let e = CommandEncoder::new(&cmd).unwrap();
+        let expected = [
+            0x03, b'b', b'o', b'a', b'r', b'd', 0, 0, 0, 0x04, b'h', b'a', b'i', b'l',
+            ESCAPE_CHAR, CMD_SATTR,
+        ];
+        assert!(e.eq(expected.iter().cloned()));
+
+        // An empty value is allowed
+        let cmd = Command::SetAttr {
+            index: 3,
+            key: b"board\0\0\0",
+            value: b"",
+        };
+        let e = CommandEncoder::new(&cmd).unwrap();
+        let expected = [0x03, b'b', b'o', b'a', b'r', b'd', 0, 0, 0, 0x00, ESCAPE_CHAR, CMD_SATTR];
+        assert!(e.eq(expected.iter().cloned()));
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_attr_len() {
+        let value = [0xA5u8; 64];
+        let cmd = Command::SetAttr {
+            index: 2,
+            key: b"longattr",
+            value: &value,
+        };
+        assert_eq!(CommandEncoder::new(&cmd).err(), Some(Error::BadArguments));
+        let e = CommandEncoder::with_attr_limits(&cmd, MAX_INDEX, 64).unwrap();
+        let mut p = CommandDecoder::new();
+        let mut last = Ok(false);
+        for byte in e.clone() {
+            last = p.receive(byte).map(|c| c.is_some());
+        }
+        assert_eq!(
+            last,
+            Err(Error::InvalidValue {
+                field: Field::AttrLength,
+                got: 64,
+            })
+        );
+        p.set_attr_len(64);
+        let mut decoded = None;
+        for byte in e {
+            if let Some(c) = p.receive(byte).unwrap() {
+                decoded = Some(c == cmd);
+            }
+        }
+        assert_eq!(decoded, Some(true));
+
+        let r = Response::GetAttr {
+            key: b"longattr",
+            value: &value[0..60],
+        };
+        assert_eq!(ResponseEncoder::new(&r).err(), Some(Error::BadArguments));
+        assert_eq!(ResponseEncoder::with_attr_len(&r, 256).err(), Some(Error::BadArguments));
+        let e = ResponseEncoder::with_attr_len(&r, 64).unwrap();
+        assert_eq!(e.clone().count(), 2 + KEY_LEN + 1 + 64);
+        let mut p = ResponseDecoder::new();
+        p.set_attr_len(64);
+        let mut decoded = None;
+        for byte in e {
+            if let Some(rsp) = p.receive(byte).unwrap() {
+                decoded = Some(rsp == r);
+            }
+        }
+        assert_eq!(decoded, Some(true));
+    }
+
+    #[test]
+    #[cfg(feature = "long-attributes")]
+    fn check_long_attr() {
+        let mut value = [0xA5u8; MAX_LONG_ATTR_LEN];
+        value[0] = ESCAPE_CHAR;
+        let cmd = Command::SetLongAttr {
+            index: 2,
+            key: b"wifipass",
+            value: &value[0..300],
+        };
+        let e = CommandEncoder::new(&cmd).unwrap();
+        // The length is little endian, and the escape is doubled
+        let expected = [0x02, b'w', b'i', b'f', b'i', b'p', b'a', b's', b's', 0x2C, 0x01, 0xFC];
+        assert!(e.clone().take(12).eq(expected.iter().cloned()));
+        assert_eq!(e.clone().count(), 1 + KEY_LEN + 2 + 300 + 1 + 2);
+        let mut p = CommandDecoder::new();
+        let mut decoded = None;
+        for byte in e {
+            if let Some(c) = p.receive(byte).unwrap() {
+                decoded = Some(c == cmd);
+            }
+        }
+        assert_eq!(decoded, Some(true));
+
+        let cmd = Command::SetLongAttr {
+            index: 2,
+            key: b"wifipass",
+            value: &[0u8; MAX_LONG_ATTR_LEN + 1],
+        };
+        assert_eq!(CommandEncoder::new(&cmd).err(), Some(Error::BadArguments));
+        let cmd = Command::GetLongAttr { index: MAX_INDEX };
+        assert_eq!(CommandEncoder::new(&cmd).err(), Some(Error::BadArguments));
+
+        let r = Response::GetLongAttr {
+            key: b"wifipass",
+            value: &value[0..300],
+        };
+        let e = ResponseEncoder::new(&r).unwrap();
+        // Padded out to the full slot
+        assert_eq!(e.clone().count(), 2 + KEY_LEN + 2 + MAX_LONG_ATTR_LEN + 1);
+        let mut p = ResponseDecoder::new();
+        let mut decoded = None;
+        for byte in e {
+            if let Some(rsp) = p.receive(byte).unwrap() {
+                decoded = Some(rsp == r);
+            }
+        }
+        assert_eq!(decoded, Some(true));
+
+        // An erased slot has a length of 0xFFFF
+        let mut p = ResponseDecoder::new();
+        p.receive(ESCAPE_CHAR).unwrap();
+        p.receive(RES_GLATTR).unwrap();
+        let mut last = Ok(None);
+        for _ in 0..KEY_LEN + 2 + MAX_LONG_ATTR_LEN {
+            last = p.receive(0xFF);
+        }
+        assert_eq!(
+            last,
+            Err(Error::InvalidValue {
+                field: Field::AttrLength,
+                got: 0xFFFF,
+            })
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_cmd_attr_index() {
+        let cmd = Command::GetAttr { index: MAX_INDEX };
+        assert_eq!(CommandEncoder::new(&cmd).err(), Some(Error::BadArguments));
+        let mut buffer = [0u8; 8];
+        assert_eq!(encode_all(&cmd, &mut buffer), Err(Error::BadArguments));
+        let e = CommandEncoder::with_attr_slots(&cmd, 32).unwrap();
+        let mut p = CommandDecoder::new();
+        let mut last = Ok(false);
+        for byte in e.clone() {
+            last = p.receive(byte).map(|c| c.is_some());
+        }
+        assert_eq!(
+            last,
+            Err(Error::InvalidValue {
+                field: Field::AttrIndex,
+                got: 16,
+            })
+        );
+        p.set_attr_slots(32);
+        let mut decoded = None;
+        for byte in e {
+            if let Some(c) = p.receive(byte).unwrap() {
+                decoded = Some(c == cmd);
+            }
+        }
+        assert_eq!(decoded, Some(true));
+    }
+
+    // Test CMD_GATTR here
+    // Test CMD_CRCIF here
+    // Test CMD_CRCEF here
+    // Test CMD_XEPAGE here
+    // Test CMD_XFINIT here
+    // Test CMD_CLKOUT here
+    // Test CMD_WUSER here
+
+    #[test]
+    #[cfg(feature = "baud-change")]
+    fn check_cmd_change_baud_decode() {
+        let mut p = CommandDecoder::new();
+        // Set 921600 baud (0x000E1000)
+        for &b in &[0x01, 0x00, 0x10, 0x0E, 0x00, ESCAPE_CHAR] {
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        assert_eq!(
+            p.receive(CMD_CHANGE_BAUD),
+            Ok(Some(Command::ChangeBaud {
+                mode: BaudMode::Set,
+                baud: 921600,
+            }))
+        );
+        for &b in &[0x02, 0x00, 0x10, 0x0E, 0x00, ESCAPE_CHAR] {
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        assert_eq!(
+            p.receive(CMD_CHANGE_BAUD),
+            Ok(Some(Command::ChangeBaud {
+                mode: BaudMode::Verify,
+                baud: 921600,
+            }))
+        );
+        for &b in &[0x03, 0x00, 0x10, 0x0E, 0x00, ESCAPE_CHAR] {
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        assert_eq!(
+            p.receive(CMD_CHANGE_BAUD),
+            Err(Error::InvalidValue {
+                field: Field::BaudMode,
+                got: 3,
+            })
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "baud-change")]
+    fn check_cmd_change_baud_encode() {
+        // These are the bytes tockloader sends for `struct.pack('<BI', mode,
+        // 115200)` followed by the escape and command.
+        let cmd = Command::ChangeBaud {
+            mode: BaudMode::Set,
+            baud: 115200,
+        };
+        let e = CommandEncoder::new(&cmd).unwrap();
+        let expected = [0x01, 0x00, 0xC2, 0x01, 0x00, ESCAPE_CHAR, CMD_CHANGE_BAUD];
+        assert!(e.eq(expected.iter().cloned()));
+
+        let cmd = Command::ChangeBaud {
+            mode: BaudMode::Verify,
+            baud: 115200,
+        };
+        let e = CommandEncoder::new(&cmd).unwrap();
+        let expected = [0x02, 0x00, 0xC2, 0x01, 0x00, ESCAPE_CHAR, CMD_CHANGE_BAUD];
+        assert!(e.eq(expected.iter().cloned()));
     }
 
     #[test]
-    fn check_cmd_write_ex_page_decode() {
-        let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(0xEF), Ok(None));
-        assert_eq!(p.receive(0xBE), Ok(None));
-        assert_eq!(p.receive(0xAD), Ok(None));
-        assert_eq!(p.receive(0xDE), Ok(None));
-        for i in 0..EXT_PAGE_SIZE {
-            let datum = i as u8;
-            assert_eq!(p.receive(datum), Ok(None));
-            if datum == ESCAPE_CHAR {
-                assert_eq!(p.receive(datum), Ok(None));
-            }
-        }
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_XWPAGE) {
-            Ok(Some(Command::WriteExPage {
-                        address,
-                        data: ref page,
-                    })) => {
-                assert_eq!(address, 0xDEADBEEF);
-                assert_eq!(page.len(), EXT_PAGE_SIZE);
-                for i in 0..EXT_PAGE_SIZE {
-                    let datum = i as u8;
-                    assert_eq!(datum, page[i as usize]);
+    #[cfg(feature = "baud-change")]
+    fn check_cmd_change_baud_roundtrip() {
+        for &mode in &[BaudMode::Set, BaudMode::Verify] {
+            // 0x00FC00FC puts an escape character in the baud rate
+            for &baud in &[9600, 115200, 1_000_000, 0x00FC_00FC] {
+                let cmd = Command::ChangeBaud { mode, baud };
+                let mut p = CommandDecoder::new();
+                let mut decoded = 0;
+                for byte in CommandEncoder::new(&cmd).unwrap() {
+                    if let Some(c) = p.receive(byte).unwrap() {
+                        assert_eq!(c, Command::ChangeBaud { mode, baud });
+                        decoded += 1;
+                    }
                 }
+                assert_eq!(decoded, 1);
             }
-            e => panic!("Did not expect: {:?}", e),
         }
     }
 
     #[test]
-    fn check_cmd_write_ex_page_encode() {
-        let mut buffer = [0xBBu8; EXT_PAGE_SIZE];
-        buffer[0] = 0xAA;
-        buffer[EXT_PAGE_SIZE - 1] = 0xCC;
-        let cmd = Command::WriteExPage {
-            address: 0xDEADBEEF,
-            data: &buffer,
-        };
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        // 4 byte address, little-endian
-        assert_eq!(e.next(), Some(0xEF));
-        assert_eq!(e.next(), Some(0xBE));
-        assert_eq!(e.next(), Some(0xAD));
-        assert_eq!(e.next(), Some(0xDE));
-        // first byte of data
-        assert_eq!(e.next(), Some(0xAA));
-        for _ in 1..(EXT_PAGE_SIZE - 1) {
-            assert_eq!(e.next(), Some(0xBB));
+    fn check_cmd_table() {
+        let mut last = 0;
+        for desc in COMMANDS {
+            let opcode = desc.kind as u8;
+            assert!(opcode > last);
+            assert_eq!(Opcode::try_from(opcode), Ok(desc.kind));
+            last = opcode;
         }
-        // last byte of data
-        assert_eq!(e.next(), Some(0xCC));
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_XWPAGE));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
     }
 
-    // Test CMD_CRCRX here
-    // Test CMD_RRANGE here
-    // Test CMD_XRRANGE here
-    // Test CMD_SATTR here
-    // Test CMD_GATTR here
-    // Test CMD_CRCIF here
-    // Test CMD_CRCEF here
-    // Test CMD_XEPAGE here
-    // Test CMD_XFINIT here
-    // Test CMD_CLKOUT here
-    // Test CMD_WUSER here
-    // Test CMD_CHANGE_BAUD here
+    #[test]
+    fn check_cmd_wrong_length() {
+        let mut p = CommandDecoder::new();
+        for &byte in &[0x00, 0x01, 0x02, ESCAPE_CHAR] {
+            assert_eq!(p.receive(byte), Ok(None));
+        }
+        assert_eq!(
+            p.receive(CMD_EPAGE),
+            Err(Error::WrongLength {
+                opcode: CMD_EPAGE,
+                expected: 4,
+                got: 3,
+            })
+        );
+        // The buffer is dropped, so the next command is fine
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+    }
+
+    #[test]
+    fn check_cmd_overflow() {
+        let mut p = CommandDecoder::new();
+        for _ in 0..MAX_FRAME_LEN + 1 {
+            assert_eq!(p.receive(0x00), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_WPAGE), Err(Error::Overflow));
+        // The next frame starts afresh
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+
+        let mut bytes = [0u8; MAX_FRAME_LEN + 3];
+        bytes[MAX_FRAME_LEN + 1] = ESCAPE_CHAR;
+        bytes[MAX_FRAME_LEN + 2] = CMD_WPAGE;
+        assert_eq!(p.push_bytes(&bytes), (bytes.len(), Err(Error::Overflow)));
+    }
 
     // Responses
 
@@ -1452,16 +4154,19 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "ext-flash")]
     fn check_rsp_exflashtimeout() {
         check_rsp_generic(Response::ExtFlashTimeout, RES_XFTIMEOUT);
     }
 
     #[test]
+    #[cfg(feature = "ext-flash")]
     fn check_rsp_exflashpageerror() {
         check_rsp_generic(Response::ExtFlashPageError, RES_XFEPE);
     }
 
     #[test]
+    #[cfg(feature = "baud-change")]
     fn check_rsp_changebaudfail() {
         check_rsp_generic(Response::ChangeBaudFail, RES_CHANGE_BAUD_FAIL);
     }
@@ -1533,6 +4238,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "ext-flash")]
     fn check_rsp_xrrange() {
         let mut p = ResponseDecoder::new();
         p.set_payload_len(4).unwrap();
@@ -1562,6 +4268,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "attributes")]
     fn check_rsp_get_attr() {
         let mut p = ResponseDecoder::new();
         assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
@@ -1648,6 +4355,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "ext-flash")]
     fn check_rsp_crc_ext_flash() {
         let mut p = ResponseDecoder::new();
         assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
@@ -1713,6 +4421,531 @@ mod tests {
         assert_eq!(e.next(), None);
     }
 
+    #[test]
+    fn check_rsp_info_spec_padding() {
+        let r = Response::Info { info: b"Tock" };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        e.set_padding_mode(PaddingMode::Spec);
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(RES_INFO));
+        assert_eq!(e.next(), Some(4));
+        assert_eq!(e.next(), Some(b'T'));
+        assert_eq!(e.next(), Some(b'o'));
+        assert_eq!(e.next(), Some(b'c'));
+        assert_eq!(e.next(), Some(b'k'));
+        for _ in 4..MAX_INFO_LEN {
+            assert_eq!(e.next(), Some(0x00));
+        }
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_rsp_pad_byte() {
+        let r = Response::GetAttr {
+            key: b"appaddr\0",
+            value: &[0x00, 0x00, 0x04, 0x00],
+        };
+        let e = ResponseEncoder::new(&r).unwrap();
+        // Default padding is zeroes, like the C bootloader
+        assert!(e.skip(2 + KEY_LEN + 1 + 4).all(|b| b == 0x00));
+
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        e.set_pad_byte(0xFF);
+        assert_eq!(e.skip(2 + KEY_LEN + 1 + 4).filter(|&b| b == 0xFF).count(), MAX_ATTR_LEN - 4);
+
+        let r = Response::Info { info: b"Tock" };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        e.set_padding_mode(PaddingMode::Spec);
+        e.set_pad_byte(0xFF);
+        assert_eq!(e.skip(2 + 1 + 4).filter(|&b| b == 0xFF).count(), MAX_INFO_LEN - 4);
+    }
+
+    #[test]
+    fn check_rsp_pad_policy() {
+        let r = Response::Info { info: b"Tock" };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        assert_eq!(e.set_pad_policy(PadPolicy::Reject), Ok(()));
+        e.set_padding_mode(PaddingMode::Spec);
+        assert_eq!(e.set_pad_policy(PadPolicy::Reject), Err(Error::BadArguments));
+        assert_eq!(e.set_pad_policy(PadPolicy::Fill(0xFF)), Ok(()));
+        assert_eq!(e.skip(2 + 1 + 4).filter(|&b| b == 0xFF).count(), MAX_INFO_LEN - 4);
+
+        let id = [0x11u8; ID_LEN];
+        let r = Response::Id { id: &id };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        assert_eq!(e.set_pad_policy(PadPolicy::Reject), Ok(()));
+        e.set_id_len(ID_LEN + 4);
+        assert_eq!(e.set_pad_policy(PadPolicy::Reject), Err(Error::BadArguments));
+    }
+
+    #[test]
+    fn check_rsp_info_spec_padding_full() {
+        let info = [b'x'; MAX_INFO_LEN];
+        let r = Response::Info { info: &info };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        e.set_padding_mode(PaddingMode::Spec);
+        // Header, length byte and 192 bytes of string
+        assert_eq!(e.count(), 2 + 1 + MAX_INFO_LEN);
+    }
+
+    #[test]
+    fn check_rsp_info_length_prefixed() {
+        let r = Response::Info { info: b"Tock" };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        e.set_padding_mode(PaddingMode::Spec);
+        let mut p = ResponseDecoder::new();
+        p.set_info_mode(InfoMode::LengthPrefixed);
+        let mut e = e.peekable();
+        while let Some(b) = e.next() {
+            let expected = if e.peek().is_none() { Some(r) } else { None };
+            assert_eq!(p.receive(b), Ok(expected));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_PONG), Ok(Some(Response::Pong)));
+
+        // A length which runs past the padding
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_INFO), Ok(None));
+        assert_eq!(p.receive(MAX_INFO_LEN as u8 + 1), Ok(None));
+        for _ in 1..MAX_INFO_LEN {
+            assert_eq!(p.receive(0x00), Ok(None));
+        }
+        let err = Error::InvalidValue {
+            field: Field::InfoLength,
+            got: MAX_INFO_LEN as u32 + 1,
+        };
+        assert_eq!(p.receive(0x00), Err(err));
+    }
+
+    #[test]
+    fn check_rsp_id() {
+        let id = [0x10, 0x32, 0x54, 0x76, 0x98, 0xBA, 0xDC, 0xFC];
+        let r = Response::Id { id: &id };
+        let e = ResponseEncoder::new(&r).unwrap();
+        // The escape in the ID is doubled
+        assert_eq!(e.clone().count(), 2 + ID_LEN + 1);
+        let mut p = ResponseDecoder::new();
+        let mut decoded = None;
+        for byte in e {
+            if let Some(rsp) = p.receive(byte).unwrap() {
+                decoded = Some(rsp == r);
+            }
+        }
+        assert_eq!(decoded, Some(true));
+
+        // A short ID is padded out, and a 16 byte one needs both ends told
+        let r = Response::Id { id: &id[0..4] };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        e.set_id_len(16);
+        assert_eq!(e.clone().count(), 2 + 16);
+        let mut p = ResponseDecoder::new();
+        p.set_id_len(16);
+        let padded = [0x10, 0x32, 0x54, 0x76, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut decoded = None;
+        for byte in e {
+            if let Some(rsp) = p.receive(byte).unwrap() {
+                decoded = Some(rsp == Response::Id { id: &padded });
+            }
+        }
+        assert_eq!(decoded, Some(true));
+    }
+
+    #[test]
+    fn check_rsp_reset() {
+        let r = Response::CrcIntFlash { crc: 0xFCFC_FCFC };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        let first = e.clone().count();
+        assert_eq!(first, 2 + 8);
+        e.next();
+        e.next();
+        e.next();
+        e.reset();
+        assert_eq!(e.count(), first);
+    }
+
+    #[test]
+    fn check_rsp_next_chunk() {
+        let r = Response::ReadRange { data: &[0x00, ESCAPE_CHAR, 0x22, 0x33] };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        assert_eq!(e.next_chunk(4), Some(&[ESCAPE_CHAR, RES_RRANGE, 0x00, ESCAPE_CHAR][..]));
+        assert_eq!(e.next_chunk(4), Some(&[ESCAPE_CHAR, 0x22, 0x33][..]));
+        assert_eq!(e.next_chunk(4), None);
+    }
+
+    #[test]
+    fn check_dma_halves() {
+        let mut page = [0u8; INT_PAGE_SIZE];
+        page[100] = ESCAPE_CHAR;
+        let cmd = Command::WritePage {
+            address: 0x30000,
+            data: &page,
+        };
+        let mut wire = [0u8; 2 * MAX_FRAME_LEN];
+        let len = encode_all(&cmd, &mut wire).unwrap();
+        let len = len + encode_all(&Command::Ping, &mut wire[len..]).unwrap();
+        // Split the two frames at every point, including between the two
+        // bytes of the escape in the page
+        for split in 0..=len {
+            let mut p = CommandDecoder::new();
+            let mut seen = 0;
+            for half in [&wire[0..split], &wire[split..len]] {
+                p.process_dma_half(half, |result| {
+                    let expected = if seen == 0 { cmd } else { Command::Ping };
+                    assert_eq!(result, Ok(expected), "split at {}", split);
+                    seen += 1;
+                });
+            }
+            assert_eq!(seen, 2, "split at {}", split);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_split_decoders() {
+        let mut buffer = [0u8; MAX_FRAME_LEN + 80];
+        let (mut commands, mut responses) = split_decoders(&mut buffer, MAX_FRAME_LEN);
+        let page = [ESCAPE_CHAR; INT_PAGE_SIZE];
+        let cmd = Command::WritePage {
+            address: 0x30000,
+            data: &page,
+        };
+        let mut wire = [0u8; 2 * MAX_FRAME_LEN];
+        let len = encode_all(&cmd, &mut wire).unwrap();
+        assert_eq!(commands.push_bytes(&wire[0..len]), (len, Ok(Some(cmd))));
+
+        let rsp = Response::GetAttr {
+            key: b"board\0\0\0",
+            value: b"hail",
+        };
+        let mut len = 0;
+        for b in ResponseEncoder::new(&rsp).unwrap() {
+            wire[len] = b;
+            len += 1;
+        }
+        // Stops after the response code
+        assert_eq!(responses.push_bytes(&wire[0..len]), (2, Ok(None)));
+        assert_eq!(responses.push_bytes(&wire[2..len]), (len - 2, Ok(Some(rsp))));
+        assert_eq!(responses.set_payload_len(80), Err(Error::BufferFull));
+        assert_eq!(responses.set_payload_len(79), Ok(()));
+    }
+
+    #[test]
+    fn check_escape_free_len() {
+        let mut bytes = [0x55u8; 40];
+        for start in 0..4 {
+            for escape in start..bytes.len() {
+                bytes[escape] = ESCAPE_CHAR;
+                assert_eq!(escape_free_len(&bytes[start..]), escape - start);
+                bytes[escape] = ESCAPE_CHAR - 1;
+            }
+            assert_eq!(escape_free_len(&bytes[start..]), bytes.len() - start);
+        }
+    }
+
+    #[test]
+    fn check_encode_all() {
+        let mut page = [0x55u8; INT_PAGE_SIZE];
+        page[0] = ESCAPE_CHAR;
+        page[INT_PAGE_SIZE - 1] = ESCAPE_CHAR;
+        let cmd = Command::WritePage {
+            address: 0x0003_00FC,
+            data: &page,
+        };
+        let mut buffer = [0u8; 2 * MAX_FRAME_LEN];
+        let len = encode_all(&cmd, &mut buffer).unwrap();
+        assert_eq!(len, 4 + 1 + INT_PAGE_SIZE + 2 + 2);
+        assert!(buffer[0..len].iter().cloned().eq(CommandEncoder::new(&cmd).unwrap()));
+        assert_eq!(encode_all(&cmd, &mut buffer[0..len - 1]), Err(Error::BufferFull));
+        let short = Command::WritePage {
+            address: 0x30000,
+            data: &page[1..],
+        };
+        assert_eq!(encode_all(&short, &mut buffer), Err(Error::BadArguments));
+    }
+
+    #[test]
+    fn check_push_bytes() {
+        let mut p = ResponseDecoder::new();
+        p.set_payload_len(8).unwrap();
+        let wire = [
+            ESCAPE_CHAR, RES_RRANGE, 1, 2, ESCAPE_CHAR, ESCAPE_CHAR, 3, 4, 5, 6, ESCAPE_CHAR,
+            ESCAPE_CHAR, ESCAPE_CHAR, RES_PONG,
+        ];
+        assert_eq!(p.push_bytes(&wire), (2, Ok(None)));
+        let (used, result) = p.push_bytes(&wire[2..]);
+        assert_eq!(used, 10);
+        assert_eq!(
+            result,
+            Ok(Some(Response::ReadRange {
+                data: &[1, 2, ESCAPE_CHAR, 3, 4, 5, 6, ESCAPE_CHAR]
+            }))
+        );
+        assert_eq!(p.push_bytes(&wire[12..]), (2, Ok(Some(Response::Pong))));
+        assert_eq!(p.push_bytes(&[]), (0, Ok(None)));
+    }
+
+    #[test]
+    fn check_opcodes() {
+        assert_eq!(Command::Ping.opcode(), 0x01);
+        assert_eq!(Response::Info { info: &[] }.code(), 0x25);
+        for v in test_vectors::COMMANDS.iter().chain(test_vectors::EXTENSION_COMMANDS) {
+            let opcode = Opcode::try_from(v.command.opcode()).unwrap();
+            assert_eq!(opcode, v.command.kind());
+            assert_eq!(v.wire_bytes().last(), Some(u8::from(opcode)));
+        }
+        for v in test_vectors::RESPONSES.iter().chain(test_vectors::EXTENSION_RESPONSES) {
+            let code = ResponseCode::try_from(v.response.code()).unwrap();
+            assert_eq!(code, v.response.kind());
+            assert_eq!(v.wire_bytes().nth(1), Some(u8::from(code)));
+        }
+        assert_eq!(Opcode::try_from(0x02), Err(Error::UnknownCommand));
+        assert_eq!(ResponseCode::try_from(0x29), Err(Error::UnknownCommand));
+    }
+
+    #[test]
+    #[cfg(feature = "baud-change")]
+    fn check_classify() {
+        assert!(!Response::Pong.is_error());
+        assert!(Response::ChangeBaudFail.is_error());
+        assert!(Response::BadAddress.is_terminal());
+        assert!(!Response::Overflow.is_terminal());
+        assert_eq!(Response::from(Error::UnknownCommand), Response::Unknown);
+        assert_eq!(Response::from(Error::BufferFull), Response::Overflow);
+        assert_eq!(Response::from(Error::Overflow), Response::Overflow);
+        assert_eq!(Response::from(Error::UnsetLength), Response::InternalError);
+    }
+
+    #[test]
+    fn check_hash() {
+        use std::collections::HashSet;
+        let mut seen = HashSet::new();
+        for v in test_vectors::COMMANDS.iter().chain(test_vectors::EXTENSION_COMMANDS) {
+            assert!(seen.insert(v.command));
+        }
+        assert!(!seen.insert(Command::Ping));
+        let errors: HashSet<Error> = [Error::Refused, Error::Refused].iter().cloned().collect();
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    #[cfg(all(feature = "ext-flash", feature = "attributes"))]
+    fn check_constructors() {
+        let page = [0u8; INT_PAGE_SIZE];
+        assert_eq!(
+            Command::write_page(0x30000, &page),
+            Ok(Command::WritePage {
+                address: 0x30000,
+                data: &page,
+            })
+        );
+        assert_eq!(Command::write_page(0x30001, &page), Err(Error::BadArguments));
+        assert_eq!(Command::write_page(0x30000, &page[1..]), Err(Error::BadArguments));
+        assert_eq!(Command::write_ex_page(0x100, &page[..EXT_PAGE_SIZE]).map(|_| ()), Ok(()));
+        assert_eq!(Command::erase_page(0x100), Err(Error::BadArguments));
+        let key = AttrKey::new("board").unwrap();
+        assert_eq!(
+            Command::set_attr(15, &key, b"hail"),
+            Ok(Command::SetAttr {
+                index: 15,
+                key: b"board\0\0\0",
+                value: b"hail",
+            })
+        );
+        let response = Response::GetAttr {
+            key: b"board\0\0\0",
+            value: b"hail",
+        };
+        assert_eq!(response.attr_key(), Some(key));
+        assert_eq!(Command::set_attr(16, &key, b"hail"), Err(Error::BadArguments));
+        assert_eq!(Command::set_attr(0, &key, &page[..56]), Err(Error::BadArguments));
+        assert_eq!(Command::get_attr(16), Err(Error::BadArguments));
+    }
+
+    #[test]
+    #[cfg(feature = "baud-change")]
+    fn check_baud() {
+        assert_eq!(Baud::from(921_600), Baud::B921600);
+        assert_eq!(u32::from(Baud::B1000000), 1_000_000);
+        assert!(Baud::Custom(230_400).is_standard());
+        assert!(!Baud::Custom(9600).is_standard());
+        assert_eq!(
+            Command::change_baud(BaudMode::Set, Baud::B115200),
+            Ok(Command::ChangeBaud {
+                mode: BaudMode::Set,
+                baud: 115_200,
+            })
+        );
+        assert_eq!(Command::change_baud(BaudMode::Set, Baud::Custom(0)), Err(Error::BadArguments));
+    }
+
+    #[test]
+    fn check_const_new() {
+        static COMMANDS: CommandDecoder = CommandDecoder::new();
+        static RESPONSES: ResponseDecoder = ResponseDecoder::new();
+        assert_eq!(COMMANDS.count, 0);
+        assert_eq!(RESPONSES.needed, None);
+    }
+
+    #[test]
+    fn check_arg_len() {
+        for v in test_vectors::COMMANDS.iter().chain(test_vectors::EXTENSION_COMMANDS) {
+            // Everything but the escape and opcode, with escapes collapsed
+            let mut args = 0;
+            let mut escaped = false;
+            for b in v.wire_bytes() {
+                if escaped || b != ESCAPE_CHAR {
+                    args += 1;
+                    escaped = false;
+                } else {
+                    escaped = true;
+                }
+            }
+            assert!(v.command.kind().arg_len().accepts(args - 1), "{}", v.name);
+        }
+        assert_eq!(Opcode::ReadRange.arg_len(), ArgLen::Fixed(6));
+        assert!(!Opcode::SetAttr.arg_len().accepts(9));
+        assert_eq!(Opcode::SetAttr.arg_len().max(), 65);
+    }
+
+    #[test]
+    fn check_display() {
+        use std::string::ToString;
+        assert_eq!(
+            Error::MismatchedResponse.to_string(),
+            "the response doesn't go with the command sent"
+        );
+        let e = Error::WrongLength {
+            opcode: CMD_EPAGE,
+            expected: 4,
+            got: 3,
+        };
+        assert_eq!(e.to_string(), "frame 0x06 should have 4 bytes of arguments, not 3");
+    }
+
+    #[test]
+    fn check_copy() {
+        let page = [0xAAu8; INT_PAGE_SIZE];
+        let cmd = Command::WritePage {
+            address: 0x30000,
+            data: &page,
+        };
+        // Encode a copy, then send the original again on a retry
+        let retry = cmd;
+        let first: usize = CommandEncoder::new(&cmd).unwrap().count();
+        let e = CommandEncoder::new(&retry).unwrap();
+        assert_eq!(e.clone().count(), first);
+        assert_eq!(retry, cmd);
+
+        let r = Response::CrcIntFlash { crc: 1 };
+        let copy = r;
+        assert_eq!(copy, r);
+    }
+
+    #[test]
+    fn check_no_panic() {
+        // Every pair of bytes, starting from each of the decoders' states
+        let mut d = CommandDecoder::new();
+        let mut r = ResponseDecoder::new();
+        for a in 0..=255u8 {
+            for b in 0..=255u8 {
+                let _ = d.receive(a);
+                let _ = d.receive(b);
+                let _ = r.receive(a);
+                let _ = r.receive(b);
+            }
+        }
+        // Far too much data
+        for i in 0..2 * MAX_FRAME_LEN {
+            let _ = d.receive(i as u8);
+            let _ = r.receive(i as u8);
+        }
+        assert_eq!(r.set_payload_len(usize::MAX), Err(Error::BufferFull));
+        assert_eq!(r.set_payload_len(MAX_FRAME_LEN), Err(Error::BufferFull));
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_getattr_value_len() {
+        // A value one byte longer than there is room for
+        let mut r = ResponseDecoder::new();
+        let mut frame = [0u8; 2 + 8 + 1 + MAX_ATTR_LEN];
+        frame[0] = ESCAPE_CHAR;
+        frame[1] = RES_GATTR;
+        frame[10] = MAX_ATTR_LEN as u8 + 1;
+        let mut result = Ok(false);
+        for &b in frame.iter() {
+            result = r.receive(b).map(|x| x.is_some());
+        }
+        assert_eq!(
+            result,
+            Err(Error::InvalidValue {
+                field: Field::AttrLength,
+                got: MAX_ATTR_LEN as u32 + 1,
+            })
+        );
+    }
+
+    #[test]
+    fn check_static_construction() {
+        use core::mem::size_of;
+        // Built at compile time, so they can go straight into a `static`
+        const DECODER: CommandDecoder = CommandDecoder::new();
+        const RESPONSES: ResponseDecoder = ResponseDecoder::new();
+        const SESSION: session::HostSession = session::HostSession::new();
+        const STORE: attributes::AttributeStore = attributes::AttributeStore::new();
+        assert_eq!(DECODER.rx_crc(), None);
+        assert_eq!(RESPONSES.count, 0);
+        assert!(!SESSION.in_flight());
+        assert_eq!(STORE.iter().count(), 0);
+        // Nothing carries more than its buffers, and a little state
+        assert!(size_of::<CommandDecoder>() <= MAX_FRAME_LEN + 128);
+        assert!(size_of::<ResponseDecoder>() <= MAX_FRAME_LEN + 128);
+        assert!(size_of::<session::HostSession>() <= MAX_FRAME_LEN + 128);
+        assert!(size_of::<device::BootloaderSession<()>>() <= 2 * MAX_FRAME_LEN + 192);
+    }
+}
+
+/// Proofs for Kani (`cargo kani`) that the decoders can't panic.
+///
+/// Each proof starts a decoder in any state it could be in and feeds it any
+/// byte. As any sequence of bytes only leads from one such state to another,
+/// that covers every sequence.
+#[cfg(all(kani, feature = "host", feature = "device"))]
+mod proofs {
+    use super::*;
+
+    fn any_state() -> DecoderState {
+        if kani::any() {
+            DecoderState::Loading
+        } else {
+            DecoderState::Escape
+        }
+    }
+
+    #[kani::proof]
+    fn command_decoder_never_panics() {
+        let mut d = CommandDecoder::new();
+        d.state = any_state();
+        d.buffer = kani::any();
+        d.count = kani::any_where(|&count: &usize| count <= MAX_FRAME_LEN);
+        let _ = d.receive(kani::any());
+    }
+
+    #[kani::proof]
+    fn response_decoder_never_panics() {
+        let mut r = ResponseDecoder::new();
+        r.state = any_state();
+        r.buffer = kani::any();
+        r.count = kani::any_where(|&count: &usize| count <= MAX_FRAME_LEN);
+        r.needed = if kani::any() {
+            Some(kani::any_where(|&needed: &usize| needed <= MAX_FRAME_LEN))
+        } else {
+            None
+        };
+        let _ = r.receive(kani::any());
+    }
 }
 
 // ****************************************************************************
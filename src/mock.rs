@@ -0,0 +1,389 @@
+//! A bootloader in memory, for testing.
+//!
+//! With the `mock` feature, a `Loopback` connects the host side of the
+//! protocol straight to a `BootloaderSession`, with no hardware involved.
+//! It's a `Transport` and a `std::io` stream, so it can be handed to
+//! `run_host_command`, or (with the `std` feature) to `Host::new`:
+//!
+//! ```ignore
+//! let mut flash = MemFlash::new(0x40000);
+//! flash.attributes_mut().set(b"board", b"hail").unwrap();
+//! let mut host = Host::new(Loopback::new(flash));
+//! host.write_image(0x30000, &app)?;
+//! ```
+//!
+//! A read with nothing waiting fails straight away, as a real port would
+//! after its timeout. With the `long-attributes` feature, the `MemFlash`
+//! also keeps long attributes, in memory rather than in flash.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::collections::VecDeque;
+use std::io;
+use std::vec;
+use std::vec::Vec;
+
+use super::attributes::AttributeStore;
+use super::crc::crc32;
+use super::device::{BootloaderSession, FlashError, FlashInterface};
+use super::transport::Transport;
+use super::{INT_PAGE_SIZE, KEY_LEN, MAX_ATTR_LEN};
+#[cfg(feature = "long-attributes")]
+use super::MAX_INDEX;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Internal flash and an attribute table, held in memory.
+#[derive(Clone)]
+pub struct MemFlash {
+    data: Vec<u8>,
+    attrs: AttributeStore,
+    #[cfg(feature = "long-attributes")]
+    long_attrs: Vec<Option<(Vec<u8>, Vec<u8>)>>,
+}
+
+/// A host's end of a pipe with a bootloader on the other end.
+pub struct Loopback<F> {
+    session: BootloaderSession<F>,
+    rx: VecDeque<u8>,
+}
+
+/// The ways the `Loopback` can fail.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MockError {
+    /// There was nothing to read.
+    Timeout,
+    /// The bootloader produced a response that couldn't be encoded.
+    BadResponse,
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl MemFlash {
+    /// `size` bytes of erased flash, starting at address 0, and an erased
+    /// attribute table.
+    pub fn new(size: usize) -> MemFlash {
+        MemFlash {
+            data: vec![0xFF; size],
+            attrs: AttributeStore::new(),
+            #[cfg(feature = "long-attributes")]
+            long_attrs: vec![None; MAX_INDEX as usize],
+        }
+    }
+
+    /// The contents of flash.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The contents of flash, for setting up a test.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    /// The attribute table.
+    pub fn attributes(&self) -> &AttributeStore {
+        &self.attrs
+    }
+
+    /// The attribute table, for setting up a test.
+    pub fn attributes_mut(&mut self) -> &mut AttributeStore {
+        &mut self.attrs
+    }
+}
+
+impl FlashInterface for MemFlash {
+    fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), FlashError> {
+        buffer.copy_from_slice(self.range(address, buffer.len())?);
+        Ok(())
+    }
+
+    fn write_page(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+        self.range(address, data.len())?.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn erase_page(&mut self, address: u32) -> Result<(), FlashError> {
+        for b in self.range(address, INT_PAGE_SIZE)? {
+            *b = 0xFF;
+        }
+        Ok(())
+    }
+
+    fn get_attr(&mut self, index: u8, key: &mut [u8], value: &mut [u8])
+        -> Result<usize, FlashError> {
+        let slot = self.attrs.slot(index).ok_or(FlashError::BadArguments)?;
+        key.copy_from_slice(&slot[0..KEY_LEN]);
+        // An erased slot has a length of 0xFF
+        let len = match slot[KEY_LEN] as usize {
+            len if len > MAX_ATTR_LEN => 0,
+            len => len,
+        };
+        // The buffer is as long as the session's attribute length
+        let n = len.min(value.len());
+        value[..n].copy_from_slice(&slot[KEY_LEN + 1..KEY_LEN + 1 + n]);
+        Ok(len)
+    }
+
+    fn set_attr(&mut self, index: u8, key: &[u8], value: &[u8]) -> Result<(), FlashError> {
+        self.attrs.set_at(index, key, value).map_err(|_| FlashError::BadArguments)
+    }
+
+    fn crc_range(&mut self, address: u32, length: u32) -> Result<u32, FlashError> {
+        Ok(crc32(self.range(address, length as usize)?))
+    }
+
+    #[cfg(feature = "long-attributes")]
+    fn get_long_attr(&mut self, index: u8, key: &mut [u8], value: &mut [u8])
+        -> Result<usize, FlashError> {
+        let slot = self.long_attrs.get(index as usize).ok_or(FlashError::BadArguments)?;
+        match *slot {
+            Some((ref k, ref v)) => {
+                key.copy_from_slice(k);
+                value[0..v.len()].copy_from_slice(v);
+                Ok(v.len())
+            }
+            // An empty slot has an all-null key and no value
+            None => {
+                key.iter_mut().for_each(|b| *b = 0);
+                Ok(0)
+            }
+        }
+    }
+
+    #[cfg(feature = "long-attributes")]
+    fn set_long_attr(&mut self, index: u8, key: &[u8], value: &[u8]) -> Result<(), FlashError> {
+        let slot = self.long_attrs.get_mut(index as usize).ok_or(FlashError::BadArguments)?;
+        *slot = Some((key.to_vec(), value.to_vec()));
+        Ok(())
+    }
+}
+
+impl<F> Loopback<F>
+where
+    F: FlashInterface,
+{
+    /// Start a bootloader using `flash`.
+    pub fn new(flash: F) -> Loopback<F> {
+        Loopback {
+            session: BootloaderSession::new(flash),
+            rx: VecDeque::new(),
+        }
+    }
+
+    /// The bootloader's flash.
+    pub fn flash(&self) -> &F {
+        self.session.flash()
+    }
+
+    /// The bootloader's flash, for setting up a test or injecting a fault.
+    pub fn flash_mut(&mut self) -> &mut F {
+        self.session.flash_mut()
+    }
+
+    /// The bootloader's session, for changing its settings.
+    pub fn session_mut(&mut self) -> &mut BootloaderSession<F> {
+        &mut self.session
+    }
+
+    /// How many response bytes are waiting to be read.
+    pub fn pending(&self) -> usize {
+        self.rx.len()
+    }
+
+    /// Stop the bootloader and get its flash back.
+    pub fn release(self) -> F {
+        self.session.release()
+    }
+
+    /// Hand `bytes` to the bootloader, queueing up any responses.
+    fn feed(&mut self, bytes: &[u8]) -> Result<(), MockError> {
+        for &b in bytes {
+            let format = self.session.reply_format();
+            if let Some(r) = self.session.receive(b) {
+                let encoder = format.encoder(&r).map_err(|_| MockError::BadResponse)?;
+                self.rx.extend(encoder);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<F> Transport for Loopback<F>
+where
+    F: FlashInterface,
+{
+    type Error = MockError;
+
+    fn read_byte(&mut self) -> Result<u8, MockError> {
+        self.rx.pop_front().ok_or(MockError::Timeout)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), MockError> {
+        self.feed(bytes)
+    }
+
+    fn flush(&mut self) -> Result<(), MockError> {
+        Ok(())
+    }
+}
+
+impl<F> io::Read for Loopback<F>
+where
+    F: FlashInterface,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.rx.is_empty() && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+        }
+        let len = buf.len().min(self.rx.len());
+        for (dst, src) in buf.iter_mut().zip(self.rx.drain(0..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl<F> io::Write for Loopback<F>
+where
+    F: FlashInterface,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.feed(buf)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad response"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl MemFlash {
+    fn range(&mut self, address: u32, len: usize) -> Result<&mut [u8], FlashError> {
+        let start = address as usize;
+        self.data.get_mut(start..start + len).ok_or(FlashError::BadAddress)
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use super::super::session::HostSession;
+    use super::super::transport::run_host_command;
+    use super::super::{Command, Response};
+
+    #[test]
+    fn check_loopback() {
+        let mut flash = MemFlash::new(0x1000);
+        flash.data_mut()[0x100] = 0x42;
+        let mut t = Loopback::new(flash);
+        let mut s = HostSession::new();
+
+        let result = run_host_command(&mut t, &mut s, &Command::Ping, |r| r == Response::Pong);
+        assert_eq!(result, Ok(Some(true)));
+
+        let cmd = Command::ReadRange {
+            address: 0x100,
+            length: 2,
+        };
+        let result = run_host_command(&mut t, &mut s, &cmd, |r| r == Response::ReadRange {
+            data: &[0x42, 0xFF],
+        });
+        assert_eq!(result, Ok(Some(true)));
+
+        let cmd = Command::ErasePage { address: 0x0 };
+        let result = run_host_command(&mut t, &mut s, &cmd, |r| r == Response::Ok);
+        assert_eq!(result, Ok(Some(true)));
+        assert_eq!(t.pending(), 0);
+
+        // Out of range
+        let cmd = Command::ErasePage { address: 0x1000 };
+        let result = run_host_command(&mut t, &mut s, &cmd, |r| r == Response::BadAddress);
+        assert_eq!(result, Ok(Some(true)));
+
+        // Nothing more to read
+        assert_eq!(t.read_byte(), Err(MockError::Timeout));
+        let result = run_host_command(&mut t, &mut s, &Command::Reset, |_| ());
+        assert_eq!(result, Ok(None));
+
+        let flash = t.release();
+        assert!(flash.data()[0..0x200].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_attr_len() {
+        for &len in &[54, 64] {
+            let mut t = Loopback::new(MemFlash::new(0x1000));
+            t.session_mut().set_attr_len(len);
+            let mut s = HostSession::new();
+            s.set_attr_len(len);
+            let cmd = Command::SetAttr {
+                index: 2,
+                key: b"board\0\0\0",
+                value: b"hail",
+            };
+            let result = run_host_command(&mut t, &mut s, &cmd, |r| r == Response::Ok);
+            assert_eq!(result, Ok(Some(true)));
+
+            let cmd = Command::GetAttr { index: 2 };
+            let result = run_host_command(&mut t, &mut s, &cmd, |r| r == Response::GetAttr {
+                key: b"board\0\0\0",
+                value: b"hail",
+            });
+            assert_eq!(result, Ok(Some(true)));
+            assert_eq!(t.pending(), 0);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "long-attributes")]
+    fn check_long_attributes() {
+        let mut t = Loopback::new(MemFlash::new(0x1000));
+        let mut s = HostSession::new();
+        let value: Vec<u8> = (0..400).map(|i| i as u8).collect();
+        let cmd = Command::SetLongAttr {
+            index: 3,
+            key: b"wifi\0\0\0\0",
+            value: &value,
+        };
+        let result = run_host_command(&mut t, &mut s, &cmd, |r| r == Response::Ok);
+        assert_eq!(result, Ok(Some(true)));
+
+        let cmd = Command::GetLongAttr { index: 3 };
+        let result = run_host_command(&mut t, &mut s, &cmd, |r| r == Response::GetLongAttr {
+            key: b"wifi\0\0\0\0",
+            value: &value,
+        });
+        assert_eq!(result, Ok(Some(true)));
+
+        // An empty slot
+        let cmd = Command::GetLongAttr { index: 4 };
+        let result = run_host_command(&mut t, &mut s, &cmd, |r| r == Response::GetLongAttr {
+            key: &[0; KEY_LEN],
+            value: &[],
+        });
+        assert_eq!(result, Ok(Some(true)));
+        // The classic attributes are kept apart
+        assert_eq!(t.flash().attributes().slot(3).map(|slot| slot[0]), Some(0xFF));
+    }
+}
@@ -0,0 +1,345 @@
+//! Several bootloaders on one bus.
+//!
+//! On an RS-485 bus every board hears every frame, including the other
+//! boards' replies, so each frame needs to say who it's for. With the
+//! `multi-drop` feature, an addressed frame starts with a header of an
+//! escape, a mark saying which way the frame is going, and a one byte
+//! address:
+//!
+//! ```text
+//! FC F0 <address> <arguments> FC <opcode>    command, to <address>
+//! FC F1 <address> FC <code> <payload>        response, from <address>
+//! ```
+//!
+//! Neither mark is an opcode or a response code, and escapes in arguments
+//! and payloads are doubled as usual, so a header can't turn up inside
+//! another frame. An `AddressFilter` finds the headers and drops every
+//! frame which isn't for this end of the link. The decoders use one once
+//! given an address with `set_address`, and the encoders write the header
+//! once given one with theirs.
+//!
+//! A board starts unaddressed, and is given an address with
+//! `Command::SetAddress`, usually while it's the only one on the bus:
+//!
+//! ```ignore
+//! session.send(&Command::SetAddress { address: 3 })?;
+//! // Once the reply arrives, the session sends to, and hears from,
+//! // board 3 only.
+//! ```
+//!
+//! A board which hasn't been given an address answers unaddressed frames,
+//! so there should only be one of those on the bus at a time.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::ESCAPE_CHAR;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Picks out the frames for one address from the traffic on a shared bus.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct AddressFilter {
+    address: u8,
+    mark: u8,
+    state: FilterState,
+    escape: bool,
+    pass: [u8; 2],
+}
+
+/// What to do with a byte given to `AddressFilter::receive`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Filtered<'a> {
+    /// Drop it; it's part of a header, or of someone else's frame.
+    Drop,
+    /// A frame for us has just started, so drop anything half received.
+    Start,
+    /// Pass these bytes on to the decoder. An escape is held back until
+    /// the byte after it shows it isn't the start of a header, so there
+    /// may be two.
+    Pass(&'a [u8]),
+}
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum FilterState {
+    /// Between frames, or in someone else's.
+    Idle,
+    /// After a mark, waiting for the address. Whether the mark was the one
+    /// we're listening for.
+    Address(bool),
+    /// In a frame for us.
+    Passing,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The mark in the header of a command, from the host.
+pub const COMMAND_MARK: u8 = 0xF0;
+
+/// The mark in the header of a response, from a bootloader.
+pub const RESPONSE_MARK: u8 = 0xF1;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl AddressFilter {
+    /// A filter for a bootloader at `address`, which passes on the commands
+    /// sent to it.
+    pub const fn commands(address: u8) -> AddressFilter {
+        AddressFilter::new(address, COMMAND_MARK)
+    }
+
+    /// A filter for a host talking to the bootloader at `address`, which
+    /// passes on that bootloader's responses.
+    pub const fn responses(address: u8) -> AddressFilter {
+        AddressFilter::new(address, RESPONSE_MARK)
+    }
+
+    const fn new(address: u8, mark: u8) -> AddressFilter {
+        AddressFilter {
+            address,
+            mark,
+            state: FilterState::Idle,
+            escape: false,
+            pass: [0u8; 2],
+        }
+    }
+
+    /// The address this filter listens for.
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Forget any frame half way through, and wait for the next header.
+    pub fn reset(&mut self) {
+        self.state = FilterState::Idle;
+        self.escape = false;
+    }
+
+    /// Look at the next byte from the bus.
+    pub fn receive(&mut self, ch: u8) -> Filtered<'_> {
+        if let FilterState::Address(ours) = self.state {
+            return if ours && ch == self.address {
+                self.state = FilterState::Passing;
+                Filtered::Start
+            } else {
+                self.state = FilterState::Idle;
+                Filtered::Drop
+            };
+        }
+        if !self.escape {
+            if ch == ESCAPE_CHAR {
+                self.escape = true;
+                return Filtered::Drop;
+            }
+            return self.pass(&[ch]);
+        }
+        self.escape = false;
+        match ch {
+            COMMAND_MARK | RESPONSE_MARK => {
+                self.state = FilterState::Address(ch == self.mark);
+                Filtered::Drop
+            }
+            ESCAPE_CHAR => self.pass(&[ESCAPE_CHAR, ch]),
+            _ => {
+                let passed = self.state == FilterState::Passing;
+                // A command ends with its opcode, but a response goes on
+                // until the next header
+                if self.mark == COMMAND_MARK && !is_sequence_mark(ch) {
+                    self.state = FilterState::Idle;
+                }
+                if passed {
+                    self.pass = [ESCAPE_CHAR, ch];
+                    Filtered::Pass(&self.pass)
+                } else {
+                    Filtered::Drop
+                }
+            }
+        }
+    }
+
+    fn pass(&mut self, bytes: &[u8]) -> Filtered<'_> {
+        if self.state != FilterState::Passing {
+            return Filtered::Drop;
+        }
+        let pass = &mut self.pass[0..bytes.len()];
+        pass.copy_from_slice(bytes);
+        Filtered::Pass(pass)
+    }
+}
+
+/// The header which starts a command to the bootloader at `address`.
+pub const fn command_header(address: u8) -> [u8; 3] {
+    [ESCAPE_CHAR, COMMAND_MARK, address]
+}
+
+/// The header which starts a response from the bootloader at `address`.
+pub const fn response_header(address: u8) -> [u8; 3] {
+    [ESCAPE_CHAR, RESPONSE_MARK, address]
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Does `ch`, after an escape, start a sequence header rather than end a
+/// command?
+#[cfg(feature = "sequence")]
+fn is_sequence_mark(ch: u8) -> bool {
+    ch == super::sequence::SEQUENCE_MARK
+}
+
+#[cfg(not(feature = "sequence"))]
+fn is_sequence_mark(_ch: u8) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(all(feature = "host", feature = "device"))]
+    use super::super::mock::MemFlash;
+    #[cfg(all(feature = "host", feature = "device"))]
+    use super::super::{BootloaderSession, Command, HostSession, Response};
+    #[cfg(all(feature = "host", feature = "device"))]
+    use super::super::INT_PAGE_SIZE;
+    use std::vec::Vec;
+
+    fn run(filter: &mut AddressFilter, bytes: &[u8]) -> (Vec<u8>, usize) {
+        let mut passed = Vec::new();
+        let mut starts = 0;
+        for &b in bytes {
+            match filter.receive(b) {
+                Filtered::Drop => {}
+                Filtered::Start => starts += 1,
+                Filtered::Pass(bytes) => passed.extend_from_slice(bytes),
+            }
+        }
+        (passed, starts)
+    }
+
+    #[test]
+    fn check_commands() {
+        let mut filter = AddressFilter::commands(3);
+        let mut bus = Vec::new();
+        // Unaddressed, then to board 2, with an escaped argument
+        bus.extend_from_slice(&[0x01, 0xFC, 0x01]);
+        bus.extend_from_slice(&command_header(2));
+        bus.extend_from_slice(&[0xFC, 0xFC, 0xFC, 0x14]);
+        // Board 2's reply, which looks like the end of a command
+        bus.extend_from_slice(&response_header(2));
+        bus.extend_from_slice(&[0xFC, 0x15]);
+        // To us, with an escape and an escape followed by a mark
+        bus.extend_from_slice(&command_header(3));
+        bus.extend_from_slice(&[0x07, 0xFC, 0xFC, 0xFC, 0xFC, 0xF0, 0xFC, 0x14]);
+        // Our own reply, echoed back
+        bus.extend_from_slice(&response_header(3));
+        bus.extend_from_slice(&[0xFC, 0x22, 0x00]);
+        let (passed, starts) = run(&mut filter, &bus);
+        assert_eq!(passed, [0x07, 0xFC, 0xFC, 0xFC, 0xFC, 0xF0, 0xFC, 0x14]);
+        assert_eq!(starts, 1);
+    }
+
+    #[test]
+    fn check_responses() {
+        let mut filter = AddressFilter::responses(0xFC);
+        let mut bus = Vec::new();
+        bus.extend_from_slice(&command_header(0xFC));
+        bus.extend_from_slice(&[0x00, 0x01, 0xFC, 0x11]);
+        bus.extend_from_slice(&response_header(0xFC));
+        bus.extend_from_slice(&[0xFC, 0x20, 0xFC, 0xFC, 0x33]);
+        bus.extend_from_slice(&response_header(1));
+        bus.extend_from_slice(&[0xFC, 0x15]);
+        let (passed, starts) = run(&mut filter, &bus);
+        assert_eq!(passed, [0xFC, 0x20, 0xFC, 0xFC, 0x33]);
+        assert_eq!(starts, 1);
+        filter.reset();
+        assert_eq!(filter.receive(0x33), Filtered::Drop);
+        assert_eq!(filter.address(), 0xFC);
+    }
+
+    /// Send `command` from `host` to every board, then hand every board's
+    /// reply to the host and to the other boards, as on a real bus.
+    #[cfg(all(feature = "host", feature = "device"))]
+    fn exchange(
+        host: &mut HostSession,
+        boards: &mut [BootloaderSession<MemFlash>],
+        command: &Command,
+    ) -> Vec<u8> {
+        let mut bus: Vec<u8> = host.send(command).unwrap().collect();
+        let mut replies = Vec::new();
+        for board in boards.iter_mut() {
+            for &b in &bus {
+                let format = board.reply_format();
+                if let Some(response) = board.receive(b) {
+                    replies.extend(format.encoder(&response).unwrap());
+                }
+            }
+        }
+        for board in boards.iter_mut() {
+            for &b in &replies {
+                assert_eq!(board.receive(b), None);
+            }
+        }
+        bus.clear();
+        for &b in &replies {
+            if let Some(Response::ReadRange { data }) = host.receive(b).unwrap() {
+                bus.extend_from_slice(data);
+            }
+        }
+        assert!(!host.in_flight());
+        bus
+    }
+
+    #[test]
+    #[cfg(all(feature = "host", feature = "device"))]
+    fn check_bus() {
+        let mut boards = [
+            BootloaderSession::new(MemFlash::new(INT_PAGE_SIZE)),
+            BootloaderSession::new(MemFlash::new(INT_PAGE_SIZE)),
+        ];
+        boards[0].flash_mut().data_mut()[0..4].copy_from_slice(&[0xFC, 0xFC, 0xF1, 0x02]);
+        boards[1].flash_mut().data_mut()[0..4].copy_from_slice(&[1, 2, 3, 4]);
+        boards[1].set_address(Some(2));
+        let mut host = HostSession::new();
+        // Only the first board is unaddressed
+        exchange(&mut host, &mut boards, &Command::SetAddress { address: 1 });
+        assert_eq!(host.address(), Some(1));
+        assert_eq!(boards[0].address(), Some(1));
+        let read = Command::ReadRange {
+            address: 0,
+            length: 4,
+        };
+        assert_eq!(exchange(&mut host, &mut boards, &read), [0xFC, 0xFC, 0xF1, 0x02]);
+        host.set_address(Some(2));
+        assert_eq!(exchange(&mut host, &mut boards, &read), [1, 2, 3, 4]);
+        // Nobody answers unaddressed frames now
+        host.set_address(None);
+        let encoder = host.send(&Command::Ping).unwrap();
+        for b in encoder {
+            assert!(boards.iter_mut().all(|board| board.receive(b).is_none()));
+        }
+    }
+}
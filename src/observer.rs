@@ -0,0 +1,143 @@
+//! Watching traffic as it is decoded.
+//!
+//! Give a decoder or session an `Observer` with `set_observer` and it will be
+//! told about every byte received, every frame decoded and every error, and
+//! how a long run of reads or writes is getting on.
+//! Like a `log::Log`, an observer is a `&'static` and its methods take
+//! `&self`, so anything it keeps count of needs to be in an atomic (or
+//! behind a lock). It must also be `Sync`, so that a decoder holding one can
+//! live in a `static`:
+//!
+//! ```ignore
+//! struct Counters {
+//!     errors: AtomicU32,
+//! }
+//!
+//! impl Observer for Counters {
+//!     fn on_error(&self, _error: Error) {
+//!         self.errors.fetch_add(1, Ordering::Relaxed);
+//!     }
+//! }
+//!
+//! static COUNTERS: Counters = Counters { errors: AtomicU32::new(0) };
+//! session.set_observer(&COUNTERS);
+//! ```
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::{Command, Error, Response};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Hooks called by the decoders and sessions. Every method does nothing
+/// unless overridden.
+pub trait Observer: Sync {
+    /// Bytes have been received, and are about to be decoded.
+    fn on_bytes(&self, _bytes: &[u8]) {}
+
+    /// The first byte of a new frame has been received. Stray bytes between
+    /// frames look like the start of a frame too.
+    fn on_frame_start(&self) {}
+
+    /// A `CommandDecoder` has decoded a command.
+    fn on_command_decoded(&self, _command: &Command) {}
+
+    /// A `ResponseDecoder` has decoded a response.
+    fn on_response_decoded(&self, _response: &Response) {}
+
+    /// Decoding failed, or a `HostSession` got a response which didn't match
+    /// the command it sent.
+    fn on_error(&self, _error: Error) {}
+
+    /// Another `bytes` bytes of a long operation are done: a page written,
+    /// a range read or a CRC checked, by a `BootloaderSession` or a `Host`.
+    /// This comes between pages, so it's a good place to pet a watchdog or
+    /// move a progress bar along.
+    fn on_progress(&self, _bytes: usize) {}
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(all(test, feature = "host", feature = "device"))]
+mod tests {
+    use super::*;
+    use super::super::session::HostSession;
+    use super::super::CommandDecoder;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Counters {
+        bytes: AtomicUsize,
+        frames: AtomicUsize,
+        commands: AtomicUsize,
+        responses: AtomicUsize,
+        errors: AtomicUsize,
+    }
+
+    impl Observer for Counters {
+        fn on_bytes(&self, bytes: &[u8]) {
+            self.bytes.fetch_add(bytes.len(), Ordering::Relaxed);
+        }
+
+        fn on_frame_start(&self) {
+            self.frames.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_command_decoded(&self, _command: &Command) {
+            self.commands.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_response_decoded(&self, _response: &Response) {
+            self.responses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_error(&self, _error: Error) {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn leak() -> &'static Counters {
+        std::boxed::Box::leak(std::boxed::Box::default())
+    }
+
+    #[test]
+    fn check_command_observer() {
+        let counters = leak();
+        let mut d = CommandDecoder::new();
+        d.set_observer(counters);
+        // A ping, an erase with bad arguments, then another ping
+        for &b in &[0xFC, 0x01, 0x01, 0x02, 0xFC, 0x06, 0xFC, 0x01] {
+            let _ = d.receive(b);
+        }
+        assert_eq!(counters.bytes.load(Ordering::Relaxed), 8);
+        assert_eq!(counters.frames.load(Ordering::Relaxed), 3);
+        assert_eq!(counters.commands.load(Ordering::Relaxed), 2);
+        assert_eq!(counters.errors.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn check_session_observer() {
+        let counters = leak();
+        let mut s = HostSession::new();
+        s.set_observer(counters);
+        s.send(&Command::Ping).unwrap();
+        // An OK when we wanted a PONG
+        assert_eq!(s.receive(0xFC), Ok(None));
+        assert_eq!(s.receive(0x15), Err(Error::MismatchedResponse));
+        assert_eq!(counters.frames.load(Ordering::Relaxed), 1);
+        assert_eq!(counters.responses.load(Ordering::Relaxed), 1);
+        assert_eq!(counters.errors.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,481 @@
+//! Owned versions of `Command` and `Response`.
+//!
+//! The borrowed types point into the decoder's buffer (or the caller's page
+//! data), so they can't be put in a queue or handed to another task. The
+//! types in this module copy the payload into fixed-capacity
+//! `heapless::Vec`s instead. Use `as_ref()` to get a borrowed `Command` or
+//! `Response` back when you want to encode it.
+//!
+//! With the `serde` feature, a `Command` or `Response` serializes just like
+//! its owned version, so a logged session can be read back as owned values.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::convert::TryFrom;
+
+use heapless::Vec;
+
+#[cfg(feature = "baud-change")]
+use super::BaudMode;
+use super::consts::MAX_FRAME_LEN;
+use super::{Command, Error, Response};
+use super::{INT_PAGE_SIZE, MAX_INFO_LEN};
+#[cfg(feature = "ext-flash")]
+use super::EXT_PAGE_SIZE;
+#[cfg(feature = "attributes")]
+use super::{KEY_LEN, MAX_ATTR_LEN};
+#[cfg(feature = "long-attributes")]
+use super::MAX_LONG_ATTR_LEN;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// The largest `ReadRange`/`ExReadRange` payload an `OwnedResponse` can hold.
+/// This is everything the `ResponseDecoder` can buffer, minus the response
+/// byte.
+pub const MAX_OWNED_DATA_LEN: usize = MAX_FRAME_LEN - 1;
+
+/// The longest unique ID an `OwnedResponse` can hold.
+pub const MAX_OWNED_ID_LEN: usize = 32;
+
+/// An owned copy of a `Command`. See `Command` for details of each variant.
+// The page variants are much larger than the rest, but without an allocator
+// there's nowhere else to put the data.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OwnedCommand {
+    Ping,
+    Info,
+    Id,
+    Reset,
+    ErasePage { address: u32 },
+    WritePage {
+        address: u32,
+        data: Vec<u8, INT_PAGE_SIZE>,
+    },
+    #[cfg(feature = "ext-flash")]
+    EraseExBlock { address: u32 },
+    #[cfg(feature = "ext-flash")]
+    WriteExPage {
+        address: u32,
+        data: Vec<u8, EXT_PAGE_SIZE>,
+    },
+    CrcRxBuffer,
+    ReadRange { address: u32, length: u16 },
+    #[cfg(feature = "ext-flash")]
+    ExReadRange { address: u32, length: u16 },
+    #[cfg(feature = "attributes")]
+    SetAttr {
+        index: u8,
+        key: Vec<u8, KEY_LEN>,
+        value: Vec<u8, MAX_ATTR_LEN>,
+    },
+    #[cfg(feature = "attributes")]
+    GetAttr { index: u8 },
+    CrcIntFlash { address: u32, length: u32 },
+    #[cfg(feature = "ext-flash")]
+    CrcExtFlash { address: u32, length: u32 },
+    #[cfg(feature = "ext-flash")]
+    EraseExPage { address: u32 },
+    #[cfg(feature = "ext-flash")]
+    ExtFlashInit,
+    ClockOut,
+    #[cfg(feature = "user-pages")]
+    WriteFlashUserPages { page1: u32, page2: u32 },
+    #[cfg(feature = "baud-change")]
+    ChangeBaud { mode: BaudMode, baud: u32 },
+    #[cfg(feature = "multi-drop")]
+    SetAddress { address: u8 },
+    #[cfg(feature = "long-attributes")]
+    SetLongAttr {
+        index: u8,
+        key: Vec<u8, KEY_LEN>,
+        value: Vec<u8, MAX_LONG_ATTR_LEN>,
+    },
+    #[cfg(feature = "long-attributes")]
+    GetLongAttr { index: u8 },
+    Exit,
+}
+
+/// An owned copy of a `Response`. See `Response` for details of each
+/// variant.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OwnedResponse {
+    Overflow,
+    Pong,
+    BadAddress,
+    InternalError,
+    BadArguments,
+    Ok,
+    Unknown,
+    #[cfg(feature = "ext-flash")]
+    ExtFlashTimeout,
+    #[cfg(feature = "ext-flash")]
+    ExtFlashPageError,
+    CrcRxBuffer { length: u16, crc: u32 },
+    ReadRange { data: Vec<u8, MAX_OWNED_DATA_LEN> },
+    #[cfg(feature = "ext-flash")]
+    ExReadRange { data: Vec<u8, MAX_OWNED_DATA_LEN> },
+    #[cfg(feature = "attributes")]
+    GetAttr {
+        key: Vec<u8, KEY_LEN>,
+        value: Vec<u8, MAX_ATTR_LEN>,
+    },
+    CrcIntFlash { crc: u32 },
+    #[cfg(feature = "ext-flash")]
+    CrcExtFlash { crc: u32 },
+    Info { info: Vec<u8, MAX_INFO_LEN> },
+    #[cfg(feature = "baud-change")]
+    ChangeBaudFail,
+    Id { id: Vec<u8, MAX_OWNED_ID_LEN> },
+    #[cfg(feature = "long-attributes")]
+    GetLongAttr {
+        key: Vec<u8, KEY_LEN>,
+        value: Vec<u8, MAX_LONG_ATTR_LEN>,
+    },
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl OwnedCommand {
+    /// Borrow this command as a `Command`, ready to give to a
+    /// `CommandEncoder`.
+    pub fn as_ref(&self) -> Command<'_> {
+        match *self {
+            OwnedCommand::Ping => Command::Ping,
+            OwnedCommand::Info => Command::Info,
+            OwnedCommand::Id => Command::Id,
+            OwnedCommand::Reset => Command::Reset,
+            OwnedCommand::ErasePage { address } => Command::ErasePage { address },
+            OwnedCommand::WritePage { address, ref data } => Command::WritePage { address, data },
+            #[cfg(feature = "ext-flash")]
+            OwnedCommand::EraseExBlock { address } => Command::EraseExBlock { address },
+            #[cfg(feature = "ext-flash")]
+            OwnedCommand::WriteExPage { address, ref data } => {
+                Command::WriteExPage { address, data }
+            }
+            OwnedCommand::CrcRxBuffer => Command::CrcRxBuffer,
+            OwnedCommand::ReadRange { address, length } => Command::ReadRange { address, length },
+            #[cfg(feature = "ext-flash")]
+            OwnedCommand::ExReadRange { address, length } => {
+                Command::ExReadRange { address, length }
+            }
+            #[cfg(feature = "attributes")]
+            OwnedCommand::SetAttr {
+                index,
+                ref key,
+                ref value,
+            } => Command::SetAttr { index, key, value },
+            #[cfg(feature = "attributes")]
+            OwnedCommand::GetAttr { index } => Command::GetAttr { index },
+            OwnedCommand::CrcIntFlash { address, length } => {
+                Command::CrcIntFlash { address, length }
+            }
+            #[cfg(feature = "ext-flash")]
+            OwnedCommand::CrcExtFlash { address, length } => {
+                Command::CrcExtFlash { address, length }
+            }
+            #[cfg(feature = "ext-flash")]
+            OwnedCommand::EraseExPage { address } => Command::EraseExPage { address },
+            #[cfg(feature = "ext-flash")]
+            OwnedCommand::ExtFlashInit => Command::ExtFlashInit,
+            OwnedCommand::ClockOut => Command::ClockOut,
+            #[cfg(feature = "user-pages")]
+            OwnedCommand::WriteFlashUserPages { page1, page2 } => {
+                Command::WriteFlashUserPages { page1, page2 }
+            }
+            #[cfg(feature = "baud-change")]
+            OwnedCommand::ChangeBaud { mode, baud } => Command::ChangeBaud { mode, baud },
+            #[cfg(feature = "multi-drop")]
+            OwnedCommand::SetAddress { address } => Command::SetAddress { address },
+            #[cfg(feature = "long-attributes")]
+            OwnedCommand::SetLongAttr {
+                index,
+                ref key,
+                ref value,
+            } => Command::SetLongAttr { index, key, value },
+            #[cfg(feature = "long-attributes")]
+            OwnedCommand::GetLongAttr { index } => Command::GetLongAttr { index },
+            OwnedCommand::Exit => Command::Exit,
+        }
+    }
+}
+
+impl<'a> From<&'a OwnedCommand> for Command<'a> {
+    fn from(command: &'a OwnedCommand) -> Command<'a> {
+        command.as_ref()
+    }
+}
+
+impl<'a, 'b> TryFrom<&'b Command<'a>> for OwnedCommand {
+    type Error = Error;
+
+    /// Copy a `Command`. Fails with `Error::BadArguments` if any of the
+    /// slices are too long to store.
+    fn try_from(command: &'b Command<'a>) -> Result<OwnedCommand, Error> {
+        Ok(match *command {
+            Command::Ping => OwnedCommand::Ping,
+            Command::Info => OwnedCommand::Info,
+            Command::Id => OwnedCommand::Id,
+            Command::Reset => OwnedCommand::Reset,
+            Command::ErasePage { address } => OwnedCommand::ErasePage { address },
+            Command::WritePage { address, data } => OwnedCommand::WritePage {
+                address,
+                data: copy(data)?,
+            },
+            #[cfg(feature = "ext-flash")]
+            Command::EraseExBlock { address } => OwnedCommand::EraseExBlock { address },
+            #[cfg(feature = "ext-flash")]
+            Command::WriteExPage { address, data } => OwnedCommand::WriteExPage {
+                address,
+                data: copy(data)?,
+            },
+            Command::CrcRxBuffer => OwnedCommand::CrcRxBuffer,
+            Command::ReadRange { address, length } => OwnedCommand::ReadRange { address, length },
+            #[cfg(feature = "ext-flash")]
+            Command::ExReadRange { address, length } => {
+                OwnedCommand::ExReadRange { address, length }
+            }
+            #[cfg(feature = "attributes")]
+            Command::SetAttr { index, key, value } => OwnedCommand::SetAttr {
+                index,
+                key: copy(key)?,
+                value: copy(value)?,
+            },
+            #[cfg(feature = "attributes")]
+            Command::GetAttr { index } => OwnedCommand::GetAttr { index },
+            Command::CrcIntFlash { address, length } => {
+                OwnedCommand::CrcIntFlash { address, length }
+            }
+            #[cfg(feature = "ext-flash")]
+            Command::CrcExtFlash { address, length } => {
+                OwnedCommand::CrcExtFlash { address, length }
+            }
+            #[cfg(feature = "ext-flash")]
+            Command::EraseExPage { address } => OwnedCommand::EraseExPage { address },
+            #[cfg(feature = "ext-flash")]
+            Command::ExtFlashInit => OwnedCommand::ExtFlashInit,
+            Command::ClockOut => OwnedCommand::ClockOut,
+            #[cfg(feature = "user-pages")]
+            Command::WriteFlashUserPages { page1, page2 } => {
+                OwnedCommand::WriteFlashUserPages { page1, page2 }
+            }
+            #[cfg(feature = "baud-change")]
+            Command::ChangeBaud { mode, baud } => OwnedCommand::ChangeBaud { mode, baud },
+            #[cfg(feature = "multi-drop")]
+            Command::SetAddress { address } => OwnedCommand::SetAddress { address },
+            #[cfg(feature = "long-attributes")]
+            Command::SetLongAttr { index, key, value } => OwnedCommand::SetLongAttr {
+                index,
+                key: copy(key)?,
+                value: copy(value)?,
+            },
+            #[cfg(feature = "long-attributes")]
+            Command::GetLongAttr { index } => OwnedCommand::GetLongAttr { index },
+            Command::Exit => OwnedCommand::Exit,
+        })
+    }
+}
+
+impl OwnedResponse {
+    /// Borrow this response as a `Response`, ready to give to a
+    /// `ResponseEncoder`.
+    pub fn as_ref(&self) -> Response<'_> {
+        match *self {
+            OwnedResponse::Overflow => Response::Overflow,
+            OwnedResponse::Pong => Response::Pong,
+            OwnedResponse::BadAddress => Response::BadAddress,
+            OwnedResponse::InternalError => Response::InternalError,
+            OwnedResponse::BadArguments => Response::BadArguments,
+            OwnedResponse::Ok => Response::Ok,
+            OwnedResponse::Unknown => Response::Unknown,
+            #[cfg(feature = "ext-flash")]
+            OwnedResponse::ExtFlashTimeout => Response::ExtFlashTimeout,
+            #[cfg(feature = "ext-flash")]
+            OwnedResponse::ExtFlashPageError => Response::ExtFlashPageError,
+            OwnedResponse::CrcRxBuffer { length, crc } => Response::CrcRxBuffer { length, crc },
+            OwnedResponse::ReadRange { ref data } => Response::ReadRange { data },
+            #[cfg(feature = "ext-flash")]
+            OwnedResponse::ExReadRange { ref data } => Response::ExReadRange { data },
+            #[cfg(feature = "attributes")]
+            OwnedResponse::GetAttr { ref key, ref value } => Response::GetAttr { key, value },
+            OwnedResponse::CrcIntFlash { crc } => Response::CrcIntFlash { crc },
+            #[cfg(feature = "ext-flash")]
+            OwnedResponse::CrcExtFlash { crc } => Response::CrcExtFlash { crc },
+            OwnedResponse::Info { ref info } => Response::Info { info },
+            #[cfg(feature = "baud-change")]
+            OwnedResponse::ChangeBaudFail => Response::ChangeBaudFail,
+            OwnedResponse::Id { ref id } => Response::Id { id },
+            #[cfg(feature = "long-attributes")]
+            OwnedResponse::GetLongAttr { ref key, ref value } => {
+                Response::GetLongAttr { key, value }
+            }
+        }
+    }
+}
+
+impl<'a> From<&'a OwnedResponse> for Response<'a> {
+    fn from(response: &'a OwnedResponse) -> Response<'a> {
+        response.as_ref()
+    }
+}
+
+impl<'a, 'b> TryFrom<&'b Response<'a>> for OwnedResponse {
+    type Error = Error;
+
+    /// Copy a `Response`. Fails with `Error::BadArguments` if any of the
+    /// slices are too long to store.
+    fn try_from(response: &'b Response<'a>) -> Result<OwnedResponse, Error> {
+        Ok(match *response {
+            Response::Overflow => OwnedResponse::Overflow,
+            Response::Pong => OwnedResponse::Pong,
+            Response::BadAddress => OwnedResponse::BadAddress,
+            Response::InternalError => OwnedResponse::InternalError,
+            Response::BadArguments => OwnedResponse::BadArguments,
+            Response::Ok => OwnedResponse::Ok,
+            Response::Unknown => OwnedResponse::Unknown,
+            #[cfg(feature = "ext-flash")]
+            Response::ExtFlashTimeout => OwnedResponse::ExtFlashTimeout,
+            #[cfg(feature = "ext-flash")]
+            Response::ExtFlashPageError => OwnedResponse::ExtFlashPageError,
+            Response::CrcRxBuffer { length, crc } => OwnedResponse::CrcRxBuffer { length, crc },
+            Response::ReadRange { data } => OwnedResponse::ReadRange { data: copy(data)? },
+            #[cfg(feature = "ext-flash")]
+            Response::ExReadRange { data } => OwnedResponse::ExReadRange { data: copy(data)? },
+            #[cfg(feature = "attributes")]
+            Response::GetAttr { key, value } => OwnedResponse::GetAttr {
+                key: copy(key)?,
+                value: copy(value)?,
+            },
+            Response::CrcIntFlash { crc } => OwnedResponse::CrcIntFlash { crc },
+            #[cfg(feature = "ext-flash")]
+            Response::CrcExtFlash { crc } => OwnedResponse::CrcExtFlash { crc },
+            Response::Info { info } => OwnedResponse::Info { info: copy(info)? },
+            #[cfg(feature = "baud-change")]
+            Response::ChangeBaudFail => OwnedResponse::ChangeBaudFail,
+            Response::Id { id } => OwnedResponse::Id { id: copy(id)? },
+            #[cfg(feature = "long-attributes")]
+            Response::GetLongAttr { key, value } => OwnedResponse::GetLongAttr {
+                key: copy(key)?,
+                value: copy(value)?,
+            },
+        })
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+fn copy<const N: usize>(data: &[u8]) -> Result<Vec<u8, N>, Error> {
+    Vec::from_slice(data).map_err(|_| Error::BadArguments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(all(feature = "ext-flash", feature = "host", feature = "device"))]
+    use super::super::{CommandDecoder, CommandEncoder};
+
+    #[test]
+    fn check_command_roundtrip() {
+        let page = [0xAAu8; INT_PAGE_SIZE];
+        let cmd = Command::WritePage {
+            address: 0x30000,
+            data: &page,
+        };
+        let owned = OwnedCommand::try_from(&cmd).unwrap();
+        assert_eq!(owned.as_ref(), cmd);
+        assert_eq!(Command::from(&owned), cmd);
+    }
+
+    #[test]
+    #[cfg(all(feature = "ext-flash", feature = "host", feature = "device"))]
+    fn check_command_outlives_decoder() {
+        let mut queue: heapless::Deque<OwnedCommand, 2> = heapless::Deque::new();
+        let mut p = CommandDecoder::new();
+        let page = [0x55u8; EXT_PAGE_SIZE];
+        let cmd = Command::WriteExPage {
+            address: 0x1000,
+            data: &page,
+        };
+        for byte in CommandEncoder::new(&cmd).unwrap() {
+            if let Some(c) = p.receive(byte).unwrap() {
+                queue.push_back(OwnedCommand::try_from(&c).unwrap()).unwrap();
+            }
+        }
+        p.reset();
+        assert_eq!(queue.pop_front().unwrap().as_ref(), cmd);
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_command_too_long() {
+        let value = [0u8; MAX_ATTR_LEN + 1];
+        let cmd = Command::SetAttr {
+            index: 0,
+            key: b"board\0\0\0",
+            value: &value,
+        };
+        assert_eq!(OwnedCommand::try_from(&cmd), Err(Error::BadArguments));
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_response_roundtrip() {
+        let r = Response::GetAttr {
+            key: b"appaddr\0",
+            value: &[0x00, 0x00, 0x04, 0x00],
+        };
+        let owned = OwnedResponse::try_from(&r).unwrap();
+        assert_eq!(owned.as_ref(), r);
+        assert_eq!(Response::from(&owned), r);
+
+        let data = [0u8; MAX_OWNED_DATA_LEN + 1];
+        let r = Response::ReadRange { data: &data };
+        assert_eq!(OwnedResponse::try_from(&r), Err(Error::BadArguments));
+    }
+
+    #[cfg(all(feature = "attributes", feature = "serde"))]
+    #[test]
+    fn check_serde() {
+        extern crate serde_json;
+
+        let cmd = Command::SetAttr {
+            index: 1,
+            key: b"board",
+            value: b"hail",
+        };
+        let json = serde_json::to_string(&cmd).unwrap();
+        assert_eq!(
+            json,
+            r#"{"SetAttr":{"index":1,"key":[98,111,97,114,100],"value":[104,97,105,108]}}"#
+        );
+        let owned: OwnedCommand = serde_json::from_str(&json).unwrap();
+        assert_eq!(owned.as_ref(), cmd);
+
+        let json = serde_json::to_string(&Response::CrcIntFlash { crc: 7 }).unwrap();
+        let owned: OwnedResponse = serde_json::from_str(&json).unwrap();
+        assert_eq!(owned, OwnedResponse::CrcIntFlash { crc: 7 });
+
+        let json = serde_json::to_string(&Error::Refused).unwrap();
+        assert_eq!(json, r#""Refused""#);
+        assert_eq!(serde_json::from_str::<Error>(&json).unwrap(), Error::Refused);
+    }
+}
@@ -0,0 +1,431 @@
+//! Splitting an image into page writes.
+//!
+//! The bootloader only writes whole, aligned pages: 512 bytes in internal
+//! flash and 256 bytes in external flash. The `PageWriter` takes a start
+//! address and a slice of any length and produces the commands to write it,
+//! padding the first and last pages as required, or refusing to if asked.
+//! It can optionally erase
+//! each page first and follow each write with a CRC command, so the host can
+//! check the page landed correctly. Given the `ExtFlashGeometry` of an
+//! external flash chip, it erases in whole blocks where it can.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+#[cfg(feature = "ext-flash")]
+use super::address::ExtFlashGeometry;
+use super::crc::crc32;
+use super::{Command, Error};
+use super::INT_PAGE_SIZE;
+#[cfg(feature = "ext-flash")]
+use super::EXT_PAGE_SIZE;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Which flash an image is being written to.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FlashTarget {
+    /// Internal flash, with 512 byte pages.
+    Internal,
+    /// External flash, with 256 byte pages.
+    #[cfg(feature = "ext-flash")]
+    External,
+}
+
+/// What to do with space the data doesn't fill: the parts of the first and
+/// last pages a `PageWriter` writes which the data doesn't cover, or the
+/// padding in a `ResponseEncoder`'s replies.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PadPolicy {
+    /// Fill them with this byte. 0xFF leaves them looking erased.
+    Fill(u8),
+    /// Don't pad at all. The data has to start and end on page boundaries,
+    /// so nothing gets written that the caller didn't supply.
+    Reject,
+}
+
+/// The `PageWriter` produces the sequence of commands needed to write an
+/// arbitrary byte slice to flash.
+///
+/// It isn't an `Iterator`, as each `WritePage` command borrows the padded
+/// page held inside the writer. Call `next_command` until it returns `None`.
+#[derive(Clone)]
+pub struct PageWriter<'a> {
+    target: FlashTarget,
+    base: u32,
+    lead: usize,
+    data: &'a [u8],
+    num_pages: usize,
+    page_index: usize,
+    step: Step,
+    page: [u8; INT_PAGE_SIZE],
+    erase: bool,
+    erased_to: u64,
+    #[cfg(feature = "ext-flash")]
+    ext_geometry: Option<ExtFlashGeometry>,
+    verify: bool,
+    pad_byte: u8,
+    expected_crc: Option<u32>,
+}
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+#[derive(Clone, Copy)]
+enum Step {
+    Erase,
+    Write,
+    Verify,
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl FlashTarget {
+    /// The page size of this flash.
+    pub fn page_size(&self) -> usize {
+        match *self {
+            FlashTarget::Internal => INT_PAGE_SIZE,
+            #[cfg(feature = "ext-flash")]
+            FlashTarget::External => EXT_PAGE_SIZE,
+        }
+    }
+}
+
+impl<'a> PageWriter<'a> {
+    /// Create a new `PageWriter` which writes `data` starting at `address`.
+    ///
+    /// If `address` isn't page aligned, the first page starts at the
+    /// previous page boundary and the bytes before `address` are filled with
+    /// the pad byte, as are the bytes after the end of `data` in the last
+    /// page. Returns `Error::BadArguments` if the data runs past the end of
+    /// the 32-bit address space.
+    pub fn new(target: FlashTarget, address: u32, data: &'a [u8]) -> Result<PageWriter<'a>, Error> {
+        PageWriter::with_pad_policy(target, address, data, PadPolicy::Fill(0xFF))
+    }
+
+    /// Create a new `PageWriter`, padding partial pages as `policy` says.
+    /// With `PadPolicy::Reject`, returns `Error::BadArguments` unless
+    /// `address` and the end of `data` are both page aligned.
+    pub fn with_pad_policy(
+        target: FlashTarget,
+        address: u32,
+        data: &'a [u8],
+        policy: PadPolicy,
+    ) -> Result<PageWriter<'a>, Error> {
+        let page_size = target.page_size();
+        let lead = address as usize & (page_size - 1);
+        let span = lead + data.len();
+        if (address as u64) + (data.len() as u64) > (1u64 << 32) {
+            return Err(Error::BadArguments);
+        }
+        let pad_byte = match policy {
+            PadPolicy::Fill(pad_byte) => pad_byte,
+            PadPolicy::Reject if lead != 0 || span & (page_size - 1) != 0 => {
+                return Err(Error::BadArguments);
+            }
+            PadPolicy::Reject => 0xFF,
+        };
+        Ok(PageWriter {
+            target,
+            base: address - lead as u32,
+            lead,
+            data,
+            num_pages: span.div_ceil(page_size),
+            page_index: 0,
+            step: Step::Erase,
+            page: [0u8; INT_PAGE_SIZE],
+            erase: false,
+            erased_to: 0,
+            #[cfg(feature = "ext-flash")]
+            ext_geometry: None,
+            verify: false,
+            pad_byte,
+            expected_crc: None,
+        })
+    }
+
+    /// Whether to send an erase command before each page. The bootloader
+    /// erases as part of a write anyway, so this is off by default.
+    pub fn set_erase(&mut self, erase: bool) {
+        self.erase = erase;
+    }
+
+    /// Erase external flash in the blocks and pages of `geometry`, rather
+    /// than a 256 byte page at a time, when `set_erase` is on. Each block
+    /// the data covers whole gets one `EraseExBlock`, and the rest get an
+    /// `EraseExPage` for each erase page they touch. An erase page only
+    /// partly covered by the data is erased whole, so anything else in it
+    /// is lost.
+    #[cfg(feature = "ext-flash")]
+    pub fn set_ext_geometry(&mut self, geometry: ExtFlashGeometry) {
+        self.ext_geometry = Some(geometry);
+    }
+
+    /// Whether to send a CRC command after each page. Off by default.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    /// Set the byte used to pad partial pages. The default is 0xFF, the
+    /// value of erased flash. This has no effect if the writer was made
+    /// with `PadPolicy::Reject`, as there is nothing to pad.
+    pub fn set_pad_byte(&mut self, pad_byte: u8) {
+        self.pad_byte = pad_byte;
+    }
+
+    /// The number of pages which will be written.
+    pub fn num_pages(&self) -> usize {
+        self.num_pages
+    }
+
+    /// Get the next command to send, or `None` once the whole image has been
+    /// covered.
+    pub fn next_command(&mut self) -> Option<Command<'_>> {
+        let page_size = self.target.page_size();
+        while self.page_index < self.num_pages {
+            let address = self.base + (self.page_index * page_size) as u32;
+            match self.step {
+                Step::Erase => {
+                    self.step = Step::Write;
+                    if self.erase && address as u64 >= self.erased_to {
+                        return Some(self.erase_command(address));
+                    }
+                }
+                Step::Write => {
+                    self.step = Step::Verify;
+                    self.fill_page();
+                    let data = &self.page[0..page_size];
+                    return Some(match self.target {
+                        FlashTarget::Internal => Command::WritePage { address, data },
+                        #[cfg(feature = "ext-flash")]
+                        FlashTarget::External => Command::WriteExPage { address, data },
+                    });
+                }
+                Step::Verify => {
+                    self.step = Step::Erase;
+                    self.page_index += 1;
+                    if self.verify {
+                        let length = page_size as u32;
+                        self.expected_crc = Some(crc32(&self.page[0..page_size]));
+                        return Some(match self.target {
+                            FlashTarget::Internal => Command::CrcIntFlash { address, length },
+                            #[cfg(feature = "ext-flash")]
+                            FlashTarget::External => Command::CrcExtFlash { address, length },
+                        });
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// The CRC the bootloader should send back in reply to the most recent
+    /// `CrcIntFlash` or `CrcExtFlash` command.
+    pub fn expected_crc(&self) -> Option<u32> {
+        self.expected_crc
+    }
+
+    /// The command which erases the page at `address`, and perhaps the ones
+    /// after it.
+    fn erase_command(&mut self, address: u32) -> Command<'static> {
+        self.erased_to = address as u64 + self.target.page_size() as u64;
+        match self.target {
+            FlashTarget::Internal => Command::ErasePage { address },
+            #[cfg(feature = "ext-flash")]
+            FlashTarget::External => match self.ext_geometry {
+                Some(geometry) => {
+                    let end = self.base as u64 + (self.num_pages * EXT_PAGE_SIZE) as u64;
+                    let block_end = address as u64 + geometry.block_size() as u64;
+                    if geometry.is_block_aligned(address) && block_end <= end {
+                        self.erased_to = block_end;
+                        return Command::EraseExBlock { address };
+                    }
+                    let address = geometry.page_containing(address);
+                    self.erased_to = address as u64 + geometry.page_size() as u64;
+                    Command::EraseExPage { address }
+                }
+                None => Command::EraseExPage { address },
+            },
+        }
+    }
+
+    fn fill_page(&mut self) {
+        let page_size = self.target.page_size();
+        let start = self.page_index * page_size;
+        for (i, b) in self.page[0..page_size].iter_mut().enumerate() {
+            let pos = start + i;
+            *b = if pos >= self.lead && pos - self.lead < self.data.len() {
+                self.data[pos - self.lead]
+            } else {
+                self.pad_byte
+            };
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "ext-flash")]
+    use std::vec::Vec;
+
+    #[test]
+    fn check_aligned() {
+        let image = [0x5Au8; INT_PAGE_SIZE * 2];
+        let mut w = PageWriter::new(FlashTarget::Internal, 0x30000, &image).unwrap();
+        assert_eq!(w.num_pages(), 2);
+        assert_eq!(
+            w.next_command(),
+            Some(Command::WritePage {
+                address: 0x30000,
+                data: &image[0..INT_PAGE_SIZE],
+            })
+        );
+        assert_eq!(
+            w.next_command(),
+            Some(Command::WritePage {
+                address: 0x30200,
+                data: &image[INT_PAGE_SIZE..],
+            })
+        );
+        assert_eq!(w.next_command(), None);
+        assert_eq!(w.next_command(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "ext-flash")]
+    fn check_unaligned_padding() {
+        let image = [0x11u8; 10];
+        let mut w = PageWriter::new(FlashTarget::External, 0x1FC, &image).unwrap();
+        assert_eq!(w.num_pages(), 2);
+        match w.next_command() {
+            Some(Command::WriteExPage { address, data }) => {
+                assert_eq!(address, 0x100);
+                assert!(data[0..0xFC].iter().all(|&b| b == 0xFF));
+                assert!(data[0xFC..].iter().all(|&b| b == 0x11));
+            }
+            x => panic!("Unexpected {:?}", x),
+        }
+        match w.next_command() {
+            Some(Command::WriteExPage { address, data }) => {
+                assert_eq!(address, 0x200);
+                assert!(data[0..6].iter().all(|&b| b == 0x11));
+                assert!(data[6..].iter().all(|&b| b == 0xFF));
+            }
+            x => panic!("Unexpected {:?}", x),
+        }
+        assert_eq!(w.next_command(), None);
+    }
+
+    #[test]
+    fn check_erase_and_verify() {
+        let image = [0x22u8; 100];
+        let mut w = PageWriter::new(FlashTarget::Internal, 0x40000, &image).unwrap();
+        w.set_erase(true);
+        w.set_verify(true);
+        w.set_pad_byte(0x00);
+        assert_eq!(w.next_command(), Some(Command::ErasePage { address: 0x40000 }));
+        let crc = match w.next_command() {
+            Some(Command::WritePage { data, .. }) => crc32(data),
+            x => panic!("Unexpected {:?}", x),
+        };
+        assert_eq!(
+            w.next_command(),
+            Some(Command::CrcIntFlash {
+                address: 0x40000,
+                length: INT_PAGE_SIZE as u32,
+            })
+        );
+        assert_eq!(w.expected_crc(), Some(crc));
+        assert_eq!(w.next_command(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "ext-flash")]
+    fn check_ext_geometry() {
+        let geometry = ExtFlashGeometry::new(0x1000, 0x200).unwrap();
+        // The end of one block, a whole block, then part of the next
+        let image = [0x44u8; 0x2700];
+        let mut w = PageWriter::new(FlashTarget::External, 0x2100, &image).unwrap();
+        w.set_erase(true);
+        w.set_ext_geometry(geometry);
+        let mut erases = Vec::new();
+        while let Some(cmd) = w.next_command() {
+            match cmd {
+                Command::EraseExBlock { address } => erases.push(("block", address)),
+                Command::EraseExPage { address } => erases.push(("page", address)),
+                _ => {}
+            }
+        }
+        assert_eq!(
+            erases,
+            [
+                ("page", 0x2000),
+                ("page", 0x2200),
+                ("page", 0x2400),
+                ("page", 0x2600),
+                ("page", 0x2800),
+                ("page", 0x2A00),
+                ("page", 0x2C00),
+                ("page", 0x2E00),
+                ("block", 0x3000),
+                ("page", 0x4000),
+                ("page", 0x4200),
+                ("page", 0x4400),
+                ("page", 0x4600),
+            ]
+        );
+    }
+
+    #[test]
+    fn check_pad_policy() {
+        let image = [0x33u8; 100];
+        let policy = PadPolicy::Fill(0x00);
+        let mut w =
+            PageWriter::with_pad_policy(FlashTarget::Internal, 0x30000, &image, policy).unwrap();
+        match w.next_command() {
+            Some(Command::WritePage { data, .. }) => {
+                assert!(data[100..].iter().all(|&b| b == 0x00));
+            }
+            x => panic!("Unexpected {:?}", x),
+        }
+        let reject = |address, data| {
+            PageWriter::with_pad_policy(FlashTarget::Internal, address, data, PadPolicy::Reject)
+                .map(|w| w.num_pages())
+        };
+        assert_eq!(reject(0x30000, &image), Err(Error::BadArguments));
+        let image = [0x33u8; INT_PAGE_SIZE * 2];
+        assert_eq!(reject(0x30000, &image), Ok(2));
+        assert_eq!(reject(0x30004, &image[4..]), Err(Error::BadArguments));
+        assert_eq!(reject(0x30000, &[]), Ok(0));
+    }
+
+    #[test]
+    fn check_bounds() {
+        assert!(PageWriter::new(FlashTarget::Internal, 0xFFFF_FF00, &[0u8; 0x100]).is_ok());
+        assert!(PageWriter::new(FlashTarget::Internal, 0xFFFF_FF00, &[0u8; 0x101]).is_err());
+        let mut w = PageWriter::new(FlashTarget::Internal, 0x30000, &[]).unwrap();
+        assert_eq!(w.num_pages(), 0);
+        assert_eq!(w.next_command(), None);
+    }
+}
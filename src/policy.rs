@@ -0,0 +1,56 @@
+//! Deciding which commands a bootloader will carry out.
+//!
+//! A bootloader guarding a secure boot chain may not want the host to read
+//! back the bootloader itself, or to change attributes once the device has
+//! been locked. Give a `BootloaderSession` an `AccessPolicy` with
+//! `set_access_policy` and every decoded command is checked against it
+//! first. A command the policy denies never reaches the `FlashInterface`;
+//! the host gets the error response set with `set_denied_error` instead.
+//!
+//! Like an `Observer`, a policy is a `&'static` and `allow` takes `&self`,
+//! so any state it keeps, such as whether the device is locked, needs to
+//! be in an atomic:
+//!
+//! ```ignore
+//! struct Lockdown {
+//!     locked: AtomicBool,
+//! }
+//!
+//! impl AccessPolicy for Lockdown {
+//!     fn allow(&self, command: &Command) -> bool {
+//!         match *command {
+//!             // Keep the bootloader's own flash private
+//!             Command::ReadRange { address, length } => {
+//!                 address >= APP_START || address + length as u32 <= BOOTLOADER_START
+//!             }
+//!             Command::SetAttr { .. } => !self.locked.load(Ordering::Relaxed),
+//!             _ => true,
+//!         }
+//!     }
+//! }
+//!
+//! static POLICY: Lockdown = Lockdown { locked: AtomicBool::new(false) };
+//! session.set_access_policy(&POLICY);
+//! ```
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::Command;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Decides whether a `BootloaderSession` may carry out a command.
+pub trait AccessPolicy: Sync {
+    /// May `command` be carried out? This is called once the command has
+    /// been decoded, before anything else is done with it, including
+    /// `Reset`.
+    fn allow(&self, command: &Command) -> bool;
+}
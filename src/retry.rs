@@ -0,0 +1,291 @@
+//! Retrying commands that fail for transient reasons.
+//!
+//! Serial links drop bytes, and a busy bootloader can answer `InternalError`
+//! or `Overflow` to a command that would work a moment later. The
+//! `RetryingSession` wraps a `HostSession` and, when that happens, tells you
+//! how long to wait before sending the command again. Once the attempts run
+//! out you get a `RetryFailure` saying what went wrong.
+//!
+//! There's no clock in here. You report timeouts by calling `timed_out`, and
+//! do the waiting yourself.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::session::HostSession;
+use super::{Command, CommandEncoder, Error, Response};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// How long to wait between attempts.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Backoff {
+    /// Send again straight away.
+    None,
+    /// Wait the same number of milliseconds every time.
+    Fixed(u32),
+    /// Wait `initial_ms`, then double the wait each time, up to `max_ms`.
+    Exponential { initial_ms: u32, max_ms: u32 },
+}
+
+/// A `HostSession` which retries commands.
+pub struct RetryingSession<'a> {
+    session: HostSession,
+    policy: Policy<'a>,
+}
+
+/// What happened when a byte was received, or a timeout reported.
+#[derive(Debug, PartialEq)]
+pub enum Outcome<'r, 'a> {
+    /// The reply to the command.
+    Response(Response<'r>),
+    /// The command failed but can be tried again. Wait this many
+    /// milliseconds, then call `resend`.
+    Retry { delay_ms: u32 },
+    /// The command failed and we've run out of attempts.
+    Failed(RetryFailure<'a>),
+}
+
+/// Why a command was given up on.
+#[derive(Debug, PartialEq)]
+pub struct RetryFailure<'a> {
+    /// The command which failed.
+    pub command: &'a Command<'a>,
+    /// How many times it was sent.
+    pub attempts: u8,
+    /// The reply to the last attempt, or `None` if it timed out.
+    pub last_response: Option<Response<'static>>,
+}
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+/// Kept apart from the `HostSession` so it can be updated while a response
+/// borrowed from the session is still alive.
+struct Policy<'a> {
+    command: Option<&'a Command<'a>>,
+    attempts: u8,
+    max_attempts: u8,
+    backoff: Backoff,
+}
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const DEFAULT_MAX_ATTEMPTS: u8 = 3;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl Backoff {
+    /// The delay before the given retry, counting from 1.
+    pub fn delay_ms(&self, retry: u8) -> u32 {
+        match *self {
+            Backoff::None => 0,
+            Backoff::Fixed(ms) => ms,
+            Backoff::Exponential { initial_ms, max_ms } => {
+                let shift = u32::from(retry.saturating_sub(1)).min(31);
+                initial_ms.checked_mul(1 << shift).map_or(max_ms, |ms| ms.min(max_ms))
+            }
+        }
+    }
+}
+
+impl<'a> RetryingSession<'a> {
+    /// Create a new `RetryingSession`. Commands are sent up to three times,
+    /// with no delay in between.
+    pub const fn new() -> RetryingSession<'a> {
+        RetryingSession {
+            session: HostSession::new(),
+            policy: Policy {
+                command: None,
+                attempts: 0,
+                max_attempts: DEFAULT_MAX_ATTEMPTS,
+                backoff: Backoff::None,
+            },
+        }
+    }
+
+    /// Set how many times a command is sent before giving up, including the
+    /// first. Zero is treated as one.
+    pub fn set_max_attempts(&mut self, max_attempts: u8) {
+        self.policy.max_attempts = max_attempts.max(1);
+    }
+
+    /// Set how long to wait between attempts.
+    pub fn set_backoff(&mut self, backoff: Backoff) {
+        self.policy.backoff = backoff;
+    }
+
+    /// Number each command, so the bootloader can tell a resent one from a
+    /// new one. See `HostSession::set_sequence`.
+    #[cfg(feature = "sequence")]
+    pub fn set_sequence(&mut self, enabled: bool) {
+        self.session.set_sequence(enabled);
+    }
+
+    /// Send a new command, as with `HostSession::send`.
+    pub fn send(&mut self, command: &'a Command<'a>) -> Result<CommandEncoder<'a>, Error> {
+        let encoder = self.session.send(command)?;
+        self.policy.command = Some(command);
+        self.policy.attempts = 1;
+        Ok(encoder)
+    }
+
+    /// Send the current command again, after being told to `Retry`, with
+    /// the same sequence number if there is one. Returns
+    /// `Error::BadArguments` if there's no command to send.
+    pub fn resend(&mut self) -> Result<CommandEncoder<'a>, Error> {
+        let command = self.policy.command.ok_or(Error::BadArguments)?;
+        let encoder = self.session.resend(command)?;
+        self.policy.attempts += 1;
+        Ok(encoder)
+    }
+
+    /// Process incoming bytes.
+    ///
+    /// Errors from the underlying `HostSession` are passed straight back; if
+    /// you want to retry after one of those, call `timed_out`.
+    pub fn receive(&mut self, ch: u8) -> Result<Option<Outcome<'_, 'a>>, Error> {
+        let policy = &mut self.policy;
+        match self.session.receive(ch)? {
+            None => Ok(None),
+            Some(Response::InternalError) => Ok(Some(policy.failed(Some(Response::InternalError)))),
+            Some(Response::Overflow) => Ok(Some(policy.failed(Some(Response::Overflow)))),
+            Some(response) => {
+                policy.command = None;
+                Ok(Some(Outcome::Response(response)))
+            }
+        }
+    }
+
+    /// Report that no reply arrived in time. Returns `None` if there's no
+    /// command being retried.
+    pub fn timed_out(&mut self) -> Option<Outcome<'static, 'a>> {
+        self.policy.command?;
+        self.session.reset();
+        Some(self.policy.failed(None))
+    }
+
+    /// How many times the current command has been sent.
+    pub fn attempts(&self) -> u8 {
+        self.policy.attempts
+    }
+}
+
+impl<'a> Default for RetryingSession<'a> {
+    fn default() -> RetryingSession<'a> {
+        RetryingSession::new()
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<'a> Policy<'a> {
+    /// The current attempt failed. Decide whether to go again.
+    fn failed(&mut self, last_response: Option<Response<'static>>) -> Outcome<'static, 'a> {
+        match self.command {
+            Some(command) if self.attempts >= self.max_attempts => {
+                self.command = None;
+                Outcome::Failed(RetryFailure {
+                    command,
+                    attempts: self.attempts,
+                    last_response,
+                })
+            }
+            Some(_) => Outcome::Retry {
+                delay_ms: self.backoff.delay_ms(self.attempts),
+            },
+            // A failure turned up with nothing in flight, which the
+            // `HostSession` will already have flagged as a mismatch.
+            None => Outcome::Retry { delay_ms: 0 },
+        }
+    }
+}
+
+#[cfg(all(test, feature = "device"))]
+mod tests {
+    use super::*;
+    use super::super::ResponseEncoder;
+
+    fn feed<'s, 'a>(
+        s: &'s mut RetryingSession<'a>,
+        response: &Response,
+    ) -> Result<Option<Outcome<'s, 'a>>, Error> {
+        let len = ResponseEncoder::new(response).unwrap().count();
+        for b in ResponseEncoder::new(response).unwrap().take(len - 1) {
+            s.receive(b)?;
+        }
+        let last = ResponseEncoder::new(response).unwrap().last().unwrap();
+        s.receive(last)
+    }
+
+    #[test]
+    fn check_retry_then_success() {
+        let cmd = Command::Ping;
+        let mut s = RetryingSession::new();
+        s.set_backoff(Backoff::Fixed(10));
+        s.send(&cmd).unwrap();
+        assert_eq!(
+            feed(&mut s, &Response::InternalError),
+            Ok(Some(Outcome::Retry { delay_ms: 10 }))
+        );
+        s.resend().unwrap();
+        assert_eq!(s.attempts(), 2);
+        assert_eq!(feed(&mut s, &Response::Pong), Ok(Some(Outcome::Response(Response::Pong))));
+    }
+
+    #[test]
+    fn check_exhausted() {
+        let cmd = Command::ErasePage { address: 0x30000 };
+        let mut s = RetryingSession::new();
+        s.set_max_attempts(2);
+        s.send(&cmd).unwrap();
+        assert_eq!(s.timed_out(), Some(Outcome::Retry { delay_ms: 0 }));
+        s.resend().unwrap();
+        assert_eq!(
+            feed(&mut s, &Response::Overflow),
+            Ok(Some(Outcome::Failed(RetryFailure {
+                command: &cmd,
+                attempts: 2,
+                last_response: Some(Response::Overflow),
+            })))
+        );
+        assert_eq!(s.timed_out(), None);
+        assert!(s.resend().is_err());
+    }
+
+    #[test]
+    fn check_backoff() {
+        let b = Backoff::Exponential {
+            initial_ms: 50,
+            max_ms: 1000,
+        };
+        assert_eq!(b.delay_ms(1), 50);
+        assert_eq!(b.delay_ms(2), 100);
+        assert_eq!(b.delay_ms(5), 800);
+        assert_eq!(b.delay_ms(6), 1000);
+        assert_eq!(b.delay_ms(200), 1000);
+        assert_eq!(Backoff::None.delay_ms(3), 0);
+    }
+}
@@ -0,0 +1,260 @@
+//! Getting bytes from a UART interrupt to the main loop.
+//!
+//! A bootloader usually receives in an interrupt handler, but shouldn't
+//! decode or touch flash there. An `RxRing` is a single-producer,
+//! single-consumer queue between the two: the interrupt handler calls
+//! `push`, and the main loop calls `drain` to feed whatever has arrived into
+//! a `CommandDecoder`. It only needs atomic loads and stores, so it works
+//! on cores without compare-and-swap, such as the Cortex-M0.
+//!
+//! ```ignore
+//! static RX: RxRing<256> = RxRing::new();
+//!
+//! fn uart_isr() {
+//!     RX.push(uart.read());
+//! }
+//!
+//! loop {
+//!     RX.drain(&mut decoder, |command| { /* ... */ });
+//! }
+//! ```
+//!
+//! If the main loop falls behind and the ring fills up, bytes are dropped
+//! and counted. The frame they belonged to can't be recovered, so `drain`
+//! resets the decoder at the gap and reports `Error::Overflow`, which the
+//! host sees as `Response::Overflow`.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use super::{Command, CommandDecoder, Error};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// A queue of received bytes, `N` long. `N` must be a power of two.
+///
+/// Only one context may push, and only one may pop or drain.
+pub struct RxRing<const N: usize> {
+    buffer: [AtomicU8; N],
+    /// How many bytes have ever been pushed. Only written by `push`.
+    head: AtomicUsize,
+    /// How many bytes have ever been popped. Only written by `pop`.
+    tail: AtomicUsize,
+    /// How many bytes have been dropped. Only written by `push`.
+    overflows: AtomicUsize,
+    /// The value of `head` when a byte was last dropped.
+    lost_at: AtomicUsize,
+    /// The value of `overflows` when `drain` last reported a gap.
+    seen: AtomicUsize,
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<const N: usize> RxRing<N> {
+    /// Create an empty `RxRing`. This is a `const fn`, so the ring can be a
+    /// `static`.
+    pub const fn new() -> RxRing<N> {
+        assert!(N.is_power_of_two());
+        RxRing {
+            buffer: [const { AtomicU8::new(0) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            overflows: AtomicUsize::new(0),
+            lost_at: AtomicUsize::new(0),
+            seen: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add a byte. Returns `false`, and counts an overflow, if the ring is
+    /// full. Call this from the interrupt handler.
+    pub fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) >= N {
+            self.lost_at.store(head, Ordering::Relaxed);
+            let overflows = self.overflows.load(Ordering::Relaxed);
+            self.overflows.store(overflows.wrapping_add(1), Ordering::Release);
+            return false;
+        }
+        self.buffer[head & (N - 1)].store(byte, Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Add several bytes, as from a FIFO or a DMA buffer. Returns how many
+    /// fitted; the rest are counted as overflows.
+    pub fn push_slice(&self, bytes: &[u8]) -> usize {
+        bytes.iter().filter(|&&b| self.push(b)).count()
+    }
+
+    /// Take the oldest byte, if there is one.
+    pub fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let byte = self.buffer[tail & (N - 1)].load(Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+
+    /// Feed every byte waiting in the ring into `decoder`, passing each
+    /// command or error to `handler`.
+    ///
+    /// Where bytes were dropped, the decoder is reset and `handler` gets
+    /// `Error::Overflow`. If bytes are dropped at two places before `drain`
+    /// reaches the first, only the second gap is reported; the first will
+    /// usually show up as a bad frame instead.
+    pub fn drain<B, H>(&self, decoder: &mut CommandDecoder<B>, mut handler: H)
+    where
+        B: AsMut<[u8]>,
+        H: FnMut(Result<Command, Error>),
+    {
+        loop {
+            let overflows = self.overflows.load(Ordering::Acquire);
+            if overflows != self.seen.load(Ordering::Relaxed)
+                && self.tail.load(Ordering::Relaxed) == self.lost_at.load(Ordering::Relaxed)
+            {
+                self.seen.store(overflows, Ordering::Relaxed);
+                decoder.reset();
+                handler(Err(Error::Overflow));
+            }
+            let byte = match self.pop() {
+                Some(byte) => byte,
+                None => break,
+            };
+            match decoder.receive(byte) {
+                Ok(Some(command)) => handler(Ok(command)),
+                Ok(None) => {}
+                Err(e) => handler(Err(e)),
+            }
+        }
+    }
+
+    /// How many bytes are waiting.
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Relaxed);
+        self.head.load(Ordering::Acquire).wrapping_sub(tail)
+    }
+
+    /// Whether no bytes are waiting.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many bytes have been dropped because the ring was full. This
+    /// only ever goes up (wrapping eventually).
+    pub fn overflows(&self) -> usize {
+        self.overflows.load(Ordering::Acquire)
+    }
+}
+
+impl<const N: usize> Default for RxRing<N> {
+    fn default() -> RxRing<N> {
+        RxRing::new()
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use super::super::{CommandEncoder, ESCAPE_CHAR};
+
+    /// Drain `ring`, returning the erase addresses and errors seen.
+    fn drain<const N: usize>(
+        ring: &RxRing<N>,
+        decoder: &mut CommandDecoder,
+    ) -> Vec<Result<u32, Error>> {
+        let mut out = Vec::new();
+        ring.drain(decoder, |r| {
+            out.push(r.map(|c| match c {
+                Command::ErasePage { address } => address,
+                c => panic!("{:?}", c),
+            }))
+        });
+        out
+    }
+
+    fn erase(address: u32) -> Vec<u8> {
+        CommandEncoder::new(&Command::ErasePage { address }).unwrap().collect()
+    }
+
+    #[test]
+    fn check_push_pop() {
+        let ring: RxRing<4> = RxRing::new();
+        assert!(ring.is_empty());
+        assert_eq!(ring.push_slice(&[1, 2, 3, 4, 5]), 4);
+        assert_eq!(ring.len(), 4);
+        assert_eq!(ring.overflows(), 1);
+        assert_eq!(ring.pop(), Some(1));
+        assert!(ring.push(6));
+        let out: Vec<u8> = core::iter::from_fn(|| ring.pop()).collect();
+        assert_eq!(out, [2, 3, 4, 6]);
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn check_wraparound() {
+        // Each frame is 6 bytes, so they land all round an 8 byte ring
+        let ring: RxRing<8> = RxRing::new();
+        let mut decoder = CommandDecoder::new();
+        for address in 0..20 {
+            let frame = erase(address * 0x200);
+            assert_eq!(ring.push_slice(&frame), frame.len());
+            assert_eq!(drain(&ring, &mut decoder), [Ok(address * 0x200)]);
+        }
+        assert_eq!(ring.overflows(), 0);
+    }
+
+    #[test]
+    fn check_escape_split() {
+        // An escaped byte in the address, and the escape at the end of one
+        // drain with the byte it escapes at the start of the next
+        let ring: RxRing<8> = RxRing::new();
+        let mut decoder = CommandDecoder::new();
+        let frame = erase(0xFC00);
+        assert_eq!(frame.len(), 7);
+        let split = frame.iter().position(|&b| b == ESCAPE_CHAR).unwrap() + 1;
+        ring.push_slice(&frame[0..4]);
+        assert_eq!(drain(&ring, &mut decoder), []);
+        for (n, &b) in frame[4..].iter().enumerate() {
+            ring.push(b);
+            if n + 4 == split {
+                assert_eq!(drain(&ring, &mut decoder), []);
+            }
+        }
+        assert_eq!(drain(&ring, &mut decoder), [Ok(0xFC00)]);
+    }
+
+    #[test]
+    fn check_overflow() {
+        let ring: RxRing<8> = RxRing::new();
+        let mut decoder = CommandDecoder::new();
+        let frame = erase(0x400);
+        // The first frame fits, and the second loses its last 4 bytes
+        ring.push_slice(&frame);
+        assert_eq!(ring.push_slice(&frame), 2);
+        assert_eq!(ring.overflows(), 4);
+        assert_eq!(drain(&ring, &mut decoder), [Ok(0x400), Err(Error::Overflow)]);
+        // The stray bytes are forgotten, so the next frame comes through
+        ring.push_slice(&frame);
+        assert_eq!(drain(&ring, &mut decoder), [Ok(0x400)]);
+        assert_eq!(drain(&ring, &mut decoder), []);
+    }
+}
@@ -0,0 +1,280 @@
+//! Checking frames survive the trip through an encoder and a decoder.
+//!
+//! Anything that carries frames (a transport, a bridge, a fuzz target) can
+//! use these to make sure a command or response comes out of the decoder
+//! exactly as it went into the encoder. Escapes are where this usually goes
+//! wrong, so the tests here lean heavily on `ESCAPE_CHAR`.
+//!
+//! With the `test-utils` feature, `assert_command_roundtrip` and
+//! `assert_response_roundtrip` wrap these up for use in tests.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::{Command, CommandDecoder, CommandEncoder, Error, Response};
+use super::{InfoMode, PaddingMode, ResponseDecoder, ResponseEncoder};
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Encode `command`, decode the bytes again and check exactly one command
+/// comes out, on the last byte, and that it matches.
+///
+/// Returns `Ok(false)` if the commands differ, or `Err` if the encoder or
+/// decoder fails.
+pub fn roundtrip_check(command: &Command) -> Result<bool, Error> {
+    let mut decoder = CommandDecoder::new();
+    let mut encoder = CommandEncoder::new(command)?.peekable();
+    let mut same = None;
+    while let Some(byte) = encoder.next() {
+        if let Some(decoded) = decoder.receive(byte)? {
+            let last = encoder.peek().is_none();
+            same = Some(same.is_none() && last && decoded == *command);
+        }
+    }
+    Ok(same == Some(true))
+}
+
+/// Encode `response`, decode the bytes again and check exactly one response
+/// comes out, on the last byte, and that it matches. The payload length of
+/// a `ReadRange` or `ExReadRange` is taken from its data. An `Info` is sent
+/// with `PaddingMode::Spec` and read with `InfoMode::LengthPrefixed`.
+///
+/// Returns `Ok(false)` if the responses differ, or `Err` if the encoder or
+/// decoder fails.
+pub fn roundtrip_check_response(response: &Response) -> Result<bool, Error> {
+    let payload_len = match *response {
+        Response::ReadRange { data } => data.len(),
+        #[cfg(feature = "ext-flash")]
+        Response::ExReadRange { data } => data.len(),
+        _ => 0,
+    };
+    check_response(response, payload_len)
+}
+
+/// Panic unless `command` survives `roundtrip_check`.
+#[cfg(feature = "test-utils")]
+pub fn assert_command_roundtrip(command: &Command) {
+    match roundtrip_check(command) {
+        Ok(true) => {}
+        Ok(false) => panic!("{:?} changed on the way through", command),
+        Err(e) => panic!("{:?} failed to round trip: {:?}", command, e),
+    }
+}
+
+/// Panic unless `response` comes back out of a `ResponseDecoder` unchanged.
+/// The decoder is given `payload_len`, as a host would after sending the
+/// `ReadRange` or `ExReadRange`; it is ignored for other responses.
+#[cfg(feature = "test-utils")]
+pub fn assert_response_roundtrip(response: &Response, payload_len: usize) {
+    match check_response(response, payload_len) {
+        Ok(true) => {}
+        Ok(false) => panic!("{:?} changed on the way through", response),
+        Err(e) => panic!("{:?} failed to round trip: {:?}", response, e),
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+fn check_response(response: &Response, payload_len: usize) -> Result<bool, Error> {
+    let mut decoder = ResponseDecoder::new();
+    decoder.set_info_mode(InfoMode::LengthPrefixed);
+    match *response {
+        Response::ReadRange { .. } => decoder.set_payload_len(payload_len)?,
+        #[cfg(feature = "ext-flash")]
+        Response::ExReadRange { .. } => decoder.set_payload_len(payload_len)?,
+        _ => {}
+    }
+    let mut encoder = ResponseEncoder::new(response)?;
+    encoder.set_padding_mode(PaddingMode::Spec);
+    let mut encoder = encoder.peekable();
+    let mut same = None;
+    while let Some(byte) = encoder.next() {
+        if let Some(decoded) = decoder.receive(byte)? {
+            let last = encoder.peek().is_none();
+            same = Some(same.is_none() && last && decoded == *response);
+        }
+    }
+    Ok(same == Some(true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{ESCAPE_CHAR, INT_PAGE_SIZE, MAX_FRAME_LEN, MAX_INFO_LEN};
+    #[cfg(feature = "attributes")]
+    use super::super::{KEY_LEN, MAX_ATTR_LEN};
+    #[cfg(feature = "ext-flash")]
+    use super::super::EXT_PAGE_SIZE;
+
+    /// Payloads made mostly of escapes: all escapes, alternating escapes,
+    /// and escapes dropped into noise from a simple LCG.
+    fn patterns() -> [[u8; MAX_FRAME_LEN]; 4] {
+        let mut out = [[ESCAPE_CHAR; MAX_FRAME_LEN]; 4];
+        out[1].iter_mut().step_by(2).for_each(|b| *b = 0x00);
+        out[2].iter_mut().skip(1).step_by(2).for_each(|b| *b = 0x00);
+        let mut state = 0x1234_5678u32;
+        for b in out[3].iter_mut() {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            if state & 0x100 == 0 {
+                *b = (state >> 24) as u8;
+            }
+        }
+        out
+    }
+
+    /// Words made of escapes, and with one escape in each position.
+    const WORDS: [u32; 7] = [
+        0xFCFC_FCFC,
+        0x0000_00FC,
+        0x0000_FC00,
+        0x00FC_0000,
+        0xFC00_0000,
+        0xFC00_00FC,
+        0x0000_0000,
+    ];
+
+    #[test]
+    fn check_commands_roundtrip() {
+        for &word in &WORDS {
+            let commands = [
+                Command::Ping,
+                Command::ErasePage { address: word },
+                Command::ReadRange {
+                    address: word,
+                    length: word as u16,
+                },
+                Command::CrcIntFlash {
+                    address: word,
+                    length: word.rotate_left(8),
+                },
+            ];
+            for cmd in &commands {
+                assert_eq!(roundtrip_check(cmd), Ok(true), "{:?}", cmd);
+            }
+        }
+        for page in patterns().iter() {
+            let cmd = Command::WritePage {
+                address: 0xFC00,
+                data: &page[0..INT_PAGE_SIZE],
+            };
+            assert_eq!(roundtrip_check(&cmd), Ok(true));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "ext-flash")]
+    fn check_ex_commands_roundtrip() {
+        for page in patterns().iter() {
+            let cmd = Command::WriteExPage {
+                address: 0xFCFC_FC00,
+                data: &page[0..EXT_PAGE_SIZE],
+            };
+            assert_eq!(roundtrip_check(&cmd), Ok(true));
+        }
+        for &word in &WORDS {
+            let cmd = Command::ExReadRange {
+                address: word,
+                length: (word >> 16) as u16,
+            };
+            assert_eq!(roundtrip_check(&cmd), Ok(true));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_attr_commands_roundtrip() {
+        for value in patterns().iter() {
+            for len in 0..=MAX_ATTR_LEN {
+                let cmd = Command::SetAttr {
+                    index: 0x0F,
+                    key: &value[0..KEY_LEN],
+                    value: &value[0..len],
+                };
+                assert_eq!(roundtrip_check(&cmd), Ok(true));
+            }
+        }
+    }
+
+    #[test]
+    fn check_responses_roundtrip() {
+        for &word in &WORDS {
+            let responses = [
+                Response::CrcRxBuffer {
+                    length: word as u16,
+                    crc: word,
+                },
+                Response::CrcIntFlash { crc: word },
+            ];
+            for r in &responses {
+                assert_eq!(roundtrip_check_response(r), Ok(true), "{:?}", r);
+            }
+        }
+        for data in patterns().iter() {
+            for &len in &[0, 1, 2, 3, 255, MAX_FRAME_LEN - 1] {
+                let r = Response::ReadRange { data: &data[0..len] };
+                assert_eq!(roundtrip_check_response(&r), Ok(true));
+            }
+        }
+    }
+
+    #[test]
+    fn check_info_roundtrip() {
+        for info in patterns().iter() {
+            for &len in &[0, 1, 8, 15, MAX_INFO_LEN] {
+                let r = Response::Info { info: &info[0..len] };
+                assert_eq!(roundtrip_check_response(&r), Ok(true));
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_attr_responses_roundtrip() {
+        for value in patterns().iter() {
+            for len in 0..=MAX_ATTR_LEN {
+                let r = Response::GetAttr {
+                    key: &value[0..KEY_LEN],
+                    value: &value[0..len],
+                };
+                assert_eq!(roundtrip_check_response(&r), Ok(true));
+            }
+        }
+    }
+
+    #[test]
+    fn check_mismatch() {
+        let cmd = Command::WritePage {
+            address: 0,
+            data: &[0; 4],
+        };
+        assert_eq!(roundtrip_check(&cmd), Err(Error::BadArguments));
+    }
+
+    #[test]
+    #[cfg(feature = "test-utils")]
+    fn check_asserts() {
+        assert_command_roundtrip(&Command::ErasePage { address: 0xFC });
+        let data = [ESCAPE_CHAR; 16];
+        assert_response_roundtrip(&Response::ReadRange { data: &data }, 16);
+        assert_response_roundtrip(&Response::Pong, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "test-utils")]
+    #[should_panic]
+    fn check_assert_short_payload() {
+        let data = [ESCAPE_CHAR; 16];
+        assert_response_roundtrip(&Response::ReadRange { data: &data }, 8);
+    }
+}
@@ -0,0 +1,151 @@
+//! Spotting retransmitted commands.
+//!
+//! Over a lossy link, such as a radio bridge, a reply can be lost after the
+//! bootloader has acted on the command. The host sends the command again,
+//! and a `WritePage` on flash which has to be erased first ends up written
+//! twice. With the `sequence` feature, a command can start with a header of
+//! an escape, a mark and a sequence number:
+//!
+//! ```text
+//! FC F2 <sequence> <arguments> FC <opcode>
+//! ```
+//!
+//! A `BootloaderSession` remembers the sequence number of the last command,
+//! and answers a command which writes or erases flash, arriving again with
+//! the same number, with the reply it gave the first time without doing it
+//! again. Other commands don't change anything, so they are simply carried
+//! out again. A command without a header is never taken as a repeat, and
+//! makes the session forget the last number, so a host starting afresh
+//! should send one (a `Ping`, say) before turning the headers on.
+//!
+//! On the host side, `HostSession::set_sequence` turns the headers on. Each
+//! `send` takes the next number, and `resend` sends the last command again
+//! with the same one:
+//!
+//! ```ignore
+//! session.set_sequence(true);
+//! let encoder = session.send(&write)?;
+//! // No reply in time, so try again
+//! let encoder = session.resend(&write)?;
+//! ```
+//!
+//! Sequence numbers run from 0 to 0xFB and then wrap, so one is never an
+//! escape and is sent as it is.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::ESCAPE_CHAR;
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The mark in the header of a command with a sequence number.
+pub const SEQUENCE_MARK: u8 = 0xF2;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// The header which starts a command with sequence number `sequence`.
+pub const fn sequence_header(sequence: u8) -> [u8; 3] {
+    [ESCAPE_CHAR, SEQUENCE_MARK, sequence]
+}
+
+/// The sequence number after `sequence`.
+pub const fn next_sequence(sequence: u8) -> u8 {
+    if sequence >= ESCAPE_CHAR - 1 {
+        0
+    } else {
+        sequence + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(all(feature = "host", feature = "device"))]
+    use super::super::mock::MemFlash;
+    #[cfg(all(feature = "host", feature = "device"))]
+    use super::super::{BootloaderSession, Command, HostSession, Response, ResponseEncoder};
+    #[cfg(all(feature = "host", feature = "device"))]
+    use super::super::workflow::SYNC_BYTES;
+    #[cfg(all(feature = "host", feature = "device"))]
+    use super::super::INT_PAGE_SIZE;
+    #[cfg(all(feature = "host", feature = "device"))]
+    use std::vec::Vec;
+
+    #[test]
+    fn check_next_sequence() {
+        assert_eq!(next_sequence(0), 1);
+        assert_eq!(next_sequence(0xFA), 0xFB);
+        assert_eq!(next_sequence(0xFB), 0);
+        assert_eq!(next_sequence(0xFF), 0);
+        assert_eq!(sequence_header(7), [0xFC, 0xF2, 0x07]);
+    }
+
+    /// Pass `bytes` to `board`, and its reply, if any, back to `host`.
+    #[cfg(all(feature = "host", feature = "device"))]
+    fn deliver(host: &mut HostSession, board: &mut BootloaderSession<MemFlash>, bytes: &[u8]) {
+        let mut reply = Vec::new();
+        for &b in bytes {
+            if let Some(response) = board.receive(b) {
+                reply.extend(ResponseEncoder::new(&response).unwrap());
+            }
+        }
+        let last = reply.pop().unwrap();
+        for b in reply {
+            assert_eq!(host.receive(b), Ok(None));
+        }
+        assert_eq!(host.receive(last), Ok(Some(Response::Ok)));
+    }
+
+    #[test]
+    #[cfg(all(feature = "host", feature = "device"))]
+    fn check_duplicates() {
+        let mut board = BootloaderSession::new(MemFlash::new(INT_PAGE_SIZE));
+        let mut host = HostSession::new();
+        host.set_sequence(true);
+        let data = [0xFCu8; INT_PAGE_SIZE];
+        let write = Command::WritePage { address: 0, data: &data };
+        let bytes: Vec<u8> = host.send(&write).unwrap().collect();
+        assert_eq!(bytes[0..3], sequence_header(0));
+        assert_eq!(host.sequence(), Some(0));
+        deliver(&mut host, &mut board, &bytes);
+        assert_eq!(board.flash().data()[0..2], [0xFC, 0xFC]);
+        // The reply was lost, and the flash has moved on since
+        board.flash_mut().data_mut()[0] = 0;
+        let bytes: Vec<u8> = host.resend(&write).unwrap().collect();
+        assert_eq!(bytes[0..3], sequence_header(0));
+        deliver(&mut host, &mut board, &bytes);
+        assert_eq!(board.flash().data()[0..2], [0x00, 0xFC]);
+        // A new command with the same arguments is carried out
+        let bytes: Vec<u8> = host.send(&write).unwrap().collect();
+        assert_eq!(bytes[0..3], sequence_header(1));
+        deliver(&mut host, &mut board, &bytes);
+        assert_eq!(board.flash().data()[0..2], [0xFC, 0xFC]);
+        // A Reset in between makes the board forget the number
+        board.flash_mut().data_mut()[0] = 0;
+        for &b in &SYNC_BYTES {
+            assert_eq!(board.receive(b), None);
+        }
+        let bytes: Vec<u8> = host.resend(&write).unwrap().collect();
+        deliver(&mut host, &mut board, &bytes);
+        assert_eq!(board.flash().data()[0..2], [0xFC, 0xFC]);
+        // As is one without a sequence number
+        board.flash_mut().data_mut()[0] = 0;
+        host.set_sequence(false);
+        let bytes: Vec<u8> = host.resend(&write).unwrap().collect();
+        assert_eq!(host.sequence(), None);
+        deliver(&mut host, &mut board, &bytes);
+        assert_eq!(board.flash().data()[0..2], [0xFC, 0xFC]);
+    }
+}
@@ -0,0 +1,578 @@
+//! Pairing commands with their responses on the host side.
+//!
+//! A flash tool has to remember which command it sent, tell the
+//! `ResponseDecoder` how long a `ReadRange` reply will be, and check that
+//! what comes back is actually the reply to that command. The `HostSession`
+//! does that bookkeeping for you.
+//!
+//! Given a `Clock`, it can also tell when a reply is overdue. See the
+//! `clock` module. With the `multi-drop` feature, it can talk to one of
+//! several bootloaders on a bus. See the `multidrop` module. With the
+//! `sequence` feature, it can number commands so a bootloader can spot one
+//! sent twice. See the `sequence` module.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::clock::{Clock, NoClock, TimedOut, Timeouts};
+use super::observer::Observer;
+#[cfg(feature = "sequence")]
+use super::sequence::next_sequence;
+use super::{Command, CommandEncoder, Error, InfoMode, Opcode, Response, ResponseDecoder};
+use super::MAX_FRAME_LEN;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// The `HostSession` encodes `Command`s and decodes the `Response`s that
+/// come back, checking each response against the command that was sent.
+pub struct HostSession<B = [u8; MAX_FRAME_LEN], C = NoClock> {
+    decoder: ResponseDecoder<B>,
+    in_flight: Option<Opcode>,
+    resync: bool,
+    observer: Option<&'static dyn Observer>,
+    clock: C,
+    timeouts: Timeouts,
+    sent_ms: u32,
+    last_byte_ms: Option<u32>,
+    #[cfg(feature = "multi-drop")]
+    address: Option<u8>,
+    #[cfg(feature = "multi-drop")]
+    new_address: u8,
+    #[cfg(feature = "sequence")]
+    sequencing: bool,
+    #[cfg(feature = "sequence")]
+    sequence: Option<u8>,
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl HostSession {
+    /// Create a new `HostSession` with no command in flight.
+    #[inline(always)]
+    pub const fn new() -> HostSession {
+        HostSession::with_buffer_and_clock([0u8; MAX_FRAME_LEN], NoClock)
+    }
+}
+
+impl<C> HostSession<[u8; MAX_FRAME_LEN], C> {
+    /// Create a new `HostSession` which uses `clock` to time responses.
+    #[inline(always)]
+    pub const fn with_clock(clock: C) -> HostSession<[u8; MAX_FRAME_LEN], C> {
+        HostSession::with_buffer_and_clock([0u8; MAX_FRAME_LEN], clock)
+    }
+}
+
+impl<B> HostSession<B> {
+    /// Create a new `HostSession` which decodes responses in `buffer`. See
+    /// `ResponseDecoder::with_buffer` for how big it needs to be.
+    #[inline(always)]
+    pub const fn with_buffer(buffer: B) -> HostSession<B> {
+        HostSession::with_buffer_and_clock(buffer, NoClock)
+    }
+}
+
+impl<B, C> HostSession<B, C> {
+    /// Create a new `HostSession` which decodes responses in `buffer` and
+    /// uses `clock` to time them.
+    #[inline(always)]
+    pub const fn with_buffer_and_clock(buffer: B, clock: C) -> HostSession<B, C> {
+        HostSession {
+            decoder: ResponseDecoder::with_buffer(buffer),
+            in_flight: None,
+            resync: false,
+            observer: None,
+            clock,
+            timeouts: Timeouts::new(),
+            sent_ms: 0,
+            last_byte_ms: None,
+            #[cfg(feature = "multi-drop")]
+            address: None,
+            #[cfg(feature = "multi-drop")]
+            new_address: 0,
+            #[cfg(feature = "sequence")]
+            sequencing: false,
+            #[cfg(feature = "sequence")]
+            sequence: None,
+        }
+    }
+}
+
+impl<B, C> HostSession<B, C>
+where
+    B: AsMut<[u8]>,
+    C: Clock,
+{
+    /// Start sending a command.
+    ///
+    /// Returns an encoder which supplies the bytes to send. The session then
+    /// expects the matching response, so any partially received response to
+    /// an earlier command is dropped. Commands which get no reply (`Reset`
+    /// and `ClockOut`) leave nothing in flight. A read whose reply won't fit
+    /// in the session's buffer gets `Error::BufferFull`.
+    pub fn send<'a>(&mut self, command: &'a Command<'a>) -> Result<CommandEncoder<'a>, Error> {
+        self.start(command, false)
+    }
+
+    /// Send `command` again, after its reply was lost or mangled. This is
+    /// the same as `send`, except that with sequence numbers on (see
+    /// `set_sequence`) it goes with the same number as the last command, so
+    /// the bootloader can tell it's a repeat.
+    pub fn resend<'a>(&mut self, command: &'a Command<'a>) -> Result<CommandEncoder<'a>, Error> {
+        self.start(command, true)
+    }
+
+    #[cfg_attr(not(feature = "sequence"), allow(unused_variables))]
+    fn start<'a>(
+        &mut self,
+        command: &'a Command<'a>,
+        repeat: bool,
+    ) -> Result<CommandEncoder<'a>, Error> {
+        #[cfg_attr(
+            not(any(feature = "multi-drop", feature = "sequence")),
+            allow(unused_mut)
+        )]
+        let mut encoder = CommandEncoder::new(command)?;
+        self.reset();
+        #[cfg(feature = "multi-drop")]
+        {
+            encoder.set_address(self.address);
+            self.decoder.set_address(self.address);
+        }
+        #[cfg(feature = "sequence")]
+        if self.sequencing {
+            let sequence = match self.sequence {
+                Some(last) if repeat => last,
+                Some(last) => next_sequence(last),
+                None => 0,
+            };
+            self.sequence = Some(sequence);
+            encoder.set_sequence(Some(sequence));
+        }
+        match *command {
+            Command::ReadRange { length, .. } => {
+                self.decoder.set_payload_len(length as usize)?;
+            }
+            #[cfg(feature = "ext-flash")]
+            Command::ExReadRange { length, .. } => {
+                self.decoder.set_payload_len(length as usize)?;
+            }
+            #[cfg(feature = "multi-drop")]
+            Command::SetAddress { address } => self.new_address = address,
+            _ => {}
+        }
+        let opcode = command.kind();
+        if opcode != Opcode::Reset && opcode != Opcode::ClockOut {
+            self.in_flight = Some(opcode);
+        }
+        self.sent_ms = self.clock.now_ms();
+        self.last_byte_ms = None;
+        Ok(encoder)
+    }
+
+    /// Process incoming bytes.
+    ///
+    /// Works like `ResponseDecoder::receive`, but once a `Response` has been
+    /// decoded it is checked against the command in flight. If it doesn't
+    /// match, or no command was in flight, `Error::MismatchedResponse` is
+    /// returned. Either way, the command is no longer in flight.
+    pub fn receive(&mut self, ch: u8) -> Result<Option<Response<'_>>, Error> {
+        if self.resync {
+            self.reset();
+        }
+        if self.in_flight.is_some() {
+            self.last_byte_ms = Some(self.clock.now_ms());
+        }
+        let in_flight = self.in_flight;
+        match self.decoder.receive(ch) {
+            Ok(None) => Ok(None),
+            Ok(Some(response)) => {
+                self.in_flight = None;
+                match in_flight {
+                    Some(opcode) if expects(opcode, &response) => {
+                        #[cfg(feature = "multi-drop")]
+                        if let (Opcode::SetAddress, Response::Ok) = (opcode, &response) {
+                            self.address = Some(self.new_address);
+                        }
+                        Ok(Some(response))
+                    }
+                    _ => {
+                        if let Some(o) = self.observer {
+                            o.on_error(Error::MismatchedResponse);
+                        }
+                        Err(Error::MismatchedResponse)
+                    }
+                }
+            }
+            Err(e) => {
+                // Start afresh with the next byte. The decoder complains if a
+                // response of fixed length turns up when we told it to
+                // expect a read.
+                self.in_flight = None;
+                self.resync = true;
+                if e == Error::SetLength {
+                    Err(Error::MismatchedResponse)
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// Check whether the response to the command in flight is overdue,
+    /// according to the limits given to `set_timeouts`. If it is, the
+    /// command is no longer in flight, and whatever had arrived of the
+    /// response is given back in the `TimedOut`. It's dropped when the
+    /// next byte arrives.
+    pub fn poll(&mut self) -> Result<(), TimedOut<'_>> {
+        if self.in_flight.is_none() || self.resync {
+            return Ok(());
+        }
+        let now = self.clock.now_ms();
+        let since_sent = now.wrapping_sub(self.sent_ms);
+        let since_byte = self.last_byte_ms.map(|t| now.wrapping_sub(t));
+        match self.timeouts.check(since_sent, since_byte) {
+            Some(kind) => {
+                self.in_flight = None;
+                self.resync = true;
+                Err(TimedOut {
+                    kind,
+                    partial: self.decoder.partial(),
+                })
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// How long to wait for responses. There are no limits unless this is
+    /// called, and none of them ever run out with the default `NoClock`.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Report everything this session sees to `observer`.
+    pub fn set_observer(&mut self, observer: &'static dyn Observer) {
+        self.observer = Some(observer);
+        self.decoder.set_observer(observer);
+    }
+
+    /// The observer given to `set_observer`, if any.
+    pub fn observer(&self) -> Option<&'static dyn Observer> {
+        self.observer
+    }
+
+    /// Choose how `Info` replies are read. See `ResponseDecoder::set_info_mode`.
+    pub fn set_info_mode(&mut self, mode: InfoMode) {
+        self.decoder.set_info_mode(mode);
+    }
+
+    /// Expect `GetAttr` replies with values padded to `len` bytes. See
+    /// `ResponseDecoder::set_attr_len`.
+    #[cfg(feature = "attributes")]
+    pub fn set_attr_len(&mut self, len: usize) {
+        self.decoder.set_attr_len(len);
+    }
+
+    /// Expect `Id` replies with `len` bytes of ID. See
+    /// `ResponseDecoder::set_id_len`.
+    pub fn set_id_len(&mut self, len: usize) {
+        self.decoder.set_id_len(len);
+    }
+
+    /// Send commands to, and only take responses from, the bootloader at
+    /// `address`, or talk unaddressed if it's `None`. See the `multidrop`
+    /// module. This takes effect from the next `send`, and a `SetAddress`
+    /// which gets `Ok` changes it too.
+    #[cfg(feature = "multi-drop")]
+    pub fn set_address(&mut self, address: Option<u8>) {
+        self.address = address;
+    }
+
+    /// The address commands are sent to. See `set_address`.
+    #[cfg(feature = "multi-drop")]
+    pub fn address(&self) -> Option<u8> {
+        self.address
+    }
+
+    /// Number each command sent, as described in the `sequence` module.
+    /// Turning this on starts again from zero.
+    #[cfg(feature = "sequence")]
+    pub fn set_sequence(&mut self, enabled: bool) {
+        self.sequencing = enabled;
+        self.sequence = None;
+    }
+
+    /// The sequence number of the last command sent, or `None` if there
+    /// are none.
+    #[cfg(feature = "sequence")]
+    pub fn sequence(&self) -> Option<u8> {
+        self.sequence
+    }
+
+    /// Is there a command waiting for a response?
+    pub fn in_flight(&self) -> bool {
+        self.in_flight.is_some()
+    }
+
+    /// Forget about any command in flight and drop any partially received
+    /// response.
+    pub fn reset(&mut self) {
+        self.decoder.restart();
+        self.in_flight = None;
+        self.resync = false;
+        self.last_byte_ms = None;
+    }
+}
+
+impl Default for HostSession {
+    fn default() -> HostSession {
+        HostSession::new()
+    }
+}
+
+/// Could `response` be the reply to `command`? Any command can get one of
+/// the generic errors, such as `BadArguments`, but otherwise each command
+/// has its own replies. A bootloader which has got out of step, and is
+/// answering the command before, will usually fail this check.
+pub fn matches(command: &Command, response: &Response) -> bool {
+    expects(command.kind(), response)
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Is `response` an acceptable reply to the command with the given opcode?
+fn expects(opcode: Opcode, response: &Response) -> bool {
+    match *response {
+        // Any command can fail
+        Response::Overflow |
+        Response::BadAddress |
+        Response::InternalError |
+        Response::BadArguments |
+        Response::Unknown => true,
+        #[cfg(feature = "ext-flash")]
+        Response::ExtFlashTimeout | Response::ExtFlashPageError => matches!(
+            opcode,
+            Opcode::EraseExBlock |
+                Opcode::WriteExPage |
+                Opcode::ExReadRange |
+                Opcode::CrcExtFlash |
+                Opcode::EraseExPage |
+                Opcode::ExtFlashInit
+        ),
+        Response::Pong => opcode == Opcode::Ping,
+        // Some older bootloaders answer ID with OK
+        Response::Ok => matches!(
+            opcode,
+            Opcode::ErasePage |
+                Opcode::WritePage |
+                Opcode::EraseExBlock |
+                Opcode::WriteExPage |
+                Opcode::SetAttr |
+                Opcode::EraseExPage |
+                Opcode::ExtFlashInit |
+                Opcode::WriteFlashUserPages |
+                Opcode::ChangeBaud |
+                Opcode::SetAddress |
+                Opcode::SetLongAttr |
+                Opcode::Id |
+                Opcode::Exit
+        ),
+        Response::CrcRxBuffer { .. } => opcode == Opcode::CrcRxBuffer,
+        Response::ReadRange { .. } => opcode == Opcode::ReadRange,
+        #[cfg(feature = "ext-flash")]
+        Response::ExReadRange { .. } => opcode == Opcode::ExReadRange,
+        #[cfg(feature = "attributes")]
+        Response::GetAttr { .. } => opcode == Opcode::GetAttr,
+        Response::CrcIntFlash { .. } => opcode == Opcode::CrcIntFlash,
+        #[cfg(feature = "ext-flash")]
+        Response::CrcExtFlash { .. } => opcode == Opcode::CrcExtFlash,
+        Response::Info { .. } => opcode == Opcode::Info,
+        Response::Id { .. } => opcode == Opcode::Id,
+        #[cfg(feature = "long-attributes")]
+        Response::GetLongAttr { .. } => opcode == Opcode::GetLongAttr,
+        #[cfg(feature = "baud-change")]
+        Response::ChangeBaudFail => opcode == Opcode::ChangeBaud,
+    }
+}
+
+#[cfg(all(test, feature = "device"))]
+mod tests {
+    use super::*;
+    use super::super::ResponseEncoder;
+
+    fn feed<'s, B: AsMut<[u8]>, C: Clock>(
+        s: &'s mut HostSession<B, C>,
+        response: &Response,
+    ) -> Result<Option<Response<'s>>, Error> {
+        let bytes: [u8; 64] = {
+            let mut buf = [0u8; 64];
+            for (i, b) in ResponseEncoder::new(response).unwrap().enumerate() {
+                buf[i] = b;
+            }
+            buf
+        };
+        let len = ResponseEncoder::new(response).unwrap().count();
+        for &b in &bytes[0..len - 1] {
+            s.receive(b)?;
+        }
+        s.receive(bytes[len - 1])
+    }
+
+    #[test]
+    fn check_read_range() {
+        let mut s = HostSession::new();
+        let cmd = Command::ReadRange {
+            address: 0x30000,
+            length: 4,
+        };
+        assert_eq!(s.send(&cmd).unwrap().count(), 4 + 2 + 2);
+        assert!(s.in_flight());
+        let r = Response::ReadRange { data: &[1, 2, 3, 4] };
+        assert_eq!(feed(&mut s, &r), Ok(Some(Response::ReadRange { data: &[1, 2, 3, 4] })));
+        assert!(!s.in_flight());
+    }
+
+    #[test]
+    fn check_error_response_accepted() {
+        let mut s = HostSession::new();
+        s.send(&Command::ErasePage { address: 0x30001 }).unwrap();
+        assert_eq!(feed(&mut s, &Response::BadAddress), Ok(Some(Response::BadAddress)));
+    }
+
+    #[test]
+    #[cfg(all(feature = "ext-flash", feature = "attributes"))]
+    fn check_mismatched_response() {
+        let mut s = HostSession::new();
+        s.send(&Command::GetAttr { index: 0 }).unwrap();
+        assert_eq!(
+            feed(&mut s, &Response::CrcIntFlash { crc: 0 }),
+            Err(Error::MismatchedResponse)
+        );
+        assert!(!s.in_flight());
+
+        // A fixed length reply to a read
+        let cmd = Command::ReadRange {
+            address: 0x30000,
+            length: 16,
+        };
+        s.send(&cmd).unwrap();
+        assert_eq!(
+            feed(&mut s, &Response::CrcExtFlash { crc: 0 }),
+            Err(Error::MismatchedResponse)
+        );
+
+        // Nothing was sent
+        assert_eq!(feed(&mut s, &Response::Pong), Err(Error::MismatchedResponse));
+
+        // Session is still usable afterwards
+        s.send(&Command::Ping).unwrap();
+        assert_eq!(feed(&mut s, &Response::Pong), Ok(Some(Response::Pong)));
+    }
+
+    #[test]
+    fn check_small_buffer() {
+        let mut s = HostSession::with_buffer([0u8; 16]);
+        let read = |length| Command::ReadRange {
+            address: 0x30000,
+            length,
+        };
+        assert_eq!(s.send(&read(16)).err(), Some(Error::BufferFull));
+        assert!(!s.in_flight());
+        s.send(&read(15)).unwrap();
+        let data = [0xAA; 15];
+        assert_eq!(
+            feed(&mut s, &Response::ReadRange { data: &data }),
+            Ok(Some(Response::ReadRange { data: &data }))
+        );
+        s.send(&Command::Ping).unwrap();
+        assert_eq!(feed(&mut s, &Response::Pong), Ok(Some(Response::Pong)));
+    }
+
+    #[test]
+    #[cfg(feature = "ext-flash")]
+    fn check_matches() {
+        let info = Command::Info;
+        assert!(matches(&info, &Response::Info { info: b"tock" }));
+        assert!(matches(&info, &Response::InternalError));
+        assert!(!matches(&info, &Response::Ok));
+        assert!(!matches(&info, &Response::ExtFlashTimeout));
+        let crc = Command::CrcExtFlash {
+            address: 0,
+            length: 256,
+        };
+        assert!(matches(&crc, &Response::CrcExtFlash { crc: 0 }));
+        assert!(matches(&crc, &Response::ExtFlashTimeout));
+        // The answer to the command before
+        assert!(!matches(&crc, &Response::CrcIntFlash { crc: 0 }));
+    }
+
+    #[test]
+    fn check_timeouts() {
+        use super::super::clock::TimeoutKind;
+        use core::cell::Cell;
+
+        struct TestClock<'a>(&'a Cell<u32>);
+
+        impl<'a> Clock for TestClock<'a> {
+            fn now_ms(&self) -> u32 {
+                self.0.get()
+            }
+        }
+
+        let now = Cell::new(u32::MAX - 5);
+        let mut s = HostSession::with_clock(TestClock(&now));
+        s.set_timeouts(Timeouts {
+            first_byte_ms: Some(100),
+            inter_byte_ms: Some(20),
+            total_ms: None,
+        });
+        s.send(&Command::Info).unwrap();
+        // Wraps around
+        now.set(now.get().wrapping_add(99));
+        assert_eq!(s.poll(), Ok(()));
+        now.set(now.get().wrapping_add(1));
+        let e = s.poll().unwrap_err();
+        assert_eq!((e.kind, e.partial), (TimeoutKind::FirstByte, &[][..]));
+        assert!(!s.in_flight());
+
+        // Half a response
+        s.send(&Command::Info).unwrap();
+        for &b in &[0xFC, 0x25, b't', b'o'] {
+            assert_eq!(s.receive(b), Ok(None));
+            now.set(now.get() + 10);
+        }
+        assert_eq!(s.poll(), Ok(()));
+        now.set(now.get() + 10);
+        let e = s.poll().unwrap_err();
+        assert_eq!((e.kind, e.partial), (TimeoutKind::InterByte, &[0x25, b't', b'o'][..]));
+        assert_eq!(s.poll(), Ok(()));
+
+        // Nothing times out once the reply is in
+        s.send(&Command::Ping).unwrap();
+        assert_eq!(feed(&mut s, &Response::Pong), Ok(Some(Response::Pong)));
+        now.set(now.get() + 1000);
+        assert_eq!(s.poll(), Ok(()));
+    }
+
+    #[test]
+    fn check_no_reply_commands() {
+        let mut s = HostSession::new();
+        s.send(&Command::Ping).unwrap();
+        s.send(&Command::Reset).unwrap();
+        assert!(!s.in_flight());
+    }
+}
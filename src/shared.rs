@@ -0,0 +1,246 @@
+//! Sharing a `CommandDecoder` between an interrupt handler and the main
+//! loop.
+//!
+//! The interrupt handler feeds each byte in as it arrives, and the main
+//! loop polls for finished commands. The decoder sits in a
+//! `critical_section::Mutex`, so a `SharedDecoder` can be a plain `static`,
+//! with no `unsafe`. Critical sections are only held for one byte, or for
+//! copying out one frame; the command is decoded and carried out with
+//! interrupts enabled.
+//!
+//! ```ignore
+//! static DECODER: SharedDecoder = SharedDecoder::new();
+//!
+//! fn uart_isr() {
+//!     DECODER.feed(uart.read());
+//! }
+//!
+//! let mut frame = [0u8; MAX_FRAME_LEN];
+//! loop {
+//!     if let Some(command) = DECODER.poll(&mut frame) {
+//!         // ...
+//!     }
+//! }
+//! ```
+//!
+//! The host waits for a response before sending the next command, so no
+//! bytes should arrive between a command finishing and the main loop
+//! polling for it. Any that do are dropped, and counted.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+
+use super::{decode_command, Command, CommandDecoder, Error, MAX_FRAME_LEN};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// A `CommandDecoder` that can be fed from an interrupt handler and polled
+/// from the main loop.
+pub struct SharedDecoder<B = [u8; MAX_FRAME_LEN]> {
+    inner: Mutex<RefCell<Inner<B>>>,
+}
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+struct Inner<B> {
+    decoder: CommandDecoder<B>,
+    /// A finished frame, as its opcode and argument length, or the error
+    /// the decoder gave, waiting for `poll`.
+    ready: Option<Result<(u8, usize), Error>>,
+    dropped: usize,
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl SharedDecoder {
+    /// Create a new `SharedDecoder`. This is a `const fn`, so it can be a
+    /// `static`.
+    pub const fn new() -> SharedDecoder {
+        SharedDecoder::with_decoder(CommandDecoder::new())
+    }
+}
+
+impl<B> SharedDecoder<B> {
+    /// Share `decoder`, which may have its own buffer or settings.
+    pub const fn with_decoder(decoder: CommandDecoder<B>) -> SharedDecoder<B> {
+        SharedDecoder {
+            inner: Mutex::new(RefCell::new(Inner {
+                decoder,
+                ready: None,
+                dropped: 0,
+            })),
+        }
+    }
+}
+
+impl<B> SharedDecoder<B>
+where
+    B: AsMut<[u8]>,
+{
+    /// Feed in a received byte. Call this from the interrupt handler.
+    ///
+    /// Returns `false` if the byte was dropped, because the last command
+    /// hasn't been polled for yet.
+    pub fn feed(&self, byte: u8) -> bool {
+        critical_section::with(|cs| {
+            let mut inner = self.inner.borrow_ref_mut(cs);
+            if inner.ready.is_some() {
+                inner.dropped = inner.dropped.wrapping_add(1);
+                return false;
+            }
+            let len = inner.decoder.count;
+            inner.ready = match inner.decoder.receive(byte) {
+                Ok(Some(_)) => Some(Ok((byte, len))),
+                Ok(None) => None,
+                Err(e) => Some(Err(e)),
+            };
+            true
+        })
+    }
+
+    /// Feed in several bytes, as from a FIFO. Returns how many were used;
+    /// the rest were dropped.
+    pub fn feed_slice(&self, bytes: &[u8]) -> usize {
+        bytes.iter().filter(|&&b| self.feed(b)).count()
+    }
+
+    /// Take the command that has arrived, if there is one, copying its
+    /// arguments into `frame`. The decoder can then take the next command
+    /// while this one is carried out.
+    ///
+    /// `frame` has to hold the arguments, which for `WritePage` is
+    /// `MAX_FRAME_LEN` bytes. If it's too short, the command is lost and
+    /// `Error::BufferFull` is returned.
+    pub fn poll<'a>(&self, frame: &'a mut [u8]) -> Option<Result<Command<'a>, Error>> {
+        let ready = critical_section::with(|cs| {
+            let mut inner = self.inner.borrow_ref_mut(cs);
+            let ready = inner.ready.take()?;
+            Some(ready.and_then(|(opcode, len)| {
+                let args = inner.decoder.buffer.as_mut().get(0..len).unwrap_or(&[]);
+                let dest = frame.get_mut(0..args.len()).ok_or(Error::BufferFull)?;
+                dest.copy_from_slice(args);
+                Ok((opcode, len))
+            }))
+        })?;
+        let (opcode, len) = match ready {
+            Ok(ready) => ready,
+            Err(e) => return Some(Err(e)),
+        };
+        // The decoder has already checked this frame, so it will decode
+        // again to the same command
+        let frame: &'a [u8] = frame;
+        let args = frame.get(0..len).unwrap_or(&[]);
+        Some(decode_command(opcode, args).and_then(|c| c.ok_or(Error::UnknownCommand)))
+    }
+
+    /// Whether a command is waiting for `poll`.
+    pub fn is_ready(&self) -> bool {
+        critical_section::with(|cs| self.inner.borrow_ref(cs).ready.is_some())
+    }
+
+    /// How many bytes have been dropped because they arrived before the
+    /// last command was polled for.
+    pub fn dropped(&self) -> usize {
+        critical_section::with(|cs| self.inner.borrow_ref(cs).dropped)
+    }
+
+    /// Run `f` on the decoder, to change its settings or read its
+    /// `rx_crc`. Interrupts are disabled while `f` runs, so keep it short.
+    pub fn with_decoder_mut<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut CommandDecoder<B>) -> R,
+    {
+        critical_section::with(|cs| f(&mut self.inner.borrow_ref_mut(cs).decoder))
+    }
+}
+
+impl Default for SharedDecoder {
+    fn default() -> SharedDecoder {
+        SharedDecoder::new()
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use super::super::{CommandEncoder, INT_PAGE_SIZE};
+
+    fn encode(command: &Command) -> Vec<u8> {
+        CommandEncoder::new(command).unwrap().collect()
+    }
+
+    #[test]
+    fn check_feed_and_poll() {
+        static DECODER: SharedDecoder = SharedDecoder::new();
+        let mut page = [0u8; INT_PAGE_SIZE];
+        page[7] = 0xFC;
+        let write = Command::WritePage {
+            address: 0x3FC00,
+            data: &page,
+        };
+        let mut frame = [0u8; MAX_FRAME_LEN];
+        assert!(DECODER.poll(&mut frame).is_none());
+
+        let bytes = encode(&write);
+        assert_eq!(DECODER.feed_slice(&bytes), bytes.len());
+        assert!(DECODER.is_ready());
+        // Nothing more gets in until the command is taken
+        assert!(!DECODER.feed(0x00));
+        assert_eq!(DECODER.dropped(), 1);
+        assert_eq!(DECODER.poll(&mut frame), Some(Ok(write)));
+        assert!(DECODER.poll(&mut frame).is_none());
+
+        let bytes = encode(&Command::Ping);
+        assert_eq!(DECODER.feed_slice(&bytes), bytes.len());
+        assert_eq!(DECODER.poll(&mut frame), Some(Ok(Command::Ping)));
+    }
+
+    #[test]
+    fn check_errors() {
+        let decoder = SharedDecoder::new();
+        let mut frame = [0u8; 8];
+        let bytes = encode(&Command::ErasePage { address: 0x400 });
+        // An extra argument byte
+        decoder.feed(0x00);
+        decoder.feed_slice(&bytes);
+        match decoder.poll(&mut frame) {
+            Some(Err(Error::WrongLength { expected: 4, got: 5, .. })) => {}
+            other => panic!("{:?}", other),
+        }
+
+        let page = [0u8; INT_PAGE_SIZE];
+        let bytes = encode(&Command::WritePage {
+            address: 0,
+            data: &page,
+        });
+        decoder.feed_slice(&bytes);
+        assert_eq!(decoder.poll(&mut frame), Some(Err(Error::BufferFull)));
+
+        decoder.with_decoder_mut(|d| d.set_rx_crc(true));
+        decoder.feed_slice(&bytes);
+        assert!(decoder.poll(&mut [0u8; MAX_FRAME_LEN]).unwrap().is_ok());
+        let rx_crc = decoder.with_decoder_mut(|d| d.rx_crc());
+        assert_eq!(rx_crc.map(|(len, _)| len), Some(4 + INT_PAGE_SIZE as u16));
+    }
+}
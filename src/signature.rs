@@ -0,0 +1,217 @@
+//! Only starting images which carry a valid signature.
+//!
+//! The host writes the image as usual, then sends a detached signature for
+//! it in a `SetAttr` with the reserved key `sig`. The value is the address
+//! and length of the signed range of internal flash, four bytes each and
+//! little endian, then the signature itself. `signature_command` builds it.
+//!
+//! A `BootloaderSession` given a `Verifier` with `set_verifier` doesn't
+//! store that attribute. It checks the signature over the flash straight
+//! away, answering `Ok` if it's good and `BadArguments` if not. Until a
+//! signature has been checked, `Exit` (see `set_exit_command`) gets
+//! `BadArguments` and the app isn't started. Writing or erasing flash
+//! after that means the image has to be signed again. `Reset` has nothing
+//! to do with it: signed or not, it only clears out what was half done.
+//!
+//! The crypto is up to the `Verifier`. The session reads the range for it
+//! a chunk at a time, so it can hash the image as it goes:
+//!
+//! ```ignore
+//! struct Ed25519(VerifyingKey);
+//!
+//! impl Verifier for Ed25519 {
+//!     fn verify(&self, image: &mut SignedImage, signature: &[u8])
+//!         -> Result<bool, FlashError> {
+//!         if image.address() != APP_START {
+//!             return Ok(false);
+//!         }
+//!         let mut hash = Sha512::new();
+//!         image.read(|chunk| hash.update(chunk))?;
+//!         match Signature::from_slice(signature) {
+//!             Ok(signature) => Ok(self.0.verify_prehashed(hash, None, &signature).is_ok()),
+//!             Err(_) => Ok(false),
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! An attribute value is normally at most `MAX_ATTR_LEN` bytes, which
+//! leaves room for a 47 byte signature. For longer ones, such as the 64
+//! bytes of an Ed25519 signature, both ends need `set_attr_len`.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use byteorder::{ByteOrder, LittleEndian};
+
+#[cfg(feature = "device")]
+use super::device::{FlashError, FlashInterface};
+use super::{Command, Error, KEY_LEN};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Checks the signature of an image in internal flash.
+#[cfg(feature = "device")]
+pub trait Verifier: Sync {
+    /// Is `signature` valid for `image`? Return `Ok(false)` if it isn't, or
+    /// if the image isn't one this verifier will vouch for, such as one at
+    /// the wrong address. Errors reading the image should be passed back.
+    fn verify(&self, image: &mut SignedImage, signature: &[u8]) -> Result<bool, FlashError>;
+}
+
+/// The range of internal flash a signature covers, as given to a
+/// `Verifier`.
+#[cfg(feature = "device")]
+pub struct SignedImage<'a> {
+    flash: &'a mut dyn FlashInterface,
+    buffer: &'a mut [u8],
+    address: u32,
+    length: u32,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The reserved attribute key which signatures are sent under.
+pub const SIGNATURE_KEY: &[u8; KEY_LEN] = b"sig\0\0\0\0\0";
+
+/// The bytes at the start of the signature attribute, before the signature:
+/// the address and the length of the signed range.
+pub const SIGNATURE_HEADER_LEN: usize = 8;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(feature = "device")]
+impl<'a> SignedImage<'a> {
+    /// The `length` bytes at `address`, read through `flash` a `buffer` at
+    /// a time.
+    pub(crate) fn new(
+        flash: &'a mut dyn FlashInterface,
+        buffer: &'a mut [u8],
+        address: u32,
+        length: u32,
+    ) -> SignedImage<'a> {
+        SignedImage {
+            flash,
+            buffer,
+            address,
+            length,
+        }
+    }
+
+    /// The address the image starts at.
+    pub fn address(&self) -> u32 {
+        self.address
+    }
+
+    /// The length of the image, in bytes.
+    pub fn len(&self) -> u32 {
+        self.length
+    }
+
+    /// Whether the image is empty.
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Read the whole image from flash, passing it to `f` a chunk at a time.
+    pub fn read<F>(&mut self, mut f: F) -> Result<(), FlashError>
+    where
+        F: FnMut(&[u8]),
+    {
+        if self.buffer.is_empty() {
+            return Err(FlashError::Internal);
+        }
+        let end = self.address.checked_add(self.length).ok_or(FlashError::BadAddress)?;
+        let mut address = self.address;
+        while address < end {
+            let len = ((end - address) as usize).min(self.buffer.len());
+            let chunk = &mut self.buffer[0..len];
+            self.flash.read(address, chunk)?;
+            f(chunk);
+            address += len as u32;
+        }
+        Ok(())
+    }
+}
+
+/// Build the `SetAttr` command which sends `signature`, for the `length`
+/// bytes of internal flash at `address`. The value is written into `buffer`,
+/// which needs room for `SIGNATURE_HEADER_LEN` bytes and the signature;
+/// otherwise `Error::BufferFull` is returned.
+///
+/// The command uses index 0, but a bootloader checking signatures doesn't
+/// store it anywhere.
+pub fn signature_command<'b>(
+    address: u32,
+    length: u32,
+    signature: &[u8],
+    buffer: &'b mut [u8],
+) -> Result<Command<'b>, Error> {
+    let len = SIGNATURE_HEADER_LEN + signature.len();
+    let value = buffer.get_mut(0..len).ok_or(Error::BufferFull)?;
+    LittleEndian::write_u32(&mut value[0..4], address);
+    LittleEndian::write_u32(&mut value[4..8], length);
+    value[SIGNATURE_HEADER_LEN..].copy_from_slice(signature);
+    Ok(Command::SetAttr {
+        index: 0,
+        key: SIGNATURE_KEY,
+        value,
+    })
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Split the value of a signature attribute into the address, the length
+/// and the signature.
+#[cfg(feature = "device")]
+pub(crate) fn parse_signature(value: &[u8]) -> Option<(u32, u32, &[u8])> {
+    if value.len() < SIGNATURE_HEADER_LEN {
+        return None;
+    }
+    let (header, signature) = value.split_at(SIGNATURE_HEADER_LEN);
+    Some((
+        LittleEndian::read_u32(&header[0..4]),
+        LittleEndian::read_u32(&header[4..8]),
+        signature,
+    ))
+}
+
+#[cfg(all(test, feature = "device"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_signature_command() {
+        let mut buffer = [0u8; 16];
+        let cmd = signature_command(0x30000, 0x1234, &[0xAA; 8], &mut buffer).unwrap();
+        match cmd {
+            Command::SetAttr { index, key, value } => {
+                assert_eq!((index, key), (0, &SIGNATURE_KEY[..]));
+                assert_eq!(parse_signature(value), Some((0x30000, 0x1234, &[0xAA; 8][..])));
+            }
+            c => panic!("{:?}", c),
+        }
+        let mut buffer = [0u8; 16];
+        assert_eq!(signature_command(0, 0, &[0; 9], &mut buffer), Err(Error::BufferFull));
+        assert_eq!(parse_signature(&[0; 7]), None);
+    }
+}
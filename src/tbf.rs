@@ -0,0 +1,176 @@
+//! Tock Binary Format headers.
+//!
+//! Every Tock app starts with a TBF header. Apps are stored back to back, so
+//! reading the base header at the start of the app region gives the size of
+//! the first app, and hence the address of the second, and so on until
+//! something that isn't a valid header (usually erased flash) turns up.
+//! Only the fixed 16 byte base header is parsed here; the TLVs that follow
+//! it are left alone.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use byteorder::{ByteOrder, LittleEndian};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// A TBF base header.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TbfHeader {
+    /// The header format version. Only version 2 is supported.
+    pub version: u16,
+    /// The length of the whole header, including the TLVs.
+    pub header_size: u16,
+    /// The length of the app, including the header and any padding.
+    pub total_size: u32,
+    /// See `FLAG_ENABLED` and `FLAG_STICKY`.
+    pub flags: u32,
+    /// The XOR of every other 32-bit word in the header.
+    pub checksum: u32,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The length of the TBF base header. Read this many bytes at an app's
+/// address to parse its header.
+pub const BASE_HEADER_LEN: usize = 16;
+
+/// The only header version we understand.
+pub const TBF_VERSION: u16 = 2;
+
+/// The kernel will start the app.
+pub const FLAG_ENABLED: u32 = 1 << 0;
+
+/// The app shouldn't be removed without being asked twice.
+pub const FLAG_STICKY: u32 = 1 << 1;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl TbfHeader {
+    /// Parse the base header at the start of `bytes`.
+    ///
+    /// Returns `None` if `bytes` is too short, the version isn't 2, or the
+    /// sizes are inconsistent - which is what you get from erased flash at
+    /// the end of the app list.
+    pub fn parse(bytes: &[u8]) -> Option<TbfHeader> {
+        if bytes.len() < BASE_HEADER_LEN {
+            return None;
+        }
+        let header = TbfHeader {
+            version: LittleEndian::read_u16(&bytes[0..2]),
+            header_size: LittleEndian::read_u16(&bytes[2..4]),
+            total_size: LittleEndian::read_u32(&bytes[4..8]),
+            flags: LittleEndian::read_u32(&bytes[8..12]),
+            checksum: LittleEndian::read_u32(&bytes[12..16]),
+        };
+        if header.version != TBF_VERSION || (header.header_size as usize) < BASE_HEADER_LEN ||
+            header.total_size < u32::from(header.header_size)
+        {
+            return None;
+        }
+        Some(header)
+    }
+
+    /// Check the checksum against the whole header (base header plus TLVs).
+    /// Returns false if `bytes` is shorter than `header_size`.
+    pub fn checksum_valid(&self, bytes: &[u8]) -> bool {
+        let len = self.header_size as usize;
+        if bytes.len() < len {
+            return false;
+        }
+        let mut checksum = 0;
+        for (i, word) in bytes[0..len].chunks(4).enumerate() {
+            // Word 3 is the checksum itself. A short final word is zero
+            // padded.
+            if i != 3 {
+                let mut padded = [0u8; 4];
+                padded[0..word.len()].copy_from_slice(word);
+                checksum ^= LittleEndian::read_u32(&padded);
+            }
+        }
+        checksum == self.checksum
+    }
+
+    /// Will the kernel start this app?
+    pub fn is_enabled(&self) -> bool {
+        self.flags & FLAG_ENABLED != 0
+    }
+
+    /// Is this app marked as sticky?
+    pub fn is_sticky(&self) -> bool {
+        self.flags & FLAG_STICKY != 0
+    }
+
+    /// Given that this header was read at `address`, where does the next app
+    /// start? Returns `None` if that would overflow the address space.
+    pub fn next_address(&self, address: u32) -> Option<u32> {
+        address.checked_add(self.total_size)
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLINK: [u8; 16] = [
+        0x02, 0x00, 0x10, 0x00, // version 2, 16 byte header
+        0x00, 0x08, 0x00, 0x00, // 2 KiB
+        0x01, 0x00, 0x00, 0x00, // enabled
+        0x03, 0x08, 0x10, 0x00, // checksum
+    ];
+
+    #[test]
+    fn check_parse() {
+        let header = TbfHeader::parse(&BLINK).unwrap();
+        assert_eq!(
+            header,
+            TbfHeader {
+                version: 2,
+                header_size: 16,
+                total_size: 0x800,
+                flags: FLAG_ENABLED,
+                checksum: 0x0010_0803,
+            }
+        );
+        assert!(header.is_enabled());
+        assert!(!header.is_sticky());
+        assert!(header.checksum_valid(&BLINK));
+        assert_eq!(header.next_address(0x30000), Some(0x30800));
+        assert_eq!(header.next_address(0xFFFF_F900), None);
+    }
+
+    #[test]
+    fn check_invalid() {
+        assert_eq!(TbfHeader::parse(&[0xFF; 16]), None);
+        assert_eq!(TbfHeader::parse(&BLINK[0..15]), None);
+        let mut bad = BLINK;
+        // Total size smaller than the header
+        bad[4] = 0x08;
+        bad[5] = 0x00;
+        assert_eq!(TbfHeader::parse(&bad), None);
+        let mut bad = BLINK;
+        bad[12] ^= 1;
+        assert!(!TbfHeader::parse(&bad).unwrap().checksum_valid(&bad));
+    }
+}
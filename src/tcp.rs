@@ -0,0 +1,254 @@
+//! Talking to a bootloader over TCP.
+//!
+//! Serial-over-LAN bridges and CI farms expose a board's UART as a TCP
+//! socket. A `TcpTransport` is a `Transport` for such a socket, and also a
+//! `std::io` stream so it can be handed to `Host::new`.
+//!
+//! The bridge may have been left with half a command in its buffers by the
+//! last client, so by default we resync on connect by flooding the
+//! bootloader with `Reset` commands and throwing away whatever comes back.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use super::session::HostSession;
+use super::transport::{run_host_command, RunError, Transport};
+use super::{Command, Response};
+use super::{CMD_RESET, ESCAPE_CHAR};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// A `Transport` over a TCP socket.
+pub struct TcpTransport {
+    stream: TcpStream,
+    resync: bool,
+}
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+/// How long to wait for each byte, unless told otherwise.
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How many `Reset`s to send when resyncing.
+const RESYNC_FLOOD_LEN: usize = 16;
+
+/// How long to wait for stray bytes while resyncing.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(50);
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl TcpTransport {
+    /// Connect to a bridge and resync.
+    pub fn connect<A>(addr: A) -> io::Result<TcpTransport>
+    where
+        A: ToSocketAddrs,
+    {
+        TcpTransport::from_stream(TcpStream::connect(addr)?)
+    }
+
+    /// Use an already connected socket, and resync.
+    pub fn from_stream(stream: TcpStream) -> io::Result<TcpTransport> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(DEFAULT_READ_TIMEOUT))?;
+        let mut t = TcpTransport {
+            stream,
+            resync: true,
+        };
+        t.resync()?;
+        Ok(t)
+    }
+
+    /// Set how long to wait for each byte. `None` waits forever.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// Turn resyncing on or off. When off, `resync` does nothing.
+    pub fn set_resync(&mut self, resync: bool) {
+        self.resync = resync;
+    }
+
+    /// Flush out anything the bootloader has half received, then throw away
+    /// whatever is on its way back.
+    pub fn resync(&mut self) -> io::Result<()> {
+        if !self.resync {
+            return Ok(());
+        }
+        // The leading null ends any escape sequence the bootloader is in the
+        // middle of.
+        let mut flood = [0u8; 1 + 2 * RESYNC_FLOOD_LEN];
+        for pair in flood[1..].chunks_mut(2) {
+            pair.copy_from_slice(&[ESCAPE_CHAR, CMD_RESET]);
+        }
+        self.stream.write_all(&flood)?;
+        self.stream.flush()?;
+        let timeout = self.stream.read_timeout()?;
+        self.stream.set_read_timeout(Some(DRAIN_TIMEOUT))?;
+        let mut junk = [0u8; 64];
+        let result = loop {
+            match self.read(&mut junk) {
+                Ok(0) => break Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.stream.set_read_timeout(timeout)?;
+        result
+    }
+
+    /// Send `command` and wait for the reply, as `run_host_command` does.
+    ///
+    /// If the reply doesn't arrive in time `session` is reset, so a reply
+    /// which turns up late is rejected rather than taken as the answer to
+    /// the next command.
+    pub fn command<F, R>(
+        &mut self,
+        session: &mut HostSession,
+        command: &Command,
+        handler: F,
+    ) -> Result<Option<R>, RunError<io::Error>>
+    where
+        F: FnOnce(Response) -> R,
+    {
+        let result = run_host_command(self, session, command, handler);
+        if let Err(RunError::Transport(ref e)) = result {
+            if e.kind() == io::ErrorKind::TimedOut {
+                session.reset();
+            }
+        }
+        result
+    }
+
+    /// Get the socket back.
+    pub fn release(self) -> TcpStream {
+        self.stream
+    }
+}
+
+impl Transport for TcpTransport {
+    type Error = io::Error;
+
+    fn read_byte(&mut self) -> Result<u8, io::Error> {
+        let mut buf = [0u8; 1];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), io::Error> {
+        self.stream.write_all(bytes)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.stream.flush()
+    }
+}
+
+impl io::Read for TcpTransport {
+    /// A read timeout is always reported as `TimedOut`. (On Unix, sockets
+    /// report it as `WouldBlock`.)
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream.read(buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+            }
+            result => result,
+        }
+    }
+}
+
+impl io::Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::device::BootloaderSession;
+    use super::super::mock::MemFlash;
+    use super::super::ResponseEncoder;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::vec::Vec;
+
+    /// Serve a bootloader on a socket until the client hangs up. Pings are
+    /// only answered once the bootloader has seen a `Reset`, so the client
+    /// has to resync.
+    fn bridge(listener: TcpListener) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut session = BootloaderSession::new(MemFlash::new(0x1000));
+        let mut seen_reset = false;
+        let mut last = 0;
+        let mut buf = [0u8; 64];
+        loop {
+            let len = match stream.read(&mut buf) {
+                Ok(0) | Err(_) => return,
+                Ok(len) => len,
+            };
+            for &b in &buf[0..len] {
+                seen_reset |= last == ESCAPE_CHAR && b == CMD_RESET;
+                last = b;
+                if let Some(r) = session.receive(b) {
+                    if seen_reset {
+                        let tx: Vec<u8> = ResponseEncoder::new(&r).unwrap().collect();
+                        stream.write_all(&tx).unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn check_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || bridge(listener));
+
+        let mut t = TcpTransport::connect(addr).unwrap();
+        let mut s = HostSession::new();
+        let result = t.command(&mut s, &Command::Ping, |r| r == Response::Pong);
+        assert_eq!(result.unwrap(), Some(true));
+
+        // No reply to a half sent command
+        t.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        t.write_bytes(&[ESCAPE_CHAR]).unwrap();
+        match t.read_byte() {
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+            x => panic!("Unexpected {:?}", x),
+        }
+
+        drop(t);
+        server.join().unwrap();
+    }
+}
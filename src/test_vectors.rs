@@ -0,0 +1,451 @@
+//! Known-good wire encodings.
+//!
+//! One vector for every command and every response, giving the value and
+//! the exact bytes that go over the wire for it, as sent by tockloader and
+//! the C bootloader. Another implementation of the protocol can check
+//! itself against these; this crate's own encoders and decoders are checked
+//! against them in the tests below.
+//!
+//! Responses are given in the layout stock tockloader expects, which is
+//! what a `ResponseEncoder` produces with `PaddingMode::Spec`.
+//!
+//! Vectors for command groups which have been left out (see the
+//! `ext-flash`, `attributes`, `baud-change` and `user-pages` features) are
+//! left out too.
+//!
+//! `EXTENSION_COMMANDS` and `EXTENSION_RESPONSES` cover the commands this
+//! crate adds to the protocol, such as the long attributes. Neither
+//! tockloader nor the C bootloader speaks them, so these vectors are only
+//! this crate's own layout, written down; they're there with the features
+//! which turn the extensions on.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+#[cfg(feature = "baud-change")]
+use super::BaudMode;
+use super::{Command, Response};
+use super::{INT_PAGE_SIZE, MAX_INFO_LEN};
+#[cfg(feature = "ext-flash")]
+use super::EXT_PAGE_SIZE;
+#[cfg(feature = "attributes")]
+use super::MAX_ATTR_LEN;
+#[cfg(feature = "long-attributes")]
+use super::MAX_LONG_ATTR_LEN;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// A command and its encoding.
+pub struct CommandVector {
+    /// What the vector is testing.
+    pub name: &'static str,
+    /// The command.
+    pub command: Command<'static>,
+    /// The bytes on the wire, in pieces. Concatenate them to get the frame.
+    pub wire: &'static [&'static [u8]],
+}
+
+/// A response and its encoding.
+pub struct ResponseVector {
+    /// What the vector is testing.
+    pub name: &'static str,
+    /// The response.
+    pub response: Response<'static>,
+    /// The bytes on the wire, in pieces. Concatenate them to get the frame.
+    pub wire: &'static [&'static [u8]],
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// Every command, as tockloader sends it.
+pub static COMMANDS: &[CommandVector] = &[
+    CommandVector {
+        name: "ping",
+        command: Command::Ping,
+        wire: &[&[0xFC, 0x01]],
+    },
+    CommandVector {
+        name: "info",
+        command: Command::Info,
+        wire: &[&[0xFC, 0x03]],
+    },
+    CommandVector {
+        name: "id",
+        command: Command::Id,
+        wire: &[&[0xFC, 0x04]],
+    },
+    CommandVector {
+        name: "reset",
+        command: Command::Reset,
+        wire: &[&[0xFC, 0x05]],
+    },
+    CommandVector {
+        name: "erase page",
+        command: Command::ErasePage { address: 0x30000 },
+        wire: &[&[0x00, 0x00, 0x03, 0x00, 0xFC, 0x06]],
+    },
+    CommandVector {
+        name: "write page, every byte escaped",
+        command: Command::WritePage {
+            address: 0x30000,
+            data: &[0xFC; INT_PAGE_SIZE],
+        },
+        wire: &[&[0x00, 0x00, 0x03, 0x00], &[0xFC; 2 * INT_PAGE_SIZE], &[0xFC, 0x07]],
+    },
+    #[cfg(feature = "ext-flash")]
+    CommandVector {
+        name: "erase external block",
+        command: Command::EraseExBlock { address: 0x800 },
+        wire: &[&[0x00, 0x08, 0x00, 0x00, 0xFC, 0x08]],
+    },
+    #[cfg(feature = "ext-flash")]
+    CommandVector {
+        name: "write external page",
+        command: Command::WriteExPage {
+            address: 0x100,
+            data: &[0x00; EXT_PAGE_SIZE],
+        },
+        wire: &[&[0x00, 0x01, 0x00, 0x00], &[0x00; EXT_PAGE_SIZE], &[0xFC, 0x09]],
+    },
+    CommandVector {
+        name: "crc rx buffer",
+        command: Command::CrcRxBuffer,
+        wire: &[&[0xFC, 0x10]],
+    },
+    CommandVector {
+        name: "read range",
+        command: Command::ReadRange {
+            address: 0x30000,
+            length: 0x200,
+        },
+        wire: &[&[0x00, 0x00, 0x03, 0x00, 0x00, 0x02, 0xFC, 0x11]],
+    },
+    #[cfg(feature = "ext-flash")]
+    CommandVector {
+        name: "read external range",
+        command: Command::ExReadRange {
+            address: 0x1000,
+            length: 16,
+        },
+        wire: &[&[0x00, 0x10, 0x00, 0x00, 0x10, 0x00, 0xFC, 0x12]],
+    },
+    #[cfg(feature = "attributes")]
+    CommandVector {
+        name: "set attribute",
+        command: Command::SetAttr {
+            index: 0,
+            key: b"board\0\0\0",
+            value: b"hail",
+        },
+        wire: &[&[0x00], b"board\0\0\0", &[0x04], b"hail", &[0xFC, 0x13]],
+    },
+    #[cfg(feature = "attributes")]
+    CommandVector {
+        name: "get attribute",
+        command: Command::GetAttr { index: 3 },
+        wire: &[&[0x03, 0xFC, 0x14]],
+    },
+    CommandVector {
+        name: "crc internal flash",
+        command: Command::CrcIntFlash {
+            address: 0x30000,
+            length: 0x1000,
+        },
+        wire: &[&[0x00, 0x00, 0x03, 0x00, 0x00, 0x10, 0x00, 0x00, 0xFC, 0x15]],
+    },
+    #[cfg(feature = "ext-flash")]
+    CommandVector {
+        name: "crc external flash",
+        command: Command::CrcExtFlash {
+            address: 0,
+            length: 0x100,
+        },
+        wire: &[&[0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0xFC, 0x16]],
+    },
+    #[cfg(feature = "ext-flash")]
+    CommandVector {
+        name: "erase external page",
+        command: Command::EraseExPage { address: 0x200 },
+        wire: &[&[0x00, 0x02, 0x00, 0x00, 0xFC, 0x17]],
+    },
+    #[cfg(feature = "ext-flash")]
+    CommandVector {
+        name: "external flash init",
+        command: Command::ExtFlashInit,
+        wire: &[&[0xFC, 0x18]],
+    },
+    CommandVector {
+        name: "clock out",
+        command: Command::ClockOut,
+        wire: &[&[0xFC, 0x19]],
+    },
+    #[cfg(feature = "user-pages")]
+    CommandVector {
+        name: "write flash user pages",
+        command: Command::WriteFlashUserPages {
+            page1: 0x1122_3344,
+            page2: 0x5566_7788,
+        },
+        wire: &[&[0x44, 0x33, 0x22, 0x11, 0x88, 0x77, 0x66, 0x55, 0xFC, 0x20]],
+    },
+    #[cfg(feature = "baud-change")]
+    CommandVector {
+        name: "change baud, set",
+        command: Command::ChangeBaud {
+            mode: BaudMode::Set,
+            baud: 115_200,
+        },
+        wire: &[&[0x01, 0x00, 0xC2, 0x01, 0x00, 0xFC, 0x21]],
+    },
+    #[cfg(feature = "baud-change")]
+    CommandVector {
+        name: "change baud, verify",
+        command: Command::ChangeBaud {
+            mode: BaudMode::Verify,
+            baud: 1_000_000,
+        },
+        wire: &[&[0x02, 0x40, 0x42, 0x0F, 0x00, 0xFC, 0x21]],
+    },
+];
+
+/// Every response, as the C bootloader sends it.
+pub static RESPONSES: &[ResponseVector] = &[
+    ResponseVector {
+        name: "overflow",
+        response: Response::Overflow,
+        wire: &[&[0xFC, 0x10]],
+    },
+    ResponseVector {
+        name: "pong",
+        response: Response::Pong,
+        wire: &[&[0xFC, 0x11]],
+    },
+    ResponseVector {
+        name: "bad address",
+        response: Response::BadAddress,
+        wire: &[&[0xFC, 0x12]],
+    },
+    ResponseVector {
+        name: "internal error",
+        response: Response::InternalError,
+        wire: &[&[0xFC, 0x13]],
+    },
+    ResponseVector {
+        name: "bad arguments",
+        response: Response::BadArguments,
+        wire: &[&[0xFC, 0x14]],
+    },
+    ResponseVector {
+        name: "ok",
+        response: Response::Ok,
+        wire: &[&[0xFC, 0x15]],
+    },
+    ResponseVector {
+        name: "unknown",
+        response: Response::Unknown,
+        wire: &[&[0xFC, 0x16]],
+    },
+    #[cfg(feature = "ext-flash")]
+    ResponseVector {
+        name: "external flash timeout",
+        response: Response::ExtFlashTimeout,
+        wire: &[&[0xFC, 0x17]],
+    },
+    #[cfg(feature = "ext-flash")]
+    ResponseVector {
+        name: "external flash page error",
+        response: Response::ExtFlashPageError,
+        wire: &[&[0xFC, 0x18]],
+    },
+    ResponseVector {
+        name: "crc rx buffer",
+        response: Response::CrcRxBuffer {
+            length: 0x200,
+            crc: 0xCBF4_3926,
+        },
+        wire: &[&[0xFC, 0x19, 0x00, 0x02, 0x26, 0x39, 0xF4, 0xCB]],
+    },
+    ResponseVector {
+        name: "read range, with an escaped byte",
+        response: Response::ReadRange {
+            data: &[0x01, 0xFC, 0x02],
+        },
+        wire: &[&[0xFC, 0x20, 0x01, 0xFC, 0xFC, 0x02]],
+    },
+    #[cfg(feature = "ext-flash")]
+    ResponseVector {
+        name: "read external range",
+        response: Response::ExReadRange { data: &[0xAA] },
+        wire: &[&[0xFC, 0x21, 0xAA]],
+    },
+    #[cfg(feature = "attributes")]
+    ResponseVector {
+        name: "get attribute",
+        response: Response::GetAttr {
+            key: b"board\0\0\0",
+            value: b"hail",
+        },
+        wire: &[&[0xFC, 0x22], b"board\0\0\0", &[0x04], b"hail", &[0x00; MAX_ATTR_LEN - 4]],
+    },
+    ResponseVector {
+        name: "crc internal flash",
+        response: Response::CrcIntFlash { crc: 0x1234_5678 },
+        wire: &[&[0xFC, 0x23, 0x78, 0x56, 0x34, 0x12]],
+    },
+    #[cfg(feature = "ext-flash")]
+    ResponseVector {
+        name: "crc external flash",
+        response: Response::CrcExtFlash { crc: 0x1234_5678 },
+        wire: &[&[0xFC, 0x24, 0x78, 0x56, 0x34, 0x12]],
+    },
+    ResponseVector {
+        name: "info",
+        response: Response::Info { info: b"tock" },
+        wire: &[&[0xFC, 0x25, 0x04], b"tock", &[0x00; MAX_INFO_LEN - 4]],
+    },
+    #[cfg(feature = "baud-change")]
+    ResponseVector {
+        name: "change baud fail",
+        response: Response::ChangeBaudFail,
+        wire: &[&[0xFC, 0x26]],
+    },
+    ResponseVector {
+        name: "id",
+        response: Response::Id { id: &[0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF] },
+        wire: &[&[0xFC, 0x27, 0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF]],
+    },
+];
+
+/// Every command this crate adds to the protocol. Not sent by tockloader
+/// or the C bootloader.
+pub static EXTENSION_COMMANDS: &[CommandVector] = &[
+    #[cfg(feature = "long-attributes")]
+    CommandVector {
+        name: "set long attribute",
+        command: Command::SetLongAttr {
+            index: 1,
+            key: b"wifi\0\0\0\0",
+            value: b"hunter2",
+        },
+        wire: &[&[0x01], b"wifi\0\0\0\0", &[0x07, 0x00], b"hunter2", &[0xFC, 0x23]],
+    },
+    #[cfg(feature = "long-attributes")]
+    CommandVector {
+        name: "get long attribute",
+        command: Command::GetLongAttr { index: 1 },
+        wire: &[&[0x01, 0xFC, 0x24]],
+    },
+];
+
+/// Every response this crate adds to the protocol. Not sent by tockloader
+/// or the C bootloader.
+pub static EXTENSION_RESPONSES: &[ResponseVector] = &[
+    #[cfg(feature = "long-attributes")]
+    ResponseVector {
+        name: "get long attribute",
+        response: Response::GetLongAttr {
+            key: b"wifi\0\0\0\0",
+            value: b"hunter2",
+        },
+        wire: &[
+            &[0xFC, 0x28],
+            b"wifi\0\0\0\0",
+            &[0x07, 0x00],
+            b"hunter2",
+            &[0x00; MAX_LONG_ATTR_LEN - 7],
+        ],
+    },
+];
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl CommandVector {
+    /// The bytes on the wire.
+    pub fn wire_bytes(&self) -> impl Iterator<Item = u8> + 'static {
+        self.wire.iter().flat_map(|piece| piece.iter().cloned())
+    }
+}
+
+impl ResponseVector {
+    /// The bytes on the wire.
+    pub fn wire_bytes(&self) -> impl Iterator<Item = u8> + 'static {
+        self.wire.iter().flat_map(|piece| piece.iter().cloned())
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(all(test, feature = "host", feature = "device"))]
+mod tests {
+    use super::*;
+    use super::super::{encode_all, CommandDecoder, CommandEncoder, PaddingMode, ResponseEncoder};
+    use super::super::MAX_FRAME_LEN;
+
+    #[test]
+    fn check_commands() {
+        let mut decoder = CommandDecoder::new();
+        for v in COMMANDS.iter().chain(EXTENSION_COMMANDS) {
+            let encoder = CommandEncoder::new(&v.command).unwrap();
+            assert!(encoder.eq(v.wire_bytes()), "encoding {}", v.name);
+            let mut buffer = [0u8; 2 * MAX_FRAME_LEN];
+            let len = encode_all(&v.command, &mut buffer).unwrap();
+            assert!(buffer[0..len].iter().cloned().eq(v.wire_bytes()), "encoding {}", v.name);
+            let mut decoded = false;
+            for b in v.wire_bytes() {
+                if let Some(c) = decoder.receive(b).unwrap() {
+                    assert_eq!(c, v.command, "decoding {}", v.name);
+                    decoded = true;
+                }
+            }
+            assert!(decoded, "decoding {}", v.name);
+        }
+    }
+
+    #[test]
+    fn check_push_bytes() {
+        let wire: std::vec::Vec<u8> = COMMANDS.iter().flat_map(|v| v.wire_bytes()).collect();
+        for chunk_len in [1, 7, wire.len()] {
+            let mut decoder = CommandDecoder::new();
+            let mut expected = COMMANDS.iter();
+            for mut chunk in wire.chunks(chunk_len) {
+                while !chunk.is_empty() {
+                    let (used, result) = decoder.push_bytes(chunk);
+                    if let Some(c) = result.unwrap() {
+                        let v = expected.next().unwrap();
+                        assert_eq!(c, v.command, "decoding {}", v.name);
+                    }
+                    chunk = &chunk[used..];
+                }
+            }
+            assert!(expected.next().is_none());
+        }
+    }
+
+    #[test]
+    fn check_responses() {
+        for v in RESPONSES.iter().chain(EXTENSION_RESPONSES) {
+            let mut encoder = ResponseEncoder::new(&v.response).unwrap();
+            encoder.set_padding_mode(PaddingMode::Spec);
+            assert!(encoder.eq(v.wire_bytes()), "encoding {}", v.name);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Replaying whole sessions of the kind tockloader has with the C
+//! bootloader.
+//!
+//! Each file in `traces/` is the traffic of one tockloader operation, in
+//! hex, with `>` lines going from the host to the bootloader and `<` lines
+//! coming back. The tests here feed them through a `Replay`, which uses
+//! both decoders, and check what comes out. Unlike the single frames in
+//! `test_vectors`, these check the decoders keep in step from one frame to
+//! the next, with the padding and lengths the C bootloader's source uses.
+//!
+//! The traces are synthetic: they were written by hand from the protocol
+//! and the C bootloader's source, not recorded from hardware. They come
+//! from the same reading of that source as the decoders do, so they can't
+//! catch a place where the reading is wrong. They should be replaced with
+//! captures of real sessions when some are available.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::vec::Vec;
+
+use super::capture::{Direction, Event, Record, Replay};
+use super::{crc32, Command, InfoMode, Response};
+#[cfg(feature = "baud-change")]
+use super::BaudMode;
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Turn a trace into records, one per line.
+fn parse(trace: &str) -> Vec<(Direction, Vec<u8>)> {
+    let mut records = Vec::new();
+    for line in trace.lines().map(str::trim) {
+        let direction = match line.chars().next() {
+            Some('>') => Direction::HostToDevice,
+            Some('<') => Direction::DeviceToHost,
+            Some('#') | None => continue,
+            Some(_) => panic!("bad line {:?}", line),
+        };
+        let bytes = line[1..]
+            .split_whitespace()
+            .map(|b| u8::from_str_radix(b, 16).unwrap())
+            .collect();
+        records.push((direction, bytes));
+    }
+    records
+}
+
+/// Replay `trace`, passing each event and its position to `handler`.
+/// Returns how many events there were.
+fn replay<H>(trace: &str, mut handler: H) -> usize
+where
+    H: FnMut(usize, Event),
+{
+    let mut replay = Replay::new();
+    replay.set_info_mode(InfoMode::LengthPrefixed);
+    let mut found = 0;
+    for (direction, data) in parse(trace) {
+        let record = Record {
+            direction,
+            offset: 0,
+            data: &data,
+        };
+        replay.feed(&record, |event| {
+            handler(found, event);
+            found += 1;
+        });
+    }
+    found
+}
+
+/// Replay `trace` and check it decodes to exactly `expected`.
+#[cfg(any(feature = "attributes", feature = "baud-change"))]
+fn check(trace: &str, expected: &[Event]) {
+    let found = replay(trace, |n, event| {
+        assert_eq!(Some(&event), expected.get(n), "event {}", n);
+    });
+    assert_eq!(found, expected.len());
+}
+
+#[test]
+#[cfg(feature = "attributes")]
+fn check_connect() {
+    let trace = include_str!("../traces/connect.trace");
+    let get_attr = |index| Event::Command(Command::GetAttr { index });
+    let attr = |key, value| Event::Response(Response::GetAttr { key, value });
+    check(
+        trace,
+        &[
+            Event::Command(Command::Ping),
+            Event::Response(Response::Pong),
+            Event::Command(Command::Info),
+            Event::Response(Response::Info {
+                info: b"tock-bootloader v1.1.0",
+            }),
+            get_attr(0),
+            attr(b"board\0\0\0", &b"hail"[..]),
+            get_attr(1),
+            attr(b"arch\0\0\0\0", &b"cortex-m4"[..]),
+            get_attr(2),
+            attr(b"appaddr\0", &b"0x30000"[..]),
+            get_attr(3),
+            attr(&[0; 8], &[]),
+        ],
+    );
+}
+
+#[test]
+fn check_flash() {
+    let trace = include_str!("../traces/flash.trace");
+    let mut page_crc = None;
+    let found = replay(trace, |n, event| match (n, event) {
+        (0, Event::Command(Command::ReadRange { address, length })) |
+        (6, Event::Command(Command::ReadRange { address, length })) => {
+            assert_eq!((address, length), (0x30000, 16));
+        }
+        (1, Event::Response(Response::ReadRange { data })) => {
+            assert_eq!(data, &[0xFF; 16]);
+        }
+        (2, Event::Command(Command::WritePage { address, data })) => {
+            assert_eq!(address, 0x30000);
+            page_crc = Some(crc32(data));
+        }
+        (3, Event::Response(Response::Ok)) => {}
+        (4, Event::Command(Command::CrcIntFlash { address, length })) => {
+            assert_eq!((address, length), (0x30000, 512));
+        }
+        (5, Event::Response(Response::CrcIntFlash { crc })) => {
+            assert_eq!(Some(crc), page_crc);
+        }
+        (7, Event::Response(Response::ReadRange { data })) => {
+            assert_eq!(&data[0..2], &[0x02, 0x00]);
+            assert_eq!(data[12], 0xFC);
+        }
+        (n, event) => panic!("event {}: {:?}", n, event),
+    });
+    assert_eq!(found, 8);
+}
+
+#[test]
+#[cfg(feature = "baud-change")]
+fn check_change_baud() {
+    let trace = include_str!("../traces/change_baud.trace");
+    let change = |mode, baud| Event::Command(Command::ChangeBaud { mode, baud });
+    check(
+        trace,
+        &[
+            change(BaudMode::Set, 115_200),
+            Event::Response(Response::Ok),
+            change(BaudMode::Verify, 115_200),
+            Event::Response(Response::Ok),
+            Event::Command(Command::Ping),
+            Event::Response(Response::Pong),
+            change(BaudMode::Set, 1_000_000),
+            Event::Response(Response::Ok),
+            change(BaudMode::Verify, 921_600),
+            Event::Response(Response::ChangeBaudFail),
+        ],
+    );
+}
@@ -0,0 +1,362 @@
+//! Commands bundled with what counts as them having worked.
+//!
+//! A scripted flashing sequence is mostly a list of "send this, expect
+//! that": erase this page and get `Ok`, read 16 bytes and get 16 bytes
+//! back, all within a second or so. A `Transaction` holds the command
+//! together with the response it should get, how long that response's
+//! payload should be, and how long to wait for it. The
+//! `TransactionSession` sends transactions and tells you how each one
+//! ended, so a script can be a list of transactions and a loop.
+//!
+//! As with the `RetryingSession`, there's no clock in here. Tell the session
+//! how much time has passed by calling `elapsed`.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::session::HostSession;
+use super::{Command, CommandEncoder, Error, Opcode, Response, ResponseCode};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// A command, the response it should get and how long to wait for it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Transaction<'a> {
+    command: Command<'a>,
+    criteria: Criteria,
+}
+
+/// A `HostSession` which sends `Transaction`s.
+pub struct TransactionSession {
+    session: HostSession,
+    criteria: Option<Criteria>,
+    waited_ms: u32,
+}
+
+/// How a transaction ended.
+#[derive(Debug, PartialEq)]
+pub enum Outcome<'r> {
+    /// The expected response, with a payload of the expected length.
+    Complete(Response<'r>),
+    /// The bootloader answered with an error, such as `BadAddress`.
+    Refused(Response<'r>),
+    /// A reply to the command, but not the expected one, or with a payload
+    /// of the wrong length.
+    Unexpected(Response<'r>),
+    /// Nothing came back in time.
+    TimedOut,
+}
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+/// Everything about a transaction except the command, which the session
+/// keeps while it waits.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct Criteria {
+    expect: Option<ResponseCode>,
+    payload_len: Option<usize>,
+    timeout_ms: u32,
+}
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const DEFAULT_TIMEOUT_MS: u32 = 1000;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<'a> Transaction<'a> {
+    /// Create a transaction which expects the usual successful reply to
+    /// `command` within a second. Reads expect as many bytes as they ask
+    /// for.
+    pub fn new(command: Command<'a>) -> Transaction<'a> {
+        let payload_len = match command {
+            Command::ReadRange { length, .. } => Some(length as usize),
+            #[cfg(feature = "ext-flash")]
+            Command::ExReadRange { length, .. } => Some(length as usize),
+            _ => None,
+        };
+        Transaction {
+            criteria: Criteria {
+                expect: success_code(command.kind()),
+                payload_len,
+                timeout_ms: DEFAULT_TIMEOUT_MS,
+            },
+            command,
+        }
+    }
+
+    /// Expect a different response, or `None` for a command which gets no
+    /// reply.
+    pub fn set_expect(&mut self, expect: Option<ResponseCode>) {
+        self.criteria.expect = expect;
+    }
+
+    /// Expect the response's payload to be exactly `length` bytes long, or
+    /// `None` to accept any length.
+    pub fn set_payload_len(&mut self, length: Option<usize>) {
+        self.criteria.payload_len = length;
+    }
+
+    /// Set how many milliseconds to wait for the response.
+    pub fn set_timeout_ms(&mut self, timeout_ms: u32) {
+        self.criteria.timeout_ms = timeout_ms;
+    }
+
+    /// The command to send.
+    pub fn command(&self) -> &Command<'a> {
+        &self.command
+    }
+
+    /// The response expected, if any.
+    pub fn expect(&self) -> Option<ResponseCode> {
+        self.criteria.expect
+    }
+
+    /// The payload length expected, if it matters.
+    pub fn payload_len(&self) -> Option<usize> {
+        self.criteria.payload_len
+    }
+
+    /// How long to wait for the response.
+    pub fn timeout_ms(&self) -> u32 {
+        self.criteria.timeout_ms
+    }
+
+    /// How the transaction ended, if `response` came back.
+    pub fn check<'r>(&self, response: Response<'r>) -> Outcome<'r> {
+        self.criteria.check(response)
+    }
+}
+
+impl TransactionSession {
+    /// Create a new `TransactionSession` with nothing in flight.
+    pub const fn new() -> TransactionSession {
+        TransactionSession {
+            session: HostSession::new(),
+            criteria: None,
+            waited_ms: 0,
+        }
+    }
+
+    /// Start a transaction, returning the bytes to send. Anything in flight
+    /// is forgotten. A transaction which expects no reply is over as soon as
+    /// it has been sent.
+    pub fn start<'a>(
+        &mut self,
+        transaction: &'a Transaction<'a>,
+    ) -> Result<CommandEncoder<'a>, Error> {
+        let encoder = self.session.send(&transaction.command)?;
+        self.criteria = transaction.criteria.expect.map(|_| transaction.criteria);
+        self.waited_ms = 0;
+        Ok(encoder)
+    }
+
+    /// Process incoming bytes, as with `HostSession::receive`. Once the
+    /// response is in, the transaction is over.
+    pub fn receive(&mut self, ch: u8) -> Result<Option<Outcome<'_>>, Error> {
+        let criteria = self.criteria;
+        match self.session.receive(ch) {
+            Ok(None) => Ok(None),
+            Ok(Some(response)) => {
+                self.criteria = None;
+                // The session has already refused anything with nothing in
+                // flight
+                Ok(criteria.map(|c| c.check(response)))
+            }
+            Err(e) => {
+                self.criteria = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// Report that `ms` milliseconds have passed. Returns
+    /// `Outcome::TimedOut`, and drops the transaction, once its timeout
+    /// has been used up.
+    pub fn elapsed(&mut self, ms: u32) -> Option<Outcome<'static>> {
+        let criteria = self.criteria?;
+        self.waited_ms = self.waited_ms.saturating_add(ms);
+        if self.waited_ms < criteria.timeout_ms {
+            return None;
+        }
+        self.reset();
+        Some(Outcome::TimedOut)
+    }
+
+    /// Is there a transaction waiting for its response?
+    pub fn in_flight(&self) -> bool {
+        self.criteria.is_some()
+    }
+
+    /// Forget about any transaction in flight.
+    pub fn reset(&mut self) {
+        self.session.reset();
+        self.criteria = None;
+        self.waited_ms = 0;
+    }
+}
+
+impl Default for TransactionSession {
+    fn default() -> TransactionSession {
+        TransactionSession::new()
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl Criteria {
+    fn check<'r>(&self, response: Response<'r>) -> Outcome<'r> {
+        if response.is_error() && self.expect != Some(response.kind()) {
+            return Outcome::Refused(response);
+        }
+        let len_ok = match (self.payload_len, payload(&response)) {
+            (Some(expected), Some(payload)) => payload.len() == expected,
+            _ => true,
+        };
+        if self.expect == Some(response.kind()) && len_ok {
+            Outcome::Complete(response)
+        } else {
+            Outcome::Unexpected(response)
+        }
+    }
+}
+
+/// The reply a command gets when all goes well, or `None` if it gets none.
+fn success_code(opcode: Opcode) -> Option<ResponseCode> {
+    match opcode {
+        Opcode::Reset | Opcode::ClockOut => None,
+        Opcode::Ping => Some(ResponseCode::Pong),
+        Opcode::Info => Some(ResponseCode::Info),
+        Opcode::CrcRxBuffer => Some(ResponseCode::CrcRxBuffer),
+        Opcode::ReadRange => Some(ResponseCode::ReadRange),
+        Opcode::ExReadRange => Some(ResponseCode::ExReadRange),
+        Opcode::GetAttr => Some(ResponseCode::GetAttr),
+        Opcode::CrcIntFlash => Some(ResponseCode::CrcIntFlash),
+        Opcode::CrcExtFlash => Some(ResponseCode::CrcExtFlash),
+        Opcode::Id => Some(ResponseCode::Id),
+        Opcode::GetLongAttr => Some(ResponseCode::GetLongAttr),
+        Opcode::ErasePage |
+        Opcode::WritePage |
+        Opcode::EraseExBlock |
+        Opcode::WriteExPage |
+        Opcode::SetAttr |
+        Opcode::EraseExPage |
+        Opcode::ExtFlashInit |
+        Opcode::WriteFlashUserPages |
+        Opcode::ChangeBaud |
+        Opcode::SetAddress |
+        Opcode::SetLongAttr |
+        Opcode::Exit => Some(ResponseCode::Ok),
+    }
+}
+
+/// The variable length part of a response, if it has one.
+fn payload<'r>(response: &Response<'r>) -> Option<&'r [u8]> {
+    match *response {
+        Response::ReadRange { data } => Some(data),
+        #[cfg(feature = "ext-flash")]
+        Response::ExReadRange { data } => Some(data),
+        #[cfg(feature = "attributes")]
+        Response::GetAttr { value, .. } => Some(value),
+        Response::Info { info } => Some(info),
+        Response::Id { id } => Some(id),
+        #[cfg(feature = "long-attributes")]
+        Response::GetLongAttr { value, .. } => Some(value),
+        _ => None,
+    }
+}
+
+#[cfg(all(test, feature = "device"))]
+mod tests {
+    use super::*;
+    #[cfg(feature = "baud-change")]
+    use super::super::{BaudMode, ResponseEncoder};
+
+    #[cfg(feature = "baud-change")]
+    fn feed<'s>(s: &'s mut TransactionSession, response: &Response) -> Option<Outcome<'s>> {
+        let len = ResponseEncoder::new(response).unwrap().count();
+        for b in ResponseEncoder::new(response).unwrap().take(len - 1) {
+            assert_eq!(s.receive(b), Ok(None));
+        }
+        let last = ResponseEncoder::new(response).unwrap().last().unwrap();
+        s.receive(last).unwrap()
+    }
+
+    #[test]
+    fn check_transaction() {
+        let t = Transaction::new(Command::ErasePage { address: 0x30000 });
+        assert_eq!(t.expect(), Some(ResponseCode::Ok));
+        assert_eq!(t.check(Response::Ok), Outcome::Complete(Response::Ok));
+        assert_eq!(t.check(Response::BadAddress), Outcome::Refused(Response::BadAddress));
+        assert_eq!(t.check(Response::Pong), Outcome::Unexpected(Response::Pong));
+
+        let mut t = Transaction::new(Command::Info);
+        t.set_payload_len(Some(4));
+        let info = Response::Info { info: b"tock" };
+        assert_eq!(t.check(info), Outcome::Complete(info));
+        let info = Response::Info { info: b"tockos" };
+        assert_eq!(t.check(info), Outcome::Unexpected(info));
+
+        assert_eq!(Transaction::new(Command::Reset).expect(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "baud-change")]
+    fn check_session() {
+        let mut s = TransactionSession::new();
+        let t = Transaction::new(Command::ReadRange {
+            address: 0x30000,
+            length: 4,
+        });
+        assert_eq!(t.payload_len(), Some(4));
+        s.start(&t).unwrap();
+        assert!(s.in_flight());
+        let rsp = Response::ReadRange { data: &[1, 2, 3, 4] };
+        assert_eq!(feed(&mut s, &rsp), Some(Outcome::Complete(rsp)));
+        assert!(!s.in_flight());
+
+        let t = Transaction::new(Command::ChangeBaud {
+            mode: BaudMode::Set,
+            baud: 921600,
+        });
+        s.start(&t).unwrap();
+        let rsp = Response::ChangeBaudFail;
+        assert_eq!(feed(&mut s, &rsp), Some(Outcome::Refused(rsp)));
+    }
+
+    #[test]
+    fn check_timeout() {
+        let mut s = TransactionSession::new();
+        let mut t = Transaction::new(Command::Ping);
+        t.set_timeout_ms(100);
+        assert_eq!(s.elapsed(1000), None);
+        s.start(&t).unwrap();
+        assert_eq!(s.elapsed(60), None);
+        assert_eq!(s.elapsed(60), Some(Outcome::TimedOut));
+        assert!(!s.in_flight());
+        assert_eq!(s.elapsed(60), None);
+    }
+}
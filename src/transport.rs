@@ -0,0 +1,271 @@
+//! Moving frames over a byte stream.
+//!
+//! Every user of this crate ends up writing the same loop: encode a frame,
+//! write it out, then read bytes one at a time and feed them to a decoder
+//! until something comes out. The `Transport` trait describes a blocking
+//! byte stream (usually a UART), and `run_host_command` and
+//! `serve_bootloader` are those loops, written once.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+#[cfg(feature = "device")]
+use super::device::{BootloaderSession, FlashInterface};
+#[cfg(feature = "host")]
+use super::session::HostSession;
+#[cfg(feature = "host")]
+use super::Command;
+#[cfg(feature = "device")]
+use super::ResponseEncoder;
+#[cfg(feature = "host")]
+use super::Response;
+#[cfg(any(feature = "host", feature = "device"))]
+use super::MAX_CHUNK_LEN;
+use super::Error;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// A blocking, bidirectional byte stream.
+pub trait Transport {
+    /// The error the underlying stream can report. A timeout on read should
+    /// be reported as an error.
+    type Error;
+
+    /// Wait for and return the next byte.
+    fn read_byte(&mut self) -> Result<u8, Self::Error>;
+
+    /// Write all of `bytes`. They may be buffered until `flush` is called.
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Self::Error>;
+
+    /// Wait until everything written has been sent.
+    fn flush(&mut self) -> Result<(), Self::Error>;
+}
+
+/// The ways a run loop can fail.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RunError<E> {
+    /// The transport reported an error.
+    Transport(E),
+    /// Something couldn't be encoded, or what came back couldn't be
+    /// decoded.
+    Protocol(Error),
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Send `command` and wait for the reply, which is passed to `handler`.
+///
+/// The reply borrows the session's receive buffer, and the borrow checker
+/// won't let us return a borrow taken inside the receive loop, so it's
+/// handed to a closure instead. Commands with no reply (`Reset` and
+/// `ClockOut`) return straight after sending, without calling `handler`.
+#[cfg(feature = "host")]
+pub fn run_host_command<T, F, R>(
+    transport: &mut T,
+    session: &mut HostSession,
+    command: &Command,
+    handler: F,
+) -> Result<Option<R>, RunError<T::Error>>
+where
+    T: Transport,
+    F: FnOnce(Response) -> R,
+{
+    {
+        let mut encoder = session.send(command).map_err(RunError::Protocol)?;
+        while let Some(chunk) = encoder.next_chunk(MAX_CHUNK_LEN) {
+            transport.write_bytes(chunk).map_err(RunError::Transport)?;
+        }
+    }
+    transport.flush().map_err(RunError::Transport)?;
+    if !session.in_flight() {
+        return Ok(None);
+    }
+    loop {
+        let ch = transport.read_byte().map_err(RunError::Transport)?;
+        if let Some(response) = session.receive(ch).map_err(RunError::Protocol)? {
+            return Ok(Some(handler(response)));
+        }
+    }
+}
+
+/// Run a bootloader: read commands from `transport`, perform them with
+/// `session` and write back the responses.
+///
+/// This only returns if something goes wrong. Errors from the transport are
+/// returned straight away; a response which can't be encoded is reported as
+/// `RunError::Protocol` and the loop stops.
+#[cfg(feature = "device")]
+pub fn serve_bootloader<T, F>(
+    transport: &mut T,
+    session: &mut BootloaderSession<F>,
+) -> RunError<T::Error>
+where
+    T: Transport,
+    F: FlashInterface,
+{
+    loop {
+        let ch = match transport.read_byte() {
+            Ok(ch) => ch,
+            Err(e) => return RunError::Transport(e),
+        };
+        let format = session.reply_format();
+        if let Some(response) = session.receive(ch) {
+            let encoder = match format.encoder(&response) {
+                Ok(encoder) => encoder,
+                Err(e) => return RunError::Protocol(e),
+            };
+            if let Err(e) = send_response(transport, encoder) {
+                return e;
+            }
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(feature = "device")]
+fn send_response<T>(
+    transport: &mut T,
+    mut encoder: ResponseEncoder,
+) -> Result<(), RunError<T::Error>>
+where
+    T: Transport,
+{
+    while let Some(chunk) = encoder.next_chunk(MAX_CHUNK_LEN) {
+        transport.write_bytes(chunk).map_err(RunError::Transport)?;
+    }
+    transport.flush().map_err(RunError::Transport)
+}
+
+#[cfg(all(test, feature = "host", feature = "device"))]
+mod tests {
+    use super::*;
+    use super::super::device::FlashError;
+    use super::super::CommandEncoder;
+
+    /// Reads come from a fixed script; writes are captured.
+    struct Script<'a> {
+        rx: &'a [u8],
+        tx: [u8; 128],
+        tx_len: usize,
+        flushed: bool,
+    }
+
+    impl<'a> Script<'a> {
+        fn new(rx: &'a [u8]) -> Script<'a> {
+            Script {
+                rx,
+                tx: [0u8; 128],
+                tx_len: 0,
+                flushed: false,
+            }
+        }
+    }
+
+    impl<'a> Transport for Script<'a> {
+        type Error = ();
+
+        fn read_byte(&mut self) -> Result<u8, ()> {
+            let (&first, rest) = self.rx.split_first().ok_or(())?;
+            self.rx = rest;
+            Ok(first)
+        }
+
+        fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), ()> {
+            self.tx[self.tx_len..self.tx_len + bytes.len()].copy_from_slice(bytes);
+            self.tx_len += bytes.len();
+            self.flushed = false;
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), ()> {
+            self.flushed = true;
+            Ok(())
+        }
+    }
+
+    struct Blank;
+
+    impl FlashInterface for Blank {
+        fn read(&mut self, _address: u32, buffer: &mut [u8]) -> Result<(), FlashError> {
+            for b in buffer.iter_mut() {
+                *b = 0xFF;
+            }
+            Ok(())
+        }
+
+        fn write_page(&mut self, _address: u32, _data: &[u8]) -> Result<(), FlashError> {
+            Ok(())
+        }
+
+        fn erase_page(&mut self, _address: u32) -> Result<(), FlashError> {
+            Ok(())
+        }
+
+        fn get_attr(&mut self, _: u8, _: &mut [u8], _: &mut [u8]) -> Result<usize, FlashError> {
+            Err(FlashError::Unsupported)
+        }
+
+        fn set_attr(&mut self, _: u8, _: &[u8], _: &[u8]) -> Result<(), FlashError> {
+            Err(FlashError::Unsupported)
+        }
+
+        fn crc_range(&mut self, _address: u32, _length: u32) -> Result<u32, FlashError> {
+            Ok(0x1234_5678)
+        }
+    }
+
+    #[test]
+    fn check_host_command() {
+        let mut t = Script::new(&[0xFC, 0x11]);
+        let mut s = HostSession::new();
+        let result = run_host_command(&mut t, &mut s, &Command::Ping, |r| r == Response::Pong);
+        assert_eq!(result, Ok(Some(true)));
+        assert_eq!(&t.tx[0..t.tx_len], &[0xFC, 0x01]);
+        assert!(t.flushed);
+
+        // Nothing comes back
+        let result = run_host_command(&mut t, &mut s, &Command::Ping, |_| ());
+        assert_eq!(result, Err(RunError::Transport(())));
+
+        // No reply expected
+        let result = run_host_command(&mut t, &mut s, &Command::Reset, |_| ());
+        assert_eq!(result, Ok(None));
+    }
+
+    #[test]
+    fn check_serve_bootloader() {
+        let mut rx = [0u8; 32];
+        let mut len = 0;
+        let crc = Command::CrcIntFlash {
+            address: 0x30000,
+            length: 0x100,
+        };
+        for cmd in &[Command::Ping, crc] {
+            for b in CommandEncoder::new(cmd).unwrap() {
+                rx[len] = b;
+                len += 1;
+            }
+        }
+        let mut t = Script::new(&rx[0..len]);
+        let mut s = BootloaderSession::new(Blank);
+        assert_eq!(serve_bootloader(&mut t, &mut s), RunError::Transport(()));
+        assert_eq!(&t.tx[0..t.tx_len], &[0xFC, 0x11, 0xFC, 0x23, 0x78, 0x56, 0x34, 0x12]);
+        assert!(t.flushed);
+    }
+}
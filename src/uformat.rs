@@ -0,0 +1,254 @@
+//! `ufmt` support for the protocol types.
+//!
+//! For bootloaders too small for `core::fmt`. As with `defmt`, payloads are
+//! shown only as their length, which also keeps the code small.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use ufmt::{uDebug, uDisplay, uWrite, uwrite, Formatter};
+
+use super::{Baud, BaudMode, Command, Error, Field, Response};
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<'a> uDebug for Command<'a> {
+    fn fmt<W>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        match *self {
+            Command::Ping => f.write_str("Ping"),
+            Command::Info => f.write_str("Info"),
+            Command::Id => f.write_str("Id"),
+            Command::Reset => f.write_str("Reset"),
+            Command::ErasePage { address } => uwrite!(f, "ErasePage({:#x})", address),
+            Command::WritePage { address, data } => {
+                uwrite!(f, "WritePage({:#x}, {} bytes)", address, data.len())
+            }
+            #[cfg(feature = "ext-flash")]
+            Command::EraseExBlock { address } => uwrite!(f, "EraseExBlock({:#x})", address),
+            #[cfg(feature = "ext-flash")]
+            Command::WriteExPage { address, data } => {
+                uwrite!(f, "WriteExPage({:#x}, {} bytes)", address, data.len())
+            }
+            Command::CrcRxBuffer => f.write_str("CrcRxBuffer"),
+            Command::ReadRange { address, length } => {
+                uwrite!(f, "ReadRange({:#x}, {})", address, length)
+            }
+            #[cfg(feature = "ext-flash")]
+            Command::ExReadRange { address, length } => {
+                uwrite!(f, "ExReadRange({:#x}, {})", address, length)
+            }
+            #[cfg(feature = "attributes")]
+            Command::SetAttr { index, value, .. } => {
+                uwrite!(f, "SetAttr({}, {} bytes)", index, value.len())
+            }
+            #[cfg(feature = "attributes")]
+            Command::GetAttr { index } => uwrite!(f, "GetAttr({})", index),
+            Command::CrcIntFlash { address, length } => {
+                uwrite!(f, "CrcIntFlash({:#x}, {})", address, length)
+            }
+            #[cfg(feature = "ext-flash")]
+            Command::CrcExtFlash { address, length } => {
+                uwrite!(f, "CrcExtFlash({:#x}, {})", address, length)
+            }
+            #[cfg(feature = "ext-flash")]
+            Command::EraseExPage { address } => uwrite!(f, "EraseExPage({:#x})", address),
+            #[cfg(feature = "ext-flash")]
+            Command::ExtFlashInit => f.write_str("ExtFlashInit"),
+            Command::ClockOut => f.write_str("ClockOut"),
+            #[cfg(feature = "user-pages")]
+            Command::WriteFlashUserPages { page1, page2 } => {
+                uwrite!(f, "WriteFlashUserPages({:#x}, {:#x})", page1, page2)
+            }
+            #[cfg(feature = "baud-change")]
+            Command::ChangeBaud { mode, baud } => uwrite!(f, "ChangeBaud({:?}, {})", mode, baud),
+            #[cfg(feature = "multi-drop")]
+            Command::SetAddress { address } => uwrite!(f, "SetAddress({})", address),
+            #[cfg(feature = "long-attributes")]
+            Command::SetLongAttr { index, value, .. } => {
+                uwrite!(f, "SetLongAttr({}, {} bytes)", index, value.len())
+            }
+            #[cfg(feature = "long-attributes")]
+            Command::GetLongAttr { index } => uwrite!(f, "GetLongAttr({})", index),
+            Command::Exit => f.write_str("Exit"),
+        }
+    }
+}
+
+impl<'a> uDebug for Response<'a> {
+    fn fmt<W>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        match *self {
+            Response::Overflow => f.write_str("Overflow"),
+            Response::Pong => f.write_str("Pong"),
+            Response::BadAddress => f.write_str("BadAddress"),
+            Response::InternalError => f.write_str("InternalError"),
+            Response::BadArguments => f.write_str("BadArguments"),
+            Response::Ok => f.write_str("Ok"),
+            Response::Unknown => f.write_str("Unknown"),
+            #[cfg(feature = "ext-flash")]
+            Response::ExtFlashTimeout => f.write_str("ExtFlashTimeout"),
+            #[cfg(feature = "ext-flash")]
+            Response::ExtFlashPageError => f.write_str("ExtFlashPageError"),
+            Response::CrcRxBuffer { length, crc } => {
+                uwrite!(f, "CrcRxBuffer({}, {:#x})", length, crc)
+            }
+            Response::ReadRange { data } => uwrite!(f, "ReadRange({} bytes)", data.len()),
+            #[cfg(feature = "ext-flash")]
+            Response::ExReadRange { data } => uwrite!(f, "ExReadRange({} bytes)", data.len()),
+            #[cfg(feature = "attributes")]
+            Response::GetAttr { value, .. } => uwrite!(f, "GetAttr({} bytes)", value.len()),
+            Response::CrcIntFlash { crc } => uwrite!(f, "CrcIntFlash({:#x})", crc),
+            #[cfg(feature = "ext-flash")]
+            Response::CrcExtFlash { crc } => uwrite!(f, "CrcExtFlash({:#x})", crc),
+            Response::Info { info } => uwrite!(f, "Info({} bytes)", info.len()),
+            #[cfg(feature = "baud-change")]
+            Response::ChangeBaudFail => f.write_str("ChangeBaudFail"),
+            Response::Id { id } => uwrite!(f, "Id({} bytes)", id.len()),
+            #[cfg(feature = "long-attributes")]
+            Response::GetLongAttr { value, .. } => {
+                uwrite!(f, "GetLongAttr({} bytes)", value.len())
+            }
+        }
+    }
+}
+
+impl uDebug for Error {
+    fn fmt<W>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        match *self {
+            Error::UnknownCommand => f.write_str("UnknownCommand"),
+            Error::BadArguments => f.write_str("BadArguments"),
+            Error::WrongLength {
+                opcode,
+                expected,
+                got,
+            } => uwrite!(f, "WrongLength({:#x}, {}, {})", opcode, expected, got),
+            Error::InvalidValue { field, got } => uwrite!(f, "InvalidValue({:?}, {})", field, got),
+            Error::UnsetLength => f.write_str("UnsetLength"),
+            Error::SetLength => f.write_str("SetLength"),
+            Error::BufferFull => f.write_str("BufferFull"),
+            Error::MismatchedResponse => f.write_str("MismatchedResponse"),
+            Error::Refused => f.write_str("Refused"),
+            Error::CrcMismatch => f.write_str("CrcMismatch"),
+            Error::Overflow => f.write_str("Overflow"),
+        }
+    }
+}
+
+impl uDisplay for Error {
+    fn fmt<W>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        match *self {
+            Error::WrongLength {
+                opcode,
+                expected,
+                got,
+            } => uwrite!(
+                f,
+                "frame {:#x} should have {} bytes of arguments, not {}",
+                opcode,
+                expected,
+                got
+            ),
+            Error::InvalidValue { field, got } => uwrite!(f, "{} can't be {}", field.name(), got),
+            e => f.write_str(e.message()),
+        }
+    }
+}
+
+impl uDebug for Field {
+    fn fmt<W>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        f.write_str(match *self {
+            Field::BaudMode => "BaudMode",
+            Field::AttrLength => "AttrLength",
+            Field::AttrIndex => "AttrIndex",
+            Field::InfoLength => "InfoLength",
+        })
+    }
+}
+
+impl uDebug for BaudMode {
+    fn fmt<W>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        f.write_str(match *self {
+            BaudMode::Set => "Set",
+            BaudMode::Verify => "Verify",
+        })
+    }
+}
+
+impl uDebug for Baud {
+    fn fmt<W>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        match *self {
+            Baud::Custom(baud) => uwrite!(f, "Custom({})", baud),
+            baud => uwrite!(f, "B{}", u32::from(baud)),
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::Infallible;
+    use std::string::String;
+
+    struct Text(String);
+
+    impl uWrite for Text {
+        type Error = Infallible;
+
+        fn write_str(&mut self, s: &str) -> Result<(), Infallible> {
+            self.0.push_str(s);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn check_ufmt() {
+        let mut out = Text(String::new());
+        let page = [0u8; 512];
+        let cmd = Command::WritePage {
+            address: 0x30000,
+            data: &page,
+        };
+        uwrite!(&mut out, "{:?}; ", cmd).unwrap();
+        uwrite!(&mut out, "{:?}; ", Response::CrcIntFlash { crc: 0xABCD }).unwrap();
+        uwrite!(&mut out, "{:?}; ", Baud::B921600).unwrap();
+        uwrite!(&mut out, "{}", Error::BufferFull).unwrap();
+        assert_eq!(
+            out.0,
+            "WritePage(0x30000, 512 bytes); CrcIntFlash(0xabcd); B921600; \
+             not enough room in the buffer"
+        );
+    }
+}
@@ -0,0 +1,322 @@
+//! Scatter-gather encoding of `Command`s.
+//!
+//! The `CommandEncoder` produces a frame one byte at a time, which means a
+//! 512 byte page gets copied into whatever transmit buffer you're using. The
+//! `VectoredEncoder` instead produces a list of segments: the address and
+//! other arguments come from a small buffer inside the encoder, and the page
+//! data is borrowed directly from the caller. Escape characters in the data
+//! are doubled by returning overlapping slices, so no data is ever copied.
+//! This suits `write_vectored` and DMA engines that take a list of
+//! descriptors.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::{Command, CommandEncoder, Error};
+use super::{CMD_WPAGE, ESCAPE_CHAR};
+#[cfg(feature = "ext-flash")]
+use super::CMD_XWPAGE;
+#[cfg(feature = "attributes")]
+use super::{CMD_SATTR, KEY_LEN};
+#[cfg(feature = "long-attributes")]
+use super::CMD_SLATTR;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// The `VectoredEncoder` takes a `Command` and gives you a list of byte
+/// slices which, sent back-to-back, make up the encoded frame.
+#[derive(Clone)]
+pub struct VectoredEncoder<'a> {
+    head: [u8; HEAD_LEN],
+    head_len: usize,
+    data: &'a [u8],
+    tail: [u8; 2],
+    tail_len: usize,
+}
+
+/// An iterator over the segments of a `VectoredEncoder`. None of the
+/// segments are empty.
+#[derive(Clone)]
+pub struct Segments<'s> {
+    encoder: &'s VectoredEncoder<'s>,
+    stage: Stage,
+    pos: usize,
+    resume_escape: bool,
+}
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+#[derive(Clone, Copy)]
+enum Stage {
+    Head,
+    Data,
+    Tail,
+    Done,
+}
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+// Enough for the largest argument prefix (SetAttr's index, key and length)
+// with every byte escaped.
+#[cfg(not(feature = "long-attributes"))]
+const HEAD_LEN: usize = 20;
+
+// The same, but SetLongAttr's length takes two bytes.
+#[cfg(feature = "long-attributes")]
+const HEAD_LEN: usize = 22;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<'a> VectoredEncoder<'a> {
+    /// Create a new `VectoredEncoder`.
+    ///
+    /// The command is checked in the same way as `CommandEncoder::new`. Use
+    /// the `segments` method to get the encoded frame.
+    pub fn new(command: &'a Command<'a>) -> Result<VectoredEncoder<'a>, Error> {
+        let encoder = CommandEncoder::new(command)?;
+        let mut v = VectoredEncoder {
+            head: [0u8; HEAD_LEN],
+            head_len: 0,
+            data: &[],
+            tail: [ESCAPE_CHAR, 0],
+            tail_len: 0,
+        };
+        match *command {
+            Command::WritePage { address, data } => {
+                v.push_u32(address);
+                v.set_data(data, CMD_WPAGE);
+            }
+            #[cfg(feature = "ext-flash")]
+            Command::WriteExPage { address, data } => {
+                v.push_u32(address);
+                v.set_data(data, CMD_XWPAGE);
+            }
+            #[cfg(feature = "attributes")]
+            Command::SetAttr { index, key, value } => {
+                v.push_escaped(index);
+                for &b in &key[0..KEY_LEN] {
+                    v.push_escaped(b);
+                }
+                v.push_escaped(value.len() as u8);
+                v.set_data(value, CMD_SATTR);
+            }
+            #[cfg(feature = "long-attributes")]
+            Command::SetLongAttr { index, key, value } => {
+                v.push_escaped(index);
+                for &b in &key[0..KEY_LEN] {
+                    v.push_escaped(b);
+                }
+                for &b in &(value.len() as u16).to_le_bytes() {
+                    v.push_escaped(b);
+                }
+                v.set_data(value, CMD_SLATTR);
+            }
+            _ => {
+                // Everything else is small enough to render in one go.
+                for byte in encoder {
+                    v.head[v.head_len] = byte;
+                    v.head_len += 1;
+                }
+            }
+        }
+        Ok(v)
+    }
+
+    /// Get the segments making up the encoded frame.
+    pub fn segments(&self) -> Segments<'_> {
+        Segments {
+            encoder: self,
+            stage: Stage::Head,
+            pos: 0,
+            resume_escape: false,
+        }
+    }
+
+    /// The total number of bytes in the encoded frame.
+    pub fn len(&self) -> usize {
+        self.segments().map(|s| s.len()).sum()
+    }
+
+    /// Whether the encoded frame is empty. This is always false, as every
+    /// frame ends with an escape and a command byte.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn push_escaped(&mut self, byte: u8) {
+        self.head[self.head_len] = byte;
+        self.head_len += 1;
+        if byte == ESCAPE_CHAR {
+            self.head[self.head_len] = byte;
+            self.head_len += 1;
+        }
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        for shift in &[0, 8, 16, 24] {
+            self.push_escaped((value >> shift) as u8);
+        }
+    }
+
+    fn set_data(&mut self, data: &'a [u8], cmd: u8) {
+        self.data = data;
+        self.tail[1] = cmd;
+        self.tail_len = 2;
+    }
+}
+
+impl<'s> Iterator for Segments<'s> {
+    type Item = &'s [u8];
+
+    /// Supply the next segment. Once all the segments have been emitted, it
+    /// returns `None` forevermore.
+    fn next(&mut self) -> Option<&'s [u8]> {
+        loop {
+            match self.stage {
+                Stage::Head => {
+                    self.stage = Stage::Data;
+                    if self.encoder.head_len > 0 {
+                        return Some(&self.encoder.head[0..self.encoder.head_len]);
+                    }
+                }
+                Stage::Data => {
+                    let data = self.encoder.data;
+                    if self.pos >= data.len() {
+                        self.stage = Stage::Tail;
+                        continue;
+                    }
+                    // If we stopped on an escape character last time, it's
+                    // been sent once and starts this segment to be sent
+                    // again.
+                    let start = self.pos;
+                    let search = if self.resume_escape { start + 1 } else { start };
+                    match data[search..].iter().position(|&b| b == ESCAPE_CHAR) {
+                        Some(offset) => {
+                            let end = search + offset;
+                            self.pos = end;
+                            self.resume_escape = true;
+                            return Some(&data[start..=end]);
+                        }
+                        None => {
+                            self.pos = data.len();
+                            return Some(&data[start..]);
+                        }
+                    }
+                }
+                Stage::Tail => {
+                    self.stage = Stage::Done;
+                    if self.encoder.tail_len > 0 {
+                        return Some(&self.encoder.tail[0..self.encoder.tail_len]);
+                    }
+                }
+                Stage::Done => return None,
+            }
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::INT_PAGE_SIZE;
+    #[cfg(feature = "baud-change")]
+    use super::super::BaudMode;
+
+    fn check_matches_encoder(cmd: &Command) {
+        let v = VectoredEncoder::new(cmd).unwrap();
+        let mut e = CommandEncoder::new(cmd).unwrap();
+        for segment in v.segments() {
+            assert!(!segment.is_empty());
+            for byte in segment {
+                assert_eq!(e.next(), Some(*byte));
+            }
+        }
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn check_write_page() {
+        let mut page = [0x11u8; INT_PAGE_SIZE];
+        page[0] = ESCAPE_CHAR;
+        page[100] = ESCAPE_CHAR;
+        page[101] = ESCAPE_CHAR;
+        page[INT_PAGE_SIZE - 1] = ESCAPE_CHAR;
+        let cmd = Command::WritePage {
+            address: 0x0003_00FC,
+            data: &page,
+        };
+        check_matches_encoder(&cmd);
+        let v = VectoredEncoder::new(&cmd).unwrap();
+        assert_eq!(v.len(), 5 + INT_PAGE_SIZE + 4 + 2);
+    }
+
+    #[test]
+    fn check_data_is_borrowed() {
+        let page = [0x22u8; INT_PAGE_SIZE];
+        let cmd = Command::WritePage {
+            address: 0x30000,
+            data: &page,
+        };
+        let v = VectoredEncoder::new(&cmd).unwrap();
+        let segments: [&[u8]; 3] = {
+            let mut s = v.segments();
+            [s.next().unwrap(), s.next().unwrap(), s.next().unwrap()]
+        };
+        assert_eq!(segments[0], &[0x00, 0x00, 0x03, 0x00]);
+        assert_eq!(segments[1].as_ptr(), page.as_ptr());
+        assert_eq!(segments[1].len(), INT_PAGE_SIZE);
+        assert_eq!(segments[2], &[ESCAPE_CHAR, CMD_WPAGE]);
+        assert_eq!(v.segments().count(), 3);
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_set_attr() {
+        let cmd = Command::SetAttr {
+            index: 3,
+            key: b"bo\xFCrd\0\0\0",
+            value: &[ESCAPE_CHAR, 0x01, ESCAPE_CHAR],
+        };
+        check_matches_encoder(&cmd);
+    }
+
+    #[test]
+    #[cfg(feature = "baud-change")]
+    fn check_small_commands() {
+        check_matches_encoder(&Command::Ping);
+        check_matches_encoder(&Command::CrcIntFlash {
+            address: 0xFCFC_FCFC,
+            length: 0xFCFC_FCFC,
+        });
+        check_matches_encoder(&Command::ChangeBaud {
+            mode: BaudMode::Set,
+            baud: 115200,
+        });
+        assert_eq!(VectoredEncoder::new(&Command::Ping).unwrap().segments().count(), 1);
+    }
+}
@@ -0,0 +1,248 @@
+//! Versions of the protocol.
+//!
+//! Newer bootloaders understand more commands than older ones. A flash tool
+//! can find out which version it is talking to (from the `bootver`
+//! attribute, or the `Info` string) and use `ProtocolVersion::supports` to
+//! avoid sending commands the bootloader will only answer with `Unknown`.
+//!
+//! For finer checks, such as working around a bug fixed in 1.1.2, parse
+//! the whole version as a `SemVer` and compare it.
+//!
+//! `SetAddress`, `SetLongAttr`, `GetLongAttr` and `Exit` are extensions
+//! of this crate's, which no version of the stock bootloader understands.
+//! They have no version, and `supports` is false for them: a host has to
+//! know some other way that the bootloader has the extension.
+//!
+//! `Command`, `Response` and `Error` are `#[non_exhaustive]`, so adding the
+//! commands from a new version isn't a breaking change. Code matching on
+//! them needs a wildcard arm, which for a bootloader should reply
+//! `Response::Unknown`.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::fmt;
+
+use super::{Command, Opcode};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// A version of the bootloader protocol. Later versions compare greater.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum ProtocolVersion {
+    /// The original protocol, spoken by bootloader 1.0.
+    V1_0,
+    /// Bootloader 1.1, which added `ChangeBaud`.
+    V1_1,
+}
+
+/// A bootloader version, such as `1.1.2`. Versions compare by major, then
+/// minor, then patch number.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SemVer {
+    /// The major version number.
+    pub major: u32,
+    /// The minor version number.
+    pub minor: u32,
+    /// The patch number.
+    pub patch: u32,
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl ProtocolVersion {
+    /// The newest version this crate implements.
+    pub const LATEST: ProtocolVersion = ProtocolVersion::V1_1;
+
+    /// Parse a bootloader version string such as `1.1.0`, as found in the
+    /// `bootver` attribute. Only the major and minor numbers matter.
+    /// Versions newer than this crate knows about are treated as `LATEST`.
+    /// Returns `None` if the string isn't a version, or is before 1.0.
+    pub fn parse(version: &[u8]) -> Option<ProtocolVersion> {
+        let version = version.split(|&b| b == 0).next().unwrap_or(&[]);
+        let mut parts = version.split(|&b| b == b'.');
+        let major = parse_number(parts.next()?)?;
+        let minor = parts.next().map_or(Some(0), parse_number)?;
+        ProtocolVersion::from_numbers(major, minor)
+    }
+
+    /// Whether a bootloader speaking this version understands `command`.
+    /// Always false for the crate's own extensions.
+    pub fn supports(self, command: &Command) -> bool {
+        command.kind().since().is_some_and(|since| self >= since)
+    }
+}
+
+impl SemVer {
+    /// The version `major.minor.patch`.
+    pub const fn new(major: u32, minor: u32, patch: u32) -> SemVer {
+        SemVer {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Parse a version such as `1.1.2`, as found in the `bootver`
+    /// attribute or the `Info` string. A leading `v` is allowed, missing
+    /// minor or patch numbers count as 0, and anything from a `-` or `+`
+    /// on (a pre-release or build tag) is ignored. Trailing nulls are
+    /// ignored too.
+    pub fn parse(version: &[u8]) -> Option<SemVer> {
+        let version = version.split(|&b| b == 0).next().unwrap_or(&[]);
+        let version = version.strip_prefix(b"v").unwrap_or(version);
+        let version = version.split(|&b| b == b'-' || b == b'+').next().unwrap_or(&[]);
+        let mut parts = version.split(|&b| b == b'.');
+        let major = parse_number(parts.next()?)?;
+        let minor = parts.next().map_or(Some(0), parse_number)?;
+        let patch = parts.next().map_or(Some(0), parse_number)?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(SemVer::new(major, minor, patch))
+    }
+
+    /// The protocol a bootloader of this version speaks, or `None` for
+    /// versions before 1.0.
+    pub fn protocol(&self) -> Option<ProtocolVersion> {
+        ProtocolVersion::from_numbers(self.major, self.minor)
+    }
+}
+
+impl fmt::Display for SemVer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl Opcode {
+    /// The version of the protocol which added this command, or `None` if
+    /// it's an extension of this crate's, not part of the protocol.
+    pub fn since(self) -> Option<ProtocolVersion> {
+        match self {
+            Opcode::Ping |
+            Opcode::Info |
+            Opcode::Id |
+            Opcode::Reset |
+            Opcode::ErasePage |
+            Opcode::WritePage |
+            Opcode::EraseExBlock |
+            Opcode::WriteExPage |
+            Opcode::CrcRxBuffer |
+            Opcode::ReadRange |
+            Opcode::ExReadRange |
+            Opcode::SetAttr |
+            Opcode::GetAttr |
+            Opcode::CrcIntFlash |
+            Opcode::CrcExtFlash |
+            Opcode::EraseExPage |
+            Opcode::ExtFlashInit |
+            Opcode::ClockOut |
+            Opcode::WriteFlashUserPages => Some(ProtocolVersion::V1_0),
+            Opcode::ChangeBaud => Some(ProtocolVersion::V1_1),
+            Opcode::SetAddress | Opcode::SetLongAttr | Opcode::GetLongAttr | Opcode::Exit => None,
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl ProtocolVersion {
+    fn from_numbers(major: u32, minor: u32) -> Option<ProtocolVersion> {
+        Some(match (major, minor) {
+            (0, _) => return None,
+            (1, 0) => ProtocolVersion::V1_0,
+            _ => ProtocolVersion::LATEST,
+        })
+    }
+}
+
+fn parse_number(digits: &[u8]) -> Option<u32> {
+    if digits.is_empty() {
+        return None;
+    }
+    digits.iter().try_fold(0u32, |n, &b| {
+        if b.is_ascii_digit() {
+            n.checked_mul(10)?.checked_add(u32::from(b - b'0'))
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "baud-change")]
+    use super::super::BaudMode;
+
+    #[test]
+    fn check_parse() {
+        assert_eq!(ProtocolVersion::parse(b"1.0.0"), Some(ProtocolVersion::V1_0));
+        assert_eq!(ProtocolVersion::parse(b"1.1.2\0\0\0"), Some(ProtocolVersion::V1_1));
+        assert_eq!(ProtocolVersion::parse(b"2"), Some(ProtocolVersion::LATEST));
+        assert_eq!(ProtocolVersion::parse(b"0.9"), None);
+        assert_eq!(ProtocolVersion::parse(b"v1.1"), None);
+        assert_eq!(ProtocolVersion::parse(b""), None);
+    }
+
+    #[test]
+    fn check_semver() {
+        assert_eq!(SemVer::parse(b"1.1.2"), Some(SemVer::new(1, 1, 2)));
+        assert_eq!(SemVer::parse(b"v1.2\0\0"), Some(SemVer::new(1, 2, 0)));
+        assert_eq!(SemVer::parse(b"1.1.3-rc1+abc"), Some(SemVer::new(1, 1, 3)));
+        assert_eq!(SemVer::parse(b"1.1.1.1"), None);
+        assert_eq!(SemVer::parse(b"1..1"), None);
+        assert!(SemVer::new(1, 10, 0) > SemVer::new(1, 9, 9));
+        assert!(SemVer::new(2, 0, 0) > SemVer::new(1, 99, 99));
+        assert_eq!(SemVer::new(1, 0, 5).protocol(), Some(ProtocolVersion::V1_0));
+        assert_eq!(SemVer::new(0, 9, 0).protocol(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "baud-change")]
+    fn check_supports() {
+        let baud = Command::ChangeBaud {
+            mode: BaudMode::Set,
+            baud: 115_200,
+        };
+        assert!(!ProtocolVersion::V1_0.supports(&baud));
+        assert!(ProtocolVersion::V1_1.supports(&baud));
+        assert!(ProtocolVersion::V1_0.supports(&Command::Ping));
+    }
+
+    #[test]
+    fn check_since() {
+        assert_eq!(Opcode::Reset.since(), Some(ProtocolVersion::V1_0));
+        assert_eq!(Opcode::ChangeBaud.since(), Some(ProtocolVersion::V1_1));
+        assert_eq!(Opcode::SetAddress.since(), None);
+        assert_eq!(Opcode::SetLongAttr.since(), None);
+        assert_eq!(Opcode::GetLongAttr.since(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "multi-drop")]
+    fn check_supports_extension() {
+        let command = Command::SetAddress { address: 3 };
+        assert!(!ProtocolVersion::V1_0.supports(&command));
+        assert!(!ProtocolVersion::LATEST.supports(&command));
+    }
+}
@@ -0,0 +1,1652 @@
+//! Multi-step operations built from individual commands.
+//!
+//! Installing an app takes a conversation with the bootloader: tockloader
+//! walks the Tock Binary Format (TBF) headers of the apps already in flash to
+//! find where they end, writes the new app there, makes sure the kernel will
+//! stop looking for apps after it, and then checks that the app was written
+//! correctly. `install_app` does the same, one command at a time, leaving
+//! the actual sending and receiving to you.
+//!
+//! Changing the baud rate is similar: the new rate is requested at the old
+//! rate, both ends switch, and the host confirms the new rate works. If it
+//! doesn't, both ends have to go back. `BaudChange` tells you what to send
+//! and when to reconfigure your UART.
+//!
+//! Before any of that, the host has to get the bootloader's attention.
+//! tockloader sends `SYNC_BYTES`, to flush out anything the bootloader has
+//! half received, then pings until it gets a `Pong`. `SyncUp` does the same.
+//!
+//! A page can be damaged on its way to the bootloader. `StagedWrite` sends
+//! each page, asks for the CRC of what arrived with `CrcRxBuffer` and sends
+//! it again if that's wrong, before committing it with a CRC of the flash.
+//! A bootloader with `BootloaderSession::set_staged_writes` only writes the
+//! page on that commit; any other writes it straight away, and the same
+//! conversation still checks each page.
+//!
+//! Reflashing a kernel at 115200 baud takes a while, and often only a few
+//! pages have changed. `PageDiff` asks for the CRC of each page already in
+//! flash, and only writes the pages which differ.
+//!
+//! Once an image is written, `VerifyImage` reads back the CRC of each page
+//! of it and reports the ranges which don't match, so that just those can
+//! be written again.
+//!
+//! Reading is simpler, but `ReadRange` takes a 16-bit length and a
+//! bootloader only sends back so much at once. `ReadPaginator` reads any
+//! amount a chunk at a time, asking again for chunks which fail.
+//!
+//! Finding an attribute by key means asking for each slot in turn, as
+//! tockloader does, and comparing keys without their null padding.
+//! `FindAttr` does that.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+#[cfg(feature = "attributes")]
+use super::attr_key::AttrKey;
+#[cfg(feature = "attributes")]
+use super::attributes::Attribute;
+use super::crc::{crc32, Crc32};
+use super::pages::{FlashTarget, PadPolicy, PageWriter};
+use super::tbf::{TbfHeader, BASE_HEADER_LEN};
+#[cfg(feature = "baud-change")]
+use super::BaudMode;
+use super::{Command, Error, Response};
+use super::{CMD_RESET, ESCAPE_CHAR, INT_PAGE_SIZE};
+#[cfg(feature = "attributes")]
+use super::MAX_INDEX;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Installs a TBF app after the apps already in flash.
+///
+/// Call `next_command`, send the command, and pass the decoded reply to
+/// `handle_response`. Repeat until `next_command` returns `None`. Every
+/// command sent gets a reply.
+#[derive(Clone)]
+pub struct InstallApp<'a> {
+    tbf: &'a [u8],
+    total_size: u32,
+    cursor: u32,
+    state: State,
+    writer: Option<PageWriter<'a>>,
+    pages_sent: usize,
+}
+
+/// Changes the bootloader's baud rate.
+///
+/// Call `next_step` and do what it says. After sending a command, pass the
+/// decoded reply to `handle_response`, or call `timed_out` if none arrives.
+/// Keep going until you get `BaudStep::Finished`, even after an error, as
+/// you may be asked to put your UART back to the old rate.
+#[cfg(feature = "baud-change")]
+#[derive(Debug, Clone)]
+pub struct BaudChange {
+    old_baud: u32,
+    new_baud: u32,
+    state: BaudState,
+}
+
+/// What the caller of `BaudChange::next_step` should do next.
+#[cfg(feature = "baud-change")]
+#[derive(Debug, PartialEq)]
+pub enum BaudStep {
+    /// Send this command and wait for the reply.
+    Send(Command<'static>),
+    /// Reconfigure the UART to this baud rate, then call `next_step` again.
+    SetUartBaud(u32),
+    /// All done. On failure the UART is back at the old rate.
+    Finished(Result<(), Error>),
+}
+
+/// Writes an image one page at a time, staging and checking each page
+/// before committing it.
+///
+/// For each page, this sends the `WritePage` (or `WriteExPage`), then a
+/// `CrcRxBuffer`. If the CRC doesn't match what was sent, the page is
+/// staged again, up to three times. Then a `CrcIntFlash` (or `CrcExtFlash`)
+/// of the page commits it and checks it landed. Call `next_command`, send
+/// the command, and pass the decoded reply to `handle_response`, until
+/// `next_command` returns `None`.
+#[derive(Clone)]
+pub struct StagedWrite<'a> {
+    writer: PageWriter<'a>,
+    target: FlashTarget,
+    address: u32,
+    page: [u8; INT_PAGE_SIZE],
+    len: usize,
+    step: StageStep,
+    attempts: u8,
+    max_attempts: u8,
+    restages: usize,
+}
+
+/// Writes only the pages of an image which differ from what is already in
+/// flash.
+///
+/// For each page, this sends a `CrcIntFlash` (or `CrcExtFlash`) of what is
+/// there now. If it matches the new page, the page is skipped. Otherwise
+/// the page is written and its CRC checked again. A changed page whose CRC
+/// happens to match is missed, but the chance of that is one in four
+/// billion. Call `next_command`, send the command, and pass the decoded
+/// reply to `handle_response`, until `next_command` returns `None`.
+#[derive(Clone)]
+pub struct PageDiff<'a> {
+    writer: PageWriter<'a>,
+    target: FlashTarget,
+    address: u32,
+    page: [u8; INT_PAGE_SIZE],
+    len: usize,
+    step: DiffStep,
+    written: usize,
+    skipped: usize,
+}
+
+/// Checks that an image is in flash, without writing anything.
+///
+/// For each page the image covers, this sends a `CrcIntFlash` (or
+/// `CrcExtFlash`) of the bytes the image puts there and compares it with
+/// their CRC. The rest of a partial page isn't checked. Call
+/// `next_command`, send the command, and pass the decoded reply to
+/// `handle_response`, which returns the range if it didn't match, until
+/// `next_command` returns `None`.
+#[derive(Clone)]
+pub struct VerifyImage<'a> {
+    target: FlashTarget,
+    address: u32,
+    data: &'a [u8],
+    offset: usize,
+    mismatches: usize,
+}
+
+/// A range of flash which doesn't hold what it should.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Mismatch {
+    /// The first address in the range.
+    pub address: u32,
+    /// The length of the range, in bytes.
+    pub length: u32,
+}
+
+/// Reads a range of flash of any length, a chunk at a time.
+///
+/// This sends a `ReadRange` (or `ExReadRange`) for each chunk, a page long
+/// unless `set_chunk_len` says otherwise, and copies the reply into place
+/// in the buffer. A chunk which fails, because the bootloader sent back an
+/// error, the wrong amount of data or nothing at all, is asked for again,
+/// up to three times in all. Call `next_command`, send the command, and
+/// pass the decoded reply to `handle_response` (or call `timed_out` if none
+/// arrives), until `next_command` returns `None`.
+pub struct ReadPaginator<'a> {
+    target: FlashTarget,
+    address: u32,
+    buffer: &'a mut [u8],
+    offset: usize,
+    chunk_len: u16,
+    attempts: u8,
+    max_attempts: u8,
+    retries: usize,
+    failed: Option<Error>,
+}
+
+/// Looks for the attribute with a given key.
+///
+/// This sends a `GetAttr` for each slot in turn, starting from 0, until
+/// one comes back with the key, ignoring null padding on either. Call
+/// `next_command`, send the command, and pass the decoded reply to
+/// `handle_response`, which returns the attribute once it's found, until
+/// `next_command` returns `None`. If the reply for an erased slot won't
+/// decode (`Error::InvalidValue` for `Field::AttrLength`), call
+/// `slot_empty` instead.
+#[cfg(feature = "attributes")]
+#[derive(Debug, Clone)]
+pub struct FindAttr {
+    key: AttrKey,
+    index: u8,
+    num_slots: u8,
+    found: bool,
+}
+
+/// Gets the bootloader's attention.
+///
+/// Call `next_step` and do what it says. After sending a `Ping`, pass the
+/// decoded reply to `handle_response`, or call `timed_out` if none arrives.
+/// Keep going until you get `SyncStep::Finished`.
+#[derive(Debug, Clone)]
+pub struct SyncUp {
+    attempts: u8,
+    max_attempts: u8,
+    state: SyncState,
+}
+
+/// What the caller of `SyncUp::next_step` should do next.
+#[derive(Debug, PartialEq)]
+pub enum SyncStep {
+    /// Send these bytes, then reset your decoder (or `HostSession`) so
+    /// that nothing received before now is kept.
+    Flush(&'static [u8]),
+    /// Send this command and wait for the reply.
+    Send(Command<'static>),
+    /// All done. On failure, the bootloader never answered with a `Pong`.
+    Finished(Result<(), Error>),
+}
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum State {
+    Scan,
+    Write,
+    Sentinel,
+    Verify,
+    Done,
+}
+
+#[cfg(feature = "baud-change")]
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum BaudState {
+    Set,
+    Switch,
+    Verify,
+    Revert(Error),
+    Finished(Result<(), Error>),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum DiffStep {
+    Probe,
+    Write,
+    Verify,
+    Done,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum StageStep {
+    Stage,
+    Verify,
+    Commit,
+    Done,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum SyncState {
+    Flush,
+    Ping,
+    Finished(Result<(), Error>),
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// Sent to get the bootloader's attention. The leading null ends any
+/// escape sequence the bootloader is in the middle of, and the `Reset`
+/// makes it drop whatever it has received so far.
+pub const SYNC_BYTES: [u8; 3] = [0x00, ESCAPE_CHAR, CMD_RESET];
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const DEFAULT_SYNC_ATTEMPTS: u8 = 30;
+
+const DEFAULT_STAGE_ATTEMPTS: u8 = 3;
+
+const DEFAULT_READ_ATTEMPTS: u8 = 3;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Install the TBF app in `tbf` into the app region starting at `appaddr`.
+///
+/// `appaddr` must be page aligned, and `tbf` must start with a version 2 TBF
+/// header whose total size covers the whole slice. Otherwise
+/// `Error::BadArguments` is returned.
+pub fn install_app(appaddr: u32, tbf: &[u8]) -> Result<InstallApp<'_>, Error> {
+    if appaddr as usize & (INT_PAGE_SIZE - 1) != 0 {
+        return Err(Error::BadArguments);
+    }
+    match TbfHeader::parse(tbf) {
+        Some(header) if header.total_size as usize >= tbf.len() => Ok(InstallApp {
+            tbf,
+            total_size: header.total_size,
+            cursor: appaddr,
+            state: State::Scan,
+            writer: None,
+            pages_sent: 0,
+        }),
+        _ => Err(Error::BadArguments),
+    }
+}
+
+impl<'a> InstallApp<'a> {
+    /// Get the next command to send, or `None` when the app is installed.
+    pub fn next_command(&mut self) -> Option<Command<'_>> {
+        let length = self.tbf.len() as u32;
+        match self.state {
+            State::Scan => Some(Command::ReadRange {
+                address: self.cursor,
+                length: BASE_HEADER_LEN as u16,
+            }),
+            State::Write => {
+                let writer = self.writer.as_mut()?;
+                self.pages_sent += 1;
+                writer.next_command()
+            }
+            State::Sentinel => Some(Command::ErasePage {
+                address: self.sentinel_address(),
+            }),
+            State::Verify => Some(Command::CrcIntFlash {
+                address: self.cursor,
+                length,
+            }),
+            State::Done => None,
+        }
+    }
+
+    /// Process the reply to the last command from `next_command`.
+    ///
+    /// Returns `Error::Refused` if the bootloader sent back an error,
+    /// `Error::CrcMismatch` if the app didn't verify, and
+    /// `Error::MismatchedResponse` for any other unexpected reply.
+    pub fn handle_response(&mut self, response: &Response) -> Result<(), Error> {
+        match (self.state, response) {
+            (_, &Response::Overflow) |
+            (_, &Response::BadAddress) |
+            (_, &Response::InternalError) |
+            (_, &Response::BadArguments) |
+            (_, &Response::Unknown) => Err(Error::Refused),
+            (State::Scan, &Response::ReadRange { data }) => {
+                match TbfHeader::parse(data) {
+                    Some(header) => {
+                        self.cursor = header.next_address(self.cursor).ok_or(Error::BadArguments)?;
+                    }
+                    None => {
+                        // No valid header, so this is the end of the apps
+                        let writer = PageWriter::new(FlashTarget::Internal, self.cursor, self.tbf)?;
+                        self.writer = Some(writer);
+                        self.state = State::Write;
+                    }
+                }
+                Ok(())
+            }
+            (State::Write, &Response::Ok) => {
+                let done = match self.writer {
+                    Some(ref w) => self.pages_sent >= w.num_pages(),
+                    None => true,
+                };
+                if done {
+                    self.state = State::Sentinel;
+                }
+                Ok(())
+            }
+            (State::Sentinel, &Response::Ok) => {
+                self.state = State::Verify;
+                Ok(())
+            }
+            (State::Verify, &Response::CrcIntFlash { crc }) => {
+                if crc == crc32(self.tbf) {
+                    self.state = State::Done;
+                    Ok(())
+                } else {
+                    Err(Error::CrcMismatch)
+                }
+            }
+            _ => Err(Error::MismatchedResponse),
+        }
+    }
+
+    /// Has the app been installed and verified?
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Where the app is being written. Only meaningful once the existing
+    /// apps have been scanned.
+    pub fn app_address(&self) -> u32 {
+        self.cursor
+    }
+
+    /// The page erased after the new app, so the kernel finds no header
+    /// there. If the app doesn't end on a page boundary, the padding in its
+    /// last page already reads as erased flash.
+    fn sentinel_address(&self) -> u32 {
+        let end = self.cursor + self.total_size;
+        let mask = INT_PAGE_SIZE as u32 - 1;
+        (end + mask) & !mask
+    }
+}
+
+#[cfg(feature = "baud-change")]
+impl BaudChange {
+    /// Change from `old_baud`, the rate the UART is at now, to `new_baud`.
+    pub const fn new(old_baud: u32, new_baud: u32) -> BaudChange {
+        BaudChange {
+            old_baud,
+            new_baud,
+            state: BaudState::Set,
+        }
+    }
+
+    /// What to do next. Calling this again without passing a reply to
+    /// `handle_response` gives you the same command to send again.
+    pub fn next_step(&mut self) -> BaudStep {
+        match self.state {
+            BaudState::Set => BaudStep::Send(Command::ChangeBaud {
+                mode: BaudMode::Set,
+                baud: self.new_baud,
+            }),
+            BaudState::Switch => {
+                self.state = BaudState::Verify;
+                BaudStep::SetUartBaud(self.new_baud)
+            }
+            BaudState::Verify => BaudStep::Send(Command::ChangeBaud {
+                mode: BaudMode::Verify,
+                baud: self.new_baud,
+            }),
+            BaudState::Revert(e) => {
+                self.state = BaudState::Finished(Err(e));
+                BaudStep::SetUartBaud(self.old_baud)
+            }
+            BaudState::Finished(result) => BaudStep::Finished(result),
+        }
+    }
+
+    /// Process the reply to the last command sent.
+    ///
+    /// `ChangeBaudFail` and error responses give `Error::Refused`, and
+    /// anything else unexpected gives `Error::MismatchedResponse`. If the
+    /// rate has already been switched, `next_step` will then ask you to
+    /// switch back.
+    pub fn handle_response(&mut self, response: &Response) -> Result<(), Error> {
+        let result = match *response {
+            Response::Ok => Ok(()),
+            Response::ChangeBaudFail |
+            Response::Overflow |
+            Response::BadAddress |
+            Response::InternalError |
+            Response::BadArguments |
+            Response::Unknown => Err(Error::Refused),
+            _ => Err(Error::MismatchedResponse),
+        };
+        self.state = match (self.state, result) {
+            (BaudState::Set, Ok(())) => BaudState::Switch,
+            (BaudState::Verify, Ok(())) => BaudState::Finished(Ok(())),
+            (BaudState::Set, Err(e)) => BaudState::Finished(Err(e)),
+            (BaudState::Verify, Err(e)) => BaudState::Revert(e),
+            (state, _) => state,
+        };
+        result
+    }
+
+    /// No reply arrived to the last command sent. If we've already switched
+    /// rates, the bootloader will have given up on the new rate and gone
+    /// back to the old one, so `next_step` will ask you to do the same.
+    pub fn timed_out(&mut self) {
+        self.state = match self.state {
+            BaudState::Verify => BaudState::Revert(Error::Refused),
+            BaudState::Set => BaudState::Finished(Err(Error::Refused)),
+            state => state,
+        };
+    }
+}
+
+impl SyncUp {
+    /// Sync up, pinging up to 30 times.
+    pub const fn new() -> SyncUp {
+        SyncUp {
+            attempts: 0,
+            max_attempts: DEFAULT_SYNC_ATTEMPTS,
+            state: SyncState::Flush,
+        }
+    }
+
+    /// Set how many times to ping before giving up. Zero is treated as one.
+    pub fn set_max_attempts(&mut self, max_attempts: u8) {
+        self.max_attempts = max_attempts.max(1);
+    }
+
+    /// What to do next.
+    pub fn next_step(&mut self) -> SyncStep {
+        match self.state {
+            SyncState::Flush => {
+                self.state = SyncState::Ping;
+                SyncStep::Flush(&SYNC_BYTES)
+            }
+            SyncState::Ping => SyncStep::Send(Command::Ping),
+            SyncState::Finished(result) => SyncStep::Finished(result),
+        }
+    }
+
+    /// Process the reply to a `Ping`. Anything but `Pong` counts as a
+    /// failed attempt, and once they have all failed the last error is
+    /// returned from then on: `Error::Refused` for error responses and
+    /// `Error::MismatchedResponse` for anything else.
+    pub fn handle_response(&mut self, response: &Response) -> Result<(), Error> {
+        let result = match *response {
+            Response::Pong => Ok(()),
+            ref r if r.is_error() => Err(Error::Refused),
+            _ => Err(Error::MismatchedResponse),
+        };
+        self.attempt(result);
+        result
+    }
+
+    /// No reply arrived to the last `Ping`.
+    pub fn timed_out(&mut self) {
+        self.attempt(Err(Error::Refused));
+    }
+
+    /// How many pings have been answered, or have timed out.
+    pub fn attempts(&self) -> u8 {
+        self.attempts
+    }
+}
+
+impl Default for SyncUp {
+    fn default() -> SyncUp {
+        SyncUp::new()
+    }
+}
+
+impl<'a> StagedWrite<'a> {
+    /// Write `data` to `target` starting at `address`, padding partial
+    /// pages with 0xFF as a `PageWriter` does.
+    pub fn new(
+        target: FlashTarget,
+        address: u32,
+        data: &'a [u8],
+    ) -> Result<StagedWrite<'a>, Error> {
+        StagedWrite::with_pad_policy(target, address, data, PadPolicy::Fill(0xFF))
+    }
+
+    /// Write `data` to `target` starting at `address`, padding partial
+    /// pages as `policy` says. See `PageWriter::with_pad_policy`.
+    pub fn with_pad_policy(
+        target: FlashTarget,
+        address: u32,
+        data: &'a [u8],
+        policy: PadPolicy,
+    ) -> Result<StagedWrite<'a>, Error> {
+        let mut staged = StagedWrite {
+            writer: PageWriter::with_pad_policy(target, address, data, policy)?,
+            target,
+            address: 0,
+            page: [0u8; INT_PAGE_SIZE],
+            len: 0,
+            step: StageStep::Stage,
+            attempts: 0,
+            max_attempts: DEFAULT_STAGE_ATTEMPTS,
+            restages: 0,
+        };
+        staged.next_page();
+        Ok(staged)
+    }
+
+    /// Set how many times a page is staged before giving up. Zero is
+    /// treated as one.
+    pub fn set_max_attempts(&mut self, max_attempts: u8) {
+        self.max_attempts = max_attempts.max(1);
+    }
+
+    /// Get the next command to send, or `None` when every page is written.
+    pub fn next_command(&mut self) -> Option<Command<'_>> {
+        let address = self.address;
+        let length = self.len as u32;
+        let data = &self.page[0..self.len];
+        match (self.step, self.target) {
+            (StageStep::Stage, FlashTarget::Internal) => Some(Command::WritePage { address, data }),
+            #[cfg(feature = "ext-flash")]
+            (StageStep::Stage, FlashTarget::External) => {
+                Some(Command::WriteExPage { address, data })
+            }
+            (StageStep::Verify, _) => Some(Command::CrcRxBuffer),
+            (StageStep::Commit, FlashTarget::Internal) => {
+                Some(Command::CrcIntFlash { address, length })
+            }
+            #[cfg(feature = "ext-flash")]
+            (StageStep::Commit, FlashTarget::External) => {
+                Some(Command::CrcExtFlash { address, length })
+            }
+            (StageStep::Done, _) => None,
+        }
+    }
+
+    /// Process the reply to the last command from `next_command`.
+    ///
+    /// Returns `Error::Refused` if the bootloader sent back an error
+    /// (including `Unknown`, from one which can't answer `CrcRxBuffer`),
+    /// `Error::CrcMismatch` if a page still arrived damaged after the last
+    /// attempt or didn't read back correctly, and
+    /// `Error::MismatchedResponse` for any other unexpected reply.
+    pub fn handle_response(&mut self, response: &Response) -> Result<(), Error> {
+        if response.is_error() {
+            return Err(Error::Refused);
+        }
+        match (self.step, response) {
+            (StageStep::Stage, &Response::Ok) => {
+                self.step = StageStep::Verify;
+                Ok(())
+            }
+            (StageStep::Verify, &Response::CrcRxBuffer { length, crc }) => {
+                let mut expected = Crc32::new();
+                expected.update(&self.address.to_le_bytes());
+                expected.update(&self.page[0..self.len]);
+                if length as usize == 4 + self.len && crc == expected.finish() {
+                    self.step = StageStep::Commit;
+                    return Ok(());
+                }
+                self.attempts += 1;
+                if self.attempts >= self.max_attempts {
+                    return Err(Error::CrcMismatch);
+                }
+                self.restages += 1;
+                self.step = StageStep::Stage;
+                Ok(())
+            }
+            (StageStep::Commit, &Response::CrcIntFlash { crc }) => self.committed(crc),
+            #[cfg(feature = "ext-flash")]
+            (StageStep::Commit, &Response::CrcExtFlash { crc }) => self.committed(crc),
+            _ => Err(Error::MismatchedResponse),
+        }
+    }
+
+    /// Has every page been committed?
+    pub fn is_done(&self) -> bool {
+        self.step == StageStep::Done
+    }
+
+    /// How many times a page has had to be staged again.
+    pub fn restages(&self) -> usize {
+        self.restages
+    }
+
+    /// The number of pages which will be written.
+    pub fn num_pages(&self) -> usize {
+        self.writer.num_pages()
+    }
+}
+
+impl<'a> PageDiff<'a> {
+    /// Write the pages of `data` which differ from what is in `target` at
+    /// `address`, padding partial pages with 0xFF as a `PageWriter` does.
+    pub fn new(target: FlashTarget, address: u32, data: &'a [u8]) -> Result<PageDiff<'a>, Error> {
+        PageDiff::with_pad_policy(target, address, data, PadPolicy::Fill(0xFF))
+    }
+
+    /// Write the pages of `data` which differ from what is in `target` at
+    /// `address`, padding partial pages as `policy` says. See
+    /// `PageWriter::with_pad_policy`.
+    pub fn with_pad_policy(
+        target: FlashTarget,
+        address: u32,
+        data: &'a [u8],
+        policy: PadPolicy,
+    ) -> Result<PageDiff<'a>, Error> {
+        let mut diff = PageDiff {
+            writer: PageWriter::with_pad_policy(target, address, data, policy)?,
+            target,
+            address: 0,
+            page: [0u8; INT_PAGE_SIZE],
+            len: 0,
+            step: DiffStep::Probe,
+            written: 0,
+            skipped: 0,
+        };
+        diff.next_page();
+        Ok(diff)
+    }
+
+    /// Get the next command to send, or `None` when every page is up to
+    /// date.
+    pub fn next_command(&mut self) -> Option<Command<'_>> {
+        let address = self.address;
+        let data = &self.page[0..self.len];
+        match (self.step, self.target) {
+            (DiffStep::Probe, _) | (DiffStep::Verify, _) => Some(self.crc_command()),
+            (DiffStep::Write, FlashTarget::Internal) => Some(Command::WritePage { address, data }),
+            #[cfg(feature = "ext-flash")]
+            (DiffStep::Write, FlashTarget::External) => {
+                Some(Command::WriteExPage { address, data })
+            }
+            (DiffStep::Done, _) => None,
+        }
+    }
+
+    /// Process the reply to the last command from `next_command`.
+    ///
+    /// Returns `Error::Refused` if the bootloader sent back an error,
+    /// `Error::CrcMismatch` if a page didn't read back correctly after
+    /// being written, and `Error::MismatchedResponse` for any other
+    /// unexpected reply.
+    pub fn handle_response(&mut self, response: &Response) -> Result<(), Error> {
+        if response.is_error() {
+            return Err(Error::Refused);
+        }
+        match (self.step, response) {
+            (DiffStep::Write, &Response::Ok) => {
+                self.step = DiffStep::Verify;
+                Ok(())
+            }
+            (DiffStep::Probe, &Response::CrcIntFlash { crc }) |
+            (DiffStep::Verify, &Response::CrcIntFlash { crc })
+                if self.target == FlashTarget::Internal =>
+            {
+                self.flash_crc(crc)
+            }
+            #[cfg(feature = "ext-flash")]
+            (DiffStep::Probe, &Response::CrcExtFlash { crc }) |
+            (DiffStep::Verify, &Response::CrcExtFlash { crc })
+                if self.target == FlashTarget::External =>
+            {
+                self.flash_crc(crc)
+            }
+            _ => Err(Error::MismatchedResponse),
+        }
+    }
+
+    /// Is every page up to date?
+    pub fn is_done(&self) -> bool {
+        self.step == DiffStep::Done
+    }
+
+    /// How many pages have been written, because they had changed.
+    pub fn pages_written(&self) -> usize {
+        self.written
+    }
+
+    /// How many pages have been skipped, because they hadn't.
+    pub fn pages_skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// The number of pages in the image.
+    pub fn num_pages(&self) -> usize {
+        self.writer.num_pages()
+    }
+}
+
+impl<'a> VerifyImage<'a> {
+    /// Check that `target` holds `data` at `address`. Returns
+    /// `Error::BadArguments` if the data runs past the end of the 32-bit
+    /// address space.
+    pub fn new(
+        target: FlashTarget,
+        address: u32,
+        data: &'a [u8],
+    ) -> Result<VerifyImage<'a>, Error> {
+        if (address as u64) + (data.len() as u64) > (1u64 << 32) {
+            return Err(Error::BadArguments);
+        }
+        Ok(VerifyImage {
+            target,
+            address,
+            data,
+            offset: 0,
+            mismatches: 0,
+        })
+    }
+
+    /// Get the next command to send, or `None` when every page has been
+    /// checked.
+    pub fn next_command(&self) -> Option<Command<'static>> {
+        let (address, length) = self.chunk()?;
+        let length = length as u32;
+        Some(match self.target {
+            FlashTarget::Internal => Command::CrcIntFlash { address, length },
+            #[cfg(feature = "ext-flash")]
+            FlashTarget::External => Command::CrcExtFlash { address, length },
+        })
+    }
+
+    /// Process the reply to the last command from `next_command`, returning
+    /// the range it covered if the CRC didn't match.
+    ///
+    /// Returns `Error::Refused` if the bootloader sent back an error, and
+    /// `Error::MismatchedResponse` for any other unexpected reply.
+    pub fn handle_response(&mut self, response: &Response) -> Result<Option<Mismatch>, Error> {
+        if response.is_error() {
+            return Err(Error::Refused);
+        }
+        let (address, length) = self.chunk().ok_or(Error::MismatchedResponse)?;
+        let crc = match (response, self.target) {
+            (&Response::CrcIntFlash { crc }, FlashTarget::Internal) => crc,
+            #[cfg(feature = "ext-flash")]
+            (&Response::CrcExtFlash { crc }, FlashTarget::External) => crc,
+            _ => return Err(Error::MismatchedResponse),
+        };
+        let data = &self.data[self.offset..self.offset + length];
+        self.offset += length;
+        if crc == crc32(data) {
+            return Ok(None);
+        }
+        self.mismatches += 1;
+        Ok(Some(Mismatch {
+            address,
+            length: length as u32,
+        }))
+    }
+
+    /// Has every page been checked?
+    pub fn is_done(&self) -> bool {
+        self.offset >= self.data.len()
+    }
+
+    /// How many pages have failed to match so far.
+    pub fn mismatches(&self) -> usize {
+        self.mismatches
+    }
+
+    /// The number of pages the image covers, and so of commands to send.
+    pub fn num_pages(&self) -> usize {
+        let page_size = self.target.page_size();
+        let lead = self.address as usize & (page_size - 1);
+        if self.data.is_empty() {
+            0
+        } else {
+            (lead + self.data.len()).div_ceil(page_size)
+        }
+    }
+}
+
+impl<'a> ReadPaginator<'a> {
+    /// Fill `buffer` from `target`, starting at `address`. Returns
+    /// `Error::BadArguments` if the range runs past the end of the 32-bit
+    /// address space.
+    pub fn new(
+        target: FlashTarget,
+        address: u32,
+        buffer: &'a mut [u8],
+    ) -> Result<ReadPaginator<'a>, Error> {
+        if (address as u64) + (buffer.len() as u64) > (1u64 << 32) {
+            return Err(Error::BadArguments);
+        }
+        Ok(ReadPaginator {
+            target,
+            address,
+            buffer,
+            offset: 0,
+            chunk_len: target.page_size() as u16,
+            attempts: 0,
+            max_attempts: DEFAULT_READ_ATTEMPTS,
+            retries: 0,
+            failed: None,
+        })
+    }
+
+    /// Ask for at most `chunk_len` bytes at a time, for bootloaders which
+    /// send back less than a page. A length of zero is taken as one.
+    pub fn set_chunk_len(&mut self, chunk_len: u16) {
+        self.chunk_len = chunk_len.max(1);
+    }
+
+    /// Try each chunk up to `max_attempts` times (at least once) before
+    /// giving up.
+    pub fn set_max_attempts(&mut self, max_attempts: u8) {
+        self.max_attempts = max_attempts.max(1);
+    }
+
+    /// Get the next command to send, or `None` once the buffer is full or
+    /// a chunk has failed too many times.
+    pub fn next_command(&self) -> Option<Command<'static>> {
+        if self.failed.is_some() {
+            return None;
+        }
+        let (address, length) = self.chunk()?;
+        Some(match self.target {
+            FlashTarget::Internal => Command::ReadRange { address, length },
+            #[cfg(feature = "ext-flash")]
+            FlashTarget::External => Command::ExReadRange { address, length },
+        })
+    }
+
+    /// Process the reply to the last command from `next_command`.
+    ///
+    /// A failed chunk is only an error once it has used up its attempts:
+    /// `Error::Refused` if the bootloader sent back an error, and
+    /// `Error::MismatchedResponse` for any other unexpected reply. After
+    /// that, the same error is returned every time.
+    pub fn handle_response(&mut self, response: &Response) -> Result<(), Error> {
+        if let Some(e) = self.failed {
+            return Err(e);
+        }
+        let (_, length) = self.chunk().ok_or(Error::MismatchedResponse)?;
+        let length = length as usize;
+        let data = match (response, self.target) {
+            (&Response::ReadRange { data }, FlashTarget::Internal) => data,
+            #[cfg(feature = "ext-flash")]
+            (&Response::ExReadRange { data }, FlashTarget::External) => data,
+            (r, _) if r.is_error() => return self.retry(Error::Refused),
+            _ => return self.retry(Error::MismatchedResponse),
+        };
+        if data.len() != length {
+            return self.retry(Error::MismatchedResponse);
+        }
+        self.buffer[self.offset..self.offset + length].copy_from_slice(data);
+        self.offset += length;
+        self.attempts = 0;
+        Ok(())
+    }
+
+    /// No reply arrived to the last command. Once the chunk has used up
+    /// its attempts, this returns `Error::Refused`.
+    pub fn timed_out(&mut self) -> Result<(), Error> {
+        match self.failed {
+            Some(e) => Err(e),
+            None => self.retry(Error::Refused),
+        }
+    }
+
+    /// Is the buffer full?
+    pub fn is_done(&self) -> bool {
+        self.offset >= self.buffer.len()
+    }
+
+    /// How many bytes have been read into the buffer.
+    pub fn bytes_read(&self) -> usize {
+        self.offset
+    }
+
+    /// How many chunks have been asked for again.
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
+    /// The number of chunks, and so of commands to send if none fail.
+    pub fn num_chunks(&self) -> usize {
+        self.buffer.len().div_ceil(self.chunk_len as usize)
+    }
+}
+
+#[cfg(feature = "attributes")]
+impl FindAttr {
+    /// Look for the attribute with `key`. Keys longer than `KEY_LEN`, after
+    /// dropping any trailing nulls, get `Error::BadArguments`.
+    pub fn new<K: AsRef<[u8]> + ?Sized>(key: &K) -> Result<FindAttr, Error> {
+        Ok(FindAttr {
+            key: AttrKey::new(key)?,
+            index: 0,
+            num_slots: MAX_INDEX,
+            found: false,
+        })
+    }
+
+    /// Look through `num_slots` slots rather than `MAX_INDEX`, for
+    /// bootloaders with a different size of attribute table.
+    pub fn set_num_slots(&mut self, num_slots: u8) {
+        self.num_slots = num_slots;
+    }
+
+    /// Get the next command to send, or `None` once the attribute has been
+    /// found or every slot has been looked at.
+    pub fn next_command(&self) -> Option<Command<'static>> {
+        if self.is_done() {
+            None
+        } else {
+            Some(Command::GetAttr { index: self.index })
+        }
+    }
+
+    /// Process the reply to the last command from `next_command`, returning
+    /// the attribute if it's the one being looked for.
+    ///
+    /// Returns `Error::Refused` if the bootloader sent back an error, and
+    /// `Error::MismatchedResponse` for any other unexpected reply.
+    pub fn handle_response<'r>(
+        &mut self,
+        response: &Response<'r>,
+    ) -> Result<Option<Attribute<'r>>, Error> {
+        if self.is_done() {
+            return Err(Error::MismatchedResponse);
+        }
+        match *response {
+            Response::GetAttr { key, value } if !value.is_empty() && self.key.matches(key) => {
+                self.found = true;
+                Ok(Some(Attribute {
+                    index: self.index,
+                    key: &key[0..self.key.as_bytes().len()],
+                    value,
+                }))
+            }
+            Response::GetAttr { .. } => {
+                self.index += 1;
+                Ok(None)
+            }
+            ref r if r.is_error() => Err(Error::Refused),
+            _ => Err(Error::MismatchedResponse),
+        }
+    }
+
+    /// The slot asked about last was erased, so its reply didn't decode.
+    pub fn slot_empty(&mut self) {
+        if !self.is_done() {
+            self.index += 1;
+        }
+    }
+
+    /// Has the attribute been found, or every slot looked at?
+    pub fn is_done(&self) -> bool {
+        self.found || self.index >= self.num_slots
+    }
+}
+
+impl Mismatch {
+    /// The address after the last one in the range, which may be 2^32.
+    pub fn end(&self) -> u64 {
+        self.address as u64 + self.length as u64
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<'a> VerifyImage<'a> {
+    /// The address and length of the next range to check: the rest of the
+    /// image, up to the end of the page it's in.
+    fn chunk(&self) -> Option<(u32, usize)> {
+        let remaining = self.data.len().checked_sub(self.offset).filter(|&n| n > 0)?;
+        let address = self.address + self.offset as u32;
+        let page_size = self.target.page_size();
+        let to_boundary = page_size - (address as usize & (page_size - 1));
+        Some((address, remaining.min(to_boundary)))
+    }
+}
+
+impl<'a> ReadPaginator<'a> {
+    /// The address and length of the next chunk to read.
+    fn chunk(&self) -> Option<(u32, u16)> {
+        let remaining = self.buffer.len().checked_sub(self.offset).filter(|&n| n > 0)?;
+        let address = self.address + self.offset as u32;
+        Some((address, remaining.min(self.chunk_len as usize) as u16))
+    }
+
+    /// The current chunk failed with `error`. Returns the error if that
+    /// was its last attempt.
+    fn retry(&mut self, error: Error) -> Result<(), Error> {
+        self.attempts = self.attempts.saturating_add(1);
+        if self.attempts >= self.max_attempts {
+            self.failed = Some(error);
+            return Err(error);
+        }
+        self.retries += 1;
+        Ok(())
+    }
+}
+
+impl<'a> StagedWrite<'a> {
+    /// Take the next page from the writer, and start staging it.
+    fn next_page(&mut self) {
+        match take_page(&mut self.writer, &mut self.page) {
+            Some((address, len)) => {
+                self.address = address;
+                self.len = len;
+                self.step = StageStep::Stage;
+                self.attempts = 0;
+            }
+            None => self.step = StageStep::Done,
+        }
+    }
+
+    /// The page has been written, and the bootloader says its CRC is `crc`.
+    fn committed(&mut self, crc: u32) -> Result<(), Error> {
+        if crc != crc32(&self.page[0..self.len]) {
+            return Err(Error::CrcMismatch);
+        }
+        self.next_page();
+        Ok(())
+    }
+}
+
+impl<'a> PageDiff<'a> {
+    /// Take the next page from the writer, and start checking it.
+    fn next_page(&mut self) {
+        match take_page(&mut self.writer, &mut self.page) {
+            Some((address, len)) => {
+                self.address = address;
+                self.len = len;
+                self.step = DiffStep::Probe;
+            }
+            None => self.step = DiffStep::Done,
+        }
+    }
+
+    /// Ask for the CRC of the current page of flash.
+    fn crc_command(&self) -> Command<'static> {
+        let address = self.address;
+        let length = self.len as u32;
+        match self.target {
+            FlashTarget::Internal => Command::CrcIntFlash { address, length },
+            #[cfg(feature = "ext-flash")]
+            FlashTarget::External => Command::CrcExtFlash { address, length },
+        }
+    }
+
+    /// The bootloader says the current page of flash has a CRC of `crc`.
+    fn flash_crc(&mut self, crc: u32) -> Result<(), Error> {
+        let matches = crc == crc32(&self.page[0..self.len]);
+        match self.step {
+            DiffStep::Probe if matches => {
+                self.skipped += 1;
+                self.next_page();
+            }
+            DiffStep::Probe => self.step = DiffStep::Write,
+            DiffStep::Verify if matches => {
+                self.written += 1;
+                self.next_page();
+            }
+            DiffStep::Verify => return Err(Error::CrcMismatch),
+            _ => return Err(Error::MismatchedResponse),
+        }
+        Ok(())
+    }
+}
+
+impl SyncUp {
+    fn attempt(&mut self, result: Result<(), Error>) {
+        if self.state != SyncState::Ping {
+            return;
+        }
+        self.attempts = self.attempts.saturating_add(1);
+        if result.is_ok() || self.attempts >= self.max_attempts {
+            self.state = SyncState::Finished(result);
+        }
+    }
+}
+
+/// Copy the next page from `writer` into `page`, returning its address and
+/// length, or `None` once there are no more.
+fn take_page(writer: &mut PageWriter, page: &mut [u8; INT_PAGE_SIZE]) -> Option<(u32, usize)> {
+    let (address, data) = match writer.next_command() {
+        Some(Command::WritePage { address, data }) => (address, data),
+        #[cfg(feature = "ext-flash")]
+        Some(Command::WriteExPage { address, data }) => (address, data),
+        _ => return None,
+    };
+    page[0..data.len()].copy_from_slice(data);
+    Some((address, data.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tbf::TBF_VERSION;
+    use byteorder::{ByteOrder, LittleEndian};
+    use std::vec::Vec;
+
+    const FLASH_BASE: u32 = 0x30000;
+    const FLASH_LEN: usize = 0x2000;
+
+    fn make_tbf(total_size: u32, len: usize) -> [u8; 0x800] {
+        let mut tbf = [0xA5u8; 0x800];
+        LittleEndian::write_u16(&mut tbf[0..2], TBF_VERSION);
+        LittleEndian::write_u16(&mut tbf[2..4], 16);
+        LittleEndian::write_u32(&mut tbf[4..8], total_size);
+        for b in &mut tbf[len..] {
+            *b = 0xFF;
+        }
+        tbf
+    }
+
+    /// Run the workflow against a simulated flash, returning the number of
+    /// commands sent.
+    fn run(flash: &mut [u8; FLASH_LEN], install: &mut InstallApp) -> Result<usize, Error> {
+        let mut count = 0;
+        loop {
+            let mut read = None;
+            let response = match install.next_command() {
+                None => return Ok(count),
+                Some(Command::ReadRange { address, .. }) => {
+                    // Borrows the flash, so it's built below
+                    read = Some((address - FLASH_BASE) as usize);
+                    Response::Ok
+                }
+                Some(Command::WritePage { address, data }) => {
+                    let start = (address - FLASH_BASE) as usize;
+                    flash[start..start + data.len()].copy_from_slice(data);
+                    Response::Ok
+                }
+                Some(Command::ErasePage { address }) => {
+                    let start = (address - FLASH_BASE) as usize;
+                    for b in &mut flash[start..start + INT_PAGE_SIZE] {
+                        *b = 0xFF;
+                    }
+                    Response::Ok
+                }
+                Some(Command::CrcIntFlash { address, length }) => {
+                    let start = (address - FLASH_BASE) as usize;
+                    Response::CrcIntFlash {
+                        crc: crc32(&flash[start..start + length as usize]),
+                    }
+                }
+                Some(c) => panic!("Unexpected {:?}", c),
+            };
+            count += 1;
+            match read {
+                Some(start) => install.handle_response(&Response::ReadRange {
+                    data: &flash[start..start + BASE_HEADER_LEN],
+                })?,
+                None => install.handle_response(&response)?,
+            }
+        }
+    }
+
+    #[test]
+    fn check_install_after_existing() {
+        let mut flash = [0xFFu8; FLASH_LEN];
+        let existing = make_tbf(0x400, 0x300);
+        flash[0..0x400].copy_from_slice(&existing[0..0x400]);
+        // Stale data where the sentinel should go
+        flash[0xC00] = 0x00;
+
+        let app = make_tbf(0x800, 0x600);
+        let mut install = install_app(FLASH_BASE, &app[0..0x600]).unwrap();
+        // 2 reads, 3 pages, 1 erase, 1 CRC
+        assert_eq!(run(&mut flash, &mut install), Ok(7));
+        assert!(install.is_done());
+        assert_eq!(install.app_address(), 0x30400);
+        assert_eq!(&flash[0x400..0xA00], &app[0..0x600]);
+        assert!(flash[0xC00..0xE00].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn check_crc_mismatch() {
+        let app = make_tbf(0x200, 0x200);
+        let mut install = install_app(FLASH_BASE, &app[0..0x200]).unwrap();
+        install.state = State::Verify;
+        assert_eq!(
+            install.handle_response(&Response::CrcIntFlash { crc: 0 }),
+            Err(Error::CrcMismatch)
+        );
+        assert_eq!(install.handle_response(&Response::BadAddress), Err(Error::Refused));
+        assert_eq!(install.handle_response(&Response::Pong), Err(Error::MismatchedResponse));
+    }
+
+    #[test]
+    fn check_bad_arguments() {
+        let app = make_tbf(0x200, 0x200);
+        assert!(install_app(FLASH_BASE + 1, &app[0..0x200]).is_err());
+        assert!(install_app(FLASH_BASE, &app[0..0x400]).is_err());
+        assert!(install_app(FLASH_BASE, &[0xFF; 16]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "baud-change")]
+    fn check_baud_change() {
+        let mut b = BaudChange::new(115200, 921600);
+        let set = Command::ChangeBaud {
+            mode: BaudMode::Set,
+            baud: 921600,
+        };
+        assert_eq!(b.next_step(), BaudStep::Send(set));
+        assert_eq!(b.handle_response(&Response::Ok), Ok(()));
+        assert_eq!(b.next_step(), BaudStep::SetUartBaud(921600));
+        let verify = Command::ChangeBaud {
+            mode: BaudMode::Verify,
+            baud: 921600,
+        };
+        assert_eq!(b.next_step(), BaudStep::Send(verify));
+        assert_eq!(b.handle_response(&Response::Ok), Ok(()));
+        assert_eq!(b.next_step(), BaudStep::Finished(Ok(())));
+    }
+
+    #[test]
+    #[cfg(feature = "baud-change")]
+    fn check_baud_change_rollback() {
+        let mut b = BaudChange::new(115200, 921600);
+        b.handle_response(&Response::Ok).unwrap();
+        assert_eq!(b.next_step(), BaudStep::SetUartBaud(921600));
+        assert_eq!(b.handle_response(&Response::ChangeBaudFail), Err(Error::Refused));
+        assert_eq!(b.next_step(), BaudStep::SetUartBaud(115200));
+        assert_eq!(b.next_step(), BaudStep::Finished(Err(Error::Refused)));
+
+        // No reply at the new rate
+        let mut b = BaudChange::new(115200, 921600);
+        b.handle_response(&Response::Ok).unwrap();
+        b.next_step();
+        b.timed_out();
+        assert_eq!(b.next_step(), BaudStep::SetUartBaud(115200));
+
+        // Refused outright, so nothing to undo
+        let mut b = BaudChange::new(115200, 12);
+        assert_eq!(b.handle_response(&Response::BadArguments), Err(Error::Refused));
+        assert_eq!(b.next_step(), BaudStep::Finished(Err(Error::Refused)));
+    }
+
+    /// Run a `StagedWrite` against a simulated staging bootloader, which
+    /// corrupts the first `damaged` pages it receives.
+    fn run_staged(
+        flash: &mut [u8; FLASH_LEN],
+        staged: &mut StagedWrite,
+        mut damaged: usize,
+    ) -> Result<(), Error> {
+        let mut rx = [0u8; INT_PAGE_SIZE];
+        let mut rx_address = 0;
+        loop {
+            let response = match staged.next_command() {
+                None => return Ok(()),
+                Some(Command::WritePage { address, data }) => {
+                    rx.copy_from_slice(data);
+                    rx_address = address;
+                    if damaged > 0 {
+                        damaged -= 1;
+                        rx[100] ^= 0x01;
+                    }
+                    Response::Ok
+                }
+                Some(Command::CrcRxBuffer) => {
+                    let mut crc = Crc32::new();
+                    crc.update(&rx_address.to_le_bytes());
+                    crc.update(&rx);
+                    Response::CrcRxBuffer {
+                        length: 4 + INT_PAGE_SIZE as u16,
+                        crc: crc.finish(),
+                    }
+                }
+                Some(Command::CrcIntFlash { address, length }) => {
+                    assert_eq!((address, length as usize), (rx_address, INT_PAGE_SIZE));
+                    let start = (address - FLASH_BASE) as usize;
+                    flash[start..start + INT_PAGE_SIZE].copy_from_slice(&rx);
+                    Response::CrcIntFlash { crc: crc32(&rx) }
+                }
+                Some(c) => panic!("{:?}", c),
+            };
+            staged.handle_response(&response)?;
+        }
+    }
+
+    #[test]
+    fn check_staged_write() {
+        let mut flash = [0u8; FLASH_LEN];
+        let data = [0x5A; INT_PAGE_SIZE + 16];
+        let mut staged = StagedWrite::new(FlashTarget::Internal, FLASH_BASE, &data).unwrap();
+        assert_eq!(staged.num_pages(), 2);
+        assert_eq!(run_staged(&mut flash, &mut staged, 0), Ok(()));
+        assert!(staged.is_done());
+        assert_eq!(staged.restages(), 0);
+        assert_eq!(&flash[0..data.len()], &data[..]);
+        assert!(flash[data.len()..2 * INT_PAGE_SIZE].iter().all(|&b| b == 0xFF));
+
+        let reject = PadPolicy::Reject;
+        assert!(StagedWrite::with_pad_policy(FlashTarget::Internal, FLASH_BASE, &data, reject)
+            .is_err());
+        assert!(PageDiff::with_pad_policy(FlashTarget::Internal, FLASH_BASE, &data, reject)
+            .is_err());
+        let mut staged = StagedWrite::with_pad_policy(
+            FlashTarget::Internal,
+            FLASH_BASE,
+            &data[0..INT_PAGE_SIZE],
+            reject,
+        )
+        .unwrap();
+        assert_eq!(run_staged(&mut flash, &mut staged, 0), Ok(()));
+    }
+
+    #[test]
+    fn check_staged_write_restage() {
+        let mut flash = [0u8; FLASH_LEN];
+        let data = [0x5A; INT_PAGE_SIZE];
+        let mut staged = StagedWrite::new(FlashTarget::Internal, FLASH_BASE, &data).unwrap();
+        assert_eq!(run_staged(&mut flash, &mut staged, 2), Ok(()));
+        assert_eq!(staged.restages(), 2);
+        assert_eq!(&flash[0..INT_PAGE_SIZE], &data[..]);
+
+        // Damaged every time
+        let mut staged = StagedWrite::new(FlashTarget::Internal, FLASH_BASE, &data).unwrap();
+        staged.set_max_attempts(2);
+        assert_eq!(run_staged(&mut flash, &mut staged, 2), Err(Error::CrcMismatch));
+        assert!(!staged.is_done());
+
+        // A bootloader without CrcRxBuffer
+        let mut staged = StagedWrite::new(FlashTarget::Internal, FLASH_BASE, &data).unwrap();
+        staged.handle_response(&Response::Ok).unwrap();
+        assert_eq!(staged.next_command(), Some(Command::CrcRxBuffer));
+        assert_eq!(staged.handle_response(&Response::Unknown), Err(Error::Refused));
+        assert_eq!(staged.handle_response(&Response::Pong), Err(Error::MismatchedResponse));
+    }
+
+    #[test]
+    fn check_page_diff() {
+        let mut flash = [0xFFu8; FLASH_LEN];
+        let mut data = [0x11u8; 3 * INT_PAGE_SIZE];
+        flash[0..data.len()].copy_from_slice(&data);
+        data[INT_PAGE_SIZE + 7] = 0x22;
+        let mut diff = PageDiff::new(FlashTarget::Internal, FLASH_BASE, &data).unwrap();
+        let mut sent = Vec::new();
+        while let Some(command) = diff.next_command() {
+            let response = match command {
+                Command::CrcIntFlash { address, length } => {
+                    let start = (address - FLASH_BASE) as usize;
+                    sent.push(("crc", address));
+                    Response::CrcIntFlash {
+                        crc: crc32(&flash[start..start + length as usize]),
+                    }
+                }
+                Command::WritePage { address, data } => {
+                    let start = (address - FLASH_BASE) as usize;
+                    flash[start..start + data.len()].copy_from_slice(data);
+                    sent.push(("write", address));
+                    Response::Ok
+                }
+                c => panic!("{:?}", c),
+            };
+            diff.handle_response(&response).unwrap();
+        }
+        let page = |n| FLASH_BASE + n * INT_PAGE_SIZE as u32;
+        let expected = [
+            ("crc", page(0)),
+            ("crc", page(1)),
+            ("write", page(1)),
+            ("crc", page(1)),
+            ("crc", page(2)),
+        ];
+        assert_eq!(sent, expected);
+        assert_eq!((diff.pages_written(), diff.pages_skipped()), (1, 2));
+        assert!(diff.is_done());
+        assert_eq!(&flash[0..data.len()], &data[..]);
+
+        // The write didn't take
+        let mut diff = PageDiff::new(FlashTarget::Internal, FLASH_BASE, &data).unwrap();
+        diff.handle_response(&Response::CrcIntFlash { crc: 0 }).unwrap();
+        assert_eq!(diff.handle_response(&Response::Ok), Ok(()));
+        let stale = Response::CrcIntFlash { crc: 0 };
+        assert_eq!(diff.handle_response(&stale), Err(Error::CrcMismatch));
+        assert_eq!(diff.handle_response(&Response::Pong), Err(Error::MismatchedResponse));
+    }
+
+    #[test]
+    fn check_verify_image() {
+        let data = [0x11u8; 2 * INT_PAGE_SIZE];
+        let mut flash = [0xFFu8; FLASH_LEN];
+        flash[256..256 + data.len()].copy_from_slice(&data);
+        flash[INT_PAGE_SIZE + 100] = 0x22;
+        // Starts partway into a page, so the ranges are 256, 512 and 256
+        // bytes long
+        let start = FLASH_BASE + 256;
+        let mut verify = VerifyImage::new(FlashTarget::Internal, start, &data).unwrap();
+        let mut ranges = Vec::new();
+        let mut mismatches = Vec::new();
+        while let Some(command) = verify.next_command() {
+            let response = match command {
+                Command::CrcIntFlash { address, length } => {
+                    let from = (address - FLASH_BASE) as usize;
+                    ranges.push((address, length));
+                    Response::CrcIntFlash {
+                        crc: crc32(&flash[from..from + length as usize]),
+                    }
+                }
+                c => panic!("{:?}", c),
+            };
+            mismatches.extend(verify.handle_response(&response).unwrap());
+        }
+        assert!(verify.is_done());
+        assert_eq!(verify.num_pages(), 3);
+        assert_eq!(ranges, [(start, 256), (FLASH_BASE + 512, 512), (FLASH_BASE + 1024, 256)]);
+        assert_eq!(
+            mismatches,
+            [Mismatch {
+                address: FLASH_BASE + 512,
+                length: 512,
+            }]
+        );
+        assert_eq!(verify.mismatches(), 1);
+        assert_eq!(
+            verify.handle_response(&Response::CrcIntFlash { crc: 0 }),
+            Err(Error::MismatchedResponse)
+        );
+
+        let mut verify = VerifyImage::new(FlashTarget::Internal, FLASH_BASE, &data).unwrap();
+        assert_eq!(verify.handle_response(&Response::Ok), Err(Error::MismatchedResponse));
+        assert_eq!(
+            verify.handle_response(&Response::BadAddress),
+            Err(Error::Refused)
+        );
+        assert!(VerifyImage::new(FlashTarget::Internal, 0xFFFF_FF00, &data).is_err());
+        assert!(VerifyImage::new(FlashTarget::Internal, 0, &[]).unwrap().is_done());
+    }
+
+    #[test]
+    fn check_read_paginator() {
+        let flash: Vec<u8> = (0..FLASH_LEN).map(|i| i as u8).collect();
+        let mut buffer = [0u8; 1000];
+        let mut reader =
+            ReadPaginator::new(FlashTarget::Internal, FLASH_BASE + 10, &mut buffer).unwrap();
+        reader.set_chunk_len(300);
+        assert_eq!(reader.num_chunks(), 4);
+        let mut sent = Vec::new();
+        let mut dropped = false;
+        while let Some(command) = reader.next_command() {
+            match command {
+                Command::ReadRange { address, length } => {
+                    sent.push((address - FLASH_BASE, length));
+                    // Lose the second chunk once, and cut the third short
+                    if sent.len() == 2 && !dropped {
+                        dropped = true;
+                        reader.timed_out().unwrap();
+                        continue;
+                    }
+                    let start = (address - FLASH_BASE) as usize;
+                    let mut end = start + length as usize;
+                    if sent.len() == 4 {
+                        end -= 1;
+                    }
+                    let response = Response::ReadRange {
+                        data: &flash[start..end],
+                    };
+                    reader.handle_response(&response).unwrap();
+                }
+                c => panic!("{:?}", c),
+            }
+        }
+        assert!(reader.is_done());
+        assert_eq!(reader.bytes_read(), 1000);
+        assert_eq!(reader.retries(), 2);
+        assert_eq!(sent, [(10, 300), (310, 300), (310, 300), (610, 300), (610, 300), (910, 100)]);
+        assert_eq!(&buffer[..], &flash[10..1010]);
+
+        // A chunk which always fails
+        let mut buffer = [0u8; 10];
+        let mut reader = ReadPaginator::new(FlashTarget::Internal, 0, &mut buffer).unwrap();
+        reader.set_max_attempts(2);
+        assert_eq!(reader.handle_response(&Response::BadAddress), Ok(()));
+        assert_eq!(reader.handle_response(&Response::BadAddress), Err(Error::Refused));
+        assert_eq!(reader.next_command(), None);
+        assert_eq!(reader.timed_out(), Err(Error::Refused));
+        assert!(!reader.is_done());
+    }
+
+    #[test]
+    #[cfg(feature = "attributes")]
+    fn check_find_attr() {
+        let mut find = FindAttr::new("board").unwrap();
+        let mut replies = [
+            Response::GetAttr {
+                key: b"arch\0\0\0\0",
+                value: b"cortex-m4",
+            },
+            // An empty slot with the right key
+            Response::GetAttr {
+                key: b"board\0\0\0",
+                value: b"",
+            },
+            Response::GetAttr {
+                key: b"board\0\0\0",
+                value: b"hail",
+            },
+        ]
+        .iter();
+        let mut sent = Vec::new();
+        let mut found = None;
+        while let Some(command) = find.next_command() {
+            sent.push(command);
+            if sent.len() == 2 {
+                find.slot_empty();
+                continue;
+            }
+            found = find.handle_response(replies.next().unwrap()).unwrap();
+        }
+        assert_eq!(sent, [0, 1, 2, 3].map(|index| Command::GetAttr { index }));
+        assert_eq!(
+            found,
+            Some(Attribute {
+                index: 3,
+                key: b"board",
+                value: b"hail",
+            })
+        );
+        assert!(find.is_done());
+
+        let mut find = FindAttr::new(b"missing\0").unwrap();
+        find.set_num_slots(2);
+        let empty = Response::GetAttr {
+            key: b"\0\0\0\0\0\0\0\0",
+            value: b"",
+        };
+        assert_eq!(find.handle_response(&empty), Ok(None));
+        assert_eq!(find.handle_response(&Response::Pong), Err(Error::MismatchedResponse));
+        assert_eq!(find.handle_response(&empty), Ok(None));
+        assert_eq!(find.next_command(), None);
+        assert!(FindAttr::new("too-long-key").is_err());
+    }
+
+    #[test]
+    fn check_sync_up() {
+        let mut sync = SyncUp::new();
+        sync.set_max_attempts(3);
+        assert_eq!(sync.next_step(), SyncStep::Flush(&[0x00, 0xFC, 0x05]));
+        assert_eq!(sync.next_step(), SyncStep::Send(Command::Ping));
+        sync.timed_out();
+        assert_eq!(sync.handle_response(&Response::Ok), Err(Error::MismatchedResponse));
+        assert_eq!(sync.next_step(), SyncStep::Send(Command::Ping));
+        assert_eq!(sync.handle_response(&Response::Pong), Ok(()));
+        assert_eq!(sync.next_step(), SyncStep::Finished(Ok(())));
+        assert_eq!(sync.attempts(), 3);
+
+        let mut sync = SyncUp::new();
+        sync.set_max_attempts(2);
+        sync.next_step();
+        sync.timed_out();
+        sync.timed_out();
+        assert_eq!(sync.next_step(), SyncStep::Finished(Err(Error::Refused)));
+    }
+}